@@ -11,5 +11,5 @@ PROGRAMDIR=
 # cargo build --color=never --no-default-features --features "jit" && \
 # cargo test --color=never --features "jit" -- --nocapture && \
 # cargo run --color=never --release --no-default-features --features "jit" -- ./test/$PROGRAMDIR/$PROGRAM.bin
-cargo run --color=never --release --features "jit" -- ./test/$PROGRAMDIR/$PROGRAM.bin
+cargo run --color=never --release --features "jit" --bin vm-gui -- ./test/$PROGRAMDIR/$PROGRAM.bin
 # cargo test -- --nocapture
@@ -0,0 +1,531 @@
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::panic;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+use harness::{AdcDevice, Button, Harness};
+use util::parse_num;
+
+const DEFAULT_MAX_CYCLES: u64 = 100_000_000;
+// the time limit is only checked every this many cycles
+const TIME_CHECK_INTERVAL: u64 = 100_000;
+// the bytes of the uart output, which are kept for the report
+const UART_CAPTURE: usize = 64 * 1024;
+
+/// an input to the firmware at a given cycle
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stimulus {
+    Press(Button),
+    Release(Button),
+    Adc(AdcDevice, u16),
+    Uart(Vec<u8>),
+}
+
+/// a single firmware run of a manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunSpec {
+    pub name: String,
+    pub firmware: OsString,
+    pub max_cycles: u64,
+    pub timeout: Option<Duration>,
+    pub halt_on_nop: bool,
+    // the firmware may call the host, e.g. to exit
+    pub semihosting: bool,
+    // sorted by the cycle
+    pub stimuli: Vec<(u64, Stimulus)>,
+    pub expect_uart: Option<String>,
+    pub expect_leds: Option<u8>,
+    pub expect_seg7: Option<String>,
+    // the status of an exit over semihosting
+    pub expect_exit: Option<u8>,
+}
+
+impl RunSpec {
+    fn new(name: &str) -> RunSpec {
+        RunSpec {
+            name: name.to_string(),
+            firmware: OsString::new(),
+            max_cycles: DEFAULT_MAX_CYCLES,
+            timeout: None,
+            halt_on_nop: false,
+            semihosting: false,
+            stimuli: Vec::new(),
+            expect_uart: None,
+            expect_leds: None,
+            expect_seg7: None,
+            expect_exit: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Passed,
+    Failed(String),
+    Error(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct RunResult {
+    pub name: String,
+    pub status: Status,
+    pub cycles: u64,
+    pub duration: Duration,
+    pub uart: String,
+}
+
+fn parse_error(file: &Path, line: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", file.display(), line, msg))
+}
+
+fn parse_button(s: &str) -> Option<Button> {
+    match s {
+        "button0" => Some(Button::Button0),
+        "button1" => Some(Button::Button1),
+        _ => None,
+    }
+}
+
+fn unescape(s: &str) -> Vec<u8> {
+    s.replace("\\n", "\n").replace("\\r", "\r").replace("\\t", "\t").into_bytes()
+}
+
+fn parse_stimulus(key: &str, val: &str) -> Option<(u64, Stimulus)> {
+    let mut args = val.splitn(2, ' ');
+    let cycle = args.next().and_then(parse_num)?;
+    let arg = args.next().unwrap_or("").trim();
+    let stimulus = match key {
+        "press" => Stimulus::Press(parse_button(arg)?),
+        "release" => Stimulus::Release(parse_button(arg)?),
+        "adc" => {
+            let mut args = arg.split_whitespace();
+            let device = match args.next() {
+                Some("poti") => AdcDevice::Poti,
+                Some("photo") => AdcDevice::Photo,
+                _ => return None,
+            };
+            Stimulus::Adc(device, args.next().and_then(parse_num)? as u16)
+        }
+        "send" => Stimulus::Uart(unescape(arg)),
+        _ => return None,
+    };
+    Some((cycle, stimulus))
+}
+
+impl Stimulus {
+    pub fn apply(&self, h: &mut Harness) {
+        match *self {
+            Stimulus::Press(button) => h.board().press(button),
+            Stimulus::Release(button) => h.board().release(button),
+            Stimulus::Adc(device, mv) => h.board().set_adc(device, mv),
+            Stimulus::Uart(ref bytes) => h.send_uart(bytes),
+        }
+    }
+}
+
+/// reads a board file with the inputs for a single run,
+/// it contains the press, release, adc and send entries of a manifest
+/// without a section. The result is sorted by the cycle.
+pub fn read_board(file: &Path) -> io::Result<Vec<(u64, Stimulus)>> {
+    let mut content = String::new();
+    File::open(file)?.read_to_string(&mut content)?;
+
+    let mut stimuli = Vec::new();
+    for (nr, line) in content.lines().enumerate().map(|(nr, l)| (nr + 1, l.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut kv = line.splitn(2, '=').map(|s| s.trim());
+        let key = kv.next().unwrap_or("");
+        let val = kv.next().ok_or_else(|| parse_error(file, nr, "expected key = value"))?;
+        stimuli.push(parse_stimulus(key, val)
+                     .ok_or_else(|| parse_error(file, nr, &format!("invalid entry: {}", line)))?);
+    }
+    stimuli.sort_by_key(|s| s.0);
+    Ok(stimuli)
+}
+
+/// reads a manifest, which describes the runs in sections like this:
+///
+/// ```text
+/// [calc]
+/// firmware = calc/calc.elf
+/// max_cycles = 10000000
+/// timeout = 10
+/// halt_on_nop = false
+/// semihosting = false
+/// press = 1000 button0
+/// release = 2000 button0
+/// adc = 0 poti 2500
+/// send = 5000 hello\n
+/// expect_uart = calc/calc.expected
+/// expect_leds = 0xf0
+/// expect_seg7 = 42
+/// expect_exit = 0
+/// ```
+///
+/// Paths are relative to the manifest, the stimuli are given with the cycle
+/// at which they are applied.
+pub fn read_manifest(file: &Path) -> io::Result<Vec<RunSpec>> {
+    let mut content = String::new();
+    File::open(file)?.read_to_string(&mut content)?;
+    let dir = file.parent().unwrap_or(Path::new("."));
+
+    let mut specs: Vec<RunSpec> = Vec::new();
+    for (nr, line) in content.lines().enumerate().map(|(nr, l)| (nr + 1, l.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            specs.push(RunSpec::new(&line[1..line.len() - 1]));
+            continue;
+        }
+
+        let spec = specs.last_mut().ok_or_else(|| parse_error(file, nr, "entry outside of a section"))?;
+        let mut kv = line.splitn(2, '=').map(|s| s.trim());
+        let key = kv.next().unwrap_or("");
+        let val = kv.next().ok_or_else(|| parse_error(file, nr, "expected key = value"))?;
+        let invalid = || parse_error(file, nr, &format!("invalid value for {}: {}", key, val));
+
+        match key {
+            "firmware" => spec.firmware = dir.join(val).into_os_string(),
+            "max_cycles" => spec.max_cycles = parse_num(val).ok_or_else(&invalid)?,
+            "timeout" => spec.timeout = Some(Duration::from_secs(parse_num(val).ok_or_else(&invalid)?)),
+            "halt_on_nop" => spec.halt_on_nop = val == "true",
+            "semihosting" => spec.semihosting = val == "true",
+            "press" | "release" | "adc" | "send" => {
+                spec.stimuli.push(parse_stimulus(key, val).ok_or_else(&invalid)?);
+                spec.stimuli.sort_by_key(|s| s.0);
+            }
+            "expect_uart" => {
+                let mut expected = String::new();
+                File::open(dir.join(val))?.read_to_string(&mut expected)?;
+                spec.expect_uart = Some(expected);
+            }
+            "expect_leds" => spec.expect_leds = Some(parse_num(val).ok_or_else(&invalid)? as u8),
+            "expect_seg7" => spec.expect_seg7 = Some(val.to_string()),
+            "expect_exit" => spec.expect_exit = Some(parse_num(val).ok_or_else(&invalid)? as u8),
+            _ => return Err(parse_error(file, nr, &format!("unknown key: {}", key))),
+        }
+    }
+
+    for spec in specs.iter() {
+        if spec.firmware.is_empty() {
+            return Err(parse_error(file, 0, &format!("no firmware given for {}", spec.name)));
+        }
+    }
+    Ok(specs)
+}
+
+/// executes a single run and checks the expectations afterwards
+pub fn run(spec: &RunSpec) -> RunResult {
+    let start = Instant::now();
+    let mut h = match Harness::new(spec.firmware.clone()) {
+        Ok(h) => h,
+        Err(e) => return RunResult {
+            name: spec.name.clone(), status: Status::Error(format!("{:?}: {}", spec.firmware, e)),
+            cycles: 0, duration: start.elapsed(), uart: String::new()
+        }
+    };
+    h.cpu_mut().set_halt_on_nop(spec.halt_on_nop);
+    h.set_semihosting(spec.semihosting);
+    // enough to see, that the output is longer than expected
+    let capture = spec.expect_uart.as_ref().map_or(0, |e| e.len() + 1).max(UART_CAPTURE);
+    h.cpu_mut().mem_mut().uart_mut().set_capture(capture);
+
+    let mut stimuli = spec.stimuli.iter().peekable();
+    let mut timed_out = false;
+    loop {
+        while let Some(&&(cycle, ref stimulus)) = stimuli.peek() {
+            if cycle > h.cycles() {
+                break;
+            }
+            stimulus.apply(&mut h);
+            stimuli.next();
+        }
+
+        if h.cycles() >= spec.max_cycles {
+            break;
+        }
+        let next_stop = stimuli.peek().map(|s| s.0).unwrap_or(spec.max_cycles)
+            .min(spec.max_cycles).min(h.cycles() + TIME_CHECK_INTERVAL);
+        // no need to continue, when the output can't match anymore
+        let expected_len = match spec.expect_uart {
+            Some(ref expected) if stimuli.peek().is_none() => expected.len(),
+            _ => usize::MAX,
+        };
+        if h.run_until(next_stop - h.cycles(), |h| h.cpu().mem().uart().output().len() >= expected_len) {
+            break;
+        }
+        if h.cycles() < next_stop {
+            // the cpu halted
+            break;
+        }
+        if spec.timeout.is_some_and(|t| start.elapsed() > t) {
+            timed_out = true;
+            break;
+        }
+    }
+
+    let uart = h.uart_output();
+    let mut failures = Vec::new();
+    if timed_out {
+        failures.push(format!("timeout after {} cycles", h.cycles()));
+    }
+    if let Some(ref expected) = spec.expect_uart {
+        if *expected != uart {
+            failures.push(format!("unexpected uart output: {:?}, expected {:?}", uart, expected));
+        }
+    }
+    if let Some(expected) = spec.expect_leds {
+        if h.board().leds() != expected {
+            failures.push(format!("unexpected leds: {:#04x}, expected {:#04x}", h.board().leds(), expected));
+        }
+    }
+    if let Some(expected) = spec.expect_exit {
+        match h.exit_status() {
+            Some(status) if status == expected => {}
+            Some(status) => failures.push(format!("unexpected exit status: {}, expected {}", status, expected)),
+            None => failures.push(format!("no exit, expected status {}", expected)),
+        }
+    }
+    if let Some(ref expected) = spec.expect_seg7 {
+        if h.board().seg7_text() != *expected {
+            failures.push(format!("unexpected seven segment display: {:?}, expected {:?}",
+                                  h.board().seg7_text(), expected));
+        }
+    }
+
+    RunResult {
+        name: spec.name.clone(),
+        status: if failures.is_empty() { Status::Passed } else { Status::Failed(failures.join("\n")) },
+        cycles: h.cycles(),
+        duration: start.elapsed(),
+        uart: uart,
+    }
+}
+
+// a panic of the vm only fails its own run
+fn run_caught(spec: &RunSpec) -> RunResult {
+    let start = Instant::now();
+    panic::catch_unwind(|| run(spec)).unwrap_or_else(|payload| {
+        let msg = payload.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        RunResult {
+            name: spec.name.clone(), status: Status::Error(format!("the vm panicked: {}", msg)),
+            cycles: 0, duration: start.elapsed(), uart: String::new()
+        }
+    })
+}
+
+/// executes all runs on the given number of threads,
+/// the results are in the same order as the specs
+pub fn run_all(specs: Vec<RunSpec>, threads: usize) -> Vec<RunResult> {
+    let count = specs.len();
+    let queue = Arc::new(Mutex::new(specs.into_iter().enumerate().collect::<VecDeque<_>>()));
+    let (tx, rx) = channel();
+
+    let workers: Vec<_> = (0..threads.max(1).min(count)).map(|_| {
+        let queue = queue.clone();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            loop {
+                let next = queue.lock().unwrap().pop_front();
+                match next {
+                    Some((i, spec)) => tx.send((i, run_caught(&spec))).unwrap(),
+                    None => break,
+                }
+            }
+        })
+    }).collect();
+    drop(tx);
+
+    let mut results: Vec<_> = rx.iter().collect();
+    for worker in workers {
+        worker.join().expect("batch worker panicked");
+    }
+    results.sort_by_key(|r| r.0);
+    results.into_iter().map(|r| r.1).collect()
+}
+
+/// the number of threads, which should be used for run_all
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+fn xml_escape(s: &str) -> String {
+    s.chars().map(|c| match c {
+        '&' => "&amp;".to_string(),
+        '<' => "&lt;".to_string(),
+        '>' => "&gt;".to_string(),
+        '"' => "&quot;".to_string(),
+        '\'' => "&apos;".to_string(),
+        c if (c as u32) < 0x20 && c != '\n' && c != '\t' && c != '\r' => format!("&#{};", c as u32),
+        c => c.to_string(),
+    }).collect()
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+pub fn write_junit<W: Write>(results: &[RunResult], out: &mut W) -> io::Result<()> {
+    let failures = results.iter().filter(|r| matches!(r.status, Status::Failed(_))).count();
+    let errors = results.iter().filter(|r| matches!(r.status, Status::Error(_))).count();
+    let time = results.iter().map(|r| secs(r.duration)).sum::<f64>();
+
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(out, "<testsuite name=\"avr-vm\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+             results.len(), failures, errors, time)?;
+    for r in results {
+        writeln!(out, "  <testcase name=\"{}\" classname=\"avr-vm\" time=\"{:.3}\">",
+                 xml_escape(&r.name), secs(r.duration))?;
+        match r.status {
+            Status::Passed => {}
+            Status::Failed(ref msg) =>
+                writeln!(out, "    <failure message=\"{}\"/>", xml_escape(msg))?,
+            Status::Error(ref msg) =>
+                writeln!(out, "    <error message=\"{}\"/>", xml_escape(msg))?,
+        }
+        writeln!(out, "    <system-out>{}</system-out>", xml_escape(&r.uart))?;
+        writeln!(out, "  </testcase>")?;
+    }
+    writeln!(out, "</testsuite>")
+}
+
+pub fn write_summary<W: Write>(results: &[RunResult], out: &mut W) -> io::Result<()> {
+    for r in results {
+        let (status, msg) = match r.status {
+            Status::Passed => ("ok", None),
+            Status::Failed(ref msg) => ("FAILED", Some(msg)),
+            Status::Error(ref msg) => ("ERROR", Some(msg)),
+        };
+        writeln!(out, "{} ... {} ({} cycles, {:.3}s)", r.name, status, r.cycles, secs(r.duration))?;
+        if let Some(msg) = msg {
+            for line in msg.lines() {
+                writeln!(out, "    {}", line)?;
+            }
+        }
+    }
+    let passed = results.iter().filter(|r| r.status == Status::Passed).count();
+    writeln!(out, "\n{} runs: {} passed, {} failed", results.len(), passed, results.len() - passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{create_dir, File};
+    use std::io::prelude::*;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use rand::{self, Rng};
+    use harness::{AdcDevice, Button};
+    use memory::PROGRAM_SIZE;
+    use super::{read_board, read_manifest, run_all, write_junit, Status, Stimulus};
+
+    // ldi r16, 'A'; out UDR, r16; rjmp .-2
+    const PRINT_A: [u8; 6] = [0x01, 0xe4, 0x0c, 0xb9, 0xfe, 0xcf];
+    // exits with status 42 over semihosting
+    const EXIT: [u8; 14] = [0x0a, 0xe2, 0x00, 0x93, 0xf0, 0xff, 0x01, 0xe0, 0x00, 0x93, 0xf6, 0xff, 0xff, 0xcf];
+
+    // a new directory for the files of a test, the tests run concurrently
+    fn tmp_dir() -> PathBuf {
+        let s = rand::thread_rng().gen_ascii_chars().take(10).collect::<String>();
+        let dir = temp_dir().join(format!("vm-batch-{}", s));
+        create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn write_tmp(dir: &Path, name: &str, content: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        File::create(&path).unwrap().write_all(content).unwrap();
+        path
+    }
+
+    #[test]
+    fn manifest() {
+        let dir = tmp_dir();
+        write_tmp(&dir, "parse.expected", b"AAA");
+        let path = write_tmp(&dir, "parse.ini", b"# test\n[a]\nfirmware = a.bin\nmax_cycles = 0x100\ntimeout = 3\n\
+                                           press = 200 button1\nadc = 100 poti 2500\nsend = 300 hi\\n\n\
+                                           expect_uart = parse.expected\nexpect_leds = 0xf0\n\n\
+                                           [b]\nfirmware = b.bin\nhalt_on_nop = true\nsemihosting = true\nexpect_seg7 = 42\n\
+                                           expect_exit = 3\n");
+        let specs = read_manifest(&path).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].name, "a");
+        assert_eq!(specs[0].firmware, dir.join("a.bin").into_os_string());
+        assert_eq!(specs[0].max_cycles, 256);
+        assert_eq!(specs[0].timeout, Some(Duration::from_secs(3)));
+        assert_eq!(specs[0].stimuli, vec![(100, Stimulus::Adc(AdcDevice::Poti, 2500)),
+                                          (200, Stimulus::Press(Button::Button1)),
+                                          (300, Stimulus::Uart(b"hi\n".to_vec()))]);
+        assert_eq!(specs[0].expect_uart, Some("AAA".to_string()));
+        assert_eq!(specs[0].expect_leds, Some(0xf0));
+        assert!(!specs[0].semihosting);
+        assert!(specs[1].halt_on_nop);
+        assert!(specs[1].semihosting);
+        assert_eq!(specs[1].expect_seg7, Some("42".to_string()));
+        assert_eq!(specs[1].expect_exit, Some(3));
+
+        let path = write_tmp(&dir, "invalid.ini", b"[a]\nfirmware = a.bin\nfoo = bar\n");
+        assert!(read_manifest(&path).is_err());
+    }
+
+    #[test]
+    fn board() {
+        let dir = tmp_dir();
+        let path = write_tmp(&dir, "board.txt", b"release = 20 button0\npress = 10 button0\nadc = 0 photo 0x100\n");
+        assert_eq!(read_board(&path).unwrap(), vec![(0, Stimulus::Adc(AdcDevice::Photo, 256)),
+                                                    (10, Stimulus::Press(Button::Button0)),
+                                                    (20, Stimulus::Release(Button::Button0))]);
+        let path = write_tmp(&dir, "board-invalid.txt", b"press = 10 button2\n");
+        assert!(read_board(&path).is_err());
+    }
+
+    #[test]
+    fn batch() {
+        let dir = tmp_dir();
+        write_tmp(&dir, "print.bin", &PRINT_A);
+        write_tmp(&dir, "print.expected", b"AAA");
+        write_tmp(&dir, "print-wrong.expected", b"AB");
+        write_tmp(&dir, "exit.bin", &EXIT);
+        write_tmp(&dir, "large.bin", &[0; PROGRAM_SIZE + 2]);
+        let path = write_tmp(&dir, "run.ini", b"[ok]\nfirmware = print.bin\nexpect_uart = print.expected\n\
+                                               [wrong]\nfirmware = print.bin\nexpect_uart = print-wrong.expected\n\
+                                               [limit]\nfirmware = print.bin\nmax_cycles = 8\n\
+                                               [missing]\nfirmware = missing.bin\n\
+                                               [exit]\nfirmware = exit.bin\nsemihosting = true\nexpect_exit = 42\n\
+                                               [disabled]\nfirmware = exit.bin\nexpect_exit = 42\n\
+                                               [large]\nfirmware = large.bin\n");
+        let results = run_all(read_manifest(&path).unwrap(), 3);
+        assert_eq!(results.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(),
+                   vec!["ok", "wrong", "limit", "missing", "exit", "disabled", "large"]);
+        assert_eq!(results[0].status, Status::Passed);
+        assert_eq!(results[0].uart, "AAA");
+        assert!(matches!(results[1].status, Status::Failed(_)));
+        assert_eq!(results[2].status, Status::Passed);
+        assert_eq!(results[2].cycles, 8);
+        assert_eq!(results[2].uart, "AAAA");
+        assert!(matches!(results[3].status, Status::Error(_)));
+        assert_eq!(results[4].status, Status::Passed);
+        assert_eq!(results[4].cycles, 4);
+        // the host can't be called without semihosting
+        assert!(matches!(results[5].status, Status::Failed(_)));
+        assert!(matches!(results[6].status, Status::Error(_)));
+
+        let mut junit = Vec::new();
+        write_junit(&results, &mut junit).unwrap();
+        let junit = String::from_utf8(junit).unwrap();
+        assert!(junit.contains("tests=\"7\" failures=\"2\" errors=\"2\""));
+        assert!(junit.contains("<testcase name=\"wrong\""));
+        assert!(junit.contains("&quot;AB&quot;"));
+    }
+}
@@ -0,0 +1,117 @@
+use data::Instruction;
+use data::Instruction::*;
+
+/// a call or an interrupt on the shadow call stack
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Frame {
+    // byte address of the called function or the vector
+    pub func: usize,
+    // byte address of the call, for an interrupt of the interrupted instruction
+    pub call_pc: usize,
+    // word address, to which the ret or reti must return
+    pub ret: usize,
+    // entered by an interrupt
+    pub isr: bool,
+}
+
+/// a ret or reti, which didn't return behind the call of its frame
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct ReturnMismatch {
+    // byte address of the ret
+    pub pc: usize,
+    // byte addresses
+    pub expected: usize,
+    pub actual: usize,
+}
+
+/// a shadow call stack, which follows call, rcall, icall and the interrupts and
+/// ret and reti independent of the stack in the sram, so a return to another
+/// address than the one after the call shows that the stack was overwritten
+///
+/// code, which changes the sp or the return address on purpose like setjmp
+/// or task switches, isn't followed
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack::default()
+    }
+
+    /// records a step of the cpu, which executed instr at the byte address pc and
+    /// continues at the word address ip, interrupted is the word address, to which
+    /// an interrupt returns, if it jumped to pc before
+    pub fn record(&mut self, pc: usize, instr: Option<Instruction>, interrupted: Option<usize>,
+                  ip: usize) -> Option<ReturnMismatch> {
+        if let Some(ret) = interrupted {
+            self.frames.push(Frame { func: pc, call_pc: ret << 1, ret: ret, isr: true });
+        }
+        let ret = match instr {
+            // avr-gcc reserves space on the stack with rcall .+0
+            Some(RCALL(0)) => return None,
+            Some(CALL(_)) => (pc >> 1) + 2,
+            Some(RCALL(_)) | Some(ICALL) => (pc >> 1) + 1,
+            Some(RET) | Some(RETI) => {
+                let frame = self.frames.pop()?;
+                if frame.ret == ip {
+                    return None;
+                }
+                return Some(ReturnMismatch { pc: pc, expected: frame.ret << 1, actual: ip << 1 });
+            }
+            _ => return None,
+        };
+        self.frames.push(Frame { func: ip << 1, call_pc: pc, ret: ret, isr: false });
+        None
+    }
+
+    /// the calls and interrupts, which didn't return yet, the outermost first
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// the byte addresses of the instruction at pc and of the calls and the
+    /// interrupted instructions below it, the innermost first, the flag is set
+    /// for the frames, which were interrupted
+    pub fn backtrace(&self, pc: usize) -> Vec<(usize, bool)> {
+        let mut trace = vec![(pc, false)];
+        trace.extend(self.frames.iter().rev().map(|f| (f.call_pc, f.isr)));
+        trace
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::Cpu;
+    use firmware::Firmware;
+    use memory::Memory;
+    use stack::StackErrorKind;
+    use super::{CallStack, Frame};
+
+    #[test]
+    fn backtrace() {
+        // main: rcall f; rjmp main; f: rcall g; ret; g: push r0; ret
+        let program = [0x01, 0xd0, 0xfe, 0xcf, 0x01, 0xd0, 0x08, 0x95, 0x0f, 0x92, 0x08, 0x95];
+        let mut cpu = Cpu::new(Memory::from_firmware(&Firmware::from_bytes(program.to_vec()).unwrap(), None), false);
+        cpu.mem_mut().set_sp(0x85f);
+        cpu.set_call_stack(Some(CallStack::new()));
+        cpu.step();
+        cpu.step();
+        let stack = cpu.call_stack().unwrap();
+        assert_eq!(stack.frames(), &[Frame { func: 4, call_pc: 0, ret: 1, isr: false },
+                                     Frame { func: 8, call_pc: 4, ret: 3, isr: false }]);
+        assert_eq!(stack.backtrace(cpu.ip() << 1), vec![(8, false), (4, false), (0, false)]);
+
+        // the push makes the ret of g return to 0x0000
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.stack_error().map(|e| (e.kind, e.pc)),
+                   Some((StackErrorKind::UnexpectedReturn { expected: 6, actual: 0 }, 10)));
+        assert_eq!(cpu.call_stack().unwrap().frames().len(), 1);
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::prelude::*;
+use data::Instruction;
+use data::Instruction::*;
+use decoder::decode;
+use dwarf::Lines;
+use firmware::{SymbolKind, Symbols};
+
+/// the executed instructions of a run and the taken and not taken branches
+/// of the conditional instructions, branches and skips
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    // the instruction at every word address
+    code: Vec<Instruction>,
+    // how often every instruction was executed
+    counts: Vec<u64>,
+    // how often every conditional instruction was taken and not taken
+    branches: Vec<(u64, u64)>,
+}
+
+/// the lines and branches of the source files, which were covered
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct Summary {
+    pub lines_hit: usize,
+    pub lines: usize,
+    pub branches_hit: usize,
+    pub branches: usize,
+}
+
+// the coverage of a source file
+#[derive(Default)]
+struct FileCoverage {
+    // the maximum count of the instructions of every line
+    lines: BTreeMap<u32, u64>,
+    // the line and the word address of the conditional instructions
+    branches: Vec<(u32, usize)>,
+    // line, name and the number of calls
+    funcs: Vec<(u32, String, u64)>,
+}
+
+fn is_branch(instr: Instruction) -> bool {
+    matches!(instr, BRBC_S(..) | CPSE(..) | SBIC_S(..) | SBR(..))
+}
+
+impl Coverage {
+    pub fn new(program: &[u8]) -> Coverage {
+        let code: Vec<Instruction> = decode(program.iter().cloned()).collect();
+        Coverage { counts: vec![0; code.len()], branches: vec![(0, 0); code.len()], code: code }
+    }
+
+    /// records the instruction at the byte address pc, after which the cpu
+    /// continues at the word address ip
+    pub fn record(&mut self, pc: usize, ip: usize) {
+        let i = pc >> 1;
+        if i >= self.code.len() {
+            return;
+        }
+        self.counts[i] += 1;
+        if is_branch(self.code[i]) {
+            if ip != i + 1 {
+                self.branches[i].0 += 1;
+            } else {
+                self.branches[i].1 += 1;
+            }
+        }
+    }
+
+    /// how often the instruction at the byte address was executed
+    pub fn count(&self, addr: u32) -> u64 {
+        self.counts.get((addr as usize) >> 1).cloned().unwrap_or(0)
+    }
+
+    /// how often the branch or skip at the byte address was taken and not taken
+    pub fn branch(&self, addr: u32) -> Option<(u64, u64)> {
+        let i = (addr as usize) >> 1;
+        self.code.get(i).filter(|&&instr| is_branch(instr)).map(|_| self.branches[i])
+    }
+
+    fn files<'a>(&self, lines: &'a Lines, symbols: &Symbols) -> BTreeMap<&'a str, FileCoverage> {
+        let mut files: BTreeMap<&str, FileCoverage> = BTreeMap::new();
+        for (i, &instr) in self.code.iter().enumerate() {
+            if instr == SecondOpWord {
+                continue;
+            }
+            if let Some((file, line)) = lines.line_at((i << 1) as u32) {
+                let cov = files.entry(file).or_default();
+                let count = cov.lines.entry(line).or_insert(0);
+                *count = (*count).max(self.counts[i]);
+                if is_branch(instr) {
+                    cov.branches.push((line, i));
+                }
+            }
+        }
+        for sym in symbols.iter().filter(|s| s.kind == SymbolKind::Func) {
+            if let Some((file, line)) = lines.line_at(sym.value) {
+                files.entry(file).or_default().funcs.push((line, sym.name.clone(), self.count(sym.value)));
+            }
+        }
+        files
+    }
+
+    pub fn summary(&self, lines: &Lines, symbols: &Symbols) -> Summary {
+        let mut summary = Summary::default();
+        for cov in self.files(lines, symbols).values() {
+            summary.lines += cov.lines.len();
+            summary.lines_hit += cov.lines.values().filter(|&&c| c > 0).count();
+            summary.branches += 2 * cov.branches.len();
+            summary.branches_hit += cov.branches.iter()
+                .map(|&(_, i)| (self.branches[i].0 > 0) as usize + (self.branches[i].1 > 0) as usize)
+                .sum::<usize>();
+        }
+        summary
+    }
+
+    /// writes the coverage of the source lines in the lcov tracefile format,
+    /// as read by genhtml
+    pub fn write_lcov<W: Write + ?Sized>(&self, lines: &Lines, symbols: &Symbols, out: &mut W) -> io::Result<()> {
+        for (file, cov) in self.files(lines, symbols) {
+            writeln!(out, "TN:")?;
+            writeln!(out, "SF:{}", file)?;
+            for &(line, ref name, _) in &cov.funcs {
+                writeln!(out, "FN:{},{}", line, name)?;
+            }
+            for &(_, ref name, calls) in &cov.funcs {
+                writeln!(out, "FNDA:{},{}", calls, name)?;
+            }
+            writeln!(out, "FNF:{}", cov.funcs.len())?;
+            writeln!(out, "FNH:{}", cov.funcs.iter().filter(|f| f.2 > 0).count())?;
+            // the branches of a line are numbered as blocks
+            let mut blocks = HashMap::new();
+            let mut hit = 0;
+            for &(line, i) in &cov.branches {
+                let block = blocks.entry(line).or_insert(0);
+                *block += 1;
+                let block = *block - 1;
+                let (taken, not_taken) = self.branches[i];
+                for (branch, &count) in [taken, not_taken].iter().enumerate() {
+                    if self.counts[i] == 0 {
+                        writeln!(out, "BRDA:{},{},{},-", line, block, branch)?;
+                    } else {
+                        writeln!(out, "BRDA:{},{},{},{}", line, block, branch, count)?;
+                    }
+                    hit += (count > 0) as usize;
+                }
+            }
+            writeln!(out, "BRF:{}", 2 * cov.branches.len())?;
+            writeln!(out, "BRH:{}", hit)?;
+            for (line, count) in &cov.lines {
+                writeln!(out, "DA:{},{}", line, count)?;
+            }
+            writeln!(out, "LF:{}", cov.lines.len())?;
+            writeln!(out, "LH:{}", cov.lines.values().filter(|&&c| c > 0).count())?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::Cpu;
+    use dwarf::Lines;
+    use firmware::{Firmware, Symbol, SymbolKind, Symbols};
+    use memory::Memory;
+    use super::{Coverage, Summary};
+
+    // ldi r16, 2; loop: dec r16; brne loop; rjmp .-2
+    const PROGRAM: [u8; 8] = [0x02, 0xe0, 0x0a, 0x95, 0xf1, 0xf7, 0xff, 0xcf];
+
+    // main.c with line 1 at 0x0, line 2 at 0x2 and line 3 at 0x6
+    fn lines() -> Lines {
+        let mut header = vec![1, 1, 0xfb, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+        header.extend_from_slice(b"\0main.c\0\0\0\0\0");
+        // set_address 0, copy, then advance_pc, advance_line and copy twice, end_sequence
+        let program = [0, 3, 2, 0, 0, 1, 2, 2, 3, 1, 1, 2, 4, 3, 1, 1, 2, 2, 0, 1, 1];
+        let mut unit = vec![3, 0];
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(&program);
+        let mut bytes = (unit.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&unit);
+        Lines::parse(&bytes, &[], &[]).unwrap()
+    }
+
+    #[test]
+    fn branches() {
+        let firmware = Firmware::from_bytes(PROGRAM.to_vec()).unwrap();
+        let mut cpu = Cpu::new(Memory::from_firmware(&firmware, None), false);
+        cpu.set_coverage(Some(Coverage::new(&firmware.program)));
+        for _ in 0..8 {
+            cpu.step();
+        }
+        let coverage = cpu.coverage().unwrap();
+        assert_eq!((coverage.count(0), coverage.count(2), coverage.count(6)), (1, 2, 3));
+        assert_eq!((coverage.branch(4), coverage.branch(2)), (Some((1, 1)), None));
+
+        let symbols = Symbols::new(vec![Symbol {
+            name: "main".to_string(), value: 0, size: 8, kind: SymbolKind::Func, section: Some(".text".to_string()),
+        }]);
+        let lines = lines();
+        assert_eq!(coverage.summary(&lines, &symbols), Summary { lines_hit: 3, lines: 3, branches_hit: 2, branches: 2 });
+        let mut out = Vec::new();
+        coverage.write_lcov(&lines, &symbols, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "TN:
+SF:main.c
+FN:1,main
+FNDA:1,main
+FNF:1
+FNH:1
+BRDA:2,0,0,1
+BRDA:2,0,1,1
+BRF:2
+BRH:2
+DA:1,1
+DA:2,2
+DA:3,3
+LF:3
+LH:3
+end_of_record
+");
+        let mut out = Vec::new();
+        Coverage::new(&PROGRAM).write_lcov(&lines, &symbols, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("BRDA:2,0,0,-\nBRDA:2,0,1,-\nBRF:2\nBRH:0\n"));
+    }
+}
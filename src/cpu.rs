@@ -1,22 +1,30 @@
+use callstack::CallStack;
+use coverage::Coverage;
 use data;
 use data::{Instruction, LDType, LDMode, LPMType};
 use data::Instruction::*;
-use memory::{Memory};
-use interrupts::{PortInterrupts, TimerInterrupts};
-use util::{bit, bit16, bitneg, bitneg16};
+use fault::{Fault, FaultKind, FaultPolicies, FaultPolicy};
+use memory::{Memory, PROGRAM_SIZE};
 #[cfg(feature = "jit")]
-use std::collections::HashMap;
+use memory::SRAM_SIZE;
+use interrupts::{interrupt_flags, PortInterrupts, TimerInterrupts};
+use history::{History, MemoryWrite, ReverseStop};
+use latency::InterruptStats;
+use profile::Profiler;
+use snapshot::Snapshot;
+use stack::{StackError, StackErrorKind, StackGuard};
+use trace::{State, Tracer};
+use uninit::{used_registers, UninitRead};
 #[cfg(feature = "jit")]
-use std::{mem};
-#[cfg(feature = "jit")]
-use data::{Register, SREG};
+use uninit::written_registers;
+use watch::{Watchpoint, WatchHit};
+use util::{bit, bit16, bitneg};
+use util::bitneg16;
+use std::collections::BTreeSet;
+use std::io;
 
 #[cfg(feature = "jit")]
-use dynasmrt;
-#[cfg(feature = "jit")]
-use dynasmrt::{ExecutableBuffer, AssemblyOffset};
-#[cfg(feature = "jit")]
-use dynasmrt::DynasmApi;
+use jit::{Aarch64, Backend, Block, BlockSpec, CodeGen, Site, X64, SRAM_START};
 
 const I: usize = 7;
 const T: usize = 6;
@@ -27,92 +35,615 @@ const N: usize = 2;
 const Z: usize = 1;
 const C: usize = 0;
 
-pub struct Cpu<'a> {
+// io register of the high byte of the sp
+const SPH: u8 = 0x3e;
+// the most blocks, which a step chains, so the callers still see the cpu regularly
+#[cfg(feature = "jit")]
+const JIT_CHAIN_MAX: u64 = 1024;
+// the pc has 14 bits and wraps around
+const PC_MASK: usize = (PROGRAM_SIZE >> 1) - 1;
+
+/// the conditions, on which the cpu stops the execution
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct HaltOn {
+    pub nop: bool,
+    pub brk: bool,
+    // a sleep with disabled interrupts, the cpu would never wake up again
+    pub sleep: bool,
+    // byte address in the flash
+    pub addr: Option<usize>,
+}
+
+// the steps of the timer, which the running block of run_chained still owes
+#[cfg(feature = "jit")]
+#[derive(Debug, Copy, Clone)]
+struct TimerDebt {
+    // the word address of the last instruction, whose step is counted in steps
+    addr: usize,
+    steps: u64,
+    // the steps, which the interpreted instructions of the block already caught up
+    paid: u64,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum HaltReason {
+    Nop, Break, Sleep, Addr(usize),
+    // the firmware exited with the status over semihosting
+    Exit(u8),
+    // a fault with FaultPolicy::Halt, see Cpu::fault
+    Fault(FaultKind),
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum StepResult {
+    Running,
+    // the cpu stays halted, further steps don't execute anything
+    Halted(HaltReason),
+}
+
+impl StepResult {
+    pub fn is_running(self) -> bool {
+        self == StepResult::Running
+    }
+}
+
+pub struct Cpu {
     ip: usize,
-    mem: Memory<'a>,
-    #[allow(dead_code)]
-    steps: u32,
+    mem: Memory,
+    // every instruction takes one cycle, see TimerInterrupts
+    cycles: u64,
     // needed for tests and timing
-    halt_on_nop: bool,
-     // cpu should should stop forever, for halt on nop
-    should_halt: bool,
+    halt_on: HaltOn,
+    // cpu should stop forever, if it is set
+    halted: Option<HaltReason>,
     sleeping: bool,
+    // word addresses, the jit ends its blocks before them
+    breakpoints: BTreeSet<usize>,
+    // execute only a single instruction per step, also with the jit
+    single_step: bool,
+    // the jit doesn't run a block past this cycle
+    cycle_limit: Option<u64>,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    interrupt_stats: Option<InterruptStats>,
+    history: Option<History>,
+    // the watchpoint, which was hit by the last step
+    watch_hit: Option<WatchHit>,
+    stack_guard: Option<StackGuard>,
+    call_stack: Option<CallStack>,
+    // the error of the stack found in the last step
+    stack_error: Option<StackError>,
+    fault_policies: FaultPolicies,
+    // the first fault of the last step
+    fault: Option<Fault>,
+    // the first read of an undefined byte in the last step
+    uninit_read: Option<UninitRead>,
     port_int: PortInterrupts,
     timer_int: TimerInterrupts,
-    // we can't just save the function pointer, because then we would free
-    // the buffer and segfault, when we try to execute the function
+    // the code generator of the jit, None interprets
+    #[cfg(feature = "jit")]
+    backend: Option<Backend>,
+    // indexed by the word address
     #[cfg(feature = "jit")]
-    blocks: HashMap<usize, (ExecutableBuffer, AssemblyOffset)>
+    blocks: Vec<Option<Block>>,
+    // set while run_chained runs a block
+    #[cfg(feature = "jit")]
+    timer_debt: Option<TimerDebt>,
 }
 
-impl<'a> Cpu<'a> {
+impl Cpu {
     pub fn new(mem: Memory, halt_on_nop: bool) -> Cpu {
         #[cfg(not(feature = "jit"))]
-        {Cpu { ip: 0, mem: mem, steps: 0,
-              halt_on_nop: halt_on_nop, sleeping: false, should_halt: false,
+        {Cpu { ip: 0, mem: mem, cycles: 0,
+              halt_on: HaltOn { nop: halt_on_nop, ..HaltOn::default() }, sleeping: false, halted: None,
+              breakpoints: BTreeSet::new(), single_step: false, cycle_limit: None, tracer: None, profiler: None, coverage: None,
+              interrupt_stats: None,
+              history: None, watch_hit: None,
+              stack_guard: None, call_stack: None, stack_error: None, fault_policies: FaultPolicies::default(), fault: None,
+              uninit_read: None,
               port_int: PortInterrupts::new(), timer_int: TimerInterrupts::new(),
         }}
         #[cfg(feature = "jit")]
-        {Cpu { ip: 0, mem: mem, steps: 0,
-              halt_on_nop: halt_on_nop, sleeping: false, should_halt: false,
+        {Cpu { ip: 0, mem: mem, cycles: 0,
+              halt_on: HaltOn { nop: halt_on_nop, ..HaltOn::default() }, sleeping: false, halted: None,
+              breakpoints: BTreeSet::new(), single_step: false, cycle_limit: None, tracer: None, profiler: None, coverage: None,
+              interrupt_stats: None,
+              history: None, watch_hit: None,
+              stack_guard: None, call_stack: None, stack_error: None, fault_policies: FaultPolicies::default(), fault: None,
+              uninit_read: None,
               port_int: PortInterrupts::new(), timer_int: TimerInterrupts::new(),
-              blocks: HashMap::new()
+              backend: Backend::host(), blocks: Vec::new(), timer_debt: None,
         }}
     }
 
-    pub fn step(&mut self) -> bool {
-        if self.should_halt {
-            return false;
+    pub fn step(&mut self) -> StepResult {
+        if let Some(reason) = self.halted {
+            return StepResult::Halted(reason);
+        }
+        if self.halt_on.addr == Some(self.ip << 1) && !self.sleeping {
+            self.halted = Some(HaltReason::Addr(self.ip << 1));
+            return StepResult::Halted(HaltReason::Addr(self.ip << 1));
         }
 
-        #[cfg(debug_assertions)]
-        {
-            if self.steps > 10000 {
-                panic!("Maximum number of steps reached!");
-            }
-            self.steps += 1;
+        if self.history.as_ref().is_some_and(|h| h.snapshot_due(self.cycles)) {
+            let snapshot = self.snapshot();
+            self.history.as_mut().unwrap().add_snapshot(snapshot);
         }
 
+        self.cycles += 1;
+        self.watch_hit = None;
+        self.stack_error = None;
+        self.fault = None;
+        self.uninit_read = None;
+        self.mem.take_fault();
+        self.mem.take_uninit_read();
+        // e.g. writes of a debugger between the steps
+        self.mem.take_watch_hit();
+        self.mem.take_accesses();
+
         self.port_int.step(&mut self.mem);
         self.timer_int.step(&mut self.mem);
+        if let Some(ref mut stats) = self.interrupt_stats {
+            stats.update_flags(interrupt_flags(&self.mem), self.cycles);
+        }
 
+        let mut interrupted = false;
+        let mut vector = None;
+        // the word address, to which an interrupt returns
+        let resume = self.ip;
         if bit(self.flags(), I) == 1 {
             if let Some(interrupt_nr) = self.pending_interrupt() {
+                interrupted = true;
+                vector = Some(interrupt_nr);
                 self.sleeping = false;
                 self.set_flags(Some(0), None, None, None, None, None, None);
                 self.mem.push16(self.ip as u16);
+                // a fault of the push, e.g. of an invalid sp, belongs to the interrupted instruction
+                if let Some(fault) = self.mem.take_fault() {
+                    self.raise(fault.kind, resume << 1, fault.addr);
+                }
                 self.ip = interrupt_nr << 1; // jump to the interrupt
             }
         }
 
+        // the pushes of an interrupt and the writes of the peripherals
+        let mut accesses = self.mem.take_accesses();
+        let pc = self.ip << 1;
+        // for the stack guard, the call stack, the uninitialized reads, the profiler and
+        // the interrupt statistics
+        let mut instr = None;
         if !self.sleeping {
+            if self.stack_guard.is_some() || self.call_stack.is_some() || self.mem.checks_uninit()
+                || self.profiler.is_some() || self.interrupt_stats.is_some() {
+                instr = Some(self.mem.get_instruction(self.ip));
+            }
+            if let Some(instr) = instr.filter(|_| self.mem.checks_uninit()) {
+                for reg in used_registers(instr) {
+                    self.mem.check_defined(reg as u16);
+                }
+            }
+            let before = match self.tracer {
+                Some(ref tracer) if tracer.traces(pc) => Some(State::capture(self)),
+                _ => None,
+            };
 
             #[cfg(not(feature = "jit"))]
-            {
-                let instr = self.mem.get_instruction(self.ip);
-                self.handle_instruction(instr);
-            }
+            self.execute_instruction();
 
             #[cfg(feature = "jit")]
             {
-                let func: extern "sysv64" fn(*mut Cpu);
-                {
-                    let mem = &self.mem;
-                    let ip = self.ip;
-                    #[cfg(debug_assertions)]
-                    println!("Executing {}", self.ip);
-                    let entry = self.blocks.entry(ip).or_insert_with(|| Cpu::compile_block(mem, ip));
-                    func = unsafe {
-                        mem::transmute(entry.0.ptr(entry.1))
-                    };
+                match self.backend {
+                    Some(backend) => {
+                        let ip_offset = self.ip_offset();
+                        if self.single_instructions() {
+                            self.run_single(backend, ip_offset);
+                        } else {
+                            self.run_chained(backend, ip_offset);
+                        }
+                        if let Some(instr) = instr.filter(|_| self.mem.checks_uninit()) {
+                            for reg in written_registers(instr) {
+                                self.mem.reg_mut(reg);
+                            }
+                        }
+                    }
+                    None => self.execute_instruction(),
                 }
-                func(self);
+            }
+
+            self.watch_hit = self.mem.take_watch_hit().map(|hit| WatchHit { pc: pc, ..hit });
+            if let Some(fault) = self.mem.take_fault() {
+                self.raise(fault.kind, pc, fault.addr);
+            }
+            self.uninit_read = self.mem.take_uninit_read().map(|read| UninitRead { pc: pc, ..read });
+
+            let executed = self.mem.take_accesses();
+            if let Some(before) = before {
+                let mut tracer = self.tracer.take().unwrap();
+                tracer.record(self, before, executed.clone());
+                self.tracer = Some(tracer);
+            }
+            accesses.extend(executed);
+            if let Some(ref mut coverage) = self.coverage {
+                coverage.record(pc, self.ip);
+            }
+        }
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.record(pc, instr, interrupted, self.ip, 1);
+        }
+        let enabled = bit(self.flags(), I) == 1;
+        if let Some(ref mut stats) = self.interrupt_stats {
+            stats.record(self.cycles, vector, pc, instr, enabled);
+        }
+        if let Some(ref mut guard) = self.stack_guard {
+            match instr {
+                // the low byte is written next, the sp in between isn't checked
+                Some(OUT(_, SPH)) => {}
+                Some(RET) | Some(RETI) => self.stack_error = guard.check(pc, self.mem.sp(), self.ip, true),
+                _ => self.stack_error = guard.check(pc, self.mem.sp(), self.ip, false),
+            }
+        }
+        if let Some(ref mut call_stack) = self.call_stack {
+            let mismatch = call_stack.record(pc, instr, interrupted.then_some(resume), self.ip);
+            if let (Some(mismatch), None) = (mismatch, self.stack_error) {
+                let kind = StackErrorKind::UnexpectedReturn { expected: mismatch.expected, actual: mismatch.actual };
+                self.stack_error = Some(StackError { kind: kind, pc: pc, sp: self.mem.sp() });
+            }
+        }
+        if self.ip > PC_MASK {
+            self.raise(FaultKind::PcOutOfRange, pc, self.ip << 1);
+            self.ip &= PC_MASK;
+        }
+        let host_call = self.mem.semihost_mut().take_call();
+        if let Some(ref mut history) = self.history {
+            for access in accesses.iter().filter(|a| a.write) {
+                history.add_write(MemoryWrite { cycles: self.cycles, pc: pc, addr: access.addr, value: access.value });
+            }
+            if let Some(call) = host_call {
+                history.add_host_call(self.cycles, call);
             }
         }
 
+        if let Some(status) = self.mem.semihost().exit_status() {
+            self.halted = Some(HaltReason::Exit(status));
+        }
+        if let Some(fault) = self.fault {
+            if self.fault_policies.get(fault.kind) == FaultPolicy::Halt {
+                self.halted = Some(HaltReason::Fault(fault.kind));
+            }
+        }
+        match self.halted {
+            Some(reason) => StepResult::Halted(reason),
+            None => StepResult::Running,
+        }
+    }
+
+    /// stop the execution at the next NOP,
+    /// step returns false afterwards
+    pub fn set_halt_on_nop(&mut self, halt_on_nop: bool) {
+        self.halt_on.nop = halt_on_nop;
+    }
+
+    pub fn set_halt_on(&mut self, halt_on: HaltOn) {
+        self.halt_on = halt_on;
+        #[cfg(feature = "jit")]
+        self.blocks.clear();
+    }
+
+    /// the debugger stops at breakpoints by checking at_breakpoint after each step,
+    /// the jit doesn't skip them, because its blocks end before them
+    pub fn add_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.insert(addr >> 1);
+        #[cfg(feature = "jit")]
+        self.blocks.clear();
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.remove(&(addr >> 1));
+        #[cfg(feature = "jit")]
+        self.blocks.clear();
+    }
+
+    /// the byte addresses of all breakpoints
+    pub fn breakpoints(&self) -> Vec<usize> {
+        self.breakpoints.iter().map(|addr| addr << 1).collect()
+    }
+
+    pub fn at_breakpoint(&self) -> bool {
+        self.breakpoints.contains(&self.ip)
+    }
+
+    /// the jit executes single instructions instead of a block, which would run
+    /// past the cycle, so the callers can stop exactly at it
+    pub fn set_cycle_limit(&mut self, limit: Option<u64>) {
+        self.cycle_limit = limit;
+    }
+
+    pub fn set_single_step(&mut self, single_step: bool) {
+        self.single_step = single_step;
+    }
+
+    /// runs the firmware with the jit of the host or with the interpreter, which
+    /// also runs it on hosts without a backend
+    #[cfg(feature = "jit")]
+    pub fn set_jit(&mut self, jit: bool) {
+        self.backend = if jit { Backend::host() } else { None };
+    }
+
+    /// whether the jit runs the firmware
+    #[cfg(feature = "jit")]
+    pub fn jit(&self) -> bool {
+        self.backend.is_some()
+    }
+
+    /// the complete state of the machine, breakpoints, watchpoints and the tracer
+    /// aren't part of it
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot {
+            ip: self.ip as u32, cycles: self.cycles, sleeping: self.sleeping, halted: self.halted,
+            port_prev: self.port_int.prev(), timer_steps: self.timer_int.steps(),
+            ..Snapshot::default()
+        };
+        self.mem.save(&mut snapshot);
+        snapshot
+    }
+
+    /// fails, if the snapshot was taken with another program
+    pub fn restore(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        self.mem.restore(snapshot)?;
+        self.ip = snapshot.ip as usize;
+        self.cycles = snapshot.cycles;
+        self.sleeping = snapshot.sleeping;
+        self.halted = snapshot.halted;
+        self.watch_hit = None;
+        self.stack_error = None;
+        self.fault = None;
+        self.uninit_read = None;
+        // the calls before the snapshot aren't known
+        if let Some(ref mut call_stack) = self.call_stack {
+            call_stack.clear();
+        }
+        self.port_int.set_prev(snapshot.port_prev);
+        self.timer_int.set_steps(snapshot.timer_steps);
+        let exit = match snapshot.halted {
+            Some(HaltReason::Exit(status)) => Some(status),
+            _ => None,
+        };
+        self.mem.semihost_mut().set_exit_status(exit);
+        Ok(())
+    }
+
+    /// the cpu executes single instructions with the jit, while there are watchpoints,
+    /// they are checked by calling watch_hit after each step
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.mem.add_watchpoint(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        self.mem.remove_watchpoint(watchpoint)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        self.mem.watchpoints()
+    }
+
+    /// the access of the last step, which triggered a watchpoint
+    pub fn watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit
+    }
+
+    /// checks the stack after each step, the errors are taken with stack_error,
+    /// the jit executes single instructions meanwhile
+    pub fn set_stack_guard(&mut self, guard: Option<StackGuard>) {
+        self.stack_guard = guard;
+    }
+
+    pub fn stack_guard(&self) -> Option<&StackGuard> {
+        self.stack_guard.as_ref()
+    }
+
+    /// the error of the stack in the last step
+    pub fn stack_error(&self) -> Option<StackError> {
+        self.stack_error
+    }
+
+    /// the faults with FaultPolicy::Halt halt the cpu, the others
+    /// are only returned by fault
+    pub fn set_fault_policies(&mut self, policies: FaultPolicies) {
+        self.fault_policies = policies;
+    }
+
+    pub fn fault_policies(&self) -> FaultPolicies {
+        self.fault_policies
+    }
+
+    /// the first fault of the last step
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    /// finds reads of registers and the sram, which were never written,
+    /// the jit executes single instructions meanwhile
+    pub fn set_check_uninit(&mut self, check: bool) {
+        self.mem.set_check_uninit(check);
+    }
+
+    /// the first read of an undefined register or byte in the last step
+    pub fn uninit_read(&self) -> Option<UninitRead> {
+        self.uninit_read
+    }
+
+    // records the fault of the instruction at the byte address pc
+    fn raise(&mut self, kind: FaultKind, pc: usize, addr: usize) {
+        if self.fault.is_none() {
+            self.fault = Some(Fault { kind: kind, pc: pc, addr: addr });
+        }
+    }
+
+    /// traces every executed instruction, the jit executes single instructions meanwhile
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        if let Some(mut old) = self.tracer.take() {
+            old.flush().expect("Couldn't write the trace");
+        }
+        self.tracer = tracer;
+        self.record_accesses();
+    }
+
+    /// attributes the cycles to the functions, the jit executes single instructions meanwhile
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// counts the executed instructions and branches, the jit executes single instructions meanwhile
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// follows the calls and returns for backtraces, a return to another address than
+    /// behind its call is taken with stack_error, the jit executes single instructions
+    /// meanwhile
+    pub fn set_call_stack(&mut self, call_stack: Option<CallStack>) {
+        self.call_stack = call_stack;
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_ref()
+    }
+
+    /// measures the latency and the duration of the interrupt handlers and the
+    /// critical sections, the jit executes single instructions meanwhile
+    pub fn set_interrupt_stats(&mut self, stats: Option<InterruptStats>) {
+        self.interrupt_stats = stats;
+    }
+
+    pub fn interrupt_stats(&self) -> Option<&InterruptStats> {
+        self.interrupt_stats.as_ref()
+    }
+
+    /// records the history for reverse execution from now on,
+    /// the jit executes single instructions meanwhile
+    pub fn set_history(&mut self, history: Option<History>) {
+        self.history = history;
+        if let Some(ref mut history) = self.history {
+            history.truncate(self.cycles);
+        }
+        self.record_accesses();
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// goes back one cycle, returns false at the start of the history
+    pub fn reverse_step(&mut self) -> bool {
+        let snapshot = match self.history.as_ref().and_then(|h| h.snapshot_before(self.cycles.wrapping_sub(1))) {
+            Some(snapshot) if self.cycles > 0 => snapshot.clone(),
+            _ => return false,
+        };
+        let target = self.cycles - 1;
+        self.replay(&snapshot, target, |_| {});
         true
     }
 
+    /// goes back to the last breakpoint or the state before the last access of a
+    /// watchpoint, None without a history
+    pub fn reverse_continue(&mut self) -> Option<ReverseStop> {
+        let mut end = self.cycles;
+        let snapshots = self.history.as_ref()?.snapshots_before(self.cycles);
+        for snapshot in &snapshots {
+            // the last state before end, at which the cpu stops
+            let mut stop = None;
+            self.replay(snapshot, end, |cpu| {
+                if cpu.cycles < end && cpu.at_breakpoint() {
+                    stop = Some((cpu.cycles, ReverseStop::Breakpoint));
+                }
+                if let Some(hit) = cpu.watch_hit {
+                    stop = Some((cpu.cycles - 1, ReverseStop::Watchpoint(hit)));
+                }
+            });
+            if let Some((cycles, reason)) = stop {
+                self.replay(snapshot, cycles, |_| {});
+                return Some(reason);
+            }
+            end = snapshot.cycles;
+        }
+        if let Some(oldest) = snapshots.last() {
+            self.replay(oldest, oldest.cycles, |_| {});
+        }
+        Some(ReverseStop::Start)
+    }
+
+    // restores the snapshot and executes until the cycle, f is called for every state
+    // on the way, the history is truncated afterwards
+    fn replay<F: FnMut(&Cpu)>(&mut self, snapshot: &Snapshot, cycles: u64, mut f: F) {
+        let tracer = self.tracer.take();
+        let mut history = self.history.take();
+        let single_step = self.single_step;
+        self.single_step = true;
+        // the output was already sent and the host calls were executed
+        let sink = self.mem.uart_mut().take_sink();
+        let output = self.mem.uart().output().len();
+        let calls = history.as_ref().map(|h| h.host_calls_after(snapshot.cycles)).unwrap_or_default();
+        self.mem.semihost_mut().set_replay(Some(calls));
+
+        self.restore(snapshot).expect("the history is from another program");
+        f(self);
+        while self.cycles < cycles && self.step().is_running() {
+            f(self);
+        }
+
+        self.mem.semihost_mut().set_replay(None);
+        self.mem.uart_mut().truncate_output(output);
+        self.mem.uart_mut().set_sink(sink);
+        self.single_step = single_step;
+        if let Some(ref mut history) = history {
+            history.truncate(self.cycles);
+        }
+        self.history = history;
+        self.tracer = tracer;
+    }
+
+    fn record_accesses(&mut self) {
+        let record = self.tracer.as_ref().is_some_and(|t| t.memory()) || self.history.is_some();
+        self.mem.set_record_accesses(record);
+    }
+
+    /// the reason, why the cpu halted
+    pub fn halt_reason(&self) -> Option<HaltReason> {
+        self.halted
+    }
+
+    /// number of executed cycles since the start
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// the instruction pointer, which counts in words
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: usize) {
+        self.ip = ip;
+        self.sleeping = false;
+    }
+
+    pub fn mem(&self) -> &Memory {
+        &self.mem
+    }
+
+    pub fn mem_mut(&mut self) -> &mut Memory {
+        &mut self.mem
+    }
+
     #[inline(always)]
     fn pending_interrupt(&mut self) -> Option<usize> {
         // we must handle the interrupt, if one of the two interrupt sources
@@ -122,132 +653,248 @@ impl<'a> Cpu<'a> {
         })
     }
 
+    // the features, which check the state after every instruction
     #[cfg(feature = "jit")]
-    #[inline(always)]
-    fn compile_block(mem: &Memory, addr: usize) -> (ExecutableBuffer, AssemblyOffset) {
-        #[cfg(debug_assertions)]
-        print!("Compiling {}", addr);
-        // we use standard C calling convention, which is documented here:
-        // https://en.wikipedia.org/wiki/X86_calling_conventions#System_V_AMD64_ABI
-        // this is the standard calling x64 convention on linux
-
-        macro_rules! cpu_call(
-            ($ops:expr, $func:expr) => {
-                dynasm!($ops
-                        ; mov rdi, r12
-                        ; mov rax, QWORD $func as _
-                        ; call rax)
-            };
-            ($ops:expr, $func:expr, $arg1:expr) => {
-                dynasm!($ops
-                        ; mov rsi, QWORD $arg1 as _
-                        ;; cpu_call!($ops, $func))
-            };
-            ($ops:expr, $func:expr, $arg1:expr, $arg2:expr) => {
-                dynasm!($ops
-                        ; mov rdx, QWORD $arg2 as _
-                        ;; cpu_call!($ops, $func, $arg1))
-            };
-            ($ops:expr, $func:expr, $arg1:expr, $arg2:expr, $arg3:expr) => {
-                dynasm!($ops
-                        ; mov rcx, QWORD $arg3 as _
-                        ;; cpu_call!($ops, $func, $arg1, $arg2))
-            };
-            ($ops:expr, $func:expr, $arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr) => {
-                dynasm!($ops
-                        ; mov r8, QWORD $arg4 as _
-                        ;; cpu_call!($ops, $func, $arg1, $arg2, $arg3))
+    fn single_instructions(&self) -> bool {
+        self.single_step || self.tracer.is_some() || self.profiler.is_some() || self.coverage.is_some()
+            || self.interrupt_stats.is_some() || self.history.is_some()
+            || self.stack_guard.is_some() || self.call_stack.is_some() || !self.mem.watchpoints().is_empty()
+            || self.mem.checks_uninit()
+    }
+
+    // runs the block at the ip and chains the blocks after it, while the deferred
+    // steps of the peripherals can't raise an interrupt. every instruction takes
+    // one cycle like in the interpreter, the timer catches up before every
+    // interpreted instruction, which may access the io registers, and at the end.
+    // the pins are only sampled once
+    #[cfg(feature = "jit")]
+    fn run_chained(&mut self, backend: Backend, ip_offset: i32) {
+        let mut budget = self.chain_budget();
+        // the steps of the timer, which are missing for the executed instructions
+        let mut deferred = 0;
+        let mut blocks = 0;
+        loop {
+            let (func, len, io) = {
+                let block = self.block(backend, ip_offset);
+                (block.func(), block.len, block.io)
             };
-            ($ops:expr, $func:expr, $arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr, $arg5:expr) => {
-                dynasm!($ops
-                        ; mov r9, QWORD $arg5 as _
-                        ;; cpu_call!($ops, $func, $arg1, $arg2, $arg3, $arg4))
+            // the step already did the first cycle
+            let steps = if blocks == 0 { len - 1 } else { len };
+            if self.cycle_limit.is_some_and(|limit| self.cycles + steps > limit) {
+                if blocks == 0 {
+                    self.run_single(backend, ip_offset);
+                }
+                break;
+            }
+            if blocks > 0 && deferred + steps > budget {
+                break;
+            }
+            // the catch up of the timer doesn't count
+            self.mem.take_io_written();
+            let start = self.ip;
+            // the step of the first instruction is owed as well
+            self.timer_debt = Some(TimerDebt { addr: start, steps: deferred + steps + 1 - len, paid: 0 });
+            let data = self.mem.data_ptr();
+            func(self, data);
+            let paid = self.timer_debt.take().map_or(0, |debt| debt.paid);
+            // the block may have changed the timer or enabled the pin interrupts
+            if io {
+                budget = self.chain_budget();
+            }
+            // the block stopped at a fault, the instructions after it didn't run
+            let steps = match self.fault {
+                Some(fault) => {
+                    let executed = self.executed_until(start, fault.pc >> 1);
+                    if blocks == 0 { executed - 1 } else { executed }
+                }
+                None => steps,
             };
-        );
+            deferred = deferred + steps - paid;
+            self.cycles += steps;
+            blocks += 1;
+            // e.g. the output of the uart must be seen by the caller first
+            let written = self.mem.take_io_written();
+            if written || blocks == JIT_CHAIN_MAX || deferred >= budget || !self.may_chain() {
+                break;
+            }
+        }
+        if deferred > 0 {
+            self.timer_int.advance(&mut self.mem, deferred);
+        }
+    }
+
+    // the timer steps of the block up to and including the instruction at the ip, so
+    // e.g. the instructions before a write of TCCR1B run with the old prescaler
+    #[cfg(feature = "jit")]
+    fn pay_timer_debt(&mut self) {
+        if let Some(mut debt) = self.timer_debt {
+            debt.steps += self.executed_until(debt.addr, self.ip) - 1;
+            debt.addr = self.ip;
+            if debt.steps > 0 {
+                self.timer_int.advance(&mut self.mem, debt.steps);
+                debt.paid += debt.steps;
+                debt.steps = 0;
+            }
+            self.timer_debt = Some(debt);
+        }
+    }
 
-        let mut ops = dynasmrt::x64::Assembler::new().expect("new Assembler");
+    // the number of instructions from the word address start up to and including end
+    #[cfg(feature = "jit")]
+    fn executed_until(&self, start: usize, end: usize) -> u64 {
+        1 + (start + 1..=end).filter(|&addr| self.mem.get_instruction(addr) != SecondOpWord).count() as u64
+    }
 
-        let offset = ops.offset();
-        dynasm!(ops
-                ; push r12       // r12 is callee-save
-                ; mov r12, rdi); // save cpu pointer in r12
+    // a block for the instruction at the ip, which isn't cached
+    #[cfg(feature = "jit")]
+    fn run_single(&mut self, backend: Backend, ip_offset: i32) {
+        let block = Cpu::compile_block(backend, &self.mem, &self.block_spec(self.ip, true, ip_offset));
+        let data = self.mem.data_ptr();
+        block.func()(self, data);
+    }
+
+    // the number of steps, which the peripherals can skip
+    #[cfg(feature = "jit")]
+    fn chain_budget(&self) -> u64 {
+        if self.port_int.enabled(&self.mem) { 0 } else { self.timer_int.quiet_steps(&self.mem) }
+    }
+
+    // the compiled block at the ip from the dispatch table
+    #[cfg(feature = "jit")]
+    fn block(&mut self, backend: Backend, ip_offset: i32) -> &Block {
+        let ip = self.ip;
+        if self.blocks.len() <= ip {
+            self.blocks.resize_with(ip.max(PC_MASK) + 1, || None);
+        }
+        if self.blocks[ip].is_none() {
+            let block = Cpu::compile_block(backend, &self.mem, &self.block_spec(ip, false, ip_offset));
+            self.blocks[ip] = Some(block);
+        }
+        self.blocks[ip].as_ref().unwrap()
+    }
 
+    // the next block can run in the same step, nothing needs the outer loop
+    #[cfg(feature = "jit")]
+    fn may_chain(&self) -> bool {
+        let interrupt = bit(self.flags(), I) == 1
+            && (self.port_int.is_pending(&self.mem) || self.timer_int.is_pending(&self.mem));
+        !interrupt && !self.sleeping && self.halted.is_none() && self.fault.is_none() && !self.mem.has_fault()
+            && self.mem.semihost().exit_status().is_none()
+            && self.ip <= PC_MASK && !self.breakpoints.contains(&self.ip)
+            && self.halt_on.addr != Some(self.ip << 1)
+    }
+
+    // the byte offset of the ip in the cpu, the blocks store it directly
+    #[cfg(feature = "jit")]
+    fn ip_offset(&self) -> i32 {
+        (&self.ip as *const usize as usize - self as *const Cpu as usize) as i32
+    }
+
+    // the block at the word address addr, which ends at the breakpoints and the halt address
+    #[cfg(feature = "jit")]
+    fn block_spec(&self, addr: usize, single: bool, ip_offset: i32) -> BlockSpec<'_> {
+        BlockSpec {
+            addr: addr, stops: &self.breakpoints, halt: self.halt_on.addr.map(|addr| addr >> 1), single: single,
+            ip_offset: ip_offset,
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    fn compile_block(backend: Backend, mem: &Memory, spec: &BlockSpec) -> Block {
+        match backend {
+            Backend::X64 => Cpu::generate::<X64>(mem, spec),
+            Backend::Aarch64 => Cpu::generate::<Aarch64>(mem, spec),
+        }
+    }
+
+    // the instructions, which the backend can't emit inline, call the interpreter
+    // with the cpu. the inline instructions don't update the ip, it is only stored
+    // before the next call and at the end of the block.
+    #[cfg(feature = "jit")]
+    fn generate<G: CodeGen>(mem: &Memory, spec: &BlockSpec) -> Block {
+        let BlockSpec { addr, stops, halt, single, ip_offset } = *spec;
+        let mut gen = G::new(ip_offset, interpret as *const u8);
+
+        // the word address, which must be stored in the ip, after inline instructions
+        let mut pending_ip = None;
+        let mut len = 0;
+        let mut io = false;
         let mut cur_addr = addr;
         loop {
-            let instr = mem.get_instruction(cur_addr);
-            #[cfg(debug_assertions)]
-            print!(" {:?} ", instr);
+            // the ip is already correct, when we end the block here
+            if cur_addr != addr && (stops.contains(&cur_addr) || halt == Some(cur_addr) || cur_addr > PC_MASK) {
+                break;
+            }
+            let mut second = false;
+            let instr = match mem.get_instruction(cur_addr) {
+                // the second word of the instruction before
+                SecondOpWord if cur_addr != addr => {
+                    cur_addr += 1;
+                    continue;
+                }
+                // a jump into the second word, which is interpreted on its own
+                SecondOpWord => {
+                    second = true;
+                    mem.decode_at(cur_addr)
+                }
+                instr => instr,
+            };
 
-            match instr {
-                SecondOpWord => {}
-                ADD(rd, rr) => cpu_call!(ops, add, rd, rr),
-                ADC(rd, rr) => cpu_call!(ops, adc, rd, rr),
-                ADIW(reg, k) => cpu_call!(ops, adiw, reg, k),
-                AND(rd, rr) => cpu_call!(ops, and, rd, rr),
-                ANDI(rd, k) => cpu_call!(ops, andi, rd, k),
-                ASR(reg) => cpu_call!(ops, asr, reg),
-                BCLR(s) => cpu_call!(ops, bclr, s),
-                BLD_ST(LDType::LD, reg, b) => cpu_call!(ops, bld, reg, b),
-                BLD_ST(LDType::ST, reg, b) => cpu_call!(ops, bst, reg, b),
-                BSET(s) => cpu_call!(ops, bset, s),
-                BRBC_S(sc, sreg, rel) => cpu_call!(ops, brbc_s, sc.as_u8(), sreg, rel),
-                CALL(ip) => cpu_call!(ops, call, ip),
-                COM(reg) => cpu_call!(ops, com, reg),
-                CP(rd, rr) => cpu_call!(ops, cp, rd, rr),
-                CPC(rd, rr) => cpu_call!(ops, cpc, rd, rr),
-                CPI(reg, k) => cpu_call!(ops, cpi, reg, k),
-                CPSE(rd, rr) => cpu_call!(ops, cpse, rd, rr),
-                C_SBI(typ, ioreg, b) => cpu_call!(ops, c_sbi, typ.as_u8(), ioreg, b),
-                DEC(reg) => cpu_call!(ops, dec, reg),
-                EOR(rd, rr) => cpu_call!(ops, eor, rd, rr),
-                ICALL => cpu_call!(ops, icall),
-                IN(reg, index) => cpu_call!(ops, in_, reg, index),
-                JMP(ip) => cpu_call!(ops, jmp, ip),
-                LD_ST(typ, reg, addrreg, mode) => cpu_call!(ops, ld_st, typ.as_u8(), reg, addrreg, mode.as_u16()),
-                LD_STS(LDType::LD, reg, k) => cpu_call!(ops, lds, reg, k),
-                LD_STS(LDType::ST, reg, k) => cpu_call!(ops, sts, reg, k),
-                LDI(reg, val) => cpu_call!(ops, ldi, reg, val),
-                LPM(reg, typ) => cpu_call!(ops, lpm, reg, typ.as_u8()),
-                LSR(reg) => cpu_call!(ops, lsr, reg),
-                MOV(rd, rr) => cpu_call!(ops, mov, rd, rr),
-                MOVW(rd, rr) => cpu_call!(ops, movw, rd, rr),
-                MUL(rd, rr) => cpu_call!(ops, mul, rd, rr),
-                NEG(rd) => cpu_call!(ops, neg, rd),
-                NOP => cpu_call!(ops, nop),
-                OR(rd, rv) => cpu_call!(ops, or, rd, rv),
-                ORI(rd, k) => cpu_call!(ops, ori, rd, k),
-                OUT(reg, index) => cpu_call!(ops, out, reg, index),
-                POP(reg) => cpu_call!(ops, pop, reg),
-                PUSH(reg) => cpu_call!(ops, push, reg),
-                RCALL(diff) => cpu_call!(ops, rcall, diff),
-                RET => cpu_call!(ops, ret),
-                RETI => cpu_call!(ops, reti),
-                RJMP(diff) => cpu_call!(ops, rjmp, diff),
-                ROR(reg) => cpu_call!(ops, ror, reg),
-                SBCI(reg, val) => cpu_call!(ops, sbci, reg, val),
-                SBC(rd, rr) => cpu_call!(ops, sbc, rd, rr),
-                SBIW(reg, k) => cpu_call!(ops, sbiw, reg, k),
-                SBIC_S(setclear, reg, b) => cpu_call!(ops, sbic_s, setclear.as_u8(), reg, b),
-                SBR(setclear, reg, b) => cpu_call!(ops, sbr, setclear.as_u8(), reg, b),
-                SLEEP => cpu_call!(ops, sleep),
-                SUB(rd, rr) => cpu_call!(ops, sub, rd, rr),
-                SUBI(reg, val) => cpu_call!(ops, subi, reg, val),
-                i@_ => panic!("ip: {:#x}, Unknown Instruction: {:?}", cur_addr, i)
+            let mut end = single || Cpu::is_end_of_block(instr);
+            io |= match instr {
+                IN(..) | OUT(..) | C_SBI(..) | SBIC_S(..) | LD_ST(..) => true,
+                LD_STS(_, _, k) => (k as usize) < SRAM_START || k as usize >= SRAM_SIZE,
+                _ => false,
+            };
+            // the semihosting may end the program
+            if let LD_STS(LDType::ST, _, k) = instr {
+                end |= k as usize >= SRAM_SIZE;
             }
+            len += 1;
+            let next = cur_addr + match instr {
+                CALL(..) | JMP(..) | LD_STS(..) => 2,
+                _ => 1,
+            };
+            // the second word of a two word instruction is skipped as well, a skip
+            // at the end of the flash leaves it, which faults
+            let skip = match instr {
+                CPSE(..) | SBIC_S(..) | SBR(..) if mem.get_instruction((cur_addr + 2) & PC_MASK) == SecondOpWord => {
+                    cur_addr + 3
+                }
+                _ => cur_addr + 2,
+            };
+            let site = Site { addr: cur_addr, next: next, skip: skip, single: single };
+            // the fast path of ld and st falls back to the interpreter
+            if let LD_ST(..) = instr {
+                if let Some(ip) = pending_ip.take() {
+                    gen.store_ip(ip);
+                }
+            }
+            let inline = if second { None } else { gen.inline(instr, site) };
+            // the interpreter and the jumps update the ip themselves
+            let updates_ip = inline.unwrap_or_else(|| {
+                if let Some(ip) = pending_ip.take() {
+                    gen.store_ip(ip);
+                }
+                gen.interpret();
+                match instr {
+                    // the cpu may halt at the fault of an illegal opcode
+                    UnknownOp(..) | IncompleteOp(..) | INC(..) | SWAP(..) => end = true,
+                    _ => {}
+                }
+                true
+            });
+            pending_ip = if updates_ip { None } else { Some(next) };
 
-            if Cpu::is_end_of_block(instr) {
+            if end {
                 break;
             }
             cur_addr += 1;
         }
-        dynasm!(ops
-                ; pop r12
-                ; ret
-        );
-        #[cfg(debug_assertions)]
-        println!("");
-        let buf = ops.finalize().unwrap();
-        (buf, offset)
+        if let Some(ip) = pending_ip {
+            gen.store_ip(ip);
+        }
+        gen.finish(len, io)
     }
 
     #[inline(always)]
@@ -258,20 +905,24 @@ impl<'a> Cpu<'a> {
         // an interrupt between the two or we may never wakeup
         // BSET(I) must never be at the end of a block!
 
-        match instr {
-            BRBC_S(..) | CALL(..) | CPSE(..) | ICALL | JMP(..) | NOP
-                | RCALL(..) | RET | RETI | RJMP(..)
-                | SBIC_S(..) | SBR(..) | SLEEP => true,
-            _ => false
-        }
+        matches!(instr, BRBC_S(..) | BREAK | CALL(..) | CPSE(..) | ICALL | JMP(..) | NOP
+                 | RCALL(..) | RET | RETI | RJMP(..)
+                 | SBIC_S(..) | SBR(..) | SLEEP)
     }
 
+    // executes the instruction at the ip
+    fn execute_instruction(&mut self) {
+        let mut instr = self.mem.get_instruction(self.ip);
+        if instr == SecondOpWord {
+            let pc = self.ip << 1;
+            self.raise(FaultKind::SecondWord, pc, pc);
+            instr = self.mem.decode_at(self.ip);
+        }
+        self.handle_instruction(instr);
+    }
 
     #[inline(always)]
-    #[cfg(not(feature = "jit"))]
     fn handle_instruction(&mut self, instr: Instruction) {
-        #[cfg(debug_assertions)]
-        println!("Executing: {:?}", instr);
         match instr {
             ADD(rd, rr) => {
                 let rdv = self.reg(rd);
@@ -374,6 +1025,13 @@ impl<'a> Cpu<'a> {
                     self.ip += 1;
                 }
             },
+            BREAK => {
+                // without a debugger attached, break behaves like a nop
+                if self.halt_on.brk {
+                    self.halted = Some(HaltReason::Break);
+                }
+                self.ip += 1;
+            }
             CALL(ip) => {
                 let retip = (self.ip + 2) as u16;
                 self.mem.push16(retip);
@@ -444,7 +1102,7 @@ impl<'a> Cpu<'a> {
             CPSE(rd, rr) => {
                 if self.reg(rd) == self.reg(rr) {
                     self.ip += 2;
-                    if self.mem.get_instruction(self.ip) == SecondOpWord {
+                    if self.mem.get_instruction(self.ip & PC_MASK) == SecondOpWord {
                         self.ip += 1;
                     }
                 } else {
@@ -478,7 +1136,7 @@ impl<'a> Cpu<'a> {
                 self.ip = ip as usize;
             },
             IN(reg, index) => {
-                *self.reg_mut(reg) = self.mem.io_reg(index);
+                *self.reg_mut(reg) = self.mem.load_io_reg(index);
                 self.ip += 1;
             }
             JMP(ip) => self.ip = ip as usize,
@@ -496,7 +1154,7 @@ impl<'a> Cpu<'a> {
                 let addr = self.get_word_reg(addrreg).wrapping_add(displacement as u16);
                 match typ {
                     LDType::LD => {
-                        *self.reg_mut(reg) = self.mem.data(addr);
+                        *self.reg_mut(reg) = self.mem.load(addr);
                     },
                     LDType::ST => {
                         let val = self.reg(reg);
@@ -514,7 +1172,7 @@ impl<'a> Cpu<'a> {
                 self.ip += 1;
             },
             LD_STS(LDType::LD, reg, k) => {
-                let val = self.mem.data(k);
+                let val = self.mem.load(k);
                 *self.reg_mut(reg) = val;
                 self.ip += 2;
             }
@@ -574,8 +1232,8 @@ impl<'a> Cpu<'a> {
                 self.ip += 1;
             }
             NOP => {
-                if self.halt_on_nop {
-                    self.should_halt = true;
+                if self.halt_on.nop {
+                    self.halted = Some(HaltReason::Nop);
                 }
 
                 self.ip += 1;
@@ -690,7 +1348,7 @@ impl<'a> Cpu<'a> {
             SBIC_S(setclear, reg, b) => {
                 if bit(self.mem.io_reg(reg), b as usize) == setclear.as_u8() {
                     self.ip += 2;
-                    if self.mem.get_instruction(self.ip) == SecondOpWord {
+                    if self.mem.get_instruction(self.ip & PC_MASK) == SecondOpWord {
                         self.ip += 1;
                     }
                 } else {
@@ -700,7 +1358,7 @@ impl<'a> Cpu<'a> {
             SBR(setclear, reg, b) => {
                 if bit(self.reg(reg), b as usize) == setclear.as_u8() {
                     self.ip += 2;
-                    if self.mem.get_instruction(self.ip) == SecondOpWord {
+                    if self.mem.get_instruction(self.ip & PC_MASK) == SecondOpWord {
                         self.ip += 1;
                     }
                 } else {
@@ -708,6 +1366,9 @@ impl<'a> Cpu<'a> {
                 }
             }
             SLEEP => {
+                if self.halt_on.sleep && bit(self.flags(), I) == 0 {
+                    self.halted = Some(HaltReason::Sleep);
+                }
                 self.sleeping = true;
                 self.ip += 1;
             }
@@ -749,10 +1410,18 @@ impl<'a> Cpu<'a> {
 
                 self.ip += 1;
             }
-            i@_ => panic!("ip: {:#x}, Unknown Instruction: {:?}", self.ip << 1, i)
+            // UnknownOp, IncompleteOp and the unsupported instructions
+            _ => self.illegal_instruction(),
         }
     }
 
+    // executed as nop
+    fn illegal_instruction(&mut self) {
+        let pc = self.ip << 1;
+        self.raise(FaultKind::IllegalOpcode, pc, pc);
+        self.ip += 1;
+    }
+
     // sets the flags
     // if a flag is None, it isn't changed, otherwise it is set to its value
     // the value (in the Some) must be 0 or 1
@@ -807,611 +1476,46 @@ impl<'a> Cpu<'a> {
     }
 
     #[inline(always)]
-    fn reg(&self, index: u8) -> u8 {
+    pub fn reg(&self, index: u8) -> u8 {
         self.mem.reg(index)
     }
 
     #[inline(always)]
-    fn flags(&self) -> u8 {
+    pub fn flags(&self) -> u8 {
         self.mem.flags()
     }
 }
 
+// the blocks of the jit call it for the instructions, which aren't inline
 #[cfg(feature = "jit")]
-extern "sysv64" fn add(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(rd);
-    let rrv = cpu.reg(rr);
-    let res = rdv.wrapping_add(rrv);
-    *cpu.reg_mut(rd) = res;
-    cpu.set_flags(
-        None, None,
-        Some(bit(rdv, 3) & bit(rrv, 3)
-             | bitneg(res, 3) & (bit(rdv, 3) | bit(rrv, 3))),
-        Some(bit(rdv, 7) & bit(rrv, 7) & bitneg(res, 7)
-             | bitneg(rdv, 7) & bitneg(rrv, 7) & bit(res, 7)),
-        Some(bit(res, 7)),
-        Some((res == 0) as u8),
-        Some(bit(rdv, 7) & bit(rrv, 7)
-             | bitneg(res, 7) & (bit(rdv, 7) | bit(rrv, 7))));
-    cpu.ip += 1;
-}
-
-#[cfg(feature = "jit")]
-extern "sysv64" fn adc(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(rd);
-    let rrv = cpu.reg(rr);
-    let res = rdv.wrapping_add(rrv).wrapping_add(bit(cpu.flags(), C));
-    *cpu.reg_mut(rd) = res;
-
-    cpu.set_flags(
-        None, None,
-        Some(bit(rdv, 3) & bit(rrv, 3)
-             | bitneg(res, 3) & (bit(rdv, 3) | bit(rrv, 3))),
-        Some(bit(rdv, 7) & bit(rrv, 7) & bitneg(res, 7)
-                         | bitneg(rdv, 7) & bitneg(rrv, 7) & bit(res, 7)),
-        Some(bit(res, 7)),
-        Some((res == 0) as u8),
-        Some(bit(rdv, 7) & bit(rrv, 7)
-             | bitneg(res, 7) & (bit(rdv, 7) | bit(rrv, 7))));
-    cpu.ip += 1;
-}
-
-#[cfg(feature = "jit")]
-extern "sysv64" fn adiw(c: *mut Cpu, reg: Register, k: u8) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.get_word_reg(reg);
-    let res = rdv.wrapping_add(k as u16);
-    cpu.set_word_reg(reg, res);
-
-    cpu.set_flags(None, None, None,
-                   Some(bitneg16(rdv, 15) & bit16(res, 15)),
-                   Some(bit16(res, 15)), Some((res == 0) as u8),
-                   Some(bit16(rdv, 15) & bitneg16(res, 15)));
-    cpu.ip += 1;
-}
-
-#[cfg(feature = "jit")]
-extern "sysv64" fn and(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    let res = cpu.reg(rd) & cpu.reg(rr);
-    *cpu.reg_mut(rd) = res;
-    cpu.set_flags(
-        None, None, None, Some(0),
-        Some(res >> 7), Some((res == 0) as u8), None);
-
-    cpu.ip += 1;
-}
-
-#[cfg(feature = "jit")]
-extern "sysv64" fn andi(c: *mut Cpu, rd: Register, k: u8) {
-    let cpu = unsafe {&mut *c};
-    let res = cpu.reg(rd) & k;
-    *cpu.reg_mut(rd) = res;
-    cpu.set_flags(
-        None, None, None, Some(0),
-        Some(res >> 7), Some((res == 0) as u8), None);
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn asr(c: *mut Cpu, reg: Register) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(reg);
-    let res = ((rdv as i8) >> 1) as u8;
-    *cpu.reg_mut(reg) = res;
-    cpu.set_flags(None, None, None, Some(bit(res, 7) ^ bit(rdv, 0)),
-                   Some(bit(res, 7)), Some((res == 0) as u8),
-                   Some(bit(rdv, 0)));
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn bclr(c: *mut Cpu, s: SREG) {
-    let cpu = unsafe {&mut *c};
-    let flags = cpu.flags();
-    cpu.mem.set_flags(flags & !(1 << s));
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn bld(c: *mut Cpu, reg: Register, b: u8) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(reg);
-    let t = bit(cpu.flags(), T);
-    *cpu.reg_mut(reg) = rdv & !(1 << b) | (t << b);
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn bst(c: *mut Cpu, reg: Register, b: u8) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(reg);
-    cpu.set_flags(None, Some(bit(rdv, b as usize)), None, None, None, None, None);
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn bset(c: *mut Cpu, s: SREG) {
-    let cpu = unsafe {&mut *c};
-    let flags = cpu.flags();
-    cpu.mem.set_flags(flags | (1 << s));
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn brbc_s(c: *mut Cpu, setclear: u8, sreg: SREG, rel: i8) {
-    let cpu = unsafe {&mut *c};
-    if bit(cpu.flags(), sreg as usize) == setclear {
-        cpu.ip = (cpu.ip as i32 + rel as i32) as usize;
-    } else {
-        cpu.ip += 1;
-    }
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn call(c: *mut Cpu, ip: u32) {
-    let cpu = unsafe {&mut *c};
-    let retip = (cpu.ip + 2) as u16;
-    cpu.mem.push16(retip);
-
-    cpu.ip = ip as usize;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn com(c: *mut Cpu, reg: Register) {
-    let cpu = unsafe {&mut *c};
-    let res = !cpu.reg(reg);
-    *cpu.reg_mut(reg) = res;
-    cpu.set_flags(None, None, None, Some(0), Some(bit(res, 7)), Some((res == 0) as u8), Some(1));
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn cp(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(rd);
-    let rrv = cpu.reg(rr);
-    let res = rdv.wrapping_sub(rrv);
-
-    cpu.set_flags(None, None,
-                   Some(bitneg(rdv, 3) & bit(rrv, 3)
-                        | bit(rrv, 3) & bit(res, 3)
-                        | bit(res, 3) & bitneg(rdv, 3)),
-                   Some(bit(rdv, 7) & bitneg(rrv, 7) & bitneg(res, 7)
-                        | bitneg(rdv, 7) & bit(rrv, 7) & bit(res, 7)),
-                   Some(bit(res, 7)),
-                   Some((res == 0) as u8),
-                   Some(bitneg(rdv, 7) & bit(rrv, 7)
-                        | bit(rrv, 7) & bit(res, 7)
-                        | bit(res, 7) & bitneg(rdv, 7)));
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn cpc(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(rd);
-    let rrv = cpu.reg(rr);
-    let res = rdv.wrapping_sub(rrv).wrapping_sub(bit(cpu.flags(), C));
-
-    let z = bit(cpu.flags(), Z);
-    cpu.set_flags(None, None,
-                   Some(bitneg(rdv, 3) & bit(rrv, 3)
-                        | bit(rrv, 3) & bit(res, 3)
-                        | bit(res, 3) & bitneg(rdv, 3)),
-                   Some(bit(rdv, 7) & bitneg(rrv, 7) & bitneg(res, 7)
-                        | bitneg(rdv, 7) & bit(rrv, 7) & bit(res, 7)),
-                   Some(bit(res, 7)),
-                   Some((res == 0) as u8 & z),
-                   Some(bitneg(rdv, 7) & bit(rrv, 7)
-                        | bit(rrv, 7) & bit(res, 7)
-                        | bit(res, 7) & bitneg(rdv, 7)));
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn cpi(c: *mut Cpu, reg: Register, k: u8) {
-    let cpu = unsafe {&mut *c};
-    let rv = cpu.reg(reg);
-    let res = rv.wrapping_sub(k);
-
-    cpu.set_flags(None, None, Some(bitneg(rv, 3) & bit(k, 3)
-                                    | bit(k, 3) & bit(res, 3) | bit(res, 3) & bitneg(rv, 3)),
-                   Some(bit(rv, 7) & bitneg(k, 7) & bitneg(res, 7)
-                        | bitneg(rv, 7) & bit(k, 7) & bit(res, 7)),
-                   Some(bit(res, 7)),
-                   Some((res == 0) as u8),
-                   Some(bitneg(rv, 7) & bit(k, 7)
-                        | bit(k, 7) & bit(res, 7)
-                        | bit(res, 7) & bitneg(rv, 7)));
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn cpse(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    if cpu.reg(rd) == cpu.reg(rr) {
-        cpu.ip += 2;
-        if cpu.mem.get_instruction(cpu.ip) == SecondOpWord {
-            cpu.ip += 1;
-        }
-    } else {
-        cpu.ip += 1;
-    }
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn c_sbi(c: *mut Cpu, typ: u8, ioreg: u8, b: u8) {
-    let cpu = unsafe {&mut *c};
-    let val = cpu.mem.io_reg(ioreg);
-    cpu.mem.set_io_reg(ioreg, (val & !(1 << b)) | (typ << b));
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn dec(c: *mut Cpu, reg: Register) {
-    let cpu = unsafe {&mut *c};
-    let res = cpu.reg(reg).wrapping_sub(1);
-    *cpu.reg_mut(reg) = res;
-    cpu.set_flags(None, None, None, Some((res == 0x7f) as u8),
-                   Some(bit(res, 7)), Some((res == 0) as u8), None);
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn eor(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    let res = cpu.reg(rd) ^ cpu.reg(rr);
-    *cpu.reg_mut(rd) = res;
-    cpu.set_flags(None, None, None, Some(0), Some(bit(res, 7)),
-                   Some((res == 0) as u8), None);
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn icall(c: *mut Cpu) {
-    let cpu = unsafe {&mut *c};
-    let retip = (cpu.ip + 1) as u16;
-    cpu.mem.push16(retip);
-
-    let ip = cpu.get_word_reg(data::Z);
-    cpu.ip = ip as usize;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn in_(c: *mut Cpu, reg: Register, index: u8) {
-    let cpu = unsafe {&mut *c};
-    *cpu.reg_mut(reg) = cpu.mem.io_reg(index);
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn jmp(c: *mut Cpu, ip: u32) {
-    let cpu = unsafe {&mut *c};
-    cpu.ip = ip as usize;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn ld_st(c: *mut Cpu, typ: u8, reg: Register, addrreg: Register, mode_u16: u16) {
-    let cpu = unsafe {&mut *c};
-    let mode = LDMode::from_u16(mode_u16);
-    let displacement = match mode {
-        LDMode::PreDecrement => {
-            let modval = cpu.get_word_reg(addrreg).wrapping_sub(1);
-            cpu.set_word_reg(addrreg, modval);
-            0
-        },
-        LDMode::PostIncrement => 0,
-        LDMode::Displacement(i) => i
-    };
-
-    let addr = cpu.get_word_reg(addrreg).wrapping_add(displacement as u16);
-    match LDType::from_u8(typ) {
-        LDType::LD => {
-            *cpu.reg_mut(reg) = cpu.mem.data(addr);
-        },
-        LDType::ST => {
-            let val = cpu.reg(reg);
-            cpu.mem.set_data(addr, val);
-        }
-    }
-
-    match mode {
-        LDMode::PostIncrement => {
-            let modval = cpu.get_word_reg(addrreg).wrapping_add(1);
-            cpu.set_word_reg(addrreg, modval);
-        },
-        _ => {}
-    };
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn lds(c: *mut Cpu, reg: Register, k: u16) {
-    let cpu = unsafe {&mut *c};
-    let val = cpu.mem.data(k);
-    *cpu.reg_mut(reg) = val;
-    cpu.ip += 2;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn sts(c: *mut Cpu, reg: Register, k: u16) {
-    let cpu = unsafe {&mut *c};
-    let val = cpu.reg(reg);
-    cpu.mem.set_data(k, val);
-    cpu.ip += 2;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn ldi(c: *mut Cpu, reg: Register, val: u8) {
-    let cpu = unsafe {&mut *c};
-    *cpu.reg_mut(reg) = val;
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn lpm(c: *mut Cpu, reg: Register, typ: u8) {
-    let cpu = unsafe {&mut *c};
-    let z = cpu.get_word_reg(data::Z);
-    *cpu.reg_mut(reg) = cpu.mem.read_program(z);
-
-    match LPMType::from_u8(typ) {
-        LPMType::ZPostIncrement => cpu.set_word_reg(data::Z, z.wrapping_add(1)),
-        _ => {}
-    }
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn lsr(c: *mut Cpu, reg: Register) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(reg);
-    let res = rdv >> 1;
-    *cpu.reg_mut(reg) = res;
-    cpu.set_flags(None, None, None, Some(bit(rdv, 0)),
-                   Some(0), Some((res == 0) as u8),
-                   Some(bit(rdv, 0)));
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn mov(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    *cpu.reg_mut(rd) = cpu.reg(rr);
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn movw(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    let val = cpu.get_word_reg(rr);
-    cpu.set_word_reg(rd, val);
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn mul(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    let res = (cpu.reg(rd) as u16) * (cpu.reg(rr) as u16);
-    cpu.set_word_reg(0, res);
-    cpu.set_flags(None, None, None, None, None, Some((res == 0) as u8), Some(bit16(res, 15)));
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn neg(c: *mut Cpu, rd: Register) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(rd);
-    let res = (0 as i8).wrapping_sub(rdv as i8) as u8;
-    *cpu.reg_mut(rd) = res;
-    cpu.set_flags(None, None, Some(bit(res, 3) | bitneg(rdv, 3)),
-                   Some((res == 0x80) as u8),
-                   Some(bit(res, 7)),
-                   Some((res == 0) as u8),
-                   Some((res != 0) as u8));
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn nop(c: *mut Cpu) {
-    let cpu = unsafe {&mut *c};
-    if cpu.halt_on_nop {
-        cpu.should_halt = true;
-    }
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn or(c: *mut Cpu, rd: Register, rv: Register) {
-    let cpu = unsafe {&mut *c};
-    let res = cpu.reg(rd) | cpu.reg(rv);
-    *cpu.reg_mut(rd) = res;
-    cpu.set_flags(None, None, None, Some(0), Some(bit(res, 7)), Some((res == 0) as u8), None);
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn ori(c: *mut Cpu, rd: Register, k: u8) {
-    let cpu = unsafe {&mut *c};
-    let res = cpu.reg(rd) | k;
-    *cpu.reg_mut(rd) = res;
-    cpu.set_flags(None, None, None, Some(0), Some(bit(res, 7)), Some((res == 0) as u8), None);
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn out(c: *mut Cpu, reg: Register, index: u8) {
-    let cpu = unsafe {&mut *c};
-    let val = cpu.reg(reg);
-    cpu.mem.set_io_reg(index, val);
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn pop(c: *mut Cpu, reg: Register) {
-    let cpu = unsafe {&mut *c};
-    let val = cpu.mem.pop();
-    *cpu.reg_mut(reg) = val;
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn push(c: *mut Cpu, reg: Register) {
-    let cpu = unsafe {&mut *c};
-    let val = cpu.reg(reg);
-    cpu.mem.push(val);
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn rcall(c: *mut Cpu, diff: i16) {
-    let cpu = unsafe {&mut *c};
-    let retip = (cpu.ip + 1) as u16;
-    cpu.mem.push16(retip);
-
-    cpu.ip = (cpu.ip as i32 + diff as i32) as usize;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn ret(c: *mut Cpu) {
-    let cpu = unsafe {&mut *c};
-    cpu.ip = cpu.mem.pop16() as usize;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn reti(c: *mut Cpu) {
-    let cpu = unsafe {&mut *c};
-    cpu.set_flags(Some(1), None, None, None, None, None, None);
-    cpu.ip = cpu.mem.pop16() as usize;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn rjmp(c: *mut Cpu, diff: i16) {
-    let cpu = unsafe {&mut *c};
-    cpu.ip = (cpu.ip as i32 + diff as i32) as usize;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn ror(c: *mut Cpu, reg: Register) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(reg);
-    let res = bit(cpu.flags(), C) << 7 | (rdv >> 1);
-    *cpu.reg_mut(reg) = res;
-    cpu.set_flags(None, None, None, Some(bit(res, 7) ^ bit(rdv, 0)),
-                   Some(bit(res, 7)), Some((res == 0) as u8),
-                   Some(bit(rdv, 0)));
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn sbci(c: *mut Cpu, reg: Register, val: u8) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(reg);
-    let rrv = val;
-    let res = rdv.wrapping_sub(rrv).wrapping_sub(bit(cpu.flags(), C));
-    *cpu.reg_mut(reg) = res;
-
-    let z = bit(cpu.flags(), Z);
-    cpu.set_flags(None, None,
-                   Some(bitneg(rdv, 3) & bit(rrv, 3)
-                       | bit(rrv, 3) & bit(res, 3)
-                       | bit(res, 3) & bitneg(rdv, 3)),
-                   Some(bit(rdv, 7) & bitneg(rrv, 7) & bitneg(res, 7)
-                       | bitneg(rdv, 7) & bit(rrv, 7) & bit(res, 7)),
-                   Some(bit(res, 7)),
-                   Some((res == 0) as u8 & z),
-                   Some(bitneg(rdv, 7) & bit(rrv, 7)
-                       | bit(rrv, 7) & bit(res, 7)
-                       | bit(res, 7) & bitneg(rdv, 7)));
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn sbc(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(rd);
-    let rrv = cpu.reg(rr);
-    let res = rdv.wrapping_sub(rrv).wrapping_sub(bit(cpu.flags(), C));
-    *cpu.reg_mut(rd) = res;
-
-    let z = bit(cpu.flags(), Z);
-    cpu.set_flags(None, None,
-                   Some(bitneg(rdv, 3) & bit(rrv, 3)
-                       | bit(rrv, 3) & bit(res, 3)
-                       | bit(res, 3) & bitneg(rdv, 3)),
-                   Some(bit(rdv, 7) & bitneg(rrv, 7) & bitneg(res, 7)
-                       | bitneg(rdv, 7) & bit(rrv, 7) & bit(res, 7)),
-                   Some(bit(res, 7)),
-                   Some((res == 0) as u8 & z),
-                   Some(bitneg(rdv, 7) & bit(rrv, 7)
-                       | bit(rrv, 7) & bit(res, 7)
-                       | bit(res, 7) & bitneg(rdv, 7)));
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn sbiw(c: *mut Cpu, reg: Register, k: u8) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.get_word_reg(reg);
-    let res = rdv.wrapping_sub(k as u16);
-    cpu.set_word_reg(reg, res);
-
-    cpu.set_flags(None, None, None,
-                   Some(bit16(rdv, 15) & bitneg16(res, 15)),
-                   Some(bit16(res, 15)), Some((res == 0) as u8),
-                   Some(bitneg16(rdv, 15) & bit16(res, 15)));
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn sbic_s(c: *mut Cpu, setclear: u8, reg: Register, b: u8) {
-    let cpu = unsafe {&mut *c};
-    if bit(cpu.mem.io_reg(reg), b as usize) == setclear {
-        cpu.ip += 2;
-        if cpu.mem.get_instruction(cpu.ip) == SecondOpWord {
-            cpu.ip += 1;
+jit_helper! {
+    fn interpret(c: *mut Cpu) -> u8 {
+        let cpu = unsafe {&mut *c};
+        let pc = cpu.ip << 1;
+        cpu.pay_timer_debt();
+        cpu.execute_instruction();
+        // the block ends at the faulting instruction
+        if let Some(fault) = cpu.mem.take_fault() {
+            cpu.raise(fault.kind, pc, fault.addr);
         }
-    } else {
-        cpu.ip += 1;
+        cpu.fault.is_some() as u8
     }
 }
-#[cfg(feature = "jit")]
-extern "sysv64" fn sbr(c: *mut Cpu, setclear: u8, reg: Register, b: u8) {
-    // TODO really doing the right thing or copy paste mistake
-    let cpu = unsafe {&mut *c};
-    if bit(cpu.reg(reg), b as usize) == setclear {
-        cpu.ip += 2;
-        if cpu.mem.get_instruction(cpu.ip) == SecondOpWord {
-            cpu.ip += 1;
-        }
-    } else {
-        cpu.ip += 1;
-    }
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn sleep(c: *mut Cpu) {
-    let cpu = unsafe {&mut *c};
-    cpu.sleeping = true;
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn sub(c: *mut Cpu, rd: Register, rr: Register) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(rd);
-    let rrv = cpu.reg(rr);
-    let res = rdv.wrapping_sub(rrv);
-    *cpu.reg_mut(rd) = res;
-    cpu.set_flags(None, None,
-                   Some(bitneg(rdv, 3) & bit(rrv, 3)
-                       | bit(rrv, 3) & bit(res, 3)
-                       | bit(res, 3) & bitneg(rdv, 3)),
-                   Some(bit(rdv, 7) & bitneg(rrv, 7) & bitneg(res, 7)
-                       | bitneg(rdv, 7) & bit(rrv, 7) & bit(res, 7)),
-                   Some(bit(res, 7)),
-                   Some((res == 0) as u8),
-                   Some(bitneg(rdv, 7) & bit(rrv, 7)
-                       | bit(rrv, 7) & bit(res, 7)
-                       | bit(res, 7) & bitneg(rdv, 7)));
-
-    cpu.ip += 1;
-}
-#[cfg(feature = "jit")]
-extern "sysv64" fn subi(c: *mut Cpu, reg: Register, val: u8) {
-    let cpu = unsafe {&mut *c};
-    let rdv = cpu.reg(reg);
-    let rrv = val;
-    let res = rdv.wrapping_sub(rrv);
-    *cpu.reg_mut(reg) = res;
-    cpu.set_flags(None, None,
-                   Some(bitneg(rdv, 3) & bit(rrv, 3)
-                       | bit(rrv, 3) & bit(res, 3)
-                       | bit(res, 3) & bitneg(rdv, 3)),
-                   Some(bit(rdv, 7) & bitneg(rrv, 7) & bitneg(res, 7)
-                       | bitneg(rdv, 7) & bit(rrv, 7) & bit(res, 7)),
-                   Some(bit(res, 7)),
-                   Some((res == 0) as u8),
-                   Some(bitneg(rdv, 7) & bit(rrv, 7)
-                       | bit(rrv, 7) & bit(res, 7)
-                       | bit(res, 7) & bitneg(rdv, 7)));
-
-    cpu.ip += 1;
-}
-
 
 #[cfg(test)]
 mod tests {
     use memory::Memory;
+    use firmware::Firmware;
     use util::{assemble_to_file};
-    use super::{Cpu};
+    use snapshot::hash;
+    use super::{Cpu, HaltOn, HaltReason, StepResult};
+    #[cfg(feature = "jit")]
+    use jit::{Backend, BlockSpec};
+    #[cfg(feature = "jit")]
+    use std::collections::BTreeSet;
+
+    // guards against endless loops in the tested code
+    const MAX_CYCLES: u64 = 10000;
 
     macro_rules! check {
         ($code: expr; reg: $($reg: expr => $regval: expr), *; expect: $($regexp: expr => $regexpval: expr), *; flags: $flagval: expr) => {{
@@ -1419,7 +1523,9 @@ mod tests {
             $(
                 *cpu.reg_mut($reg) = $regval;
             )*
-                while cpu.step() {}
+                while cpu.step().is_running() {
+                    assert!(cpu.cycles() < MAX_CYCLES, "Maximum number of cycles reached!");
+                }
             $(
                 assert_eq!(cpu.reg($regexp), $regexpval);
             )*
@@ -2111,6 +2217,166 @@ mod tests {
                flags: 0b00001101);
     }
 
+    #[test]
+    fn halt_on() {
+        // ldi r16, 1; break; sleep; rjmp .-2
+        let program = vec![0x01, 0xe0, 0x98, 0x95, 0x88, 0x95, 0xfe, 0xcf];
+        let create = |halt_on: HaltOn| {
+            let mem = Memory::from_firmware(&Firmware::from_bytes(program.clone()).unwrap(), None);
+            let mut cpu = Cpu::new(mem, false);
+            cpu.set_halt_on(halt_on);
+            while cpu.step().is_running() {
+                assert!(cpu.cycles() < MAX_CYCLES, "Maximum number of cycles reached!");
+            }
+            cpu
+        };
+
+        let cpu = create(HaltOn { brk: true, ..HaltOn::default() });
+        assert_eq!(cpu.halt_reason(), Some(HaltReason::Break));
+        assert_eq!(cpu.reg(16), 1);
+        let cpu = create(HaltOn { sleep: true, ..HaltOn::default() });
+        assert_eq!(cpu.halt_reason(), Some(HaltReason::Sleep));
+        let cpu = create(HaltOn { addr: Some(4), ..HaltOn::default() });
+        assert_eq!(cpu.halt_reason(), Some(HaltReason::Addr(4)));
+        assert_eq!(cpu.ip(), 2);
+    }
+
+    #[test]
+    fn semihost_exit() {
+        // ldi r16, 42; sts 0xfff0, r16; ldi r16, 1; sts 0xfff6, r16; rjmp .-2
+        let program = vec![0x0a, 0xe2, 0x00, 0x93, 0xf0, 0xff, 0x01, 0xe0, 0x00, 0x93, 0xf6, 0xff, 0xff, 0xcf];
+        let mut mem = Memory::from_firmware(&Firmware::from_bytes(program).unwrap(), None);
+        mem.semihost_mut().set_enabled(true);
+        let mut cpu = Cpu::new(mem, false);
+        while cpu.step().is_running() {
+            assert!(cpu.cycles() < MAX_CYCLES, "Maximum number of cycles reached!");
+        }
+        assert_eq!(cpu.step(), StepResult::Halted(HaltReason::Exit(42)));
+        assert_eq!(cpu.cycles(), 4);
+    }
+
+    // the results and flags of the arithmetic for some edge cases
+    fn arithmetic_program() -> Vec<u8> {
+        fn rr(op: u16, d: u16, r: u16) -> u16 { op | (r & 0x10) << 5 | d << 4 | r & 0xf }
+        fn imm(op: u16, d: u16, k: u16) -> u16 { op | (k & 0xf0) << 4 | (d - 16) << 4 | k & 0xf }
+        fn word(op: u16, k: u16) -> u16 { op | (k & 0x30) << 2 | k & 0xf }
+        let values = [0, 1, 0x7f, 0x80, 0xff];
+        // add, adc, sub, sbc, and, or, eor, cp, cpc, mov
+        let ops = [0x0c00, 0x1c00, 0x1800, 0x0800, 0x2000, 0x2800, 0x2400, 0x1400, 0x0400, 0x2c00];
+        // subi, sbci, andi, ori, cpi
+        let imms = [0x5000, 0x4000, 0x7000, 0x6000, 0x3000];
+        // ldi r26, 0x60; ldi r27, 0
+        let mut program = vec![imm(0xe000, 26, 0x60), imm(0xe000, 27, 0)];
+        fn case(program: &mut Vec<u16>, sreg: u16, a: u16, b: u16, instr: u16) {
+            // ldi r19, sreg; out SREG, r19; ldi r16, a; ldi r17, b
+            program.extend_from_slice(&[imm(0xe000, 19, sreg), 0xbf3f, imm(0xe000, 16, a), imm(0xe000, 17, b)]);
+            // instr; in r18, SREG; st X+, r16; st X+, r18
+            program.extend_from_slice(&[instr, 0xb72f, 0x920d | 16 << 4, 0x920d | 18 << 4]);
+        }
+        for &sreg in &[0, 0x03] {
+            for &a in &values {
+                for &b in &values {
+                    for &op in &ops {
+                        case(&mut program, sreg, a, b, rr(op, 16, 17));
+                    }
+                    for &op in &imms {
+                        case(&mut program, sreg, a, b, imm(op, 16, b));
+                    }
+                }
+                // com r16, dec r16
+                case(&mut program, sreg, a, 0, 0x9400 | 16 << 4);
+                case(&mut program, sreg, a, 0, 0x940a | 16 << 4);
+            }
+        }
+        for &k in &[0, 1, 0x3f] {
+            for &(low, high) in &[(0, 0), (0xff, 0xff), (0xff, 0x7f), (0, 0x80), (0xc1, 0)] {
+                // ldi r24, low; ldi r25, high; adiw/sbiw r24, k; in r18, SREG; st X+, r24; st X+, r25; st X+, r18
+                for &op in &[0x9600, 0x9700] {
+                    program.extend_from_slice(&[imm(0xe000, 24, low), imm(0xe000, 25, high), word(op, k), 0xb72f,
+                                                0x920d | 24 << 4, 0x920d | 25 << 4, 0x920d | 18 << 4]);
+                }
+            }
+        }
+        program.iter().flat_map(|w| vec![*w as u8, (w >> 8) as u8]).collect()
+    }
+
+    fn check_arithmetic(mut cpu: Cpu) {
+        while cpu.step().is_running() {}
+        assert_eq!(cpu.halt_reason(), Some(HaltReason::Nop));
+        // the end of the results in X
+        assert_eq!((cpu.reg(26), cpu.reg(27)), (0xbe, 0x06));
+        let results = (0x60..0x6be).map(|addr| cpu.mem().data(addr)).collect::<Vec<_>>();
+        assert_eq!(hash(&results), 0xf10f_caf7);
+    }
+
+    #[test]
+    fn arithmetic() {
+        // the hash was taken with the interpreter and must be the same with the jit
+        let program = Firmware::from_bytes(arithmetic_program()).unwrap();
+        check_arithmetic(Cpu::new(Memory::from_firmware(&program, None), true));
+    }
+
+    #[test]
+    #[cfg(feature = "jit")]
+    fn interpreter_fallback() {
+        let program = Firmware::from_bytes(arithmetic_program()).unwrap();
+        let mut cpu = Cpu::new(Memory::from_firmware(&program, None), true);
+        cpu.set_jit(false);
+        check_arithmetic(cpu);
+    }
+
+    #[test]
+    #[cfg(feature = "jit")]
+    fn backends() {
+        // the backends of the other hosts can't run, but they must emit every block
+        let bytes = arithmetic_program();
+        let words = bytes.len() / 2;
+        let mem = Memory::from_firmware(&Firmware::from_bytes(bytes).unwrap(), None);
+        let stops = BTreeSet::new();
+        for &backend in &[Backend::X64, Backend::Aarch64] {
+            // a block up to the nop after the program and every instruction on its own
+            let spec = BlockSpec { addr: 0, stops: &stops, halt: None, single: false, ip_offset: 0x100 };
+            let block = Cpu::compile_block(backend, &mem, &spec);
+            assert_eq!(block.len as usize, words + 1);
+            for addr in 0..words {
+                let spec = BlockSpec { addr: addr, stops: &stops, halt: None, single: true, ip_offset: 0x100 };
+                let block = Cpu::compile_block(backend, &mem, &spec);
+                assert_eq!(block.len, 1);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "jit")]
+    fn timer_cycles() {
+        // ldi r16, 1; out TCCR1B, r16; 8 * ldi r18, 0; in r17, TCNT1L; nop
+        let mut program = vec![0xe001, 0xbd0e];
+        program.extend_from_slice(&[0xe020; 8]);
+        program.extend_from_slice(&[0xb51c, 0x0000]);
+        let bytes = program.iter().flat_map(|w: &u16| vec![*w as u8, (w >> 8) as u8]).collect::<Vec<_>>();
+        let program = Firmware::from_bytes(bytes).unwrap();
+        // the jit must count the cycles like the interpreter
+        let cpus = [true, false].iter().map(|&jit| {
+            let mut cpu = Cpu::new(Memory::from_firmware(&program, None), true);
+            cpu.set_jit(jit);
+            cpu
+        }).collect::<Vec<_>>();
+        for mut cpu in cpus {
+            // the timer counts from the instruction after the write of TCCR1B on
+            while cpu.step().is_running() {}
+            assert_eq!(cpu.reg(17), 9);
+            assert_eq!(cpu.mem().data(0x4c), 10);
+            assert_eq!(cpu.cycles(), 12);
+        }
+    }
+
+    #[test]
+    fn send() {
+        // the vm must be movable to other threads
+        fn assert_send<T: Send>() {}
+        assert_send::<Cpu>();
+    }
+
     fn create(code: &str) -> Cpu {
         Cpu::new(Memory::new(assemble_to_file(code), None), true)
     }
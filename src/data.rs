@@ -22,6 +22,7 @@ pub enum Instruction {
     BCLR(SREG),
     BLD_ST(LDType, Register, u8),
     BRBC_S(SetClear, SREG, i8),
+    BREAK,
     BSET(SREG),
     CALL(u32),
     C_SBI(SetClear, u8, u8),
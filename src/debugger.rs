@@ -0,0 +1,594 @@
+use std::collections::HashSet;
+use std::io;
+use std::io::prelude::*;
+use cpu::{Cpu, HaltReason, StepResult};
+use history::ReverseStop;
+use data::Instruction::SecondOpWord;
+use data::{X, Y, Z};
+use fault::{Fault, FaultPolicy};
+use firmware::{Symbols, DATA_OFFSET};
+use memory::{PROGRAM_SIZE, SRAM_SIZE};
+use uninit::UninitRead;
+use util::parse_num;
+use watch::{Watchpoint, WatchHit, WatchKind};
+
+const PROMPT: &str = "(vm) ";
+// the io registers start after the general purpose registers
+const IO_OFFSET: u16 = 0x20;
+/// the names of the io registers of the atmega32,
+/// index 0 is at 0x20 in the data memory
+pub const IO_NAMES: [&str; 64] = [
+    "TWBR", "TWSR", "TWAR", "TWDR", "ADCL", "ADCH", "ADCSRA", "ADMUX",
+    "ACSR", "UBRRL", "UCSRB", "UCSRA", "UDR", "SPCR", "SPSR", "SPDR",
+    "PIND", "DDRD", "PORTD", "PINC", "DDRC", "PORTC", "PINB", "DDRB",
+    "PORTB", "PINA", "DDRA", "PORTA", "EECR", "EEDR", "EEARL", "EEARH",
+    "UCSRC", "WDTCR", "ASSR", "OCR2", "TCNT2", "TCCR2", "ICR1L", "ICR1H",
+    "OCR1BL", "OCR1BH", "OCR1AL", "OCR1AH", "TCNT1L", "TCNT1H", "TCCR1B", "TCCR1A",
+    "SFIOR", "OSCCAL", "TCNT0", "TCCR0", "MCUCSR", "MCUCR", "TWCR", "SPMCR",
+    "TIFR", "TIMSK", "GIFR", "GICR", "OCR0", "SPL", "SPH", "SREG",
+];
+// bit 7 to 0 of SREG
+const SREG_FLAGS: &[u8; 8] = b"ITHSVNZC";
+// the default number of instructions shown before and after the pc
+const LIST_CONTEXT: usize = 5;
+const HEXDUMP_WIDTH: u16 = 16;
+const NO_HISTORY: &str = "no history, reverse execution isn't enabled";
+
+const HELP: &str = "commands:
+  s, step [n]        execute n instructions (default: 1)
+  c, continue        run until a breakpoint, a watchpoint or a halt
+  u, until <loc>     run until the location in the flash is reached
+  rs, reverse-step [n]
+                     go back n instructions (default: 1)
+  rc, reverse-continue
+                     go back to a breakpoint, before the last access of a
+                     watchpoint or to the start of the history
+  lw, last-write <var>
+                     show the last write to the variable
+  b, break [<loc>]   set a breakpoint or list the breakpoints
+  d, delete <loc>    remove a breakpoint
+  w, watch [<var> [== <val>]]
+                     stop after a write to the variable in the data memory,
+                     optionally only of the value, or list the watchpoints
+  rwatch <var> [== <val>]
+                     stop after a read of the variable
+  awatch <var> [== <val>]
+                     stop after a read or write of the variable
+  unwatch <var>      remove the watchpoints of the variable
+  r, regs            show the registers, SREG, SP and PC
+  bt, backtrace      show the calls and interrupts, which led to the pc
+  x <var> [n]        hexdump n bytes of the data memory
+                     (default: the size of the variable or 16)
+  io                 show the io registers
+  l, list [n]        disassemble n instructions around the pc (default: 5)
+  q, quit            leave the debugger
+locations are byte addresses like 0x1a4, symbols or symbol+offset,
+variables are data addresses, symbols, symbol+offset or io registers like PORTB.
+an empty line repeats the last command.";
+
+/// a small command line debugger, which works directly on the cpu
+pub struct Debugger {
+    symbols: Symbols,
+    // repeated on an empty line
+    last: String,
+    // the reads of uninitialized bytes, which were already reported
+    uninit_reads: HashSet<UninitRead>,
+}
+
+impl Debugger {
+    pub fn new(symbols: Symbols) -> Debugger {
+        Debugger { symbols: symbols, last: String::new(), uninit_reads: HashSet::new() }
+    }
+
+    /// reads commands until quit or the end of the input
+    pub fn run<R: BufRead, W: Write>(&mut self, cpu: &mut Cpu, input: R, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{}", self.instruction(cpu, cpu.ip(), true))?;
+        write!(out, "{}", PROMPT)?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.command(cpu, &line?, out)? {
+                return Ok(());
+            }
+            write!(out, "{}", PROMPT)?;
+            out.flush()?;
+        }
+        writeln!(out)
+    }
+
+    /// executes a command line, returns false, if the debugger should quit
+    pub fn command<W: Write>(&mut self, cpu: &mut Cpu, line: &str, out: &mut W) -> io::Result<bool> {
+        let line = if line.trim().is_empty() { self.last.clone() } else { line.trim().to_string() };
+        self.last = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (cmd, args) = match words.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => return Ok(true),
+        };
+
+        let reply = match (cmd, args) {
+            ("q", []) | ("quit", []) => return Ok(false),
+            ("h", []) | ("help", []) => Ok(HELP.to_string()),
+            ("s", _) | ("step", _) if args.len() <= 1 => match args.first() {
+                Some(n) => parse_num(n).filter(|&n| n > 0).ok_or_else(|| format!("invalid count: {}", n)),
+                None => Ok(1),
+            }.map(|n| self.resume(cpu, Some(n))),
+            ("c", []) | ("continue", []) => Ok(self.resume(cpu, None)),
+            ("u", [loc]) | ("until", [loc]) => self.until(cpu, loc),
+            ("rs", _) | ("reverse-step", _) if args.len() <= 1 => match args.first() {
+                Some(n) => parse_num(n).filter(|&n| n > 0).ok_or_else(|| format!("invalid count: {}", n)),
+                None => Ok(1),
+            }.and_then(|n| self.reverse_step(cpu, n)),
+            ("rc", []) | ("reverse-continue", []) => self.reverse_continue(cpu),
+            ("lw", [var]) | ("last-write", [var]) => self.last_write(cpu, var),
+            ("b", []) | ("break", []) => Ok(cpu.breakpoints().iter()
+                .map(|&addr| format!("breakpoint at {}", self.code_location(addr as u32)))
+                .collect::<Vec<_>>().join("\n")),
+            ("b", [loc]) | ("break", [loc]) => self.code_addr(loc).map(|addr| {
+                cpu.add_breakpoint(addr as usize);
+                format!("breakpoint at {}", self.code_location(addr))
+            }),
+            ("d", [loc]) | ("delete", [loc]) => self.code_addr(loc).and_then(|addr| {
+                if !cpu.breakpoints().contains(&(addr as usize)) {
+                    return Err(format!("no breakpoint at {}", self.code_location(addr)));
+                }
+                cpu.remove_breakpoint(addr as usize);
+                Ok(String::new())
+            }),
+            ("w", []) | ("watch", []) => Ok(cpu.watchpoints().iter()
+                .map(|w| self.describe_watchpoint(w))
+                .collect::<Vec<_>>().join("\n")),
+            ("w", _) | ("watch", _) => self.watch(cpu, WatchKind::Write, args),
+            ("rwatch", _) => self.watch(cpu, WatchKind::Read, args),
+            ("awatch", _) => self.watch(cpu, WatchKind::Access, args),
+            ("unwatch", [var]) => self.data_addr(var).and_then(|(addr, _)| {
+                let watchpoints: Vec<_> = cpu.watchpoints().iter().filter(|w| w.addr == addr).cloned().collect();
+                if watchpoints.is_empty() {
+                    return Err(format!("no watchpoint at {}", self.data_location(addr)));
+                }
+                for w in &watchpoints {
+                    cpu.remove_watchpoint(w);
+                }
+                Ok(String::new())
+            }),
+            ("r", []) | ("regs", []) => Ok(self.regs(cpu)),
+            ("bt", []) | ("backtrace", []) => self.backtrace(cpu),
+            ("x", _) if !args.is_empty() && args.len() <= 2 => self.data_addr(args[0]).and_then(|(addr, len)| {
+                let len = match args.get(1) {
+                    Some(n) => parse_num(n).ok_or_else(|| format!("invalid count: {}", n))? as u16,
+                    None => len,
+                };
+                self.hexdump(cpu, addr, len)
+            }),
+            ("io", []) => Ok(io_regs(cpu)),
+            ("l", _) | ("list", _) if args.len() <= 1 => match args.first() {
+                Some(n) => parse_num(n).ok_or_else(|| format!("invalid count: {}", n)),
+                None => Ok(LIST_CONTEXT as u64),
+            }.map(|n| self.list(cpu, n as usize)),
+            _ => Err(format!("unknown command: {}, try help", line)),
+        };
+        match reply {
+            Ok(ref text) if text.is_empty() => {}
+            Ok(text) | Err(text) => writeln!(out, "{}", text)?,
+        }
+        Ok(true)
+    }
+
+    // runs the given number of steps or until the cpu stops
+    fn resume(&mut self, cpu: &mut Cpu, steps: Option<u64>) -> String {
+        cpu.set_single_step(steps.is_some());
+        let mut n = 0;
+        let mut warnings = String::new();
+        let stop = loop {
+            let result = cpu.step();
+            if let Some(fault) = cpu.fault() {
+                if cpu.fault_policies().get(fault.kind) == FaultPolicy::Warn {
+                    warnings.push_str(&format!("warning: {}\n", self.describe_fault(&fault)));
+                } else {
+                    break Some(self.describe_fault(&fault));
+                }
+            }
+            if let Some(read) = cpu.uninit_read() {
+                if self.uninit_reads.insert(read) {
+                    warnings.push_str(&format!("warning: {}\n", self.describe_uninit(&read)));
+                }
+            }
+            if let StepResult::Halted(reason) = result {
+                break Some(describe(reason));
+            }
+            if let Some(hit) = cpu.watch_hit() {
+                break Some(self.describe_hit(&hit));
+            }
+            if let Some(error) = cpu.stack_error() {
+                break Some(format!("{} at {}", error.describe(), self.code_location(error.pc as u32)));
+            }
+            if cpu.at_breakpoint() {
+                break Some(format!("breakpoint at {}", self.code_location((cpu.ip() << 1) as u32)));
+            }
+            n += 1;
+            if steps == Some(n) {
+                break None;
+            }
+        };
+        cpu.set_single_step(false);
+        let current = self.instruction(cpu, cpu.ip(), true);
+        match stop {
+            Some(msg) => format!("{}{}\n{}", warnings, msg, current),
+            None => format!("{}{}", warnings, current),
+        }
+    }
+
+    fn backtrace(&self, cpu: &Cpu) -> Result<String, String> {
+        let call_stack = cpu.call_stack().ok_or_else(|| "the calls aren't followed".to_string())?;
+        Ok(call_stack.backtrace(cpu.ip() << 1).iter().enumerate()
+            .map(|(i, &(pc, interrupted))| format!("#{:<2} {}{}", i, self.code_location(pc as u32),
+                                                  if interrupted { " (interrupted)" } else { "" }))
+            .collect::<Vec<_>>().join("\n"))
+    }
+
+    fn describe_fault(&self, fault: &Fault) -> String {
+        format!("{} at {}", fault.describe(), self.code_location(fault.pc as u32))
+    }
+
+    fn describe_uninit(&self, read: &UninitRead) -> String {
+        let what = match read.register() {
+            Some(reg) => format!("r{}", reg),
+            None => self.data_location(read.addr),
+        };
+        format!("read of uninitialized {} at {}", what, self.code_location(read.pc as u32))
+    }
+
+    fn until(&mut self, cpu: &mut Cpu, loc: &str) -> Result<String, String> {
+        let addr = self.code_addr(loc)? as usize;
+        // a temporary breakpoint, unless there is already one
+        let temporary = !cpu.breakpoints().contains(&addr);
+        if temporary {
+            cpu.add_breakpoint(addr);
+        }
+        let stop = self.resume(cpu, None);
+        if temporary {
+            cpu.remove_breakpoint(addr);
+        }
+        Ok(stop)
+    }
+
+    fn reverse_step(&self, cpu: &mut Cpu, steps: u64) -> Result<String, String> {
+        if cpu.history().is_none() {
+            return Err(NO_HISTORY.to_string());
+        }
+        let complete = (0..steps).all(|_| cpu.reverse_step());
+        let current = self.instruction(cpu, cpu.ip(), true);
+        Ok(if complete { current } else { format!("start of the history\n{}", current) })
+    }
+
+    fn reverse_continue(&self, cpu: &mut Cpu) -> Result<String, String> {
+        let msg = match cpu.reverse_continue() {
+            Some(ReverseStop::Breakpoint) => format!("breakpoint at {}", self.code_location((cpu.ip() << 1) as u32)),
+            Some(ReverseStop::Watchpoint(hit)) => self.describe_hit(&hit),
+            Some(ReverseStop::Start) => "start of the history".to_string(),
+            None => return Err(NO_HISTORY.to_string()),
+        };
+        Ok(format!("{}\n{}", msg, self.instruction(cpu, cpu.ip(), true)))
+    }
+
+    fn last_write(&self, cpu: &Cpu, var: &str) -> Result<String, String> {
+        let (addr, len) = self.data_addr(var)?;
+        let history = cpu.history().ok_or_else(|| NO_HISTORY.to_string())?;
+        match history.writes().iter().rev().find(|w| w.addr >= addr && w.addr - addr < len) {
+            Some(w) => Ok(format!("{} = {:02x} in cycle {} by {}", self.data_location(w.addr), w.value, w.cycles,
+                                  self.code_location(w.pc as u32))),
+            None => Ok(format!("{} wasn't written since cycle {}", self.data_location(addr),
+                               history.start().unwrap_or(cpu.cycles()))),
+        }
+    }
+
+    // watch <var> [== <val>]
+    fn watch(&self, cpu: &mut Cpu, kind: WatchKind, args: &[&str]) -> Result<String, String> {
+        let (addr, len) = match *args {
+            [var] | [var, "==", _] => self.data_addr(var)?,
+            _ => return Err(format!("usage: {} <var> [== <val>]", watch_command(kind))),
+        };
+        let mut watchpoint = Watchpoint::new(addr, len, kind);
+        if let [_, _, val] = *args {
+            let val = parse_num(val).filter(|&v| v <= 0xff).ok_or_else(|| format!("invalid value: {}", val))?;
+            watchpoint = watchpoint.with_value(val as u8);
+        }
+        cpu.add_watchpoint(watchpoint);
+        Ok(self.describe_watchpoint(&watchpoint))
+    }
+
+    fn describe_watchpoint(&self, w: &Watchpoint) -> String {
+        let value = w.value.map_or(String::new(), |v| format!(" == {:#04x}", v));
+        format!("{} at {}, {} byte{}{}", watch_name(w.kind),
+                self.data_location(w.addr), w.len, if w.len == 1 { "" } else { "s" }, value)
+    }
+
+    fn describe_hit(&self, hit: &WatchHit) -> String {
+        let access = if hit.write {
+            format!("{:02x} -> {:02x}", hit.old, hit.new)
+        } else {
+            format!("read {:02x}", hit.new)
+        };
+        format!("{} at {}: {} by {}", watch_name(hit.watchpoint.kind),
+                self.data_location(hit.addr), access, self.code_location(hit.pc as u32))
+    }
+
+    // a byte address in the flash
+    fn code_addr(&self, loc: &str) -> Result<u32, String> {
+        let (name, offset) = split_offset(loc)?;
+        let addr = match parse_num(name) {
+            Some(addr) => addr as u32,
+            None => match self.symbols.get(name) {
+                Some(sym) if sym.value < DATA_OFFSET => sym.value,
+                _ => return Err(format!("unknown location: {}", name)),
+            },
+        } + offset;
+        if addr % 2 != 0 || addr >= PROGRAM_SIZE as u32 {
+            return Err(format!("invalid address in the flash: {:#x}", addr));
+        }
+        Ok(addr)
+    }
+
+    // an address in the data memory and the size of the variable
+    fn data_addr(&self, var: &str) -> Result<(u16, u16), String> {
+        let (name, offset) = split_offset(var)?;
+        let (addr, len) = if let Some(addr) = parse_num(name) {
+            (addr as u32, HEXDUMP_WIDTH as u32)
+        } else if let Some(i) = IO_NAMES.iter().position(|n| n.eq_ignore_ascii_case(name)) {
+            (IO_OFFSET as u32 + i as u32, 1)
+        } else {
+            match self.symbols.get(name).and_then(|sym| sym.data_addr().map(|addr| (addr, sym.size))) {
+                Some((addr, size)) => (addr as u32, size.max(1)),
+                None => return Err(format!("unknown variable: {}", name)),
+            }
+        };
+        let addr = addr + offset;
+        if addr >= SRAM_SIZE as u32 {
+            return Err(format!("{:#x} is outside of the data memory", addr));
+        }
+        Ok((addr as u16, len.min(SRAM_SIZE as u32 - addr) as u16))
+    }
+
+    fn code_location(&self, addr: u32) -> String {
+        match self.symbols.function_at(addr) {
+            Some(sym) if sym.value == addr => format!("{:#06x} <{}>", addr, sym.name),
+            Some(sym) => format!("{:#06x} <{}+{}>", addr, sym.name, addr - sym.value),
+            None => format!("{:#06x}", addr),
+        }
+    }
+
+    fn data_location(&self, addr: u16) -> String {
+        if addr >= IO_OFFSET && addr < IO_OFFSET + IO_NAMES.len() as u16 {
+            return format!("{:#06x} <{}>", addr, IO_NAMES[(addr - IO_OFFSET) as usize]);
+        }
+        match self.symbols.object_at(addr) {
+            Some(sym) => {
+                let offset = addr as u32 + DATA_OFFSET - sym.value;
+                if offset == 0 {
+                    format!("{:#06x} <{}>", addr, sym.name)
+                } else {
+                    format!("{:#06x} <{}+{}>", addr, sym.name, offset)
+                }
+            }
+            None => format!("{:#06x}", addr),
+        }
+    }
+
+    // a line of the disassembly, current marks the pc
+    fn instruction(&self, cpu: &Cpu, ip: usize, current: bool) -> String {
+        format!("{} {}: {:?}", if current { "=>" } else { "  " },
+                self.code_location((ip << 1) as u32), cpu.mem().get_instruction(ip))
+    }
+
+    fn list(&self, cpu: &Cpu, context: usize) -> String {
+        let is_start = |ip: usize| cpu.mem().get_instruction(ip) != SecondOpWord;
+        let mut start = cpu.ip();
+        for _ in 0..context {
+            match (0..start).rev().find(|&ip| is_start(ip)) {
+                Some(ip) => start = ip,
+                None => break,
+            }
+        }
+        let after = (cpu.ip() + 1..PROGRAM_SIZE >> 1).filter(|&ip| is_start(ip)).take(context);
+        (start..cpu.ip() + 1).filter(|&ip| is_start(ip)).chain(after)
+            .map(|ip| self.instruction(cpu, ip, ip == cpu.ip()))
+            .collect::<Vec<_>>().join("\n")
+    }
+
+    fn regs(&self, cpu: &Cpu) -> String {
+        let mut lines: Vec<String> = (0..4).map(|row| {
+            (row * 8..row * 8 + 8).map(|r| format!("r{:<2} {:02x}", r, cpu.reg(r))).collect::<Vec<_>>().join("  ")
+        }).collect();
+        let pair = |r| (cpu.reg(r + 1) as u16) << 8 | cpu.reg(r) as u16;
+        lines.push(format!("X {:#06x}  Y {:#06x}  Z {:#06x}", pair(X), pair(Y), pair(Z)));
+        let flags: String = SREG_FLAGS.iter().enumerate()
+            .map(|(i, &f)| if cpu.flags() >> (7 - i) & 1 == 1 { f as char } else { '-' })
+            .collect();
+        lines.push(format!("SREG {:#04x} [{}]  SP {:#06x}  PC {}  cycles {}", cpu.flags(), flags,
+                           cpu.mem().sp(), self.code_location((cpu.ip() << 1) as u32), cpu.cycles()));
+        lines.join("\n")
+    }
+
+    fn hexdump(&self, cpu: &Cpu, addr: u16, len: u16) -> Result<String, String> {
+        if addr as u32 + len as u32 > SRAM_SIZE as u32 {
+            return Err(format!("{:#x} is outside of the data memory", addr as u32 + len as u32 - 1));
+        }
+        let mut lines = Vec::new();
+        let mut row = addr;
+        while row < addr + len {
+            let end = (row + HEXDUMP_WIDTH).min(addr + len);
+            let bytes: Vec<u8> = (row..end).map(|a| cpu.mem().data(a)).collect();
+            let ascii: String = bytes.iter()
+                .map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' })
+                .collect();
+            lines.push(format!("{:<24} {:<48} |{}|", self.data_location(row) + ":", hex(&bytes), ascii));
+            row = end;
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+fn io_regs(cpu: &Cpu) -> String {
+    IO_NAMES.chunks(4).enumerate().map(|(row, names)| {
+        names.iter().enumerate().map(|(i, name)| {
+            let addr = IO_OFFSET + (row * 4 + i) as u16;
+            format!("{:>6} ({:#04x}) {:02x}", name, addr, cpu.mem().data(addr))
+        }).collect::<Vec<_>>().join("  ")
+    }).collect::<Vec<_>>().join("\n")
+}
+
+// splits symbol+offset
+fn split_offset(loc: &str) -> Result<(&str, u32), String> {
+    match loc.find('+') {
+        Some(i) => parse_num(&loc[i + 1..])
+            .map(|offset| (&loc[..i], offset as u32))
+            .ok_or_else(|| format!("invalid offset: {}", &loc[i + 1..])),
+        None => Ok((loc, 0)),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+fn watch_command(kind: WatchKind) -> &'static str {
+    match kind {
+        WatchKind::Read => "rwatch",
+        WatchKind::Write => "watch",
+        WatchKind::Access => "awatch",
+    }
+}
+
+fn watch_name(kind: WatchKind) -> &'static str {
+    match kind {
+        WatchKind::Read => "read watchpoint",
+        WatchKind::Write => "watchpoint",
+        WatchKind::Access => "access watchpoint",
+    }
+}
+
+fn describe(reason: HaltReason) -> String {
+    match reason {
+        HaltReason::Nop => "halted at nop".to_string(),
+        HaltReason::Break => "halted at break".to_string(),
+        HaltReason::Sleep => "halted at sleep with interrupts disabled".to_string(),
+        HaltReason::Addr(addr) => format!("halted at {:#x}", addr),
+        HaltReason::Exit(status) => format!("exited with status {}", status),
+        HaltReason::Fault(kind) => format!("halted at a fault ({})", kind.name()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use callstack::CallStack;
+    use cpu::Cpu;
+    use firmware::{Firmware, Symbol, SymbolKind, Symbols};
+    use history::History;
+    use memory::Memory;
+    use stack::StackGuard;
+    use super::Debugger;
+
+    // ldi r16, 'A'; sts 0x60, r16; subi r16, -1; rjmp .-8
+    const COUNTER: [u8; 10] = [0x01, 0xe4, 0x00, 0x93, 0x60, 0x00, 0x0f, 0x5f, 0xfc, 0xcf];
+
+    fn debugger() -> (Debugger, Cpu) {
+        let symbols = Symbols::new(vec![
+            Symbol { name: "main".to_string(), value: 0, size: 10, kind: SymbolKind::Func, section: Some(".text".to_string()) },
+            Symbol { name: "counter".to_string(), value: 0x800060, size: 1, kind: SymbolKind::Object, section: Some(".data".to_string()) },
+        ]);
+        let mem = Memory::from_firmware(&Firmware::from_bytes(COUNTER.to_vec()).unwrap(), None);
+        (Debugger::new(symbols), Cpu::new(mem, false))
+    }
+
+    fn cmd(d: &mut Debugger, cpu: &mut Cpu, line: &str) -> String {
+        let mut out = Vec::new();
+        assert!(d.command(cpu, line, &mut out).unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (mut d, mut cpu) = debugger();
+        assert_eq!(cmd(&mut d, &mut cpu, "b main+6"), "breakpoint at 0x0006 <main+6>\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "c"), "breakpoint at 0x0006 <main+6>\n=> 0x0006 <main+6>: SUBI(16, 255)\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "s"), "=> 0x0008 <main+8>: RJMP(-3)\n");
+        // an empty line repeats the step
+        assert_eq!(cmd(&mut d, &mut cpu, ""), "=> 0x0002 <main+2>: LD_STS(ST, 16, 96)\n");
+        // until stops at the breakpoint on the way
+        assert_eq!(cmd(&mut d, &mut cpu, "u 0x8"), "breakpoint at 0x0006 <main+6>\n=> 0x0006 <main+6>: SUBI(16, 255)\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "d main+6"), "");
+        assert_eq!(cmd(&mut d, &mut cpu, "d main+6"), "no breakpoint at 0x0006 <main+6>\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "u 0x8"), "breakpoint at 0x0008 <main+8>\n=> 0x0008 <main+8>: RJMP(-3)\n");
+        assert!(cpu.breakpoints().is_empty());
+        assert_eq!(cmd(&mut d, &mut cpu, "b 0x3"), "invalid address in the flash: 0x3\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "b foo"), "unknown location: foo\n");
+        let mut out = Vec::new();
+        assert!(!d.command(&mut cpu, "q", &mut out).unwrap());
+    }
+
+    #[test]
+    fn watchpoints() {
+        let (mut d, mut cpu) = debugger();
+        assert_eq!(cmd(&mut d, &mut cpu, "w counter"), "watchpoint at 0x0060 <counter>, 1 byte\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "c"),
+                   "watchpoint at 0x0060 <counter>: 00 -> 41 by 0x0002 <main+2>\n=> 0x0006 <main+6>: SUBI(16, 255)\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "c"),
+                   "watchpoint at 0x0060 <counter>: 41 -> 42 by 0x0002 <main+2>\n=> 0x0006 <main+6>: SUBI(16, 255)\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "unwatch counter"), "");
+        assert_eq!(cmd(&mut d, &mut cpu, "unwatch counter"), "no watchpoint at 0x0060 <counter>\n");
+        assert!(cpu.watchpoints().is_empty());
+
+        assert_eq!(cmd(&mut d, &mut cpu, "w counter == 0x45"), "watchpoint at 0x0060 <counter>, 1 byte == 0x45\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "rwatch SPL"), "read watchpoint at 0x005d <SPL>, 1 byte\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "w"),
+                   "watchpoint at 0x0060 <counter>, 1 byte == 0x45\nread watchpoint at 0x005d <SPL>, 1 byte\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "c"),
+                   "watchpoint at 0x0060 <counter>: 44 -> 45 by 0x0002 <main+2>\n=> 0x0006 <main+6>: SUBI(16, 255)\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "awatch counter =="), "usage: awatch <var> [== <val>]\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "w counter == 0x100"), "invalid value: 0x100\n");
+    }
+
+    #[test]
+    fn reverse() {
+        let (mut d, mut cpu) = debugger();
+        assert_eq!(cmd(&mut d, &mut cpu, "rs"), "no history, reverse execution isn't enabled\n");
+        cpu.set_history(Some(History::new(4, 10)));
+        cmd(&mut d, &mut cpu, "s 10");
+        assert_eq!(cmd(&mut d, &mut cpu, "rs"), "=> 0x0008 <main+8>: RJMP(-3)\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "lw counter"), "0x0060 <counter> = 43 in cycle 8 by 0x0002 <main+2>\n");
+        cmd(&mut d, &mut cpu, "w counter");
+        assert_eq!(cmd(&mut d, &mut cpu, "rc"),
+                   "watchpoint at 0x0060 <counter>: 42 -> 43 by 0x0002 <main+2>\n=> 0x0002 <main+2>: LD_STS(ST, 16, 96)\n");
+        assert_eq!(cpu.cycles(), 7);
+        assert_eq!(cmd(&mut d, &mut cpu, "unwatch counter"), "");
+        assert_eq!(cmd(&mut d, &mut cpu, "rc"), "start of the history\n=> 0x0000 <main>: LDI(16, 65)\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "lw counter"), "0x0060 <counter> wasn't written since cycle 0\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "rs 2"), "start of the history\n=> 0x0000 <main>: LDI(16, 65)\n");
+    }
+
+    #[test]
+    fn stack_guard() {
+        let (mut d, mut cpu) = debugger();
+        cpu.set_stack_guard(Some(StackGuard::new(0x100, 10)));
+        cpu.mem_mut().set_sp(0x50);
+        assert_eq!(cmd(&mut d, &mut cpu, "c"), "stack overflow into the io registers, sp 0x0050 at 0x0000 <main>\n\
+                                                => 0x0002 <main+2>: LD_STS(ST, 16, 96)\n");
+    }
+
+    #[test]
+    fn inspect() {
+        let (mut d, mut cpu) = debugger();
+        cmd(&mut d, &mut cpu, "s 2");
+        assert!(cmd(&mut d, &mut cpu, "r").contains("r16 41"));
+        assert_eq!(cmd(&mut d, &mut cpu, "x counter 2"),
+                   format!("{:<24} {:<48} |A.|\n", "0x0060 <counter>:", "41 00"));
+        assert!(cmd(&mut d, &mut cpu, "x portb").starts_with("0x0038 <PORTB>:"));
+        assert_eq!(cmd(&mut d, &mut cpu, "x 0x900"), "0x900 is outside of the data memory\n");
+        // the second word of sts is skipped
+        assert_eq!(cmd(&mut d, &mut cpu, "l 1"), "   0x0002 <main+2>: LD_STS(ST, 16, 96)\n\
+                                                  => 0x0006 <main+6>: SUBI(16, 255)\n   \
+                                                  0x0008 <main+8>: RJMP(-3)\n");
+        assert_eq!(cmd(&mut d, &mut cpu, "bt"), "the calls aren't followed\n");
+        cpu.set_call_stack(Some(CallStack::new()));
+        assert_eq!(cmd(&mut d, &mut cpu, "bt"), "#0  0x0006 <main+6>\n");
+    }
+}
@@ -144,6 +144,7 @@ impl<T: Iterator<Item=u8>> Iterator for DecodedIterator<T> {
                                                     0b0000 => RET,
                                                     0b0001 => RETI,
                                                     0b1000 => SLEEP,
+                                                    0b1001 => BREAK,
                                                     0b1100 => LPM(0, LPMType::Z),
                                                     _ => UnknownOp(b)
                                                 },
@@ -467,6 +468,11 @@ mod tests {
         decode_expect("BRVS d\nd:nop", vec![BRBC_S(Set, 3, 1), NOP]);
     }
 
+    #[test]
+    fn break_() {
+        decode_expect("BREAK", vec![BREAK]);
+    }
+
     #[test]
     fn bst() {
         decode_expect("BST R0, 0", vec![BLD_ST(LDType::ST, 0, 0)]);
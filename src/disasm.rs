@@ -0,0 +1,372 @@
+use std::io;
+use std::io::prelude::*;
+use data::Instruction;
+use data::Instruction::*;
+use data::{LDMode, LDType, LPMType, Register, SetClear, X, Y};
+use decoder::decode;
+use firmware::{Firmware, Section, SymbolKind, DATA_OFFSET};
+
+// the names of brbs, brbc, bset and bclr for each bit of SREG
+const BRANCH_SET: [&str; 8] = ["brcs", "breq", "brmi", "brvs", "brlt", "brhs", "brts", "brie"];
+const BRANCH_CLEAR: [&str; 8] = ["brcc", "brne", "brpl", "brvc", "brge", "brhc", "brtc", "brid"];
+const SET_FLAG: [&str; 8] = ["sec", "sez", "sen", "sev", "ses", "seh", "set", "sei"];
+const CLEAR_FLAG: [&str; 8] = ["clc", "clz", "cln", "clv", "cls", "clh", "clt", "cli"];
+// avr-objdump replaces runs of zeros with ..., if they are at least this long
+// or shorter than SKIP_ZEROS_AT_END at the end of a symbol
+const SKIP_ZEROS: usize = 8;
+const SKIP_ZEROS_AT_END: usize = 3;
+
+/// renders instructions like avr-objdump -d
+pub struct Disassembler {
+    // the labels in the code sections and the variables in the data
+    // memory, sorted by address with one name for each address
+    labels: Vec<(u32, String)>,
+    variables: Vec<(u32, String)>,
+    // lsl, rol, tst, clr and ser, which avr-objdump doesn't use
+    aliases: bool,
+}
+
+impl Disassembler {
+    pub fn new(firmware: &Firmware, aliases: bool) -> Disassembler {
+        let sections = sections(firmware);
+        let in_code = |section: &Option<String>| match *section {
+            Some(ref name) => sections.iter().any(|s| s.name == *name),
+            None => false,
+        };
+        // like avr-objdump, functions are preferred, if several symbols have the same
+        // address, and the name of the section is used, if there is no symbol
+        let mut labels: Vec<_> = firmware.symbols.iter()
+            .filter(|s| in_code(&s.section))
+            .map(|s| (s.value, if s.kind == SymbolKind::Func { 0 } else { 1 }, s.name.clone()))
+            .chain(sections.iter().map(|s| (s.addr, 2, s.name.clone())))
+            .collect();
+        labels.sort();
+        labels.dedup_by_key(|l| l.0);
+        let mut variables: Vec<_> = firmware.symbols.iter()
+            .filter(|s| s.section.is_some() && s.data_addr().is_some())
+            .map(|s| (s.value, s.name.clone()))
+            .collect();
+        variables.sort();
+        variables.dedup_by_key(|v| v.0);
+
+        Disassembler {
+            labels: labels.into_iter().map(|(value, _, name)| (value, name)).collect(),
+            variables: variables,
+            aliases: aliases,
+        }
+    }
+
+    /// a byte address in the flash with the label before it, e.g. 0x6e <main+0x2>
+    pub fn location(&self, addr: u32) -> String {
+        with_symbol(&self.labels, addr)
+    }
+
+    /// the mnemonic, the operands and a comment for the instruction at the byte address
+    pub fn instruction(&self, instr: Instruction, addr: u32) -> String {
+        let imm = |m, d: Register, k: u8| (m, format!("r{}, 0x{:02X}", d, k), Some(k.to_string()));
+        let io = |m, a: u8, b: u8| (m, format!("0x{:02x}, {}", a, b), Some(a.to_string()));
+        let reg = |m, d: Register| (m, format!("r{}", d), None);
+        let regs = |m, d: Register, r: Register| (m, format!("r{}, r{}", d, r), None);
+        let rel = |m, k: i32| {
+            // k is relative to the instruction, avr-objdump shows it relative to the next one
+            let target = (addr as i32 + k * 2) as u32;
+            (m, format!(".{:<+8}", (k - 1) * 2), Some(self.location(target)))
+        };
+        let abs = |m, k: u32| (m, if k == 0 { "0".to_string() } else { format!("{:#x}", k * 2) }, Some(self.location(k * 2)));
+        let none = |m| (m, String::new(), None);
+
+        let (mnemonic, operands, comment): (&str, String, Option<String>) = match instr {
+            UnknownOp(w) => (".word", format!("0x{:04x}", w), Some("????".to_string())),
+            IncompleteOp(b) => (".byte", format!("0x{:02x}", b), None),
+            SecondOpWord => (".word", String::new(), Some("second word of the previous instruction".to_string())),
+
+            ADC(d, r) if self.aliases && d == r => reg("rol", d),
+            ADC(d, r) => regs("adc", d, r),
+            ADD(d, r) if self.aliases && d == r => reg("lsl", d),
+            ADD(d, r) => regs("add", d, r),
+            ADIW(d, k) => imm("adiw", d, k),
+            AND(d, r) if self.aliases && d == r => reg("tst", d),
+            AND(d, r) => regs("and", d, r),
+            ANDI(d, k) => imm("andi", d, k),
+            ASR(d) => reg("asr", d),
+            BCLR(s) => none(CLEAR_FLAG[s as usize]),
+            BLD_ST(LDType::LD, d, b) => ("bld", format!("r{}, {}", d, b), None),
+            BLD_ST(LDType::ST, d, b) => ("bst", format!("r{}, {}", d, b), None),
+            BRBC_S(SetClear::Set, s, k) => rel(BRANCH_SET[s as usize], k as i32),
+            BRBC_S(SetClear::Clear, s, k) => rel(BRANCH_CLEAR[s as usize], k as i32),
+            BREAK => none("break"),
+            BSET(s) => none(SET_FLAG[s as usize]),
+            CALL(k) => abs("call", k),
+            C_SBI(SetClear::Clear, a, b) => io("cbi", a, b),
+            C_SBI(SetClear::Set, a, b) => io("sbi", a, b),
+            COM(d) => reg("com", d),
+            CP(d, r) => regs("cp", d, r),
+            CPC(d, r) => regs("cpc", d, r),
+            CPI(d, k) => imm("cpi", d, k),
+            CPSE(d, r) => regs("cpse", d, r),
+            DEC(d) => reg("dec", d),
+            EOR(d, r) if self.aliases && d == r => reg("clr", d),
+            EOR(d, r) => regs("eor", d, r),
+            ICALL => none("icall"),
+            IN(d, a) => ("in", format!("r{}, 0x{:02x}", d, a), Some(a.to_string())),
+            INC(d) => reg("inc", d),
+            JMP(k) => abs("jmp", k),
+            LD_ST(LDType::LD, d, ptr, mode) =>
+                (if displacement(mode) { "ldd" } else { "ld" }, format!("r{}, {}", d, pointer(ptr, mode)), None),
+            LD_ST(LDType::ST, r, ptr, mode) =>
+                (if displacement(mode) { "std" } else { "st" }, format!("{}, r{}", pointer(ptr, mode), r), None),
+            LD_STS(LDType::LD, d, k) => ("lds", format!("r{}, 0x{:04X}", d, k), Some(self.variable(k))),
+            LD_STS(LDType::ST, r, k) => ("sts", format!("0x{:04X}, r{}", k, r), Some(self.variable(k))),
+            LDI(d, 0xff) if self.aliases => reg("ser", d),
+            LDI(d, k) => imm("ldi", d, k),
+            // the decoder returns the same for lpm and lpm r0, Z, the first is far more common
+            LPM(0, LPMType::Z) => none("lpm"),
+            LPM(d, LPMType::Z) => ("lpm", format!("r{}, Z", d), None),
+            LPM(d, LPMType::ZPostIncrement) => ("lpm", format!("r{}, Z+", d), None),
+            LSR(d) => reg("lsr", d),
+            MOV(d, r) => regs("mov", d, r),
+            MOVW(d, r) => regs("movw", d, r),
+            MUL(d, r) => regs("mul", d, r),
+            NEG(d) => reg("neg", d),
+            NOP => none("nop"),
+            OR(d, r) => regs("or", d, r),
+            ORI(d, k) => imm("ori", d, k),
+            OUT(r, a) => ("out", format!("0x{:02x}, r{}", a, r), Some(a.to_string())),
+            POP(d) => reg("pop", d),
+            PUSH(r) => reg("push", r),
+            RCALL(k) => rel("rcall", k as i32),
+            RET => none("ret"),
+            RETI => none("reti"),
+            RJMP(k) => rel("rjmp", k as i32),
+            ROR(d) => reg("ror", d),
+            SBC(d, r) => regs("sbc", d, r),
+            SBCI(d, k) => imm("sbci", d, k),
+            SBIC_S(SetClear::Clear, a, b) => io("sbic", a, b),
+            SBIC_S(SetClear::Set, a, b) => io("sbis", a, b),
+            SBIW(d, k) => imm("sbiw", d, k),
+            SBR(SetClear::Clear, d, b) => ("sbrc", format!("r{}, {}", d, b), None),
+            SBR(SetClear::Set, d, b) => ("sbrs", format!("r{}, {}", d, b), None),
+            SLEEP => none("sleep"),
+            SUB(d, r) => regs("sub", d, r),
+            SUBI(d, k) => imm("subi", d, k),
+            SWAP(d) => reg("swap", d),
+        };
+
+        let mut line = mnemonic.to_string();
+        if !operands.is_empty() {
+            line.push('\t');
+            line.push_str(&operands);
+        }
+        if let Some(comment) = comment {
+            line.push_str("\t; ");
+            line.push_str(&comment);
+        }
+        line
+    }
+
+    /// disassembles the code sections of the firmware with the output format
+    /// of avr-objdump -d, file is the name shown in the header
+    pub fn write<W: Write>(&self, firmware: &Firmware, file: &str, out: &mut W) -> io::Result<()> {
+        let format = if firmware.code.is_empty() { "binary" } else { "elf32-avr" };
+        write!(out, "\n{}:     file format {}\n\n", file, format)?;
+        for section in sections(firmware) {
+            write!(out, "\nDisassembly of section {}:\n", section.name)?;
+            self.write_section(firmware, &section, out)?;
+        }
+        Ok(())
+    }
+
+    fn write_section<W: Write>(&self, firmware: &Firmware, section: &Section, out: &mut W) -> io::Result<()> {
+        let start = section.addr as usize;
+        let end = (section.addr + section.size) as usize;
+        let bytes = firmware.program.get(start..end.min(firmware.program.len())).unwrap_or(&[]);
+        let code: Vec<Instruction> = decode(bytes.iter().cloned()).collect();
+
+        // avr-objdump leaves out the leading zeros of the addresses in groups of 4
+        let end_digits = format!("{:08x}", end);
+        let zeros = end_digits.chars().take_while(|&c| c == '0').count();
+        let width = 8 - if zeros > 0 && zeros < 8 { (zeros - 1) & !3 } else { 0 };
+
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let addr = (start + offset) as u32;
+            if let Ok(i) = self.labels.binary_search_by_key(&addr, |l| l.0) {
+                write!(out, "\n{:08x} <{}>:\n", addr, self.labels[i].1)?;
+            }
+
+            // avr-objdump disassembles from one label to the next
+            let stop = self.labels.iter().map(|l| l.0 as usize)
+                .find(|&l| l > start + offset)
+                .map_or(bytes.len(), |l| (l - start).min(bytes.len()));
+            let zero_end = (offset..stop).find(|&i| bytes[i] != 0).unwrap_or(stop);
+            let zeros = zero_end - offset;
+            if zeros >= SKIP_ZEROS || (zero_end == stop && zeros < SKIP_ZEROS_AT_END && zeros > 0) {
+                offset = if zero_end == stop { zero_end } else { offset + (zeros & !3) };
+                writeln!(out, "\t...")?;
+                continue;
+            }
+
+            let instr = code[offset / 2];
+            let len = match code.get(offset / 2 + 1) {
+                Some(&SecondOpWord) => 4,
+                _ => (bytes.len() - offset).min(2),
+            };
+            let hex: String = bytes[offset..offset + len].iter().map(|b| format!("{:02x} ", b)).collect();
+            writeln!(out, "{:>width$x}:\t{:<12}\t{}", addr, hex, self.instruction(instr, addr), width = width)?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    // an address in the data memory, like avr-objdump shows them
+    fn variable(&self, addr: u16) -> String {
+        with_symbol(&self.variables, addr as u32 + DATA_OFFSET)
+    }
+}
+
+// files without sections look like avr-objdump -D -b binary
+fn sections(firmware: &Firmware) -> Vec<Section> {
+    if firmware.code.is_empty() {
+        vec![Section { name: ".data".to_string(), addr: 0, size: firmware.program.len() as u32 }]
+    } else {
+        firmware.code.clone()
+    }
+}
+
+// the address with the symbol before it
+fn with_symbol(symbols: &[(u32, String)], addr: u32) -> String {
+    let i = match symbols.binary_search_by_key(&addr, |s| s.0) {
+        Ok(i) => i,
+        Err(0) => return format!("{:#x}", addr),
+        Err(i) => i - 1,
+    };
+    match addr - symbols[i].0 {
+        0 => format!("{:#x} <{}>", addr, symbols[i].1),
+        offset => format!("{:#x} <{}+{:#x}>", addr, symbols[i].1, offset),
+    }
+}
+
+fn displacement(mode: LDMode) -> bool {
+    match mode {
+        LDMode::Displacement(q) => q > 0,
+        _ => false,
+    }
+}
+
+fn pointer(ptr: Register, mode: LDMode) -> String {
+    let name = match ptr {
+        X => "X",
+        Y => "Y",
+        _ => "Z",
+    };
+    match mode {
+        LDMode::PostIncrement => format!("{}+", name),
+        LDMode::PreDecrement => format!("-{}", name),
+        LDMode::Displacement(0) => name.to_string(),
+        LDMode::Displacement(q) => format!("{}+{}", name, q),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dwarf::Lines;
+    use data::Instruction::*;
+    use data::{LDMode, LDType, LPMType, SetClear, X, Y, Z};
+    use firmware::{Firmware, Section, Symbol, SymbolKind, Symbols};
+    use super::Disassembler;
+
+    fn symbol(name: &str, value: u32, kind: SymbolKind, section: Option<&str>) -> Symbol {
+        Symbol { name: name.to_string(), value: value, size: 0, kind: kind, section: section.map(|s| s.to_string()) }
+    }
+
+    fn firmware() -> Firmware {
+        let program = vec![
+            0x0c, 0x94, 0x04, 0x00, // jmp __ctors_end
+            0x0c, 0x94, 0x08, 0x00, // jmp main
+            0x11, 0x24, // eor r1, r1
+            0x1f, 0xbe, // out 0x3f, r1
+            0x0e, 0x94, 0x08, 0x00, // call main
+            0x80, 0x91, 0x60, 0x00, // lds r24, counter
+            0x88, 0x0f, // add r24, r24
+            0x80, 0x93, 0x61, 0x00, // sts counter + 1, r24
+            0x01, 0xf4, // brne .+0
+            0xfa, 0xcf, // rjmp .-12
+            0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let symbols = Symbols::new(vec![
+            symbol("__vectors", 0, SymbolKind::Other, Some(".text")),
+            symbol("__tmp_reg__", 0, SymbolKind::Other, None),
+            symbol("__ctors_start", 8, SymbolKind::Other, Some(".text")),
+            symbol("__ctors_end", 8, SymbolKind::Other, Some(".text")),
+            symbol("main", 0x10, SymbolKind::Func, Some(".text")),
+            symbol("counter", 0x800060, SymbolKind::Object, Some(".bss")),
+        ]);
+        let code = vec![Section { name: ".text".to_string(), addr: 0, size: program.len() as u32 }];
+        Firmware { program: program, symbols: symbols, code: code, lines: Lines::default() }
+    }
+
+    #[test]
+    fn objdump() {
+        let fw = firmware();
+        let mut out = Vec::new();
+        Disassembler::new(&fw, false).write(&fw, "test.elf", &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "
+test.elf:     file format elf32-avr
+
+
+Disassembly of section .text:
+
+00000000 <__vectors>:
+   0:\t0c 94 04 00 \tjmp\t0x8\t; 0x8 <__ctors_end>
+   4:\t0c 94 08 00 \tjmp\t0x10\t; 0x10 <main>
+
+00000008 <__ctors_end>:
+   8:\t11 24       \teor\tr1, r1
+   a:\t1f be       \tout\t0x3f, r1\t; 63
+   c:\t0e 94 08 00 \tcall\t0x10\t; 0x10 <main>
+
+00000010 <main>:
+  10:\t80 91 60 00 \tlds\tr24, 0x0060\t; 0x800060 <counter>
+  14:\t88 0f       \tadd\tr24, r24
+  16:\t80 93 61 00 \tsts\t0x0061, r24\t; 0x800061 <counter+0x1>
+  1a:\t01 f4       \tbrne\t.+0      \t; 0x1c <main+0xc>
+  1c:\tfa cf       \trjmp\t.-12     \t; 0x12 <main+0x2>
+\t...
+");
+    }
+
+    #[test]
+    fn instructions() {
+        let fw = firmware();
+        let d = Disassembler::new(&fw, false);
+        let cases = vec![
+            (LD_ST(LDType::LD, 24, Z, LDMode::Displacement(0)), "ld\tr24, Z"),
+            (LD_ST(LDType::LD, 24, Y, LDMode::Displacement(3)), "ldd\tr24, Y+3"),
+            (LD_ST(LDType::ST, 24, X, LDMode::PostIncrement), "st\tX+, r24"),
+            (LD_ST(LDType::ST, 24, Z, LDMode::PreDecrement), "st\t-Z, r24"),
+            (SBIC_S(SetClear::Set, 0x16, 2), "sbis\t0x16, 2\t; 22"),
+            (BSET(7), "sei"),
+            (BCLR(7), "cli"),
+            (BRBC_S(SetClear::Set, 1, -3), "breq\t.-8      \t; 0x1a <main+0xa>"),
+            (LPM(0, LPMType::Z), "lpm"),
+            (LPM(24, LPMType::ZPostIncrement), "lpm\tr24, Z+"),
+            (ADIW(24, 1), "adiw\tr24, 0x01\t; 1"),
+            (UnknownOp(0xffff), ".word\t0xffff\t; ????"),
+            (EOR(1, 1), "eor\tr1, r1"),
+        ];
+        for (instr, expected) in cases {
+            assert_eq!(d.instruction(instr, 0x20), expected);
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        let d = Disassembler::new(&firmware(), true);
+        assert_eq!(d.instruction(EOR(1, 1), 0), "clr\tr1");
+        assert_eq!(d.instruction(ADD(24, 24), 0), "lsl\tr24");
+        assert_eq!(d.instruction(ADC(24, 24), 0), "rol\tr24");
+        assert_eq!(d.instruction(AND(24, 24), 0), "tst\tr24");
+        assert_eq!(d.instruction(LDI(24, 0xff), 0), "ser\tr24");
+        assert_eq!(d.instruction(ADD(24, 25), 0), "add\tr24, r25");
+    }
+}
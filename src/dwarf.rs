@@ -0,0 +1,393 @@
+use std::io;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+/// a row of the line table, the following addresses up to the next row
+/// belong to the line, unless it ends a sequence
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+struct Row {
+    addr: u32,
+    // index in Lines::files
+    file: usize,
+    line: u32,
+    end: bool,
+}
+
+/// the source lines of the addresses in the flash from the line table
+/// in .debug_line of an elf file
+#[derive(Debug, Clone, Default)]
+pub struct Lines {
+    files: Vec<String>,
+    // sorted by the address, the end of a sequence comes before a new one at the same address
+    rows: Vec<Row>,
+}
+
+impl Lines {
+    /// the file and the line of the byte address in the flash
+    pub fn line_at(&self, addr: u32) -> Option<(&str, u32)> {
+        let i = self.rows.partition_point(|row| row.addr <= addr);
+        match self.rows[..i].last() {
+            Some(row) if !row.end => Some((&self.files[row.file], row.line)),
+            _ => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// parses the line tables of all compilation units, debug_str and debug_line_str
+    /// are only needed for dwarf 5
+    pub fn parse(debug_line: &[u8], debug_str: &[u8], debug_line_str: &[u8]) -> io::Result<Lines> {
+        let mut lines = Lines::default();
+        let mut offset = 0;
+        while offset + 4 <= debug_line.len() {
+            let mut r = Reader { bytes: debug_line, pos: offset };
+            let len = r.u32()?;
+            if len == 0xffff_ffff {
+                return Err(invalid("64 bit dwarf isn't supported"));
+            }
+            let end = r.pos + len as usize;
+            if end > debug_line.len() {
+                return Err(invalid("line table outside of .debug_line"));
+            }
+            let mut unit = Reader { bytes: &debug_line[..end], pos: r.pos };
+            lines.parse_unit(&mut unit, debug_str, debug_line_str)?;
+            offset = end;
+        }
+        lines.rows.sort_by_key(|row| (row.addr, !row.end));
+        Ok(lines)
+    }
+
+    fn parse_unit(&mut self, r: &mut Reader, debug_str: &[u8], debug_line_str: &[u8]) -> io::Result<()> {
+        let version = r.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(invalid("unsupported version of the line table"));
+        }
+        if version >= 5 {
+            // address and segment selector size
+            r.skip(2)?;
+        }
+        let header_len = r.u32()? as usize;
+        let program = r.pos + header_len;
+        let min_inst_len = r.u8()? as u32;
+        if version >= 4 {
+            // maximum operations per instruction
+            r.skip(1)?;
+        }
+        r.skip(1)?;
+        let line_base = r.u8()? as i8 as i64;
+        let line_range = r.u8()?;
+        let opcode_base = r.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return Err(invalid("invalid line table header"));
+        }
+        let mut lengths = Vec::new();
+        for _ in 1..opcode_base {
+            lengths.push(r.u8()?);
+        }
+
+        // the indices of the files of the unit in self.files
+        let mut files = Vec::new();
+        if version < 5 {
+            let mut dirs = Vec::new();
+            loop {
+                let dir = r.string()?;
+                if dir.is_empty() {
+                    break;
+                }
+                dirs.push(dir);
+            }
+            loop {
+                let name = r.string()?;
+                if name.is_empty() {
+                    break;
+                }
+                let dir = r.uleb()? as usize;
+                r.uleb()?;
+                r.uleb()?;
+                // directory 0 is the one of the compilation
+                let path = join(if dir == 0 { None } else { dirs.get(dir - 1) }, name);
+                files.push(self.file(path));
+            }
+        } else {
+            let strs = Strings { debug_str: debug_str, debug_line_str: debug_line_str };
+            let dirs: Vec<String> = r.entries(&strs)?.into_iter().map(|(path, _)| path).collect();
+            for (name, dir) in r.entries(&strs)? {
+                let path = join(dirs.get(dir), name);
+                files.push(self.file(path));
+            }
+        }
+
+        r.pos = program;
+        let file = |files: &Vec<usize>, index: u64| {
+            // the files are counted from 1 before dwarf 5
+            let index = if version < 5 { (index as usize).wrapping_sub(1) } else { index as usize };
+            files.get(index).cloned().ok_or_else(|| invalid("invalid file in line table"))
+        };
+        let (mut addr, mut file_index, mut line) = (0u32, 1u64, 1i64);
+        while r.pos < r.bytes.len() {
+            let op = r.u8()?;
+            if op >= opcode_base {
+                let adjusted = op - opcode_base;
+                addr = addr.wrapping_add((adjusted / line_range) as u32 * min_inst_len);
+                line += line_base + (adjusted % line_range) as i64;
+                self.rows.push(Row { addr: addr, file: file(&files, file_index)?, line: line as u32, end: false });
+                continue;
+            }
+            match op {
+                0 => {
+                    let len = r.uleb()? as usize;
+                    let next = r.pos + len;
+                    if len == 0 {
+                        continue;
+                    }
+                    match r.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            self.rows.push(Row { addr: addr, file: file(&files, file_index)?, line: line as u32, end: true });
+                            addr = 0;
+                            file_index = 1;
+                            line = 1;
+                        }
+                        DW_LNE_SET_ADDRESS => addr = match len - 1 {
+                            2 => r.u16()? as u32,
+                            _ => r.u32()?,
+                        },
+                        DW_LNE_DEFINE_FILE => {
+                            let name = r.string()?;
+                            files.push(self.file(name));
+                        }
+                        _ => {}
+                    }
+                    r.pos = next;
+                }
+                DW_LNS_COPY => {
+                    self.rows.push(Row { addr: addr, file: file(&files, file_index)?, line: line as u32, end: false });
+                }
+                DW_LNS_ADVANCE_PC => addr = addr.wrapping_add((r.uleb()? as u32).wrapping_mul(min_inst_len)),
+                DW_LNS_ADVANCE_LINE => line = line.wrapping_add(r.sleb()?),
+                DW_LNS_SET_FILE => file_index = r.uleb()?,
+                DW_LNS_CONST_ADD_PC => addr = addr.wrapping_add(((255 - opcode_base) / line_range) as u32 * min_inst_len),
+                DW_LNS_FIXED_ADVANCE_PC => addr = addr.wrapping_add(r.u16()? as u32),
+                _ => {
+                    for _ in 0..lengths[op as usize - 1] {
+                        r.uleb()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, path: String) -> usize {
+        match self.files.iter().position(|f| *f == path) {
+            Some(i) => i,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn join(dir: Option<&String>, name: String) -> String {
+    match dir {
+        Some(dir) if !name.starts_with('/') => format!("{}/{}", dir, name),
+        _ => name,
+    }
+}
+
+// the string sections, which are referenced by dwarf 5
+struct Strings<'a> {
+    debug_str: &'a [u8],
+    debug_line_str: &'a [u8],
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn skip(&mut self, n: usize) -> io::Result<()> {
+        if self.pos + n > self.bytes.len() {
+            return Err(invalid("unexpected end of line table"));
+        }
+        self.pos += n;
+        Ok(())
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        self.skip(1)?;
+        Ok(self.bytes[self.pos - 1])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(self.u8()? as u16 | (self.u8()? as u16) << 8)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(self.u16()? as u32 | (self.u16()? as u32) << 16)
+    }
+
+    fn uleb(&mut self) -> io::Result<u64> {
+        let (mut val, mut shift) = (0u64, 0);
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> io::Result<i64> {
+        let (mut val, mut shift) = (0i64, 0);
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    val |= -1 << shift;
+                }
+                return Ok(val);
+            }
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.bytes.get(self.pos..).and_then(|s| s.iter().position(|b| *b == 0))
+            .ok_or_else(|| invalid("unterminated string in line table"))?;
+        let s = String::from_utf8_lossy(&self.bytes[self.pos..self.pos + len]).into_owned();
+        self.pos += len + 1;
+        Ok(s)
+    }
+
+    // the directory or file entries of dwarf 5 as path and directory index
+    fn entries(&mut self, strs: &Strings) -> io::Result<Vec<(String, usize)>> {
+        let mut format = Vec::new();
+        for _ in 0..self.u8()? {
+            format.push((self.uleb()?, self.uleb()?));
+        }
+        let mut entries = Vec::new();
+        for _ in 0..self.uleb()? {
+            let (mut path, mut dir) = (String::new(), 0);
+            for &(content, form) in &format {
+                let val = match form {
+                    DW_FORM_STRING => {
+                        set_path(content, &mut path, self.string()?);
+                        continue;
+                    }
+                    DW_FORM_LINE_STRP | DW_FORM_STRP => {
+                        let section = if form == DW_FORM_STRP { strs.debug_str } else { strs.debug_line_str };
+                        let offset = self.u32()? as usize;
+                        let s = Reader { bytes: section, pos: offset.min(section.len()) }.string()?;
+                        set_path(content, &mut path, s);
+                        continue;
+                    }
+                    DW_FORM_UDATA => self.uleb()?,
+                    DW_FORM_DATA1 => self.u8()? as u64,
+                    DW_FORM_DATA2 => self.u16()? as u64,
+                    DW_FORM_DATA4 => self.u32()? as u64,
+                    DW_FORM_DATA8 => {
+                        self.skip(8)?;
+                        0
+                    }
+                    DW_FORM_DATA16 => {
+                        self.skip(16)?;
+                        0
+                    }
+                    DW_FORM_BLOCK => {
+                        let len = self.uleb()? as usize;
+                        self.skip(len)?;
+                        0
+                    }
+                    _ => return Err(invalid("unsupported form in line table")),
+                };
+                if content == DW_LNCT_DIRECTORY_INDEX {
+                    dir = val as usize;
+                }
+            }
+            entries.push((path, dir));
+        }
+        Ok(entries)
+    }
+}
+
+fn set_path(content: u64, path: &mut String, s: String) {
+    if content == DW_LNCT_PATH {
+        *path = s;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lines;
+
+    // a dwarf 3 line table: main.c in src with 0x0 line 3, 0x4 line 5 and 0x8 line 4,
+    // which ends at 0xc
+    fn table() -> Vec<u8> {
+        let mut header = vec![
+            1, 1, 0xfb, 14, 13, // min_inst_len, default_is_stmt, line_base -5, line_range, opcode_base
+            0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1, // standard_opcode_lengths
+        ];
+        header.extend_from_slice(b"src\0\0main.c\0\x01\0\0\0");
+        let program = [
+            0, 3, 2, 0x00, 0x00, // set_address 0
+            3, 2, 1, // advance_line 2, copy
+            // special: addr + 4, line + 2: (2 - -5) + 4 * 14 + 13
+            (7 + 4 * 14 + 13) as u8,
+            // special: addr + 4, line - 1
+            (4 + 4 * 14 + 13) as u8,
+            2, 4, 0, 1, 1, // advance_pc 4, end_sequence
+        ];
+        let mut unit = vec![3, 0];
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(&program);
+        let mut bytes = (unit.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&unit);
+        bytes
+    }
+
+    #[test]
+    fn lines() {
+        let lines = Lines::parse(&table(), &[], &[]).unwrap();
+        assert_eq!(lines.line_at(0), Some(("src/main.c", 3)));
+        assert_eq!(lines.line_at(2), Some(("src/main.c", 3)));
+        assert_eq!(lines.line_at(4), Some(("src/main.c", 5)));
+        assert_eq!(lines.line_at(0xa), Some(("src/main.c", 4)));
+        assert_eq!(lines.line_at(0xc), None);
+        assert!(Lines::parse(&table()[..20], &[], &[]).is_err());
+    }
+}
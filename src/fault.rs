@@ -0,0 +1,241 @@
+/// the errors of a buggy firmware, which the vm detects
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum FaultKind {
+    // a read above RAMEND, it reads 0
+    InvalidRead,
+    // a write above RAMEND, it is ignored
+    InvalidWrite,
+    // an opcode, which the atmega32 or the vm doesn't support, it is executed as nop
+    IllegalOpcode,
+    // a jump to the second word of a two word instruction, which is executed as
+    // an instruction on its own
+    SecondWord,
+    // the pc left the flash, it wraps around like the 14 bits of the real one
+    PcOutOfRange,
+    // timer 1 was set to the external clock on T1, which the vm doesn't support,
+    // the timer stops instead
+    UnsupportedClock,
+}
+
+pub const FAULT_KINDS: [FaultKind; 6] = [
+    FaultKind::InvalidRead, FaultKind::InvalidWrite, FaultKind::IllegalOpcode,
+    FaultKind::SecondWord, FaultKind::PcOutOfRange, FaultKind::UnsupportedClock,
+];
+
+impl FaultKind {
+    /// the name on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            FaultKind::InvalidRead => "invalid-read",
+            FaultKind::InvalidWrite => "invalid-write",
+            FaultKind::IllegalOpcode => "illegal-opcode",
+            FaultKind::SecondWord => "second-word",
+            FaultKind::PcOutOfRange => "pc-out-of-range",
+            FaultKind::UnsupportedClock => "unsupported-clock",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FaultKind> {
+        FAULT_KINDS.iter().cloned().find(|kind| kind.name() == name)
+    }
+
+    fn index(self) -> usize {
+        FAULT_KINDS.iter().position(|&kind| kind == self).unwrap()
+    }
+}
+
+/// what the cpu does after a fault
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum FaultPolicy {
+    // the cpu halts with HaltReason::Fault
+    Halt,
+    // the cpu continues, the caller may report the fault
+    Warn,
+    // the cpu continues, but a debugger stops
+    Break,
+}
+
+/// the policy for every kind of fault, all halt by default
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct FaultPolicies([FaultPolicy; 6]);
+
+impl Default for FaultPolicies {
+    fn default() -> FaultPolicies {
+        FaultPolicies([FaultPolicy::Halt; 6])
+    }
+}
+
+impl FaultPolicies {
+    pub fn get(&self, kind: FaultKind) -> FaultPolicy {
+        self.0[kind.index()]
+    }
+
+    pub fn set(&mut self, kind: FaultKind, policy: FaultPolicy) {
+        self.0[kind.index()] = policy;
+    }
+
+    pub fn set_all(&mut self, policy: FaultPolicy) {
+        self.0 = [policy; 6];
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Fault {
+    pub kind: FaultKind,
+    // byte address of the instruction
+    pub pc: usize,
+    // the address in the data memory for invalid accesses and the written io register,
+    // the target of the jump for PcOutOfRange and the pc otherwise
+    pub addr: usize,
+}
+
+impl Fault {
+    pub fn describe(&self) -> String {
+        match self.kind {
+            FaultKind::InvalidRead => format!("read of {:#06x} outside the data memory", self.addr),
+            FaultKind::InvalidWrite => format!("write to {:#06x} outside the data memory", self.addr),
+            FaultKind::IllegalOpcode => "illegal opcode".to_string(),
+            FaultKind::SecondWord => "jump into the second word of an instruction".to_string(),
+            FaultKind::PcOutOfRange => format!("jump to {:#06x} outside the flash", self.addr),
+            FaultKind::UnsupportedClock => "external clock for timer 1, which isn't supported".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::{Cpu, HaltReason, StepResult};
+    use firmware::Firmware;
+    use memory::{Memory, PROGRAM_SIZE};
+    use super::{Fault, FaultKind, FaultPolicies, FaultPolicy};
+
+    fn cpu(program: &[u8], policy: FaultPolicy) -> Cpu {
+        let mem = Memory::from_firmware(&Firmware::from_bytes(program.to_vec()).unwrap(), None);
+        let mut cpu = Cpu::new(mem, false);
+        // the faults of every instruction, also with the jit
+        cpu.set_single_step(true);
+        let mut policies = FaultPolicies::default();
+        policies.set_all(policy);
+        cpu.set_fault_policies(policies);
+        cpu
+    }
+
+    #[test]
+    fn illegal_opcode() {
+        let mut cpu = cpu(&[0xff, 0xff, 0x00, 0x00], FaultPolicy::Halt);
+        assert_eq!(cpu.step(), StepResult::Halted(HaltReason::Fault(FaultKind::IllegalOpcode)));
+        assert_eq!(cpu.fault(), Some(Fault { kind: FaultKind::IllegalOpcode, pc: 0, addr: 0 }));
+        assert_eq!(cpu.fault().unwrap().describe(), "illegal opcode");
+
+        let mut cpu = self::cpu(&[0xff, 0xff, 0x00, 0x00], FaultPolicy::Warn);
+        assert_eq!(cpu.step(), StepResult::Running);
+        assert_eq!((cpu.fault().map(|f| f.kind), cpu.ip()), (Some(FaultKind::IllegalOpcode), 1));
+        cpu.step();
+        assert_eq!(cpu.fault(), None);
+    }
+
+    #[test]
+    fn invalid_access() {
+        // ldi r16, 0x42; sts 0x900, r16; lds r17, 0x900
+        let mut cpu = cpu(&[0x02, 0xe4, 0x00, 0x93, 0x00, 0x09, 0x10, 0x91, 0x00, 0x09], FaultPolicy::Warn);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.fault(), Some(Fault { kind: FaultKind::InvalidWrite, pc: 2, addr: 0x900 }));
+        assert_eq!(cpu.fault().unwrap().describe(), "write to 0x0900 outside the data memory");
+        *cpu.mem_mut().reg_mut(17) = 0xff;
+        cpu.step();
+        assert_eq!(cpu.fault(), Some(Fault { kind: FaultKind::InvalidRead, pc: 6, addr: 0x900 }));
+        assert_eq!((cpu.reg(17), cpu.mem().data(0x900)), (0, 0));
+
+        let mut policies = FaultPolicies::default();
+        policies.set(FaultKind::InvalidRead, FaultPolicy::Warn);
+        let mut cpu = self::cpu(&[0x02, 0xe4, 0x00, 0x93, 0x00, 0x09], FaultPolicy::Warn);
+        cpu.set_fault_policies(policies);
+        cpu.step();
+        assert_eq!(cpu.step(), StepResult::Halted(HaltReason::Fault(FaultKind::InvalidWrite)));
+
+        // sts 0xfff6, r16 without semihosting
+        let mut cpu = self::cpu(&[0x00, 0x93, 0xf6, 0xff], FaultPolicy::Warn);
+        cpu.step();
+        assert_eq!(cpu.fault(), Some(Fault { kind: FaultKind::InvalidWrite, pc: 0, addr: 0xfff6 }));
+    }
+
+    #[test]
+    fn chained() {
+        // ldi r16, 0x42; ldi r17, 0x01; lds r18, 0x900; ldi r19, 0x01; rjmp .-2
+        let program = [0x02, 0xe4, 0x11, 0xe0, 0x20, 0x91, 0x00, 0x09, 0x31, 0xe0, 0xff, 0xcf];
+        let mut cpu = cpu(&program, FaultPolicy::Halt);
+        // the jit runs whole blocks, but stops at the faulting instruction
+        cpu.set_single_step(false);
+        let mut result = StepResult::Running;
+        while result == StepResult::Running {
+            result = cpu.step();
+        }
+        assert_eq!(result, StepResult::Halted(HaltReason::Fault(FaultKind::InvalidRead)));
+        assert_eq!(cpu.fault(), Some(Fault { kind: FaultKind::InvalidRead, pc: 4, addr: 0x900 }));
+        assert_eq!((cpu.reg(17), cpu.reg(19), cpu.ip(), cpu.cycles()), (1, 0, 4, 3));
+    }
+
+    #[test]
+    fn jumps() {
+        // rjmp .+2 into sts 0xe041, r16, whose second word is ldi r20, 0x01
+        let mut cpu = cpu(&[0x01, 0xc0, 0x00, 0x93, 0x41, 0xe0], FaultPolicy::Warn);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.fault(), Some(Fault { kind: FaultKind::SecondWord, pc: 4, addr: 4 }));
+        assert_eq!((cpu.reg(20), cpu.ip()), (1, 3));
+
+        // jmp 0x8000
+        let mut cpu = self::cpu(&[0x0c, 0x94, 0x00, 0x40], FaultPolicy::Warn);
+        cpu.step();
+        assert_eq!(cpu.fault(), Some(Fault { kind: FaultKind::PcOutOfRange, pc: 0, addr: 0x8000 }));
+        assert_eq!(cpu.ip(), 0);
+    }
+
+    #[test]
+    fn skip_at_the_end() {
+        // jmp 0x7ffc to cpse r0, r0 in the last but one word of the flash
+        let mut program = vec![0; PROGRAM_SIZE];
+        program[..4].copy_from_slice(&[0x0c, 0x94, 0xfe, 0x3f]);
+        program[PROGRAM_SIZE - 4..PROGRAM_SIZE - 2].copy_from_slice(&[0x00, 0x10]);
+        let mut cpu = cpu(&program, FaultPolicy::Warn);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.fault(), Some(Fault { kind: FaultKind::PcOutOfRange, pc: 0x7ffc, addr: 0x8000 }));
+        assert_eq!(cpu.ip(), 0);
+    }
+
+    #[test]
+    fn interrupt() {
+        // the pending int0 pushes the return address to an sp outside the sram
+        let mut cpu = cpu(&[0x00, 0x00, 0x00, 0x00], FaultPolicy::Warn);
+        cpu.mem_mut().set_data(0x5e, 0x09);
+        cpu.mem_mut().set_data(0x5d, 0x00);
+        cpu.mem_mut().set_data(0x5b, 0x40);
+        cpu.mem_mut().set_data(0x5a, 0x40);
+        cpu.mem_mut().set_data(0x5f, 0x80);
+        cpu.step();
+        assert_eq!(cpu.fault(), Some(Fault { kind: FaultKind::InvalidWrite, pc: 0, addr: 0x900 }));
+    }
+
+    #[test]
+    fn external_clock() {
+        // ldi r16, 6; out TCCR1B, r16; rjmp .-2
+        let mut cpu = cpu(&[0x06, 0xe0, 0x0e, 0xbd, 0xff, 0xcf], FaultPolicy::Warn);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.fault(), Some(Fault { kind: FaultKind::UnsupportedClock, pc: 2, addr: 0x4e }));
+        // the timer stops
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!((cpu.fault(), cpu.mem().data(0x4c)), (None, 0));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(FaultKind::from_name("illegal-opcode"), Some(FaultKind::IllegalOpcode));
+        assert_eq!(FaultKind::from_name("pc-out-of-range").map(|k| k.name()), Some("pc-out-of-range"));
+        assert_eq!(FaultKind::from_name("foo"), None);
+    }
+}
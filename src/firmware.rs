@@ -0,0 +1,384 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::slice;
+use dwarf::Lines;
+use memory::PROGRAM_SIZE;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SHF_EXECINSTR: u32 = 4;
+// section indices from here on are reserved, e.g. for absolute symbols
+const SHN_LORESERVE: usize = 0xff00;
+// avr-gcc places the data address space at this offset in the elf file
+pub const DATA_OFFSET: u32 = 0x800000;
+
+/// the file formats, a firmware can be loaded from
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Format {
+    // elf, if the file starts with the elf magic, intel hex for .hex files
+    // and a raw binary otherwise
+    Auto,
+    Elf,
+    Bin,
+    IHex,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SymbolKind {
+    Func, Object, Other
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+    // the name of the section, None for absolute and undefined symbols
+    pub section: Option<String>,
+}
+
+impl Symbol {
+    /// address of the symbol in the data memory,
+    /// if it is located there
+    pub fn data_addr(&self) -> Option<u16> {
+        if self.value >= DATA_OFFSET && self.value < DATA_OFFSET + 0x10000 {
+            Some((self.value - DATA_OFFSET) as u16)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn new(mut symbols: Vec<Symbol>) -> Symbols {
+        symbols.sort_by_key(|s| s.value);
+        Symbols { symbols: symbols }
+    }
+
+    /// all symbols, sorted by their value
+    pub fn iter(&self) -> slice::Iter<'_, Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    /// finds the function containing the given byte address in flash
+    pub fn function_at(&self, addr: u32) -> Option<&Symbol> {
+        self.symbols.iter()
+            .filter(|s| s.kind == SymbolKind::Func)
+            .find(|s| s.value <= addr && addr < s.value + s.size.max(1))
+    }
+
+    /// finds the variable containing the given address in the data memory
+    pub fn object_at(&self, addr: u16) -> Option<&Symbol> {
+        let addr = addr as u32 + DATA_OFFSET;
+        self.symbols.iter()
+            .filter(|s| s.kind == SymbolKind::Object)
+            .find(|s| s.value <= addr && addr < s.value + s.size.max(1))
+    }
+}
+
+/// an executable section of an elf file
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+}
+
+/// a program image together with the information,
+/// which could be extracted from the file it was loaded from
+pub struct Firmware {
+    pub program: Vec<u8>,
+    pub symbols: Symbols,
+    // empty, if the file has no sections
+    pub code: Vec<Section>,
+    // empty without debug information
+    pub lines: Lines,
+}
+
+impl Firmware {
+    /// loads a raw binary (as generated by avr-objcopy -O binary)
+    /// or an elf file
+    pub fn load(file: OsString) -> io::Result<Firmware> {
+        Firmware::load_as(file, Format::Auto)
+    }
+
+    /// fails, if the program doesn't fit into the flash
+    pub fn load_as(file: OsString, format: Format) -> io::Result<Firmware> {
+        let mut bytes = Vec::new();
+        File::open(&file)?.read_to_end(&mut bytes)?;
+        let is_hex = Path::new(&file).extension().is_some_and(|e| e == "hex" || e == "ihex");
+        let firmware = match format {
+            Format::Auto if is_hex && !bytes.starts_with(ELF_MAGIC) => parse_ihex(&bytes),
+            Format::Auto => Firmware::from_bytes(bytes),
+            Format::Elf => parse_elf(&bytes),
+            Format::Bin => Ok(Firmware { program: bytes, symbols: Symbols::default(), code: Vec::new(),
+                                         lines: Lines::default() }),
+            Format::IHex => parse_ihex(&bytes),
+        }?;
+        firmware.check_size()
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> io::Result<Firmware> {
+        let firmware = if bytes.starts_with(ELF_MAGIC) {
+            parse_elf(&bytes)?
+        } else {
+            Firmware { program: bytes, symbols: Symbols::default(), code: Vec::new(), lines: Lines::default() }
+        };
+        firmware.check_size()
+    }
+
+    fn check_size(self) -> io::Result<Firmware> {
+        if self.program.len() > PROGRAM_SIZE {
+            return Err(invalid(&format!("the program has {} bytes, but the flash only {}",
+                                        self.program.len(), PROGRAM_SIZE)));
+        }
+        Ok(self)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> io::Result<u16> {
+    if offset + 2 > bytes.len() {
+        return Err(invalid("unexpected end of elf file"));
+    }
+    Ok(bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> io::Result<u32> {
+    Ok(u16_at(bytes, offset)? as u32 | (u16_at(bytes, offset + 2)? as u32) << 16)
+}
+
+fn str_at(bytes: &[u8], offset: usize) -> io::Result<String> {
+    let len = bytes.get(offset..).and_then(|s| s.iter().position(|b| *b == 0))
+        .ok_or_else(|| invalid("unterminated string in elf file"))?;
+    Ok(String::from_utf8_lossy(&bytes[offset..offset + len]).into_owned())
+}
+
+fn parse_elf(bytes: &[u8]) -> io::Result<Firmware> {
+    if bytes.len() < 0x34 || bytes[4] != ELFCLASS32 || bytes[5] != ELFDATA2LSB {
+        return Err(invalid("only 32 bit little endian elf files are supported"));
+    }
+
+    let phoff = u32_at(bytes, 0x1c)? as usize;
+    let shoff = u32_at(bytes, 0x20)? as usize;
+    let phentsize = u16_at(bytes, 0x2a)? as usize;
+    let phnum = u16_at(bytes, 0x2c)? as usize;
+    let shentsize = u16_at(bytes, 0x2e)? as usize;
+    let shnum = u16_at(bytes, 0x30)? as usize;
+    let shstrndx = u16_at(bytes, 0x32)? as usize;
+
+    // the flash content are all loadable segments, which have their physical
+    // address in the flash. This includes the initial values of .data
+    let mut program = Vec::new();
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        let typ = u32_at(bytes, ph)?;
+        let offset = u32_at(bytes, ph + 4)? as usize;
+        let paddr = u32_at(bytes, ph + 12)? as usize;
+        let filesz = u32_at(bytes, ph + 16)? as usize;
+        if typ != PT_LOAD || paddr >= DATA_OFFSET as usize || filesz == 0 {
+            continue;
+        }
+        let segment = bytes.get(offset..offset + filesz)
+            .ok_or_else(|| invalid("segment outside of elf file"))?;
+        if program.len() < paddr + filesz {
+            program.resize(paddr + filesz, 0);
+        }
+        program[paddr..paddr + filesz].copy_from_slice(segment);
+    }
+
+    // the names of the sections, if there is a string table for them
+    let mut sections = Vec::new();
+    for i in 0..shnum {
+        let name = if shstrndx != 0 && shstrndx < shnum {
+            let strtab = u32_at(bytes, shoff + shstrndx * shentsize + 16)? as usize;
+            str_at(bytes, strtab + u32_at(bytes, shoff + i * shentsize)? as usize)?
+        } else {
+            String::new()
+        };
+        sections.push(name);
+    }
+
+    let mut code = Vec::new();
+    let mut symbols = Vec::new();
+    // .debug_line, .debug_str and .debug_line_str
+    let mut debug: [&[u8]; 3] = [&[], &[], &[]];
+    for i in 0..shnum {
+        let sh = shoff + i * shentsize;
+        if let Some(j) = [".debug_line", ".debug_str", ".debug_line_str"].iter().position(|n| *n == sections[i]) {
+            let offset = u32_at(bytes, sh + 16)? as usize;
+            let size = u32_at(bytes, sh + 20)? as usize;
+            debug[j] = bytes.get(offset..offset + size).ok_or_else(|| invalid("section outside of elf file"))?;
+        }
+        let flags = u32_at(bytes, sh + 8)?;
+        let addr = u32_at(bytes, sh + 12)?;
+        if flags & SHF_EXECINSTR != 0 && addr < DATA_OFFSET {
+            code.push(Section { name: sections[i].clone(), addr: addr, size: u32_at(bytes, sh + 20)? });
+        }
+        if u32_at(bytes, sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let offset = u32_at(bytes, sh + 16)? as usize;
+        let size = u32_at(bytes, sh + 20)? as usize;
+        let link = u32_at(bytes, sh + 24)? as usize;
+        let entsize = u32_at(bytes, sh + 36)? as usize;
+        let strtab = u32_at(bytes, shoff + link * shentsize + 16)? as usize;
+        if entsize == 0 {
+            return Err(invalid("symbol table without entry size"));
+        }
+
+        for sym in (offset..offset + size).step_by(entsize) {
+            let name = str_at(bytes, strtab + u32_at(bytes, sym)? as usize)?;
+            if name.is_empty() {
+                continue;
+            }
+            let info = *bytes.get(sym + 12).ok_or_else(|| invalid("unexpected end of elf file"))?;
+            let shndx = u16_at(bytes, sym + 14)? as usize;
+            symbols.push(Symbol {
+                name: name,
+                value: u32_at(bytes, sym + 4)?,
+                size: u32_at(bytes, sym + 8)?,
+                kind: match info & 0xf {
+                    STT_FUNC => SymbolKind::Func,
+                    STT_OBJECT => SymbolKind::Object,
+                    _ => SymbolKind::Other,
+                },
+                section: if shndx == 0 || shndx >= SHN_LORESERVE { None } else { sections.get(shndx).cloned() },
+            });
+        }
+    }
+
+    // the line table is only needed for the coverage, a firmware with a broken one still runs
+    let lines = Lines::parse(debug[0], debug[1], debug[2]).unwrap_or_default();
+    Ok(Firmware { program: program, symbols: Symbols::new(symbols), code: code, lines: lines })
+}
+
+// intel hex as generated by avr-objcopy -O ihex
+fn parse_ihex(bytes: &[u8]) -> io::Result<Firmware> {
+    let text = String::from_utf8_lossy(bytes);
+    let mut program = Vec::new();
+    let mut base = 0;
+    for line in text.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        if !line.starts_with(':') || line.len() % 2 != 1 {
+            return Err(invalid("invalid record in hex file"));
+        }
+        let record = (1..line.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&line[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid("invalid record in hex file"))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(invalid("invalid record length in hex file"));
+        }
+        if record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0 {
+            return Err(invalid("wrong checksum in hex file"));
+        }
+
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0 => {
+                let addr = base + ((record[1] as usize) << 8 | record[2] as usize);
+                if program.len() < addr + data.len() {
+                    program.resize(addr + data.len(), 0);
+                }
+                program[addr..addr + data.len()].copy_from_slice(data);
+            }
+            1 => break,
+            2 if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 4,
+            4 if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 16,
+            // start addresses
+            3 | 5 => {}
+            _ => return Err(invalid("unsupported record in hex file")),
+        }
+    }
+    Ok(Firmware { program: program, symbols: Symbols::default(), code: Vec::new(), lines: Lines::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use memory::PROGRAM_SIZE;
+    use super::{parse_ihex, Firmware, SymbolKind};
+
+    // builds a minimal elf file with one loadable segment, a .text section and one symbol
+    fn elf() -> Vec<u8> {
+        fn push16(v: &mut Vec<u8>, val: u16) { v.push(val as u8); v.push((val >> 8) as u8); }
+        fn push32(v: &mut Vec<u8>, val: u32) { push16(v, val as u16); push16(v, (val >> 16) as u16); }
+
+        let mut v = b"\x7fELF\x01\x01\x01".to_vec();
+        v.resize(0x1c, 0);
+        push32(&mut v, 0x34); // phoff
+        push32(&mut v, 0x60); // shoff
+        v.resize(0x2a, 0);
+        push16(&mut v, 32); push16(&mut v, 1); // phentsize, phnum
+        push16(&mut v, 40); push16(&mut v, 4); // shentsize, shnum
+        push16(&mut v, 2); // shstrndx
+        // program header at 0x34
+        for val in &[1, 0x54, 0, 0, 4, 4, 5, 2] { push32(&mut v, *val); }
+        // segment content at 0x54
+        v.extend_from_slice(&[0x00, 0x00, 0xff, 0xcf]);
+        v.resize(0x60, 0);
+        // section headers at 0x60: null, symtab, strtab, .text
+        v.resize(0x60 + 40, 0);
+        for val in &[0, 2, 0, 0, 0x100, 32, 2, 0, 4, 16] { push32(&mut v, *val); }
+        for val in &[0, 3, 0, 0, 0x120, 12, 0, 0, 1, 0] { push32(&mut v, *val); }
+        for val in &[6, 1, 6, 0, 0x54, 4, 0, 0, 2, 0] { push32(&mut v, *val); }
+        // symtab at 0x100: null symbol, "main"
+        v.resize(0x100 + 16, 0);
+        push32(&mut v, 1); push32(&mut v, 2); push32(&mut v, 4);
+        v.push(0x12); v.push(0); push16(&mut v, 3);
+        // strtab at 0x120
+        v.extend_from_slice(b"\0main\0.text\0");
+        v
+    }
+
+    #[test]
+    fn load_elf() {
+        let fw = Firmware::from_bytes(elf()).unwrap();
+        assert_eq!(fw.program, vec![0x00, 0x00, 0xff, 0xcf]);
+        let main = fw.symbols.get("main").unwrap();
+        assert_eq!(main.value, 2);
+        assert_eq!(main.kind, SymbolKind::Func);
+        assert_eq!(main.section, Some(".text".to_string()));
+        assert_eq!(fw.code.len(), 1);
+        assert_eq!((fw.code[0].name.as_str(), fw.code[0].addr, fw.code[0].size), (".text", 0, 4));
+        assert_eq!(fw.symbols.function_at(4).unwrap().name, "main");
+        assert!(fw.symbols.function_at(0).is_none());
+    }
+
+    #[test]
+    fn load_ihex() {
+        let fw = parse_ihex(b":0400000000C0FFCF6E\n:02000800FECF29\n:00000001FF\n").unwrap();
+        assert_eq!(fw.program, vec![0x00, 0xc0, 0xff, 0xcf, 0, 0, 0, 0, 0xfe, 0xcf]);
+        assert!(parse_ihex(b":0400000000C0FFCF6F\n").is_err());
+        assert!(parse_ihex(b"0400000000C0FFCF6E\n").is_err());
+    }
+
+    #[test]
+    fn load_bin() {
+        let fw = Firmware::from_bytes(vec![0, 0]).unwrap();
+        assert_eq!(fw.program, vec![0, 0]);
+        assert!(fw.symbols.get("main").is_none());
+        assert!(Firmware::from_bytes(vec![0; PROGRAM_SIZE]).is_ok());
+        assert!(Firmware::from_bytes(vec![0; PROGRAM_SIZE + 2]).is_err());
+    }
+}
@@ -0,0 +1,568 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use cpu::{Cpu, HaltReason, StepResult};
+use fault::{FaultKind, FaultPolicy};
+use history::ReverseStop;
+use memory::{PROGRAM_SIZE, SRAM_SIZE};
+use watch::{Watchpoint, WatchHit, WatchKind};
+
+// avr-gdb places the data memory at this offset, the flash starts at 0
+const DATA_OFFSET: u32 = 0x800000;
+const EEPROM_OFFSET: u32 = 0x810000;
+const EEPROM_SIZE: u32 = 1024;
+// the registers of avr-gdb: r0 - r31, SREG, SP and PC
+const NUM_REGS: usize = 35;
+const SREG: usize = 32;
+const SP: usize = 33;
+const PC: usize = 34;
+// how often a running cpu checks for an interrupt by gdb
+const POLL_INTERVAL: u64 = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGILL: u8 = 4;
+const SIGSEGV: u8 = 11;
+
+/// the state of a gdb session, reply returns the answer to a packet
+pub struct Session {
+    // the last stop reply, for the ? packet
+    stop: String,
+    // the firmware exited, the connection is closed after the reply
+    exited: bool,
+}
+
+impl Default for Session {
+    fn default() -> Session {
+        Session::new()
+    }
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session { stop: format!("S{:02x}", SIGTRAP), exited: false }
+    }
+
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// handles a packet without the framing, interrupted is polled while
+    /// the cpu runs and returns true, when gdb requested to stop
+    /// None closes the connection
+    pub fn reply<F>(&mut self, cpu: &mut Cpu, packet: &str, interrupted: F) -> Option<String>
+        where F: FnMut() -> bool {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match cmd {
+            "?" => self.stop.clone(),
+            "g" => (0..NUM_REGS).map(|r| read_reg(cpu, r)).collect(),
+            "G" => match parse_hex(args) {
+                Some(ref bytes) if bytes.len() >= 32 + 1 + 2 + 2 && write_regs(cpu, bytes) => "OK".to_string(),
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(r) if r < NUM_REGS => read_reg(cpu, r),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let reg = parts.next().and_then(|r| usize::from_str_radix(r, 16).ok());
+                match (reg, parts.next().and_then(parse_hex)) {
+                    (Some(r), Some(bytes)) if r < NUM_REGS && write_reg(cpu, r, &bytes) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => parse_range(args)
+                .and_then(|(addr, len)| read_mem(cpu, addr, len))
+                .unwrap_or_else(|| "E01".to_string()),
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_range);
+                match (range, parts.next().and_then(parse_hex)) {
+                    (Some((addr, len)), Some(ref bytes)) if bytes.len() == len && write_mem(cpu, addr, bytes) =>
+                        "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let typ = parts.next();
+                let addr = parts.next().and_then(|a| u32::from_str_radix(a, 16).ok());
+                let len = parts.next().and_then(|l| u16::from_str_radix(l, 16).ok());
+                match (typ, addr) {
+                    // software and hardware breakpoints are the same for us
+                    (Some("0"), Some(addr)) | (Some("1"), Some(addr)) if addr < PROGRAM_SIZE as u32 => {
+                        if cmd == "Z" {
+                            cpu.add_breakpoint(addr as usize);
+                        } else {
+                            cpu.remove_breakpoint(addr as usize);
+                        }
+                        "OK".to_string()
+                    }
+                    (Some("0"), _) | (Some("1"), _) => "E01".to_string(),
+                    // watchpoints for writes, reads and both
+                    (Some(t @ "2"), Some(addr)) | (Some(t @ "3"), Some(addr)) | (Some(t @ "4"), Some(addr)) => {
+                        let kind = match t {
+                            "2" => WatchKind::Write,
+                            "3" => WatchKind::Read,
+                            _ => WatchKind::Access,
+                        };
+                        match len {
+                            Some(len) if addr >= DATA_OFFSET
+                                && addr.checked_add(len as u32).is_some_and(|end| end <= DATA_OFFSET + SRAM_SIZE as u32) => {
+                                let watchpoint = Watchpoint::new((addr - DATA_OFFSET) as u16, len, kind);
+                                if cmd == "Z" {
+                                    cpu.add_watchpoint(watchpoint);
+                                } else {
+                                    cpu.remove_watchpoint(&watchpoint);
+                                }
+                                "OK".to_string()
+                            }
+                            _ => "E01".to_string(),
+                        }
+                    }
+                    _ => String::new(),
+                }
+            }
+            "s" | "c" => match u32::from_str_radix(args, 16) {
+                Ok(addr) if addr >= PROGRAM_SIZE as u32 => "E01".to_string(),
+                addr => {
+                    if let Ok(addr) = addr {
+                        cpu.set_ip((addr >> 1) as usize);
+                    }
+                    self.stop = self.resume(cpu, cmd == "s", interrupted);
+                    self.stop.clone()
+                }
+            },
+            // reverse step and continue
+            "b" if cpu.history().is_none() => "E01".to_string(),
+            "b" if args == "s" || args == "c" => {
+                self.stop = match (args, cpu.reverse_step()) {
+                    ("s", true) => format!("S{:02x}", SIGTRAP),
+                    ("s", false) => format!("T{:02x}replaylog:begin;", SIGTRAP),
+                    _ => match cpu.reverse_continue() {
+                        Some(ReverseStop::Watchpoint(hit)) => watch_stop(&hit),
+                        Some(ReverseStop::Start) => format!("T{:02x}replaylog:begin;", SIGTRAP),
+                        _ => format!("S{:02x}", SIGTRAP),
+                    },
+                };
+                self.stop.clone()
+            }
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "D" => {
+                self.exited = true;
+                "OK".to_string()
+            }
+            "k" => return None,
+            "q" => match args.split(':').next().unwrap_or("") {
+                "Supported" if cpu.history().is_some() => "PacketSize=1000;ReverseStep+;ReverseContinue+".to_string(),
+                "Supported" => "PacketSize=1000".to_string(),
+                "Attached" => "1".to_string(),
+                "C" => "QC1".to_string(),
+                "fThreadInfo" => "m1".to_string(),
+                "sThreadInfo" => "l".to_string(),
+                "Offsets" => "Text=0;Data=0;Bss=0".to_string(),
+                _ => String::new(),
+            },
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn resume<F>(&mut self, cpu: &mut Cpu, single: bool, mut interrupted: F) -> String where F: FnMut() -> bool {
+        cpu.set_single_step(single);
+        let mut steps = 0;
+        let stop = loop {
+            match cpu.step() {
+                StepResult::Halted(HaltReason::Exit(status)) => {
+                    self.exited = true;
+                    break format!("W{:02x}", status);
+                }
+                StepResult::Halted(HaltReason::Fault(kind)) => break format!("S{:02x}", signal(kind)),
+                StepResult::Halted(_) => break format!("S{:02x}", SIGTRAP),
+                StepResult::Running => {}
+            }
+            match cpu.fault() {
+                Some(fault) if cpu.fault_policies().get(fault.kind) == FaultPolicy::Break =>
+                    break format!("S{:02x}", signal(fault.kind)),
+                _ => {}
+            }
+            if let Some(hit) = cpu.watch_hit() {
+                break watch_stop(&hit);
+            }
+            if cpu.stack_error().is_some() {
+                break format!("S{:02x}", SIGSEGV);
+            }
+            if single || cpu.at_breakpoint() {
+                break format!("S{:02x}", SIGTRAP);
+            }
+            steps += 1;
+            if steps % POLL_INTERVAL == 0 && interrupted() {
+                break format!("S{:02x}", SIGINT);
+            }
+        };
+        cpu.set_single_step(false);
+        stop
+    }
+}
+
+fn signal(kind: FaultKind) -> u8 {
+    match kind {
+        FaultKind::IllegalOpcode | FaultKind::SecondWord | FaultKind::UnsupportedClock => SIGILL,
+        FaultKind::InvalidRead | FaultKind::InvalidWrite | FaultKind::PcOutOfRange => SIGSEGV,
+    }
+}
+
+fn watch_stop(hit: &WatchHit) -> String {
+    let kind = match hit.watchpoint.kind {
+        WatchKind::Write => "watch",
+        WatchKind::Read => "rwatch",
+        WatchKind::Access => "awatch",
+    };
+    format!("T{:02x}{}:{:x};", SIGTRAP, kind, DATA_OFFSET + hit.addr as u32)
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_range(s: &str) -> Option<(u32, usize)> {
+    let mut parts = s.split(',');
+    let addr = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+// registers are little endian, the pc is a byte address
+fn read_reg(cpu: &Cpu, reg: usize) -> String {
+    match reg {
+        SREG => to_hex(&[cpu.flags()]),
+        SP => to_hex(&[cpu.mem().sp() as u8, (cpu.mem().sp() >> 8) as u8]),
+        PC => {
+            let pc = (cpu.ip() << 1) as u32;
+            to_hex(&[pc as u8, (pc >> 8) as u8, (pc >> 16) as u8, (pc >> 24) as u8])
+        }
+        r => to_hex(&[cpu.reg(r as u8)]),
+    }
+}
+
+fn reg_value(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0u32, |acc, b| acc << 8 | *b as u32)
+}
+
+// fails for a pc outside of the flash
+fn write_reg(cpu: &mut Cpu, reg: usize, bytes: &[u8]) -> bool {
+    let val = reg_value(bytes);
+    match reg {
+        SREG => cpu.mem_mut().set_flags(val as u8),
+        SP => cpu.mem_mut().set_sp(val as u16),
+        PC if val >= PROGRAM_SIZE as u32 => return false,
+        PC => cpu.set_ip((val >> 1) as usize),
+        r => *cpu.mem_mut().reg_mut(r as u8) = val as u8,
+    }
+    true
+}
+
+fn write_regs(cpu: &mut Cpu, bytes: &[u8]) -> bool {
+    // gdb may send a pc with two or four bytes
+    let pc = &bytes[35..bytes.len().min(39)];
+    if reg_value(pc) >= PROGRAM_SIZE as u32 {
+        return false;
+    }
+    for r in 0..32 {
+        write_reg(cpu, r, &bytes[r..r + 1]);
+    }
+    write_reg(cpu, SREG, &bytes[32..33]);
+    write_reg(cpu, SP, &bytes[33..35]);
+    write_reg(cpu, PC, pc)
+}
+
+fn read_mem(cpu: &Cpu, addr: u32, len: usize) -> Option<String> {
+    let end = addr.checked_add(len as u32)?;
+    let bytes: Vec<u8> = if end <= PROGRAM_SIZE as u32 {
+        (addr..end).map(|a| cpu.mem().read_program(a as u16)).collect()
+    } else if addr >= DATA_OFFSET && end <= DATA_OFFSET + SRAM_SIZE as u32 {
+        (addr..end).map(|a| cpu.mem().data((a - DATA_OFFSET) as u16)).collect()
+    } else if addr >= EEPROM_OFFSET && end <= EEPROM_OFFSET + EEPROM_SIZE {
+        // there is no eeprom, gdb shows it as erased
+        vec![0xff; len]
+    } else {
+        return None;
+    };
+    Some(to_hex(&bytes))
+}
+
+// the flash can't be written, breakpoints are set with Z packets
+fn write_mem(cpu: &mut Cpu, addr: u32, bytes: &[u8]) -> bool {
+    if addr < DATA_OFFSET || addr.checked_add(bytes.len() as u32).is_none_or(|end| end > DATA_OFFSET + SRAM_SIZE as u32) {
+        return false;
+    }
+    for (i, b) in bytes.iter().enumerate() {
+        cpu.mem_mut().set_data((addr - DATA_OFFSET) as u16 + i as u16, *b);
+    }
+    true
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |acc, b| acc.wrapping_add(b))
+}
+
+// reads the next packet and acknowledges it, Ctrl-C outside of a
+// packet is ignored, because the cpu is already stopped
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut byte = [0; 1];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] != b'$' {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut cs = [0; 2];
+        stream.read_exact(&mut cs)?;
+
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let valid = u8::from_str_radix(&String::from_utf8_lossy(&cs), 16).ok() == Some(checksum(&data));
+        stream.write_all(if valid { b"+" } else { b"-" })?;
+        if valid {
+            return Ok(Some(data));
+        }
+    }
+}
+
+fn write_packet(stream: &mut TcpStream, data: &str) -> io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum(data))?;
+    stream.flush()
+}
+
+// checks without blocking, if gdb sent a Ctrl-C
+fn poll_interrupt(stream: &mut TcpStream) -> bool {
+    let mut byte = [0; 1];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let interrupted = match stream.read(&mut byte) {
+        Ok(1) => byte[0] == 0x03,
+        _ => false,
+    };
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
+
+/// waits for gdb on the address, e.g. "localhost:1234",
+/// and serves a single session. Returns the exit status of the
+/// firmware, if it exited.
+pub fn serve(cpu: &mut Cpu, addr: &str) -> io::Result<Option<u8>> {
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("vm: waiting for gdb on {}", listener.local_addr()?);
+    let (stream, _) = listener.accept()?;
+    handle(cpu, stream)
+}
+
+pub fn handle(cpu: &mut Cpu, mut stream: TcpStream) -> io::Result<Option<u8>> {
+    stream.set_nodelay(true)?;
+    let mut session = Session::new();
+    while let Some(packet) = read_packet(&mut stream)? {
+        let mut poll = stream.try_clone()?;
+        match session.reply(cpu, &packet, || poll_interrupt(&mut poll)) {
+            Some(reply) => write_packet(&mut stream, &reply)?,
+            None => break,
+        }
+        if session.exited() {
+            break;
+        }
+    }
+    Ok(match cpu.halt_reason() {
+        Some(HaltReason::Exit(status)) => Some(status),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use cpu::Cpu;
+    use fault::{FaultPolicies, FaultPolicy};
+    use firmware::Firmware;
+    use history::History;
+    use memory::Memory;
+    use super::{checksum, handle, Session};
+
+    // ldi r16, 'A'; out UDR, r16; rjmp .-2
+    const PRINT_A: [u8; 6] = [0x01, 0xe4, 0x0c, 0xb9, 0xfe, 0xcf];
+
+    fn cpu() -> Cpu {
+        let mut mem = Memory::from_firmware(&Firmware::from_bytes(PRINT_A.to_vec()).unwrap(), None);
+        mem.uart_mut().set_echo(false);
+        Cpu::new(mem, false)
+    }
+
+    fn reply(session: &mut Session, cpu: &mut Cpu, packet: &str) -> String {
+        session.reply(cpu, packet, || false).unwrap()
+    }
+
+    #[test]
+    fn registers() {
+        let mut cpu = cpu();
+        let mut s = Session::new();
+        assert_eq!(reply(&mut s, &mut cpu, "s"), "S05");
+        assert_eq!(reply(&mut s, &mut cpu, "p10"), "41");
+        assert_eq!(reply(&mut s, &mut cpu, "p22"), "02000000");
+        assert_eq!(reply(&mut s, &mut cpu, "P21=5f08"), "OK");
+        assert_eq!(reply(&mut s, &mut cpu, "p21"), "5f08");
+        assert_eq!(cpu.mem().sp(), 0x85f);
+
+        let g = reply(&mut s, &mut cpu, "g");
+        assert_eq!(g.len(), 39 * 2);
+        assert_eq!(&g[32..34], "41");
+        let g = g.replacen("41", "42", 1);
+        assert_eq!(reply(&mut s, &mut cpu, &format!("G{}", g)), "OK");
+        assert_eq!(cpu.reg(16), 0x42);
+
+        // a pc outside of the flash
+        assert_eq!(reply(&mut s, &mut cpu, "P22=00800000"), "E01");
+        let g = format!("{}00800000", &g[..70]);
+        assert_eq!(reply(&mut s, &mut cpu, &format!("G{}", g.replacen("42", "43", 1))), "E01");
+        assert_eq!(cpu.reg(16), 0x42);
+        assert_eq!(reply(&mut s, &mut cpu, "c10000"), "E01");
+        assert_eq!(reply(&mut s, &mut cpu, "s8000"), "E01");
+        assert_eq!(cpu.ip(), 1);
+    }
+
+    #[test]
+    fn memory() {
+        let mut cpu = cpu();
+        let mut s = Session::new();
+        assert_eq!(reply(&mut s, &mut cpu, "m0,6"), "01e40cb9fecf");
+        assert_eq!(reply(&mut s, &mut cpu, "M800100,2:abcd"), "OK");
+        assert_eq!(reply(&mut s, &mut cpu, "m800100,2"), "abcd");
+        assert_eq!(cpu.mem().data(0x100), 0xab);
+        // registers are mapped into the data memory
+        assert_eq!(reply(&mut s, &mut cpu, "M800010,1:07"), "OK");
+        assert_eq!(cpu.reg(16), 7);
+        assert_eq!(reply(&mut s, &mut cpu, "M0,1:00"), "E01");
+        assert_eq!(reply(&mut s, &mut cpu, "m900000,1"), "E01");
+        assert_eq!(reply(&mut s, &mut cpu, "Mffffffff,2:0000"), "E01");
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut cpu = cpu();
+        let mut s = Session::new();
+        assert_eq!(reply(&mut s, &mut cpu, "Z0,4,2"), "OK");
+        assert_eq!(reply(&mut s, &mut cpu, "c"), "S05");
+        assert_eq!(cpu.ip(), 2);
+        assert_eq!(reply(&mut s, &mut cpu, "c"), "S05");
+        assert_eq!(cpu.ip(), 2);
+        assert_eq!(cpu.mem().uart().output(), b"AA");
+        assert_eq!(reply(&mut s, &mut cpu, "z0,4,2"), "OK");
+
+        let mut polls = 0;
+        assert_eq!(s.reply(&mut cpu, "c", || { polls += 1; polls == 3 }).unwrap(), "S02");
+        assert_eq!(reply(&mut s, &mut cpu, "?"), "S02");
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut cpu = cpu();
+        let mut s = Session::new();
+        // out UDR, r16 writes to 0x2c
+        assert_eq!(reply(&mut s, &mut cpu, "Z2,80002c,1"), "OK");
+        assert_eq!(reply(&mut s, &mut cpu, "c"), "T05watch:80002c;");
+        assert_eq!(cpu.ip(), 2);
+        assert_eq!(cpu.watch_hit().map(|hit| (hit.pc, hit.new)), Some((2, 0x41)));
+        assert_eq!(reply(&mut s, &mut cpu, "z2,80002c,1"), "OK");
+        assert!(cpu.watchpoints().is_empty());
+        assert_eq!(reply(&mut s, &mut cpu, "Z3,80002c,1"), "OK");
+        assert_eq!(reply(&mut s, &mut cpu, "Z2,2c,1"), "E01");
+        assert_eq!(reply(&mut s, &mut cpu, "Z4,800000"), "E01");
+        assert_eq!(reply(&mut s, &mut cpu, "Z2,fffffff0,20"), "E01");
+    }
+
+    #[test]
+    fn reverse() {
+        let mut cpu = cpu();
+        let mut s = Session::new();
+        assert_eq!(reply(&mut s, &mut cpu, "bs"), "E01");
+        assert_eq!(reply(&mut s, &mut cpu, "qSupported"), "PacketSize=1000");
+        cpu.set_history(Some(History::new(4, 10)));
+        assert_eq!(reply(&mut s, &mut cpu, "qSupported"), "PacketSize=1000;ReverseStep+;ReverseContinue+");
+        for _ in 0..6 {
+            reply(&mut s, &mut cpu, "s");
+        }
+        assert_eq!(reply(&mut s, &mut cpu, "bs"), "S05");
+        assert_eq!(cpu.cycles(), 5);
+        assert_eq!(reply(&mut s, &mut cpu, "Z2,80002c,1"), "OK");
+        assert_eq!(reply(&mut s, &mut cpu, "bc"), "T05watch:80002c;");
+        assert_eq!((cpu.cycles(), cpu.ip()), (3, 1));
+        assert_eq!(reply(&mut s, &mut cpu, "?"), "T05watch:80002c;");
+        assert_eq!(reply(&mut s, &mut cpu, "z2,80002c,1"), "OK");
+        assert_eq!(reply(&mut s, &mut cpu, "bc"), "T05replaylog:begin;");
+        assert_eq!(cpu.cycles(), 0);
+        assert_eq!(reply(&mut s, &mut cpu, "bs"), "T05replaylog:begin;");
+    }
+
+    #[test]
+    fn faults() {
+        let mem = Memory::from_firmware(&Firmware::from_bytes(vec![0xff, 0xff, 0x00, 0x00]).unwrap(), None);
+        let mut cpu = Cpu::new(mem, false);
+        let mut s = Session::new();
+        let mut policies = FaultPolicies::default();
+        policies.set_all(FaultPolicy::Break);
+        cpu.set_fault_policies(policies);
+        assert_eq!(reply(&mut s, &mut cpu, "c"), "S04");
+        assert_eq!(cpu.ip(), 1);
+        policies.set_all(FaultPolicy::Halt);
+        cpu.set_fault_policies(policies);
+        cpu.set_ip(0);
+        assert_eq!(reply(&mut s, &mut cpu, "c"), "S04");
+        assert_eq!(reply(&mut s, &mut cpu, "?"), "S04");
+    }
+
+    #[test]
+    fn tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut cpu = cpu();
+            handle(&mut cpu, listener.accept().unwrap().0).unwrap()
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut send = |data: &str| -> String {
+            write!(client, "${}#{:02x}", data, checksum(data)).unwrap();
+            let mut buf = [0; 64];
+            let mut answer = String::new();
+            while !answer.contains('#') || answer.len() < answer.find('#').unwrap() + 3 {
+                let n = client.read(&mut buf).unwrap();
+                answer.push_str(&String::from_utf8_lossy(&buf[..n]));
+            }
+            answer
+        };
+        assert_eq!(send("?"), "+$S05#b8");
+        assert_eq!(send("m0,2"), "+$01e4#fa");
+        write!(client, "$k#6b").unwrap();
+        assert_eq!(server.join().unwrap(), None);
+    }
+}
@@ -0,0 +1,309 @@
+use std::ffi::OsString;
+use std::io;
+use std::sync::{Arc, Mutex};
+use cpu::{Cpu, HaltReason};
+use firmware::{Firmware, Symbol, Symbols};
+use io::{IO, Wire, HIGH, LOW};
+use memory::Memory;
+
+const PORTA: usize = 0;
+const PORTB: usize = 1;
+const PORTC: usize = 2;
+const PORTD: usize = 3;
+
+// the wiring of the SPiCboard, the order matches the enums of the libspicboard
+const LED_PINS: [(usize, usize); 8] = [
+    (PORTD, 7), (PORTC, 0), (PORTC, 1), (PORTC, 6),
+    (PORTC, 7), (PORTA, 7), (PORTA, 6), (PORTA, 5),
+];
+// segments a to g of both displays, the displays are enabled by PD0 and PD1
+const SEG7_PINS: [usize; 7] = [4, 5, 6, 0, 1, 3, 2];
+const SEG7_ENABLE_PINS: [usize; 2] = [0, 1];
+// segments of the digits 0-9 and A-F, bit 0 is segment a
+const SEG7_DIGITS: [u8; 16] = [
+    0x3f, 0x06, 0x5b, 0x4f, 0x66, 0x6d, 0x7d, 0x07,
+    0x7f, 0x6f, 0x77, 0x7c, 0x39, 0x5e, 0x79, 0x71,
+];
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Button {
+    Button0, Button1
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AdcDevice {
+    Photo, Poti
+}
+
+/// the peripherals of the SPiCboard without a gui
+pub struct Board {
+    io: Arc<IO>,
+    // the last segments shown on each display
+    seg7: Arc<Mutex<[u8; 2]>>,
+}
+
+impl Default for Board {
+    fn default() -> Board {
+        Board::new()
+    }
+}
+
+impl Board {
+    pub fn new() -> Board {
+        let io = IO::new();
+        io.nreset.set(LOW);
+        io.gnd.set(LOW);
+        io.vcc.set(HIGH);
+
+        // pins, which are not driven by the microcontroller, are
+        // pulled to vcc by the leds, the displays and the buttons
+        for &(port, pin) in LED_PINS.iter() {
+            io.p[port][pin].set(HIGH);
+        }
+        for &pin in SEG7_PINS.iter() {
+            io.p[PORTB][pin].set(HIGH);
+        }
+        for &pin in SEG7_ENABLE_PINS.iter() {
+            io.p[PORTD][pin].set(HIGH);
+        }
+        io.p[PORTD][2].set(HIGH);
+        io.p[PORTD][3].set(HIGH);
+
+        let seg7 = Arc::new(Mutex::new([0; 2]));
+        let segments: Vec<Arc<Wire>> = SEG7_PINS.iter().map(|&pin| io.p[PORTB][pin].clone()).collect();
+        let enable: Vec<Arc<Wire>> = SEG7_ENABLE_PINS.iter().map(|&pin| io.p[PORTD][pin].clone()).collect();
+        for wire in segments.iter().chain(enable.iter()) {
+            let seg7 = seg7.clone();
+            let segments = segments.clone();
+            let enable = enable.clone();
+            wire.add_listener(move || {
+                for (display, en) in enable.iter().enumerate() {
+                    if en.as_bin() == 1 {
+                        continue;
+                    }
+                    seg7.lock().unwrap()[display] = segments.iter().enumerate()
+                        .fold(0, |acc, (i, seg)| acc | (1 - seg.as_bin()) << i);
+                }
+            });
+        }
+
+        Board { io: Arc::new(io), seg7: seg7 }
+    }
+
+    pub fn io(&self) -> &Arc<IO> {
+        &self.io
+    }
+
+    /// the state of the leds as used by sb_led_set_all_leds
+    pub fn leds(&self) -> u8 {
+        LED_PINS.iter().enumerate()
+            .fold(0, |acc, (i, &(port, pin))| acc | (1 - self.io.p[port][pin].as_bin()) << i)
+    }
+
+    pub fn led(&self, led: usize) -> bool {
+        (self.leds() >> led) & 1 == 1
+    }
+
+    /// the segments, which were shown last on the display,
+    /// bit 0 is segment a and bit 6 segment g
+    pub fn seg7_segments(&self, display: usize) -> u8 {
+        self.seg7.lock().unwrap()[display]
+    }
+
+    /// the hex digit shown on the display, a space if it is blank
+    pub fn seg7_digit(&self, display: usize) -> Option<char> {
+        let segments = self.seg7_segments(display);
+        if segments == 0 {
+            return Some(' ');
+        }
+        SEG7_DIGITS.iter().position(|&s| s == segments)
+            .and_then(|d| ::std::char::from_digit(d as u32, 16))
+            .map(|c| c.to_ascii_uppercase())
+    }
+
+    /// both digits of the display, unknown patterns are shown as '?'
+    pub fn seg7_text(&self) -> String {
+        (0..2).map(|d| self.seg7_digit(d).unwrap_or('?')).collect()
+    }
+
+    pub fn press(&self, button: Button) {
+        self.button_wire(button).set(LOW);
+    }
+
+    pub fn release(&self, button: Button) {
+        self.button_wire(button).set(HIGH);
+    }
+
+    /// sets the voltage in millivolts at the adc input of the device
+    pub fn set_adc(&self, device: AdcDevice, mv: u16) {
+        let pin = match device {
+            AdcDevice::Photo => 0,
+            AdcDevice::Poti => 1,
+        };
+        self.io.p[PORTA][pin].set(mv);
+    }
+
+    fn button_wire(&self, button: Button) -> &Wire {
+        match button {
+            Button::Button0 => &self.io.p[PORTD][2],
+            Button::Button1 => &self.io.p[PORTD][3],
+        }
+    }
+}
+
+/// runs a firmware on a board and allows to inspect the state of the cpu
+pub struct Harness {
+    cpu: Cpu,
+    board: Arc<Board>,
+    symbols: Symbols,
+}
+
+impl Harness {
+    pub fn new(file: OsString) -> io::Result<Harness> {
+        Harness::with_board(Arc::new(Board::new()), file)
+    }
+
+    pub fn with_board(board: Arc<Board>, file: OsString) -> io::Result<Harness> {
+        Ok(Harness::from_firmware(board, Firmware::load(file)?))
+    }
+
+    pub fn from_firmware(board: Arc<Board>, firmware: Firmware) -> Harness {
+        let mut mem = Memory::from_firmware(&firmware, Some(board.io().clone()));
+        mem.uart_mut().set_echo(false);
+        Harness { cpu: Cpu::new(mem, false), board: board, symbols: firmware.symbols }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    /// lets the firmware call the host, e.g. to exit with a status or to open files,
+    /// it is disabled by default
+    pub fn set_semihosting(&mut self, enabled: bool) {
+        self.cpu.mem_mut().semihost_mut().set_enabled(enabled);
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn board(&self) -> &Board {
+        &self.board
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cpu.cycles()
+    }
+
+    /// the status, if the firmware exited over semihosting
+    pub fn exit_status(&self) -> Option<u8> {
+        match self.cpu.halt_reason() {
+            Some(HaltReason::Exit(status)) => Some(status),
+            _ => None,
+        }
+    }
+
+    /// executes the given number of cycles
+    /// returns false, if the cpu halted before
+    pub fn run(&mut self, cycles: u64) -> bool {
+        let end = self.cpu.cycles() + cycles;
+        self.cpu.set_cycle_limit(Some(end));
+        let mut running = true;
+        while running && self.cpu.cycles() < end {
+            running = self.cpu.step().is_running();
+        }
+        self.cpu.set_cycle_limit(None);
+        running
+    }
+
+    /// executes until the condition is true, but at most max_cycles cycles
+    /// returns, if the condition became true
+    pub fn run_until<F>(&mut self, max_cycles: u64, mut cond: F) -> bool where F: FnMut(&Harness) -> bool {
+        let end = self.cpu.cycles() + max_cycles;
+        self.cpu.set_cycle_limit(Some(end));
+        let mut reached = true;
+        while !cond(self) {
+            if self.cpu.cycles() >= end || !self.cpu.step().is_running() {
+                reached = false;
+                break;
+            }
+        }
+        self.cpu.set_cycle_limit(None);
+        reached
+    }
+
+    pub fn run_until_uart(&mut self, max_cycles: u64, text: &str) -> bool {
+        self.run_until(max_cycles, |h| h.uart_output().contains(text))
+    }
+
+    /// everything the firmware has written to the uart
+    pub fn uart_output(&self) -> String {
+        String::from_utf8_lossy(self.cpu.mem().uart().output()).into_owned()
+    }
+
+    pub fn send_uart(&mut self, bytes: &[u8]) {
+        self.cpu.mem_mut().uart_mut().send(bytes);
+    }
+
+    pub fn reg(&self, index: u8) -> u8 {
+        self.cpu.mem().reg(index)
+    }
+
+    pub fn data(&self, addr: u16) -> u8 {
+        self.cpu.mem().data(addr)
+    }
+
+    pub fn symbol(&self, name: &str) -> &Symbol {
+        self.symbols.get(name).unwrap_or_else(|| panic!("Unknown symbol: {}", name))
+    }
+
+    /// the content of a variable in the data memory
+    pub fn read_symbol(&self, name: &str) -> Vec<u8> {
+        let sym = self.symbol(name);
+        let addr = sym.data_addr().unwrap_or_else(|| panic!("{} is not in the data memory", name));
+        (0..sym.size as u16).map(|i| self.data(addr + i)).collect()
+    }
+
+    /// reads a little endian integer with the size of the variable
+    pub fn read_symbol_uint(&self, name: &str) -> u32 {
+        self.read_symbol(name).iter().rev().fold(0, |acc, b| acc << 8 | *b as u32)
+    }
+
+    pub fn assert_uart(&self, expected: &str) {
+        assert_eq!(self.uart_output(), expected, "unexpected uart output after {} cycles", self.cycles());
+    }
+
+    pub fn assert_reg(&self, index: u8, expected: u8) {
+        assert_eq!(self.reg(index), expected, "unexpected value of r{} after {} cycles", index, self.cycles());
+    }
+
+    pub fn assert_symbol(&self, name: &str, expected: u32) {
+        assert_eq!(self.read_symbol_uint(name), expected,
+                   "unexpected value of {} after {} cycles", name, self.cycles());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use io::{HIGH, LOW};
+    use super::Board;
+
+    #[test]
+    fn board_seg7() {
+        let board = Board::new();
+        let p = &board.io().p;
+        // shows 4 on the first display: segments b, c, f and g
+        for &(pin, val) in &[(4, HIGH), (5, LOW), (6, LOW), (0, HIGH), (1, HIGH), (3, LOW), (2, LOW)] {
+            p[1][pin].set(val);
+        }
+        p[3][0].set(LOW);
+        p[3][0].set(HIGH);
+        assert_eq!(board.seg7_text(), "4 ");
+        p[3][1].set(LOW);
+        assert_eq!(board.seg7_text(), "44");
+        p[1][4].set(LOW);
+        p[3][1].set(HIGH);
+        assert_eq!(board.seg7_digit(1), None);
+        assert_eq!(board.seg7_text(), "4?");
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::VecDeque;
+use semihost::HostCall;
+use snapshot::Snapshot;
+use watch::WatchHit;
+
+/// a write of the cpu in the data memory
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct MemoryWrite {
+    // the cycle of the instruction
+    pub cycles: u64,
+    // byte address of the instruction, for an interrupt the address of its vector
+    pub pc: usize,
+    pub addr: u16,
+    pub value: u8,
+}
+
+/// where reverse_continue stopped
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ReverseStop {
+    Breakpoint,
+    // before the instruction with the access
+    Watchpoint(WatchHit),
+    // the oldest state in the history
+    Start,
+}
+
+/// the past of the cpu for reverse execution, the cpu takes a snapshot every interval
+/// cycles and journals all writes, the states in between are restored by executing
+/// from the snapshot before them again
+///
+/// only the last depth snapshots are kept, changes from outside like input at the
+/// wires or the uart aren't recorded. the results of the host calls of semihosting
+/// are journaled, the host isn't called again, when they are executed again
+pub struct History {
+    interval: u64,
+    depth: usize,
+    snapshots: VecDeque<Snapshot>,
+    journal: VecDeque<MemoryWrite>,
+    // with the cycle of the instruction
+    host_calls: VecDeque<(u64, HostCall)>,
+}
+
+impl History {
+    pub fn new(interval: u64, depth: usize) -> History {
+        assert!(interval > 0 && depth > 0);
+        History {
+            interval: interval, depth: depth, snapshots: VecDeque::new(), journal: VecDeque::new(),
+            host_calls: VecDeque::new(),
+        }
+    }
+
+    /// the cycles of the oldest state, which can be restored
+    pub fn start(&self) -> Option<u64> {
+        self.snapshots.front().map(|s| s.cycles)
+    }
+
+    /// the journal of the writes, the oldest first
+    pub fn writes(&self) -> &VecDeque<MemoryWrite> {
+        &self.journal
+    }
+
+    /// the last write to the address
+    pub fn last_write(&self, addr: u16) -> Option<&MemoryWrite> {
+        self.journal.iter().rev().find(|w| w.addr == addr)
+    }
+
+    /// a new snapshot is taken, when this returns true
+    pub fn snapshot_due(&self, cycles: u64) -> bool {
+        self.snapshots.back().is_none_or(|s| cycles >= s.cycles + self.interval)
+    }
+
+    pub fn add_snapshot(&mut self, snapshot: Snapshot) {
+        self.snapshots.push_back(snapshot);
+        if self.snapshots.len() > self.depth {
+            self.snapshots.pop_front();
+            let start = self.snapshots[0].cycles;
+            while self.journal.front().is_some_and(|w| w.cycles <= start) {
+                self.journal.pop_front();
+            }
+            while self.host_calls.front().is_some_and(|c| c.0 <= start) {
+                self.host_calls.pop_front();
+            }
+        }
+    }
+
+    pub fn add_write(&mut self, write: MemoryWrite) {
+        self.journal.push_back(write);
+    }
+
+    pub fn add_host_call(&mut self, cycles: u64, call: HostCall) {
+        self.host_calls.push_back((cycles, call));
+    }
+
+    /// the results of the host calls after the cycle, the oldest first
+    pub fn host_calls_after(&self, cycles: u64) -> VecDeque<HostCall> {
+        self.host_calls.iter().filter(|c| c.0 > cycles).map(|c| c.1.clone()).collect()
+    }
+
+    /// the last snapshot at or before the cycle
+    pub fn snapshot_before(&self, cycles: u64) -> Option<&Snapshot> {
+        self.snapshots.iter().rev().find(|s| s.cycles <= cycles)
+    }
+
+    /// the snapshots before the cycle, the newest first
+    pub fn snapshots_before(&self, cycles: u64) -> Vec<Snapshot> {
+        self.snapshots.iter().rev().filter(|s| s.cycles < cycles).cloned().collect()
+    }
+
+    /// forgets everything after the cycle, it is recorded again, when the cpu continues
+    pub fn truncate(&mut self, cycles: u64) {
+        while self.snapshots.back().is_some_and(|s| s.cycles > cycles) {
+            self.snapshots.pop_back();
+        }
+        while self.journal.back().is_some_and(|w| w.cycles > cycles) {
+            self.journal.pop_back();
+        }
+        while self.host_calls.back().is_some_and(|c| c.0 > cycles) {
+            self.host_calls.pop_back();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use cpu::Cpu;
+    use firmware::Firmware;
+    use memory::Memory;
+    use watch::{Watchpoint, WatchKind};
+    use super::{History, ReverseStop};
+
+    // ldi r16, 'A'; sts 0x60, r16; subi r16, -1; rjmp .-8
+    const COUNTER: [u8; 10] = [0x01, 0xe4, 0x00, 0x93, 0x60, 0x00, 0x0f, 0x5f, 0xfc, 0xcf];
+    // clock(0x100) over semihosting in cycle 6, then rjmp .-2
+    const CLOCK: [u8; 20] = [0x00, 0xe0, 0x00, 0x93, 0xf0, 0xff, 0x01, 0xe0, 0x00, 0x93, 0xf1, 0xff,
+                             0x07, 0xe0, 0x00, 0x93, 0xf6, 0xff, 0xff, 0xcf];
+
+    fn cpu(steps: u64, history: bool) -> Cpu {
+        let mem = Memory::from_firmware(&Firmware::from_bytes(COUNTER.to_vec()).unwrap(), None);
+        let mut cpu = Cpu::new(mem, false);
+        // the same cycles with and without the history, also with the jit
+        cpu.set_single_step(true);
+        if history {
+            cpu.set_history(Some(History::new(4, 100)));
+        }
+        for _ in 0..steps {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn reverse_step() {
+        let mut cpu = cpu(20, true);
+        for cycles in (0..20).rev() {
+            assert!(cpu.reverse_step());
+            assert_eq!(cpu.snapshot(), self::cpu(cycles, false).snapshot());
+        }
+        assert!(!cpu.reverse_step());
+        // the future is recorded again
+        for _ in 0..20 {
+            cpu.step();
+        }
+        assert_eq!(cpu.snapshot(), self::cpu(20, false).snapshot());
+        assert!(cpu.reverse_step());
+        assert_eq!(cpu.cycles(), 19);
+    }
+
+    #[test]
+    fn journal() {
+        let mut cpu = cpu(20, true);
+        // sts in the cycles 2, 5, ..., 20
+        let w = *cpu.history().unwrap().last_write(0x60).unwrap();
+        assert_eq!((w.cycles, w.pc, w.value), (20, 2, 0x47));
+        assert_eq!(cpu.history().unwrap().writes().len(), 7);
+        assert!(cpu.history().unwrap().last_write(0x61).is_none());
+        cpu.reverse_step();
+        assert_eq!(cpu.history().unwrap().last_write(0x60).unwrap().cycles, 17);
+    }
+
+    #[test]
+    fn reverse_continue() {
+        let mut cpu = cpu(20, true);
+        cpu.add_watchpoint(Watchpoint::new(0x60, 1, WatchKind::Write));
+        // before the sts in cycle 20
+        match cpu.reverse_continue() {
+            Some(ReverseStop::Watchpoint(hit)) => assert_eq!((hit.pc, hit.old, hit.new), (2, 0x46, 0x47)),
+            stop => panic!("unexpected stop: {:?}", stop),
+        }
+        assert_eq!((cpu.cycles(), cpu.ip(), cpu.mem().data(0x60)), (19, 1, 0x46));
+        assert!(matches!(cpu.reverse_continue(), Some(ReverseStop::Watchpoint(_))));
+        assert_eq!(cpu.cycles(), 16);
+        cpu.remove_watchpoint(&Watchpoint::new(0x60, 1, WatchKind::Write));
+
+        cpu.add_breakpoint(6);
+        assert_eq!(cpu.reverse_continue(), Some(ReverseStop::Breakpoint));
+        assert_eq!((cpu.cycles(), cpu.ip()), (14, 3));
+        cpu.remove_breakpoint(6);
+        assert_eq!(cpu.reverse_continue(), Some(ReverseStop::Start));
+        assert_eq!(cpu.cycles(), 0);
+        assert_eq!(self::cpu(5, false).reverse_continue(), None);
+    }
+
+    #[test]
+    fn depth() {
+        let mem = Memory::from_firmware(&Firmware::from_bytes(COUNTER.to_vec()).unwrap(), None);
+        let mut cpu = Cpu::new(mem, false);
+        cpu.set_history(Some(History::new(4, 2)));
+        for _ in 0..20 {
+            cpu.step();
+        }
+        // only the snapshots at 12 and 16 are kept
+        assert_eq!(cpu.history().unwrap().start(), Some(12));
+        assert!(cpu.history().unwrap().writes().iter().all(|w| w.cycles > 12));
+        assert_eq!(cpu.reverse_continue(), Some(ReverseStop::Start));
+        assert_eq!(cpu.cycles(), 12);
+    }
+
+    #[test]
+    fn host_calls() {
+        let mem = Memory::from_firmware(&Firmware::from_bytes(CLOCK.to_vec()).unwrap(), None);
+        let mut cpu = Cpu::new(mem, false);
+        cpu.mem_mut().semihost_mut().set_enabled(true);
+        cpu.set_history(Some(History::new(100, 10)));
+        for _ in 0..8 {
+            cpu.step();
+        }
+        let clock = (0x100..0x104).map(|a| cpu.mem().data(a)).collect::<Vec<_>>();
+        // the replay from the snapshot at 0 gets the same time
+        thread::sleep(Duration::from_millis(20));
+        assert!(cpu.reverse_step());
+        assert_eq!(cpu.cycles(), 7);
+        assert_eq!((0x100..0x104).map(|a| cpu.mem().data(a)).collect::<Vec<_>>(), clock);
+        assert!(cpu.reverse_step());
+        assert!(cpu.reverse_step());
+        assert_eq!(cpu.mem().data(0x100), 0);
+    }
+}
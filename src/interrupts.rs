@@ -17,9 +17,15 @@ const OCIE1A: usize = 4;
 const OCF1A: usize = 4;
 const OCR1A: u8 = 0x2a;
 const TCNT1: u8 = 0x2c;
-const TCCR1B: u16 = 0x4e;
+pub const TCCR1B: u16 = 0x4e;
 const CS1: u8 = 0;
 
+/// the flags of the interrupts, which are set, as mask of their vector numbers
+pub fn interrupt_flags(mem: &Memory) -> u32 {
+    let gifr = mem.data(GIFR);
+    (bit(gifr, INTF0) as u32) << 1 | (bit(gifr, INTF1) as u32) << 2 | (bit(mem.data(TIFR), OCF1A) as u32) << 7
+}
+
 pub struct PortInterrupts {
     // saves the previous value of the ports
     // used for edge detection
@@ -31,6 +37,14 @@ impl PortInterrupts {
         PortInterrupts {prev: [0; 2]}
     }
 
+    pub fn prev(&self) -> [u8; 2] {
+        self.prev
+    }
+
+    pub fn set_prev(&mut self, prev: [u8; 2]) {
+        self.prev = prev;
+    }
+
     #[inline(always)]
     pub fn step(&mut self, mem: &mut Memory) {
         for int_nr in 0..2 {
@@ -51,6 +65,18 @@ impl PortInterrupts {
         }
     }
 
+    /// the pins are sampled in every step, while one of the interrupts is enabled
+    #[cfg(feature = "jit")]
+    pub fn enabled(&self, mem: &Memory) -> bool {
+        mem.data(GICR) & (0b11 << INT_BASE) != 0
+    }
+
+    /// pending_interrupt would return Some
+    #[cfg(feature = "jit")]
+    pub fn is_pending(&self, mem: &Memory) -> bool {
+        mem.data(GIFR) & (1 << INTF0 | 1 << INTF1) != 0
+    }
+
     #[inline(always)]
     pub fn pending_interrupt(&mut self, mem: &mut Memory) -> Option<usize> {
         let gifr = mem.data(GIFR);
@@ -77,19 +103,21 @@ impl TimerInterrupts {
         TimerInterrupts {steps: 0}
     }
 
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    pub fn set_steps(&mut self, steps: u32) {
+        self.steps = steps;
+    }
+
     #[inline(always)]
     pub fn step(&mut self, mem: &mut Memory) {
-        let clock_select = bits(mem.data(TCCR1B) as u16, CS1, 3);
-        if clock_select == 0 { return; }
-        self.steps += 1;
-        let prescaler = match clock_select {
-            0b001 => 1,
-            0b010 => 8,
-            0b011 => 64,
-            0b100 => 256,
-            0b101 => 1024,
-            _ => panic!("Unsupported clock select: {}", clock_select)
+        let prescaler = match TimerInterrupts::prescaler(mem) {
+            Some(prescaler) => prescaler,
+            None => return,
         };
+        self.steps += 1;
         if self.steps >= prescaler { // tick real timer?
             self.steps = 0;
             // only ctc mode
@@ -105,6 +133,77 @@ impl TimerInterrupts {
         }
     }
 
+    #[inline(always)]
+    fn prescaler(mem: &Memory) -> Option<u32> {
+        let clock_select = bits(mem.data(TCCR1B) as u16, CS1, 3);
+        match clock_select {
+            0 => None,
+            0b001 => Some(1),
+            0b010 => Some(8),
+            0b011 => Some(64),
+            0b100 => Some(256),
+            0b101 => Some(1024),
+            // the external clock on T1 isn't supported, the write faulted
+            _ => None,
+        }
+    }
+
+    /// the value of TCCR1B selects the external clock on T1
+    #[inline(always)]
+    pub fn is_external_clock(tccr1b: u8) -> bool {
+        bits(tccr1b as u16, CS1, 3) >= 0b110
+    }
+
+    // the steps up to the tick, which reaches OCR1A
+    #[cfg(feature = "jit")]
+    fn steps_to_match(&self, mem: &Memory, prescaler: u32) -> u64 {
+        let first = prescaler.saturating_sub(self.steps).max(1) as u64;
+        let ticks = match mem.io_reg16(OCR1A).wrapping_sub(mem.io_reg16(TCNT1)) {
+            0 => 0x10000,
+            ticks => ticks as u64,
+        };
+        first + (ticks - 1) * prescaler as u64
+    }
+
+    /// the number of steps, which don't set the flag of the compare match
+    #[cfg(feature = "jit")]
+    pub fn quiet_steps(&self, mem: &Memory) -> u64 {
+        match TimerInterrupts::prescaler(mem) {
+            Some(prescaler) => self.steps_to_match(mem, prescaler) - 1,
+            None => u64::MAX,
+        }
+    }
+
+    /// does n steps at once
+    #[cfg(feature = "jit")]
+    pub fn advance(&mut self, mem: &mut Memory, n: u64) {
+        let prescaler = match TimerInterrupts::prescaler(mem) {
+            Some(prescaler) => prescaler,
+            None => return,
+        };
+        if n >= self.steps_to_match(mem, prescaler) {
+            for _ in 0..n {
+                self.step(mem);
+            }
+            return;
+        }
+        let first = prescaler.saturating_sub(self.steps).max(1) as u64;
+        if n < first {
+            self.steps += n as u32;
+            return;
+        }
+        let ticks = 1 + (n - first) / prescaler as u64;
+        self.steps = ((n - first) % prescaler as u64) as u32;
+        let timer_val = mem.io_reg16(TCNT1).wrapping_add(ticks as u16);
+        mem.set_io_reg16(TCNT1, timer_val);
+    }
+
+    /// pending_interrupt would return Some
+    #[cfg(feature = "jit")]
+    pub fn is_pending(&self, mem: &Memory) -> bool {
+        bit(mem.data(TIMSK), OCIE1A) == 1 && bit(mem.data(TIFR), OCF1A) == 1
+    }
+
     #[inline(always)]
     pub fn pending_interrupt(&mut self, mem: &mut Memory) -> Option<usize> {
         let tifr = mem.data(TIFR);
@@ -115,3 +214,39 @@ impl TimerInterrupts {
         None
     }
 }
+
+// the jit defers the steps of the timer
+#[cfg(all(test, feature = "jit"))]
+mod tests {
+    use firmware::Firmware;
+    use memory::Memory;
+    use super::{TimerInterrupts, OCR1A, TCCR1B, TCNT1, TIFR};
+
+    #[test]
+    fn advance() {
+        // prescaler 8, 3 steps since the last tick, compare match at 5
+        let setup = || {
+            let mut mem = Memory::from_firmware(&Firmware::from_bytes(vec![0, 0]).unwrap(), None);
+            mem.set_data(TCCR1B, 0b010);
+            mem.set_io_reg16(OCR1A, 5);
+            mem.set_io_reg16(TCNT1, 1);
+            let mut timer = TimerInterrupts::new();
+            timer.set_steps(3);
+            (timer, mem)
+        };
+        let (mut timer, mut mem) = setup();
+        let (mut stepped, mut stepped_mem) = setup();
+
+        let quiet = timer.quiet_steps(&mem);
+        assert_eq!(quiet, 5 + 3 * 8 - 1);
+        timer.advance(&mut mem, quiet);
+        for _ in 0..quiet {
+            stepped.step(&mut stepped_mem);
+        }
+        assert_eq!((timer.steps(), mem.io_reg16(TCNT1)), (stepped.steps(), stepped_mem.io_reg16(TCNT1)));
+        assert_eq!(mem.data(TIFR), 0);
+        // the next step reaches OCR1A
+        timer.step(&mut mem);
+        assert_eq!((mem.data(TIFR), mem.io_reg16(TCNT1)), (0x10, 0));
+    }
+}
@@ -1,41 +1,39 @@
-use std::mem;
-use std::ptr;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU16, Ordering};
 
 pub const HIGH: u16 = 5000;
 pub const LOW: u16 = 0;
 
 pub struct Wire {
-    mv: RefCell<u16>,
-    listeners: RefCell<Vec<Box<FnMut()>>>,
+    mv: AtomicU16,
+    listeners: Mutex<Vec<Box<dyn FnMut() + Send>>>,
 }
 
 impl Wire {
     #[allow(dead_code)]
     pub fn new() -> Wire {
         Wire {
-            mv: RefCell::new(LOW),
-            listeners: RefCell::new(Vec::new()),
+            mv: AtomicU16::new(LOW),
+            listeners: Mutex::new(Vec::new()),
         }
     }
 
     #[allow(dead_code)]
-    pub fn add_listener<F>(&self, f: F) where F: FnMut() + 'static {
-        self.listeners.borrow_mut().push(Box::new(f));
+    pub fn add_listener<F>(&self, f: F) where F: FnMut() + Send + 'static {
+        self.listeners.lock().unwrap().push(Box::new(f));
     }
 
     #[inline(always)]
     pub fn set(&self, mv: u16) {
-        *self.mv.borrow_mut() = mv;
-        for listener in self.listeners.borrow_mut().iter_mut() {
+        self.mv.store(mv, Ordering::SeqCst);
+        for listener in self.listeners.lock().unwrap().iter_mut() {
             listener();
         }
     }
 
     #[inline(always)]
     pub fn mv(&self) -> u16 {
-        *self.mv.borrow()
+        self.mv.load(Ordering::SeqCst)
     }
 
     #[inline(always)]
@@ -44,34 +42,28 @@ impl Wire {
     }
 }
 
+/// the wires connected to the microcontroller,
+/// it is shared between the VM and the peripherals
 pub struct IO {
-    pub nreset: Rc<Wire>,
-    pub vcc: Rc<Wire>,
-    pub gnd: Rc<Wire>,
-    pub p: [[Rc<Wire>; 8]; 4]
+    pub nreset: Arc<Wire>,
+    pub vcc: Arc<Wire>,
+    pub gnd: Arc<Wire>,
+    pub p: [[Arc<Wire>; 8]; 4]
 }
 
 impl IO {
     #[allow(dead_code)]
     pub fn new() -> IO {
-        // see http://stackoverflow.com/a/31361031
-        let mut p: [[Rc<Wire>; 8]; 4] = unsafe { [mem::uninitialized(),
-                                              mem::uninitialized(),
-                                              mem::uninitialized(),
-                                              mem::uninitialized()] };
-        for outer in p.iter_mut() {
-            for elem in outer.iter_mut() {
-                unsafe {
-                    ptr::write(elem, Rc::new(Wire::new()));
-                }
-            }
+        fn port() -> [Arc<Wire>; 8] {
+            [Arc::new(Wire::new()), Arc::new(Wire::new()), Arc::new(Wire::new()), Arc::new(Wire::new()),
+             Arc::new(Wire::new()), Arc::new(Wire::new()), Arc::new(Wire::new()), Arc::new(Wire::new())]
         }
 
         IO {
-            nreset: Rc::new(Wire::new()),
-            vcc: Rc::new(Wire::new()),
-            gnd: Rc::new(Wire::new()),
-            p: p
+            nreset: Arc::new(Wire::new()),
+            vcc: Arc::new(Wire::new()),
+            gnd: Arc::new(Wire::new()),
+            p: [port(), port(), port(), port()]
         }
     }
 }
@@ -0,0 +1,396 @@
+// the backend for aarch64
+//
+// the calling convention is the procedure call standard of arm:
+// https://github.com/ARM-software/abi-aa/blob/main/aapcs64/aapcs64.rst
+//
+// x19 holds the cpu pointer and x20 the data memory, so the registers are at
+// [x20 + n] and SREG at [x20 + SREG]. the ip is too far into the cpu for the
+// offset of a store, x21 points to it. the flags of the host don't fit the ones
+// of the avr, the borrow is inverted and there is no half carry, so the inline
+// instructions calculate SREG from the operands in w9 and w10 and the result
+// in w11, which still has the carry or the borrow above the result.
+
+use data::{Instruction, LDType, LDMode, SetClear};
+use data::Instruction::*;
+use dynasmrt;
+use dynasmrt::{DynasmApi, DynasmLabelApi};
+use dynasmrt::aarch64::Assembler;
+use memory::SRAM_SIZE;
+use super::{Block, CodeGen, Site, ARITHMETIC, LOGIC, SRAM_START, SREG, SREG_C, SREG_Z, WORD};
+
+// dynasm for aarch64, it assembles for x86-64 by default
+macro_rules! dynasm_a64 {
+    ($ops:expr; $($t:tt)*) => {
+        dynasm!($ops ; .arch aarch64 ; $($t)*)
+    };
+}
+
+// how the result in w11 was calculated
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Op {
+    Add,
+    Sub,
+    Logic,
+}
+
+pub struct Aarch64 {
+    ops: Assembler,
+    offset: dynasmrt::AssemblyOffset,
+    interpret: *const u8,
+    // the end of the block, after a fault in the interpreter
+    exit: dynasmrt::DynamicLabel,
+}
+
+impl Aarch64 {
+    // loads the value into the register with movz and movk
+    fn load_imm(&mut self, reg: u32, value: u64) {
+        dynasm_a64!(self.ops
+                ; movz X(reg), (value & 0xffff) as u32);
+        for shift in 1..4 {
+            let part = (value >> (16 * shift)) & 0xffff;
+            if part != 0 {
+                dynasm_a64!(self.ops
+                        ; movk X(reg), part as u32, lsl 16 * shift);
+            }
+        }
+    }
+
+    // the bits of SREG in w12 for a result with the width 8 or 16
+    fn flags(&mut self, op: Op, width: u32) {
+        let top = width - 1;
+        let mask = (1u32 << width) - 1;
+        dynasm_a64!(self.ops
+                ; ubfx w14, w11, top, 1        // N
+                ; tst w11, mask
+                ; cset w13, eq                 // Z
+                ; lsl w12, w13, 1
+                ; orr w12, w12, w14, lsl 2);
+        if op == Op::Logic {
+            // V is 0 and S = N
+            dynasm_a64!(self.ops
+                    ; orr w12, w12, w14, lsl 4);
+            return;
+        }
+        dynasm_a64!(self.ops
+                ; ubfx w13, w11, width, 1      // C
+                ; orr w12, w12, w13
+                ; eor w13, w9, w10
+                ; eor w13, w13, w11
+                ; ubfx w13, w13, 4, 1          // H
+                ; orr w12, w12, w13, lsl 5);
+        match op {
+            Op::Add => dynasm_a64!(self.ops
+                               ; eor w13, w9, w11
+                               ; eor w15, w10, w11),
+            _ => dynasm_a64!(self.ops
+                         ; eor w13, w9, w10
+                         ; eor w15, w9, w11),
+        }
+        dynasm_a64!(self.ops
+                ; and w13, w13, w15
+                ; ubfx w13, w13, top, 1        // V
+                ; orr w12, w12, w13, lsl 3
+                ; eor w14, w14, w13
+                ; orr w12, w12, w14, lsl 4);   // S = N ^ V
+    }
+
+    // copies the bits of w12 in the mask into SREG
+    fn sreg(&mut self, mask: u8) {
+        dynasm_a64!(self.ops
+                ; movz w13, mask as u32
+                ; and w12, w12, w13
+                ; ldrb w13, [x20, SREG]
+                ; movz w15, mask as u32
+                ; bic w13, w13, w15
+                ; orr w13, w13, w12
+                ; strb w13, [x20, SREG]);
+    }
+
+    // sbc, sbci and cpc only keep Z, if the result is zero
+    fn sreg_keep_z(&mut self, mask: u8) {
+        dynasm_a64!(self.ops
+                ; ldrb w13, [x20, SREG]
+                ; movz w15, !SREG_Z as u32
+                ; orr w13, w13, w15
+                ; and w12, w12, w13);
+        self.sreg(mask);
+    }
+
+    // the carry of SREG in w13
+    fn load_carry(&mut self) {
+        dynasm_a64!(self.ops
+                ; ldrb w13, [x20, SREG]
+                ; and w13, w13, SREG_C as u32);
+    }
+
+    // the byte instructions with the operands in w9 and w10, the compares don't
+    // store the result into rd
+    fn alu(&mut self, instr: Instruction, rd: u8) {
+        match instr {
+            ADD(..) => dynasm_a64!(self.ops; add w11, w9, w10),
+            ADC(..) => {
+                self.load_carry();
+                dynasm_a64!(self.ops; add w11, w9, w10; add w11, w11, w13)
+            }
+            SUB(..) | SUBI(..) | CP(..) | CPI(..) => dynasm_a64!(self.ops; sub w11, w9, w10),
+            SBC(..) | SBCI(..) | CPC(..) => {
+                self.load_carry();
+                dynasm_a64!(self.ops; sub w11, w9, w10; sub w11, w11, w13)
+            }
+            AND(..) | ANDI(..) => dynasm_a64!(self.ops; and w11, w9, w10),
+            OR(..) | ORI(..) => dynasm_a64!(self.ops; orr w11, w9, w10),
+            _ => dynasm_a64!(self.ops; eor w11, w9, w10),
+        }
+        match instr {
+            CP(..) | CPC(..) | CPI(..) => {}
+            _ => dynasm_a64!(self.ops; strb w11, [x20, rd as u32]),
+        }
+        match instr {
+            ADD(..) | ADC(..) => {
+                self.flags(Op::Add, 8);
+                self.sreg(ARITHMETIC);
+            }
+            SUB(..) | SUBI(..) | CP(..) | CPI(..) => {
+                self.flags(Op::Sub, 8);
+                self.sreg(ARITHMETIC);
+            }
+            SBC(..) | SBCI(..) | CPC(..) => {
+                self.flags(Op::Sub, 8);
+                self.sreg_keep_z(ARITHMETIC);
+            }
+            _ => {
+                self.flags(Op::Logic, 8);
+                self.sreg(LOGIC);
+            }
+        }
+    }
+}
+
+impl CodeGen for Aarch64 {
+    fn new(ip_offset: i32, interpret: *const u8) -> Aarch64 {
+        let mut ops = Assembler::new().expect("new Assembler");
+        let offset = ops.offset();
+        dynasm_a64!(ops
+                ; stp x29, x30, [sp, -48]!   // the frame and the return address
+                ; stp x19, x20, [sp, 16]     // x19, x20 and x21 are callee-save
+                ; str x21, [sp, 32]
+                ; mov x29, sp
+                ; mov x19, x0                // save cpu pointer in x19
+                ; mov x20, x1);              // and the data memory in x20
+        let exit = ops.new_dynamic_label();
+        let mut gen = Aarch64 { ops: ops, offset: offset, interpret: interpret, exit: exit };
+        gen.load_imm(21, ip_offset as u64);
+        dynasm_a64!(gen.ops
+                ; add x21, x19, x21);
+        gen
+    }
+
+    fn store_ip(&mut self, ip: usize) {
+        self.load_imm(9, ip as u64);
+        dynasm_a64!(self.ops
+                ; str x9, [x21]);
+    }
+
+    fn interpret(&mut self) {
+        self.load_imm(16, self.interpret as u64);
+        dynasm_a64!(self.ops
+                ; mov x0, x19
+                ; blr x16
+                ; tst w0, 0xff
+                ; b.ne =>self.exit);
+    }
+
+    fn inline(&mut self, instr: Instruction, site: Site) -> Option<bool> {
+        match instr {
+            ADD(rd, rr) | ADC(rd, rr) | SUB(rd, rr) | SBC(rd, rr) | AND(rd, rr) | OR(rd, rr) | EOR(rd, rr)
+                | CP(rd, rr) | CPC(rd, rr) => {
+                dynasm_a64!(self.ops
+                        ; ldrb w9, [x20, rd as u32]
+                        ; ldrb w10, [x20, rr as u32]);
+                self.alu(instr, rd);
+            }
+            SUBI(reg, k) | SBCI(reg, k) | ANDI(reg, k) | ORI(reg, k) | CPI(reg, k) => {
+                dynasm_a64!(self.ops
+                        ; ldrb w9, [x20, reg as u32]
+                        ; movz w10, k as u32);
+                self.alu(instr, reg);
+            }
+            COM(reg) => {
+                dynasm_a64!(self.ops
+                        ; ldrb w9, [x20, reg as u32]
+                        ; mvn w11, w9
+                        ; and w11, w11, 0xff
+                        ; strb w11, [x20, reg as u32]);
+                self.flags(Op::Logic, 8);
+                dynasm_a64!(self.ops
+                        ; orr w12, w12, SREG_C as u32);
+                self.sreg(LOGIC | SREG_C);
+            }
+            DEC(reg) => {
+                // the flags of a subtraction, but C stays
+                dynasm_a64!(self.ops
+                        ; ldrb w9, [x20, reg as u32]
+                        ; movz w10, 1
+                        ; sub w11, w9, w10
+                        ; strb w11, [x20, reg as u32]);
+                self.flags(Op::Sub, 8);
+                self.sreg(LOGIC);
+            }
+            ADIW(reg, k) | SBIW(reg, k) => {
+                dynasm_a64!(self.ops
+                        ; ldrh w9, [x20, reg as u32]
+                        ; movz w10, k as u32);
+                match instr {
+                    ADIW(..) => dynasm_a64!(self.ops; add w11, w9, w10),
+                    _ => dynasm_a64!(self.ops; sub w11, w9, w10),
+                }
+                dynasm_a64!(self.ops
+                        ; strh w11, [x20, reg as u32]);
+                self.flags(if let ADIW(..) = instr { Op::Add } else { Op::Sub }, 16);
+                self.sreg(WORD);
+            }
+            MOV(rd, rr) => {
+                dynasm_a64!(self.ops
+                        ; ldrb w9, [x20, rr as u32]
+                        ; strb w9, [x20, rd as u32]);
+            }
+            MOVW(rd, rr) => {
+                dynasm_a64!(self.ops
+                        ; ldrh w9, [x20, rr as u32]
+                        ; strh w9, [x20, rd as u32]);
+            }
+            LDI(reg, val) => {
+                dynasm_a64!(self.ops
+                        ; movz w9, val as u32
+                        ; strb w9, [x20, reg as u32]);
+            }
+            BSET(s) | BCLR(s) => {
+                dynasm_a64!(self.ops
+                        ; ldrb w9, [x20, SREG]
+                        ; movz w10, 1 << s);
+                match instr {
+                    BSET(..) => dynasm_a64!(self.ops; orr w9, w9, w10),
+                    _ => dynasm_a64!(self.ops; bic w9, w9, w10),
+                }
+                dynasm_a64!(self.ops
+                        ; strb w9, [x20, SREG]);
+            }
+            // the sram has no side effects, the io registers and the watchpoints,
+            // the trace and the uninitialized reads of single steps need the interpreter
+            LD_STS(typ, reg, k) if !site.single && k as usize >= SRAM_START && (k as usize) < SRAM_SIZE => {
+                match typ {
+                    LDType::LD => dynasm_a64!(self.ops
+                                          ; ldrb w9, [x20, k as u32]
+                                          ; strb w9, [x20, reg as u32]),
+                    LDType::ST => dynasm_a64!(self.ops
+                                          ; ldrb w9, [x20, reg as u32]
+                                          ; strb w9, [x20, k as u32]),
+                }
+            }
+            LD_ST(typ, reg, addrreg, mode) if !site.single => {
+                dynasm_a64!(self.ops
+                        ; ldrh w9, [x20, addrreg as u32]);
+                match mode {
+                    LDMode::PreDecrement => dynasm_a64!(self.ops
+                                                    ; sub w9, w9, 1
+                                                    ; and w9, w9, 0xffff
+                                                    ; strh w9, [x20, addrreg as u32]),
+                    LDMode::Displacement(d) => dynasm_a64!(self.ops
+                                                       ; add w9, w9, d as u32
+                                                       ; and w9, w9, 0xffff),
+                    LDMode::PostIncrement => {}
+                }
+                // anything outside of the sram is interpreted, which does
+                // everything again
+                dynasm_a64!(self.ops
+                        ; cmp w9, SRAM_START as u32
+                        ; b.lo >slow
+                        ; cmp w9, SRAM_SIZE as u32
+                        ; b.hs >slow);
+                match typ {
+                    LDType::LD => dynasm_a64!(self.ops
+                                          ; ldrb w10, [x20, x9]
+                                          ; strb w10, [x20, reg as u32]),
+                    LDType::ST => dynasm_a64!(self.ops
+                                          ; ldrb w10, [x20, reg as u32]
+                                          ; strb w10, [x20, x9]),
+                }
+                if let LDMode::PostIncrement = mode {
+                    dynasm_a64!(self.ops
+                            ; ldrh w9, [x20, addrreg as u32]
+                            ; add w9, w9, 1
+                            ; strh w9, [x20, addrreg as u32]);
+                }
+                dynasm_a64!(self.ops
+                        ; b >done
+                        ; slow:);
+                if let LDMode::PreDecrement = mode {
+                    // undo the decrement for the interpreter
+                    dynasm_a64!(self.ops
+                            ; ldrh w9, [x20, addrreg as u32]
+                            ; add w9, w9, 1
+                            ; strh w9, [x20, addrreg as u32]);
+                }
+                // the interpreter incremented the ip and the inline path leaves it
+                // to the next store, which writes the same value
+                self.interpret();
+                dynasm_a64!(self.ops
+                        ; done:);
+            }
+            BRBC_S(sc, sreg, rel) => {
+                self.store_ip(site.next);
+                dynasm_a64!(self.ops
+                        ; ldrb w9, [x20, SREG]);
+                match sc {
+                    SetClear::Set => dynasm_a64!(self.ops; tbz w9, sreg as u32, >not_taken),
+                    SetClear::Clear => dynasm_a64!(self.ops; tbnz w9, sreg as u32, >not_taken),
+                }
+                self.store_ip((site.addr as isize + rel as isize) as usize);
+                dynasm_a64!(self.ops
+                        ; not_taken:);
+                return Some(true);
+            }
+            CPSE(rd, rr) | SBR(_, rd, rr) => {
+                self.store_ip(site.next);
+                dynasm_a64!(self.ops
+                        ; ldrb w10, [x20, rd as u32]);
+                match instr {
+                    CPSE(..) => dynasm_a64!(self.ops
+                                        ; ldrb w11, [x20, rr as u32]
+                                        ; cmp w10, w11
+                                        ; b.ne >not_skipped),
+                    // rr is the bit here
+                    SBR(SetClear::Set, ..) => dynasm_a64!(self.ops
+                                                      ; tbz w10, rr as u32, >not_skipped),
+                    _ => dynasm_a64!(self.ops
+                                 ; tbnz w10, rr as u32, >not_skipped),
+                }
+                self.store_ip(site.skip);
+                dynasm_a64!(self.ops
+                        ; not_skipped:);
+                return Some(true);
+            }
+            RJMP(diff) => {
+                self.store_ip((site.addr as isize + diff as isize) as usize);
+                return Some(true);
+            }
+            JMP(ip) => {
+                self.store_ip(ip as usize);
+                return Some(true);
+            }
+            _ => return None,
+        }
+        Some(false)
+    }
+
+    fn finish(mut self, len: u64, io: bool) -> Block {
+        dynasm_a64!(self.ops
+                ; =>self.exit
+                ; ldr x21, [sp, 32]
+                ; ldp x19, x20, [sp, 16]
+                ; ldp x29, x30, [sp], 48
+                ; ret);
+        let buf = self.ops.finalize().unwrap();
+        Block { buf: buf, offset: self.offset, len: len, io: io }
+    }
+}
@@ -0,0 +1,129 @@
+//! the code generators of the jit
+//!
+//! Cpu::generate walks the instructions of a block and a backend emits
+//! them. the common instructions are emitted inline, the others call the
+//! interpreter. every backend is compiled on every host, so the code generators
+//! are checked everywhere, but only the one of the host is run.
+
+use cpu::Cpu;
+use data::Instruction;
+use dynasmrt::{AssemblyOffset, ExecutableBuffer};
+use std::collections::BTreeSet;
+use std::mem;
+
+mod aarch64;
+mod x64;
+
+pub use self::aarch64::Aarch64;
+pub use self::x64::X64;
+
+// defines a helper, which the blocks call. the blocks use the c calling
+// convention of the host, but on x86-64 always the one of system v, also on windows
+macro_rules! jit_helper {
+    (fn $name:ident($arg:ident: $typ:ty) -> $ret:ty $body:block) => {
+        #[cfg(target_arch = "x86_64")]
+        extern "sysv64" fn $name($arg: $typ) -> $ret $body
+        #[cfg(not(target_arch = "x86_64"))]
+        extern "C" fn $name($arg: $typ) -> $ret $body
+    };
+}
+
+// the cpu and the data memory
+#[cfg(target_arch = "x86_64")]
+type BlockFn = extern "sysv64" fn(*mut Cpu, *mut u8);
+#[cfg(not(target_arch = "x86_64"))]
+type BlockFn = extern "C" fn(*mut Cpu, *mut u8);
+
+// the address of SREG in the data memory
+const SREG: u32 = 0x5f;
+// the first address, which has no side effects
+pub const SRAM_START: usize = 0x60;
+// the bits of SREG, which the instructions change
+const ARITHMETIC: u8 = 0x3f; // H, S, V, N, Z and C
+const LOGIC: u8 = 0x1e; // S, V, N and Z
+const WORD: u8 = 0x1f; // S, V, N, Z and C
+const SREG_Z: u8 = 1 << 1;
+const SREG_C: u8 = 1 << 0;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Backend {
+    X64,
+    Aarch64,
+}
+
+impl Backend {
+    /// the backend for the architecture of the host, None if there is none and
+    /// the interpreter runs the firmware
+    pub fn host() -> Option<Backend> {
+        if cfg!(target_arch = "x86_64") {
+            Some(Backend::X64)
+        } else if cfg!(target_arch = "aarch64") {
+            Some(Backend::Aarch64)
+        } else {
+            None
+        }
+    }
+}
+
+// a compiled block of the jit. we can't just save the function pointer, because
+// then we would free the buffer and segfault, when we try to execute the function
+pub struct Block {
+    buf: ExecutableBuffer,
+    offset: AssemblyOffset,
+    // the number of instructions, each takes one cycle
+    pub len: u64,
+    // reads or writes the io registers or the semihosting registers
+    pub io: bool,
+}
+
+impl Block {
+    pub fn func(&self) -> BlockFn {
+        unsafe { mem::transmute(self.buf.ptr(self.offset)) }
+    }
+}
+
+// where a block starts and where it must end
+pub struct BlockSpec<'a> {
+    // the word address of the first instruction
+    pub addr: usize,
+    // the breakpoints, at which the block ends before the instruction
+    pub stops: &'a BTreeSet<usize>,
+    // the word address of the halt condition, the block ends before it
+    pub halt: Option<usize>,
+    // the block executes only the first instruction
+    pub single: bool,
+    // the byte offset of the ip in the cpu
+    pub ip_offset: i32,
+}
+
+// the word addresses around an instruction
+#[derive(Debug, Copy, Clone)]
+pub struct Site {
+    pub addr: usize,
+    // the instruction after it
+    pub next: usize,
+    // the instruction after the next one, which a skip jumps to
+    pub skip: usize,
+    // the block executes a single instruction, the memory accesses need the interpreter
+    pub single: bool,
+}
+
+// a code generator, which holds the cpu and the data memory in two registers
+pub trait CodeGen: Sized {
+    // starts a block. the ip is at ip_offset in the cpu and interpret executes
+    // the instruction at the ip, it returns a non-zero byte after a fault
+    fn new(ip_offset: i32, interpret: *const u8) -> Self;
+
+    // stores the ip, which the inline instructions before didn't update
+    fn store_ip(&mut self, ip: usize);
+
+    // calls interpret with the cpu, the block ends there after a fault
+    fn interpret(&mut self);
+
+    // emits the instruction inline, None if it must be interpreted. returns
+    // whether the code updated the ip
+    fn inline(&mut self, instr: Instruction, site: Site) -> Option<bool>;
+
+    fn finish(self, len: u64, io: bool) -> Block;
+}
+
@@ -0,0 +1,335 @@
+// the backend for x86-64
+//
+// we use standard C calling convention, which is documented here:
+// https://en.wikipedia.org/wiki/X86_calling_conventions#System_V_AMD64_ABI
+// this is the standard calling x64 convention on linux
+//
+// r12 holds the cpu pointer and r13 the data memory, so the registers are at
+// [r13 + n] and SREG at [r13 + SREG]. the inline instructions take their flags
+// from the flags of the host.
+
+use data::{Instruction, LDType, LDMode, SetClear};
+use data::Instruction::*;
+use dynasmrt;
+use dynasmrt::{DynasmApi, DynasmLabelApi};
+use dynasmrt::x64::Assembler;
+use memory::SRAM_SIZE;
+use super::{Block, CodeGen, Site, ARITHMETIC, LOGIC, SRAM_START, SREG, SREG_C, SREG_Z, WORD};
+
+pub struct X64 {
+    ops: Assembler,
+    offset: dynasmrt::AssemblyOffset,
+    ip_offset: i32,
+    interpret: *const u8,
+    // the end of the block, after a fault in the interpreter
+    exit: dynasmrt::DynamicLabel,
+}
+
+impl X64 {
+    // copies the flags of the host after an instruction into the bits of SREG
+    // in the mask, S is calculated from N and V.
+    // lahf loads SF, ZF, AF and CF into ah at bit 7, 6, 4 and 0, seto gives OF
+    fn sreg(&mut self, mask: u8) {
+        dynasm!(self.ops
+                ; lahf
+                ; seto cl
+                ; mov dl, ah
+                ; and dl, 1               // C
+                ; mov al, ah
+                ; shr al, 5
+                ; and al, 2               // Z
+                ; or dl, al
+                ; mov al, ah
+                ; shr al, 5
+                ; and al, 4               // N
+                ; or dl, al
+                ; mov al, cl
+                ; shl al, 3               // V
+                ; or dl, al
+                ; mov al, ah
+                ; shl al, 1
+                ; and al, 0x20            // H
+                ; or dl, al
+                ; mov al, ah
+                ; shr al, 7
+                ; xor al, cl
+                ; shl al, 4               // S = N ^ V
+                ; or dl, al
+                ; and dl, mask as i8
+                ; mov al, BYTE [r13 + SREG as i32]
+                ; and al, !mask as i8
+                ; or al, dl
+                ; mov BYTE [r13 + SREG as i32], al);
+    }
+
+    // sbc, sbci and cpc only keep Z, if the result is zero
+    fn sreg_keep_z(&mut self, mask: u8) {
+        dynasm!(self.ops
+                ; mov r8b, BYTE [r13 + SREG as i32]);
+        self.sreg(mask);
+        dynasm!(self.ops
+                ; or r8b, !SREG_Z as i8
+                ; and BYTE [r13 + SREG as i32], r8b);
+    }
+
+    // the carry of SREG into CF
+    fn load_carry(&mut self) {
+        dynasm!(self.ops
+                ; movzx ecx, BYTE [r13 + SREG as i32]
+                ; bt ecx, 0);
+    }
+}
+
+impl CodeGen for X64 {
+    fn new(ip_offset: i32, interpret: *const u8) -> X64 {
+        let mut ops = Assembler::new().expect("new Assembler");
+        let offset = ops.offset();
+        dynasm!(ops
+                ; push r12       // r12 and r13 are callee-save
+                ; push r13
+                ; sub rsp, 8     // keeps the stack aligned to 16 bytes for the calls
+                ; mov r12, rdi   // save cpu pointer in r12
+                ; mov r13, rsi); // and the data memory in r13
+        let exit = ops.new_dynamic_label();
+        X64 { ops: ops, offset: offset, ip_offset: ip_offset, interpret: interpret, exit: exit }
+    }
+
+    fn store_ip(&mut self, ip: usize) {
+        // sign extended, a jump before the flash stays out of range
+        dynasm!(self.ops
+                ; mov QWORD [r12 + self.ip_offset], ip as i32);
+    }
+
+    fn interpret(&mut self) {
+        dynasm!(self.ops
+                ; mov rdi, r12
+                ; mov rax, QWORD self.interpret as _
+                ; call rax
+                ; test al, al
+                ; jnz =>self.exit);
+    }
+
+    fn inline(&mut self, instr: Instruction, site: Site) -> Option<bool> {
+        match instr {
+            ADD(rd, rr) | ADC(rd, rr) | SUB(rd, rr) | SBC(rd, rr) | AND(rd, rr) | OR(rd, rr) | EOR(rd, rr) => {
+                dynasm!(self.ops
+                        ; mov al, BYTE [r13 + rd as i32]);
+                match instr {
+                    ADD(..) => dynasm!(self.ops; add al, BYTE [r13 + rr as i32]),
+                    ADC(..) => {
+                        self.load_carry();
+                        dynasm!(self.ops; adc al, BYTE [r13 + rr as i32])
+                    }
+                    SUB(..) => dynasm!(self.ops; sub al, BYTE [r13 + rr as i32]),
+                    SBC(..) => {
+                        self.load_carry();
+                        dynasm!(self.ops; sbb al, BYTE [r13 + rr as i32])
+                    }
+                    AND(..) => dynasm!(self.ops; and al, BYTE [r13 + rr as i32]),
+                    OR(..) => dynasm!(self.ops; or al, BYTE [r13 + rr as i32]),
+                    _ => dynasm!(self.ops; xor al, BYTE [r13 + rr as i32]),
+                }
+                dynasm!(self.ops
+                        ; mov BYTE [r13 + rd as i32], al);
+                match instr {
+                    ADD(..) | ADC(..) | SUB(..) => self.sreg(ARITHMETIC),
+                    SBC(..) => self.sreg_keep_z(ARITHMETIC),
+                    _ => self.sreg(LOGIC),
+                }
+            }
+            SUBI(reg, k) | SBCI(reg, k) | ANDI(reg, k) | ORI(reg, k) => {
+                dynasm!(self.ops
+                        ; mov al, BYTE [r13 + reg as i32]);
+                match instr {
+                    SUBI(..) => dynasm!(self.ops; sub al, k as i8),
+                    SBCI(..) => {
+                        self.load_carry();
+                        dynasm!(self.ops; sbb al, k as i8)
+                    }
+                    ANDI(..) => dynasm!(self.ops; and al, k as i8),
+                    _ => dynasm!(self.ops; or al, k as i8),
+                }
+                dynasm!(self.ops
+                        ; mov BYTE [r13 + reg as i32], al);
+                match instr {
+                    SUBI(..) => self.sreg(ARITHMETIC),
+                    SBCI(..) => self.sreg_keep_z(ARITHMETIC),
+                    _ => self.sreg(LOGIC),
+                }
+            }
+            CP(rd, rr) => {
+                dynasm!(self.ops
+                        ; mov al, BYTE [r13 + rd as i32]
+                        ; cmp al, BYTE [r13 + rr as i32]);
+                self.sreg(ARITHMETIC);
+            }
+            CPC(rd, rr) => {
+                dynasm!(self.ops
+                        ; mov dl, BYTE [r13 + rd as i32]);
+                self.load_carry();
+                dynasm!(self.ops
+                        ; sbb dl, BYTE [r13 + rr as i32]);
+                self.sreg_keep_z(ARITHMETIC);
+            }
+            CPI(reg, k) => {
+                dynasm!(self.ops
+                        ; cmp BYTE [r13 + reg as i32], k as i8);
+                self.sreg(ARITHMETIC);
+            }
+            COM(reg) => {
+                // xor sets CF and OF to 0, but com sets C
+                dynasm!(self.ops
+                        ; xor BYTE [r13 + reg as i32], -1);
+                self.sreg(LOGIC);
+                dynasm!(self.ops
+                        ; or BYTE [r13 + SREG as i32], SREG_C as i8);
+            }
+            DEC(reg) => {
+                // dec doesn't change CF like the one of the avr
+                dynasm!(self.ops
+                        ; dec BYTE [r13 + reg as i32]);
+                self.sreg(LOGIC);
+            }
+            ADIW(reg, k) | SBIW(reg, k) => {
+                dynasm!(self.ops
+                        ; mov ax, WORD [r13 + reg as i32]);
+                match instr {
+                    ADIW(..) => dynasm!(self.ops; add ax, k as i16),
+                    _ => dynasm!(self.ops; sub ax, k as i16),
+                }
+                dynasm!(self.ops
+                        ; mov WORD [r13 + reg as i32], ax);
+                self.sreg(WORD);
+            }
+            MOV(rd, rr) => {
+                dynasm!(self.ops
+                        ; mov al, BYTE [r13 + rr as i32]
+                        ; mov BYTE [r13 + rd as i32], al);
+            }
+            MOVW(rd, rr) => {
+                dynasm!(self.ops
+                        ; mov ax, WORD [r13 + rr as i32]
+                        ; mov WORD [r13 + rd as i32], ax);
+            }
+            LDI(reg, val) => {
+                dynasm!(self.ops
+                        ; mov BYTE [r13 + reg as i32], val as i8);
+            }
+            BSET(s) => {
+                dynasm!(self.ops
+                        ; or BYTE [r13 + SREG as i32], (1u8 << s) as i8);
+            }
+            BCLR(s) => {
+                dynasm!(self.ops
+                        ; and BYTE [r13 + SREG as i32], !(1u8 << s) as i8);
+            }
+            // the sram has no side effects, the io registers and the watchpoints,
+            // the trace and the uninitialized reads of single steps need the interpreter
+            LD_STS(typ, reg, k) if !site.single && k as usize >= SRAM_START && (k as usize) < SRAM_SIZE => {
+                match typ {
+                    LDType::LD => dynasm!(self.ops
+                                          ; mov al, BYTE [r13 + k as i32]
+                                          ; mov BYTE [r13 + reg as i32], al),
+                    LDType::ST => dynasm!(self.ops
+                                          ; mov al, BYTE [r13 + reg as i32]
+                                          ; mov BYTE [r13 + k as i32], al),
+                }
+            }
+            LD_ST(typ, reg, addrreg, mode) if !site.single => {
+                dynasm!(self.ops
+                        ; movzx eax, WORD [r13 + addrreg as i32]);
+                match mode {
+                    LDMode::PreDecrement => dynasm!(self.ops; sub ax, 1; mov WORD [r13 + addrreg as i32], ax),
+                    LDMode::Displacement(d) => dynasm!(self.ops; add ax, d as i16),
+                    LDMode::PostIncrement => {}
+                }
+                // anything outside of the sram is interpreted, which does
+                // everything again
+                dynasm!(self.ops
+                        ; cmp ax, SRAM_START as i16
+                        ; jb >slow
+                        ; cmp ax, SRAM_SIZE as i16
+                        ; jae >slow);
+                match typ {
+                    LDType::LD => dynasm!(self.ops
+                                          ; mov cl, BYTE [r13 + rax]
+                                          ; mov BYTE [r13 + reg as i32], cl),
+                    LDType::ST => dynasm!(self.ops
+                                          ; mov cl, BYTE [r13 + reg as i32]
+                                          ; mov BYTE [r13 + rax], cl),
+                }
+                if let LDMode::PostIncrement = mode {
+                    dynasm!(self.ops
+                            ; add WORD [r13 + addrreg as i32], 1);
+                }
+                dynasm!(self.ops
+                        ; jmp >done
+                        ; slow:);
+                if let LDMode::PreDecrement = mode {
+                    // undo the decrement for the interpreter
+                    dynasm!(self.ops
+                            ; add WORD [r13 + addrreg as i32], 1);
+                }
+                // the interpreter incremented the ip and the inline path leaves it
+                // to the next store, which writes the same value
+                self.interpret();
+                dynasm!(self.ops
+                        ; done:);
+            }
+            BRBC_S(sc, sreg, rel) => {
+                self.store_ip(site.next);
+                dynasm!(self.ops
+                        ; test BYTE [r13 + SREG as i32], (1u8 << sreg) as i8);
+                match sc {
+                    SetClear::Set => dynasm!(self.ops; jz >not_taken),
+                    SetClear::Clear => dynasm!(self.ops; jnz >not_taken),
+                }
+                self.store_ip((site.addr as isize + rel as isize) as usize);
+                dynasm!(self.ops
+                        ; not_taken:);
+                return Some(true);
+            }
+            CPSE(rd, rr) | SBR(_, rd, rr) => {
+                self.store_ip(site.next);
+                match instr {
+                    CPSE(..) => dynasm!(self.ops
+                                        ; mov al, BYTE [r13 + rd as i32]
+                                        ; cmp al, BYTE [r13 + rr as i32]
+                                        ; jne >not_skipped),
+                    // rr is the bit here
+                    SBR(SetClear::Set, ..) => dynasm!(self.ops
+                                                      ; test BYTE [r13 + rd as i32], (1u8 << rr) as i8
+                                                      ; jz >not_skipped),
+                    _ => dynasm!(self.ops
+                                 ; test BYTE [r13 + rd as i32], (1u8 << rr) as i8
+                                 ; jnz >not_skipped),
+                }
+                self.store_ip(site.skip);
+                dynasm!(self.ops
+                        ; not_skipped:);
+                return Some(true);
+            }
+            RJMP(diff) => {
+                self.store_ip((site.addr as isize + diff as isize) as usize);
+                return Some(true);
+            }
+            JMP(ip) => {
+                self.store_ip(ip as usize);
+                return Some(true);
+            }
+            _ => return None,
+        }
+        Some(false)
+    }
+
+    fn finish(mut self, len: u64, io: bool) -> Block {
+        dynasm!(self.ops
+                ; =>self.exit
+                ; add rsp, 8
+                ; pop r13
+                ; pop r12
+                ; ret);
+        let buf = self.ops.finalize().unwrap();
+        Block { buf: buf, offset: self.offset, len: len, io: io }
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::prelude::*;
+use data::Instruction;
+use data::Instruction::*;
+use firmware::Symbols;
+
+// the number of critical sections in the report
+const MAX_SECTIONS: usize = 10;
+
+/// the names of the interrupt vectors of the atmega32
+pub const VECTOR_NAMES: [&str; 21] = [
+    "RESET", "INT0", "INT1", "INT2", "TIMER2_COMP", "TIMER2_OVF", "TIMER1_CAPT", "TIMER1_COMPA",
+    "TIMER1_COMPB", "TIMER1_OVF", "TIMER0_COMP", "TIMER0_OVF", "SPI_STC", "USART_RXC", "USART_UDRE",
+    "USART_TXC", "ADC", "EE_RDY", "ANA_COMP", "TWI", "SPM_RDY",
+];
+
+/// the minimum, maximum and sum of some cycles
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct Timing {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub total: u64,
+}
+
+impl Timing {
+    fn add(&mut self, cycles: u64) {
+        self.min = if self.count == 0 { cycles } else { self.min.min(cycles) };
+        self.max = self.max.max(cycles);
+        self.total += cycles;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.total as f64 / self.count as f64 }
+    }
+}
+
+/// the latency from the flag to the first instruction of the handler and the
+/// duration of the handler up to its reti
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct VectorStats {
+    pub latency: Timing,
+    pub duration: Timing,
+}
+
+/// a stretch of cycles with the I flag cleared
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct CriticalSection {
+    // the cycle of the instruction, which cleared the flag
+    pub start: u64,
+    pub cycles: u64,
+    // byte address of the instruction or the vector
+    pub pc: usize,
+    // cleared by the entry of an interrupt handler
+    pub isr: bool,
+}
+
+/// the latency and the duration of the interrupt handlers per vector and the
+/// longest critical sections, measured in cycles
+#[derive(Debug, Clone, Default)]
+pub struct InterruptStats {
+    // the last cycle
+    cycles: u64,
+    // the interrupt flags of the last cycle, see interrupt_flags
+    flags: u32,
+    // the cycle, in which the flag of the vector was set
+    raised: [Option<u64>; 32],
+    // the handlers, which were entered and didn't return yet, with the cycle of the entry
+    active: Vec<(usize, u64)>,
+    vectors: BTreeMap<usize, VectorStats>,
+    // the section, which wasn't ended yet
+    disabled: Option<CriticalSection>,
+    // the longest ones first
+    sections: Vec<CriticalSection>,
+}
+
+impl InterruptStats {
+    pub fn new() -> InterruptStats {
+        InterruptStats::default()
+    }
+
+    /// records the interrupt flags after the peripherals set them in the cycle
+    pub fn update_flags(&mut self, flags: u32, cycles: u64) {
+        let set = flags & !self.flags;
+        for (vector, raised) in self.raised.iter_mut().enumerate() {
+            if set & (1 << vector) != 0 {
+                *raised = Some(cycles);
+            }
+        }
+        self.flags = flags;
+    }
+
+    /// records a step of the cpu, which executed instr at the byte address pc,
+    /// entered is the vector, if an interrupt jumped to pc before, enabled the
+    /// I flag after the step
+    pub fn record(&mut self, cycles: u64, entered: Option<usize>, pc: usize, instr: Option<Instruction>,
+                  enabled: bool) {
+        self.cycles = cycles;
+        if let Some(vector) = entered {
+            let latency = self.raised.get_mut(vector).and_then(|r| r.take()).map(|r| cycles - r).unwrap_or(0);
+            self.vectors.entry(vector).or_default().latency.add(latency);
+            self.active.push((vector, cycles));
+        }
+        if let (Some(RETI), Some((vector, start))) = (instr, self.active.last().cloned()) {
+            self.active.pop();
+            self.vectors.entry(vector).or_default().duration.add(cycles - start + 1);
+        }
+        match self.disabled {
+            None if !enabled => {
+                let isr = entered.is_some();
+                self.disabled = Some(CriticalSection { start: cycles, cycles: 0, pc: pc, isr: isr });
+            }
+            Some(section) if enabled => {
+                self.disabled = None;
+                self.add_section(CriticalSection { cycles: cycles - section.start, ..section });
+            }
+            _ => {}
+        }
+    }
+
+    fn add_section(&mut self, section: CriticalSection) {
+        let i = self.sections.partition_point(|s| s.cycles >= section.cycles);
+        if i < MAX_SECTIONS {
+            self.sections.insert(i, section);
+            self.sections.truncate(MAX_SECTIONS);
+        }
+    }
+
+    /// the statistics of the vectors, which were entered
+    pub fn vectors(&self) -> &BTreeMap<usize, VectorStats> {
+        &self.vectors
+    }
+
+    /// the longest stretches with the I flag cleared, the longest first,
+    /// including the one, which lasts up to now
+    pub fn critical_sections(&self) -> Vec<CriticalSection> {
+        let mut stats = self.clone();
+        if let Some(section) = self.disabled {
+            stats.add_section(CriticalSection { cycles: self.cycles - section.start, ..section });
+        }
+        stats.sections
+    }
+
+    /// a table of the vectors and the longest critical sections
+    pub fn write_report<W: Write + ?Sized>(&self, symbols: &Symbols, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{:>6} {:<13} {:>8} {:>26} {:>26}", "vector", "name", "count",
+                 "latency min/mean/max", "duration min/mean/max")?;
+        for (&vector, stats) in &self.vectors {
+            let name = VECTOR_NAMES.get(vector).cloned().unwrap_or("?");
+            let timing = |t: &Timing| format!("{} / {:.1} / {}", t.min, t.mean(), t.max);
+            writeln!(out, "{:>6} {:<13} {:>8} {:>26} {:>26}", vector, name, stats.latency.count,
+                     timing(&stats.latency), timing(&stats.duration))?;
+        }
+        writeln!(out)?;
+        writeln!(out, "longest stretches with interrupts disabled:")?;
+        writeln!(out, "{:>10} {:>12}  cleared at", "cycles", "start")?;
+        for section in self.critical_sections() {
+            let func = symbols.function_at(section.pc as u32)
+                .map(|f| format!(" <{}+{}>", f.name, section.pc as u32 - f.value))
+                .unwrap_or_default();
+            let open = if Some(section.start) == self.disabled.map(|s| s.start) { " (still disabled)" } else { "" };
+            writeln!(out, "{:>10} {:>12}  {:#06x}{}{}{}", section.cycles, section.start, section.pc, func,
+                     if section.isr { " (interrupt)" } else { "" }, open)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::Cpu;
+    use firmware::{Firmware, Symbols};
+    use memory::Memory;
+    use super::{CriticalSection, InterruptStats, Timing};
+
+    #[test]
+    fn timer() {
+        // 0x00: rjmp main; 0x0e: reti at the vector of TIMER1_COMPA
+        let mut program = vec![0x0f, 0xc0];
+        program.extend_from_slice(&[0; 26]);
+        program.extend_from_slice(&[0x18, 0x95, 0x00, 0x00]);
+        // main: ldi r16, 0x08; out SPH, r16; ldi r16, 0x5f; out SPL, r16
+        program.extend_from_slice(&[0x08, 0xe0, 0x0e, 0xbf, 0x0f, 0xe5, 0x0d, 0xbf]);
+        // ldi r16, 3; out OCR1AL, r16; ldi r16, 0x10; out TIMSK, r16; ldi r16, 1; out TCCR1B, r16
+        program.extend_from_slice(&[0x03, 0xe0, 0x0a, 0xbd, 0x00, 0xe1, 0x09, 0xbf, 0x01, 0xe0, 0x0e, 0xbd]);
+        // loop: cli; nop; nop; nop; sei; rjmp loop
+        program.extend_from_slice(&[0xf8, 0x94, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x78, 0x94, 0xfa, 0xcf]);
+        let firmware = Firmware::from_bytes(program).unwrap();
+        let mut cpu = Cpu::new(Memory::from_firmware(&firmware, None), false);
+        cpu.set_interrupt_stats(Some(InterruptStats::new()));
+        for _ in 0..40 {
+            cpu.step();
+        }
+        let stats = cpu.interrupt_stats().unwrap();
+        let timer = stats.vectors()[&7];
+        // the flag is set every 3 cycles, but the cli of the loop delays the handler
+        assert_eq!(timer.latency, Timing { count: 5, min: 0, max: 4, total: 14 });
+        assert_eq!(timer.duration, Timing { count: 5, min: 1, max: 1, total: 5 });
+        // the reset clears the I flag up to the first sei
+        let sections = stats.critical_sections();
+        assert_eq!(sections[0], CriticalSection { start: 1, cycles: 15, pc: 0, isr: false });
+        assert!(sections.iter().any(|s| s.cycles == 4 && s.pc == 0x34 && !s.isr));
+
+        let mut out = Vec::new();
+        stats.write_report(&Symbols::new(vec![]), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("     7 TIMER1_COMPA "));
+    }
+}
@@ -0,0 +1,66 @@
+//! VM with JIT-compiler for the AVR ATmega32
+//!
+//! A program is loaded into a `Memory`, which is executed by a `Cpu`.
+//! Peripherals are connected to the pins of the microcontroller by
+//! the `Wire`s of an `IO`.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+
+#[cfg(feature = "jit")]
+#[macro_use]
+extern crate dynasm;
+#[cfg(feature = "jit")]
+extern crate dynasmrt;
+extern crate rand;
+#[cfg(feature = "gui")]
+extern crate gtk;
+#[cfg(feature = "gui")]
+extern crate gdk_pixbuf;
+#[cfg(feature = "gui")]
+extern crate cairo;
+#[cfg(feature = "gui")]
+extern crate gdk;
+
+#[macro_use]
+mod util;
+#[cfg(feature = "jit")]
+#[macro_use]
+mod jit;
+pub mod decoder;
+pub mod data;
+pub mod callstack;
+pub mod cpu;
+pub mod coverage;
+pub mod memory;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod io;
+#[cfg(feature = "gui")]
+pub mod widgets;
+mod ports;
+mod interrupts;
+pub mod uart;
+pub mod semihost;
+pub mod snapshot;
+pub mod gdb;
+pub mod debugger;
+pub mod disasm;
+pub mod dwarf;
+pub mod fault;
+pub mod history;
+pub mod latency;
+pub mod profile;
+pub mod stack;
+pub mod trace;
+pub mod uninit;
+pub mod vcd;
+pub mod watch;
+pub mod firmware;
+pub mod harness;
+pub mod batch;
+
+pub use cpu::Cpu;
+pub use memory::Memory;
+pub use io::{IO, Wire, HIGH, LOW};
+pub use firmware::{Firmware, Symbol, SymbolKind, Symbols};
+pub use util::parse_num;
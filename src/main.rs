@@ -1,89 +1,780 @@
-#![cfg_attr(feature = "jit", feature(plugin))]
-#![cfg_attr(feature = "jit", plugin(dynasm))]
 #![cfg_attr(feature = "strict", deny(warnings))]
 
-#[cfg(feature = "jit")]
-extern crate dynasmrt;
-extern crate rand;
-#[cfg(feature = "gui")]
-extern crate gtk;
-#[cfg(feature = "gui")]
-extern crate gdk_pixbuf;
-#[cfg(feature = "gui")]
-extern crate cairo;
-#[cfg(feature = "gui")]
-extern crate gdk;
+extern crate avr_vm;
 
 use std::env::args;
-#[macro_use]
-mod util;
-mod decoder;
-mod data;
-mod cpu;
-mod memory;
-#[cfg(feature = "gui")]
-mod gui;
-mod io;
-#[cfg(feature = "gui")]
-mod widgets;
-mod ports;
-mod interrupts;
 use std::ffi::OsString;
-use cpu::{Cpu};
-use memory::{Memory};
+use std::fs::File;
+use std::collections::HashSet;
+use std::io;
+use std::io::{stderr, stdin, stdout, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use avr_vm::{batch, gdb, parse_num, Cpu, Firmware, Memory, Symbols, IO};
+use avr_vm::callstack::CallStack;
+use avr_vm::debugger::Debugger;
+use avr_vm::disasm::Disassembler;
+use avr_vm::fault::{FaultKind, FaultPolicies, FaultPolicy};
+use avr_vm::cpu::{HaltOn, HaltReason, StepResult};
+use avr_vm::firmware::{Format, DATA_OFFSET};
+use avr_vm::coverage::Coverage;
+use avr_vm::history::History;
+use avr_vm::latency::InterruptStats;
+use avr_vm::profile::Profiler;
+use avr_vm::snapshot::Snapshot;
+use avr_vm::stack::StackGuard;
+use avr_vm::trace::{TraceFormat, Tracer};
+use avr_vm::uninit::UninitRead;
+use avr_vm::vcd::Vcd;
+use avr_vm::harness::{Board, Harness};
 
-fn main() {
-    if args().count() != 2 {
-        println!("usage: vm <program>");
-        return;
-    }
-
-    #[cfg(not(feature = "gui"))]
-    {
-        let mem = Memory::new(OsString::from(args().nth(1).expect("There must be an argument")), None);
-        let mut cpu = Cpu::new(mem, true);
-        while cpu.step() {}
-    }
-
-    #[cfg(feature = "gui")]
-    {
-        use widgets::{Button, Led, Poti, Seg7};
-        use io::IO;
-
-        let io = IO::new();
-        let mut gui = gui::init();
-        let _ = Led::new(&mut gui, "red0", 0xff, 0x00, 0x00, io.vcc.clone(), io.p[3][7].clone());
-        let _ = Led::new(&mut gui, "green0", 0x00, 0xff, 0x00, io.vcc.clone(), io.p[2][0].clone());
-        let _ = Led::new(&mut gui, "yellow0", 0xff, 0xff, 0x00, io.vcc.clone(), io.p[2][1].clone());
-        let _ = Led::new(&mut gui, "blue0", 0x00, 0x00, 0xff, io.vcc.clone(), io.p[2][6].clone());
-        let _ = Led::new(&mut gui, "red1", 0xff, 0x00, 0x00, io.vcc.clone(), io.p[2][7].clone());
-        let _ = Led::new(&mut gui, "green1", 0x00, 0xff, 0x00, io.vcc.clone(), io.p[0][7].clone());
-        let _ = Led::new(&mut gui, "yellow1", 0xff, 0xff, 0x00, io.vcc.clone(), io.p[0][6].clone());
-        let _ = Led::new(&mut gui, "blue1", 0x00, 0x00, 0xff, io.vcc.clone(), io.p[0][5].clone());
-        let _ = Button::new(&mut gui, "button0", io.p[3][3].clone());
-        let _ = Button::new(&mut gui, "button1", io.p[3][2].clone());
-        let _ = Poti::new(&mut gui, "potentiometer", io.gnd.clone(), io.p[0][1].clone(), io.vcc.clone());
-        let _ = Poti::new(&mut gui, "light sensor", io.gnd.clone(), io.p[0][0].clone(), io.vcc.clone());
-        let mut dis2 = Seg7::new(&mut gui, "dis2", io.p[1][1].clone(), io.p[1][0].clone(), io.p[3][1].clone(),
-                         io.p[1][6].clone(), io.vcc.clone(), io.p[1][5].clone(), io.p[1][4].clone(),
-                         io.p[3][1].clone(), io.p[1][3].clone(), io.p[1][2].clone());
-        let mut dis1 = Seg7::new(&mut gui, "dis1", io.p[1][1].clone(), io.p[1][0].clone(), io.p[3][0].clone(),
-                             io.p[1][6].clone(), io.vcc.clone(), io.p[1][5].clone(), io.p[1][4].clone(),
-                             io.p[3][0].clone(), io.p[1][3].clone(), io.p[1][2].clone());
-
-        io.nreset.set(io::LOW);
-        io.gnd.set(io::LOW);
-        io.vcc.set(io::HIGH);
-
-        let mem = Memory::new(OsString::from(args().nth(1).expect("There must be an argument")), Some(&io));
-        let mut cpu = Cpu::new(mem, false);
-
-        while gui.step() {
-            for _ in 0..100 {
-                cpu.step();
+const USAGE: &str = "usage: vm [options] <program>
+       vm batch <manifest> [<junit.xml>]
+       vm disasm [-a|--aliases] <program>
+       vm bench [-c|--max-cycles <n>] <program>
+
+options:
+  -g, --gui               show the SPiCboard instead of running headless
+  -c, --max-cycles <n>    stop after n cycles, the suffixes s, ms and us
+                          give a time, which is converted with F_CPU
+      --halt-on <cond>    stop at nop, break, sleep (with interrupts disabled),
+                          an address like 0x1a4 or none, can be repeated
+                          (default: nop, none with --semihosting)
+  -s, --semihosting       allow the firmware to exit with a status and to
+                          access the host, see README
+  -f, --format <fmt>      format of the program: auto, elf, bin or ihex
+      --f-cpu <hz>        clock frequency of the microcontroller (default: 1000000)
+  -b, --board <file>      inputs for the board at given cycles, see README
+  -u, --uart <dest>       where the uart output goes: stdout, stderr, none or a file
+      --uart-in <src>     uart input: stdin or a file
+  -t, --trace <file>      write the executed instructions to the file, - for stdout
+      --trace-format <fmt>  text (default) or bin, see README
+      --trace-mem         include the memory and io accesses in the trace
+      --trace-range <a>-<b>  only trace the instructions from a to b (exclusive),
+                          can be repeated
+      --trace-func <name> only trace the instructions of the function, can be repeated
+      --profile <file>    write the cycles per function and the call graph to
+                          the file, - for stdout
+      --flamegraph <file>  write the collapsed call stacks with their cycles
+                          for flamegraph.pl or inferno
+      --coverage <file>   write the executed lines and branches as lcov tracefile,
+                          the lines are taken from the debug info of the elf file
+      --irq-stats <file>  write the latency and duration of the interrupt handlers
+                          and the longest critical sections, - for stdout
+      --vcd <file>        record the pins in a value change dump, e.g. for GTKWave
+      --vcd-reg <name>    also record the io register like TCNT1, can be repeated
+      --load-snapshot <file>  start from the state saved with --save-snapshot
+      --save-snapshot <file>  save the state, when the program stops
+      --stack-guard <mode>  check the stack pointer and returns, warn about
+                          errors or halt at the first one, see README
+      --stack-limit <addr>  lowest address of the stack for --stack-guard
+                          (default: __heap_start of the elf file)
+      --fault <kind>=<policy>  what happens at a fault of the firmware, the kinds
+                          are invalid-read, invalid-write, illegal-opcode,
+                          second-word, pc-out-of-range, unsupported-clock
+                          or all, the policies
+                          halt, warn and break (only with --gdb and --debug),
+                          can be repeated (default: halt, break with --gdb
+                          and --debug)
+      --check-uninit      warn about reads of registers and memory, which
+                          were never written
+      --backtrace         follow the calls, warn about returns to another address
+                          and show the calls, when the program halts at a fault,
+                          a break, an address or the stack guard
+      --gdb <port>        wait for avr-gdb on the port of localhost, e.g. 1234
+  -d, --debug             start the built-in debugger, see help in it
+  -h, --help              show this help
+
+exit codes:
+  0        the program halted or the gui was closed
+  0-127    with --semihosting: the exit status of the program,
+           a status above 127 is reported as 127
+  240      the maximum number of cycles was reached
+  241      the stack guard halted the program
+  242      the program halted at a fault
+  243      a batch run failed
+  244      invalid arguments or the program couldn't be loaded";
+
+// halted by a halt condition or the gui was closed
+const EXIT_OK: i32 = 0;
+// the exit status of the firmware is passed through up to this
+const EXIT_FIRMWARE_MAX: i32 = 127;
+// the codes of the vm are above the ones of the firmware and of the signals
+const EXIT_MAX_CYCLES: i32 = 240;
+const EXIT_STACK: i32 = 241;
+const EXIT_FAULT: i32 = 242;
+const EXIT_BATCH_FAILED: i32 = 243;
+const EXIT_USAGE: i32 = 244;
+
+const DEFAULT_F_CPU: u64 = 1_000_000;
+// the debuggers can go back up to a million cycles
+const HISTORY_INTERVAL: u64 = 10_000;
+const HISTORY_DEPTH: usize = 100;
+
+struct Options {
+    program: String,
+    gui: bool,
+    max_cycles: Option<String>,
+    halt_on: Option<HaltOn>,
+    semihosting: bool,
+    format: Format,
+    f_cpu: u64,
+    board: Option<String>,
+    uart: String,
+    uart_in: Option<String>,
+    trace: Option<String>,
+    trace_format: TraceFormat,
+    trace_mem: bool,
+    trace_ranges: Vec<(u32, u32)>,
+    trace_funcs: Vec<String>,
+    profile: Option<String>,
+    flamegraph: Option<String>,
+    coverage: Option<String>,
+    irq_stats: Option<String>,
+    vcd: Option<String>,
+    vcd_regs: Vec<String>,
+    load_snapshot: Option<String>,
+    save_snapshot: Option<String>,
+    // halt at the first error of the stack, if true
+    stack_guard: Option<bool>,
+    stack_limit: Option<u16>,
+    // None for all kinds
+    faults: Vec<(Option<FaultKind>, FaultPolicy)>,
+    check_uninit: bool,
+    backtrace: bool,
+    gdb: Option<String>,
+    debug: bool,
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("vm: {}", msg);
+    exit(EXIT_USAGE);
+}
+
+fn parse_args(args: Vec<String>) -> Options {
+    let mut opts = Options {
+        program: String::new(), gui: false, max_cycles: None, halt_on: None, semihosting: false,
+        format: Format::Auto, f_cpu: DEFAULT_F_CPU, board: None, uart: "stdout".to_string(), uart_in: None, trace: None,
+        trace_format: TraceFormat::Text, trace_mem: false, trace_ranges: Vec::new(), trace_funcs: Vec::new(),
+        profile: None, flamegraph: None, coverage: None, irq_stats: None, vcd: None, vcd_regs: Vec::new(), load_snapshot: None, save_snapshot: None,
+        stack_guard: None, stack_limit: None, faults: Vec::new(), check_uninit: false, backtrace: false,
+        gdb: None, debug: false,
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| fail(&format!("missing value for {}", arg)));
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(EXIT_OK);
+            }
+            "-g" | "--gui" => opts.gui = true,
+            "-c" | "--max-cycles" => opts.max_cycles = Some(value()),
+            "--halt-on" => {
+                let cond = value();
+                let halt_on = opts.halt_on.get_or_insert(HaltOn::default());
+                match cond.as_str() {
+                    "nop" => halt_on.nop = true,
+                    "break" => halt_on.brk = true,
+                    "sleep" => halt_on.sleep = true,
+                    "none" => {}
+                    addr => halt_on.addr = Some(parse_num(addr).unwrap_or_else(
+                        || fail(&format!("invalid halt condition: {}", addr))) as usize),
+                }
             }
-            dis1.step();
-            dis2.step();
+            "-s" | "--semihosting" => opts.semihosting = true,
+            "-f" | "--format" => opts.format = match value().as_str() {
+                "auto" => Format::Auto,
+                "elf" => Format::Elf,
+                "bin" => Format::Bin,
+                "ihex" | "hex" => Format::IHex,
+                f => fail(&format!("unknown format: {}", f)),
+            },
+            "--f-cpu" => opts.f_cpu = parse_num(&value()).filter(|&f| f > 0)
+                .unwrap_or_else(|| fail("invalid clock frequency")),
+            "-b" | "--board" => opts.board = Some(value()),
+            "-u" | "--uart" => opts.uart = value(),
+            "--uart-in" => opts.uart_in = Some(value()),
+            "-t" | "--trace" => opts.trace = Some(value()),
+            "--trace-format" => opts.trace_format = match value().as_str() {
+                "text" => TraceFormat::Text,
+                "bin" => TraceFormat::Binary,
+                f => fail(&format!("unknown trace format: {}", f)),
+            },
+            "--trace-mem" => opts.trace_mem = true,
+            "--trace-range" => {
+                let range = value();
+                let bounds: Vec<_> = range.splitn(2, '-').map(parse_num).collect();
+                match bounds[..] {
+                    [Some(start), Some(end)] if start < end => opts.trace_ranges.push((start as u32, end as u32)),
+                    _ => fail(&format!("invalid trace range: {}", range)),
+                }
+            }
+            "--trace-func" => opts.trace_funcs.push(value()),
+            "--profile" => opts.profile = Some(value()),
+            "--flamegraph" => opts.flamegraph = Some(value()),
+            "--coverage" => opts.coverage = Some(value()),
+            "--irq-stats" => opts.irq_stats = Some(value()),
+            "--vcd" => opts.vcd = Some(value()),
+            "--vcd-reg" => opts.vcd_regs.push(value()),
+            "--load-snapshot" => opts.load_snapshot = Some(value()),
+            "--save-snapshot" => opts.save_snapshot = Some(value()),
+            "--stack-guard" => opts.stack_guard = match value().as_str() {
+                "warn" => Some(false),
+                "halt" => Some(true),
+                m => fail(&format!("unknown stack guard mode: {}", m)),
+            },
+            "--stack-limit" => opts.stack_limit = Some(parse_num(&value()).filter(|&a| a <= 0xffff)
+                .unwrap_or_else(|| fail("invalid stack limit")) as u16),
+            "--fault" => {
+                let fault = value();
+                let (kind, policy) = fault.split_at(fault.find('=').unwrap_or_else(
+                    || fail(&format!("invalid fault policy: {}", fault))));
+                let kind = match kind {
+                    "all" => None,
+                    kind => Some(FaultKind::from_name(kind).unwrap_or_else(
+                        || fail(&format!("unknown fault: {}", kind)))),
+                };
+                let policy = match &policy[1..] {
+                    "halt" => FaultPolicy::Halt,
+                    "warn" => FaultPolicy::Warn,
+                    "break" => FaultPolicy::Break,
+                    p => fail(&format!("unknown fault policy: {}", p)),
+                };
+                opts.faults.push((kind, policy));
+            }
+            "--check-uninit" => opts.check_uninit = true,
+            "--backtrace" => opts.backtrace = true,
+            "--gdb" => opts.gdb = Some(value()),
+            "-d" | "--debug" => opts.debug = true,
+            _ if arg.starts_with('-') && arg != "-" => fail(&format!("unknown option: {}\n{}", arg, USAGE)),
+            _ if opts.program.is_empty() => opts.program = arg,
+            _ => fail(USAGE),
         }
     }
+    if opts.program.is_empty() {
+        fail(USAGE);
+    }
+    opts
+}
+
+// converts the maximum number of cycles, which may be given as time
+fn max_cycles(opts: &Options) -> Option<u64> {
+    opts.max_cycles.as_ref().map(|s| {
+        let (num, scale) = if let Some(us) = s.strip_suffix("us") {
+            (us, 1_000_000)
+        } else if let Some(ms) = s.strip_suffix("ms") {
+            (ms, 1_000)
+        } else if let Some(secs) = s.strip_suffix('s') {
+            (secs, 1)
+        } else {
+            return parse_num(s).unwrap_or_else(|| fail(&format!("invalid number of cycles: {}", s)));
+        };
+        let time = parse_num(num).unwrap_or_else(|| fail(&format!("invalid time: {}", s)));
+        time.checked_mul(opts.f_cpu).unwrap_or_else(|| fail(&format!("time too large: {}", s))) / scale
+    })
+}
+
+fn uart_sink(dest: &str) -> Option<Box<dyn Write + Send>> {
+    match dest {
+        "stdout" | "-" => Some(Box::new(stdout())),
+        "stderr" => Some(Box::new(stderr())),
+        "none" => None,
+        file => Some(Box::new(BufWriter::new(File::create(file).unwrap_or_else(|e| fail(&format!("{}: {}", file, e)))))),
+    }
+}
+
+// the bytes for the uart, stdin is read by a thread, because it may block
+fn uart_input(src: &str) -> Receiver<Vec<u8>> {
+    let (tx, rx) = channel();
+    if src == "stdin" || src == "-" {
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            while let Ok(n) = stdin().read(&mut buf) {
+                if n == 0 || tx.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+    } else {
+        let mut bytes = Vec::new();
+        File::open(src).and_then(|mut f| f.read_to_end(&mut bytes))
+            .unwrap_or_else(|e| fail(&format!("{}: {}", src, e)));
+        tx.send(bytes).unwrap();
+    }
+    rx
+}
+
+fn report(cpu: &Cpu, f_cpu: u64) {
+    let reason = match cpu.halt_reason() {
+        Some(HaltReason::Nop) => "halted at nop".to_string(),
+        Some(HaltReason::Break) => "halted at break".to_string(),
+        Some(HaltReason::Sleep) => "halted at sleep with interrupts disabled".to_string(),
+        Some(HaltReason::Addr(addr)) => format!("halted at {:#x}", addr),
+        Some(HaltReason::Exit(status)) => format!("exited with status {}", status),
+        Some(HaltReason::Fault(_)) => match cpu.fault() {
+            Some(fault) => format!("halted at {} at {:#06x}", fault.describe(), fault.pc),
+            None => "halted at a fault".to_string(),
+        },
+        None => "maximum number of cycles reached".to_string(),
+    };
+    eprintln!("vm: {} after {} cycles ({:.6}s at {} Hz)",
+              reason, cpu.cycles(), cpu.cycles() as f64 / f_cpu as f64, f_cpu);
+}
+
+// the policies for the faults, the debuggers break by default
+fn fault_policies(opts: &Options, interactive: bool) -> FaultPolicies {
+    let mut policies = FaultPolicies::default();
+    if interactive {
+        policies.set_all(FaultPolicy::Break);
+    }
+    for &(kind, policy) in &opts.faults {
+        if policy == FaultPolicy::Break && !interactive {
+            fail("the fault policy break needs --gdb or --debug");
+        }
+        match kind {
+            Some(kind) => policies.set(kind, policy),
+            None => policies.set_all(policy),
+        }
+    }
+    policies
+}
+
+// the byte address in the flash with the function, e.g. 0x0104 <main+4>
+fn location(symbols: &Symbols, pc: usize) -> String {
+    let func = symbols.function_at(pc as u32)
+        .map(|f| format!(" <{}+{}>", f.name, pc as u32 - f.value))
+        .unwrap_or_default();
+    format!("{:#06x}{}", pc, func)
+}
+
+// the calls, which led to pc, e.g. #1  0x0104 <main+4>
+fn print_backtrace(symbols: &Symbols, call_stack: &CallStack, pc: usize) {
+    eprintln!("vm: backtrace:");
+    for (i, &(addr, interrupted)) in call_stack.backtrace(pc).iter().enumerate() {
+        eprintln!("  #{:<2} {}{}", i, location(symbols, addr), if interrupted { " (interrupted)" } else { "" });
+    }
+}
+
+// the register or the address with the variable, e.g. r24 or 0x0064 <counter+1>
+fn uninit_location(symbols: &Symbols, read: &UninitRead) -> String {
+    if let Some(reg) = read.register() {
+        return format!("r{}", reg);
+    }
+    let var = symbols.object_at(read.addr)
+        .map(|o| format!(" <{}+{}>", o.name, read.addr as u32 + DATA_OFFSET - o.value))
+        .unwrap_or_default();
+    format!("{:#06x}{}", read.addr, var)
+}
+
+// the halt conditions, if none are given
+fn halt_on(opts: &Options) -> HaltOn {
+    opts.halt_on.unwrap_or(HaltOn { nop: !opts.semihosting, ..HaltOn::default() })
+}
+
+fn firmware_exit_code(status: u8) -> i32 {
+    (status as i32).min(EXIT_FIRMWARE_MAX)
+}
+
+fn exit_code(reason: HaltReason) -> i32 {
+    match reason {
+        HaltReason::Exit(status) => firmware_exit_code(status),
+        HaltReason::Fault(_) => EXIT_FAULT,
+        _ => EXIT_OK,
+    }
+}
+
+fn run_headless(opts: &Options, firmware: Firmware) -> i32 {
+    let max_cycles = max_cycles(opts);
+    let stimuli = opts.board.as_ref().map(|b| {
+        batch::read_board(Path::new(b)).unwrap_or_else(|e| fail(&e.to_string()))
+    }).unwrap_or_default();
+    let uart_in = opts.uart_in.as_ref().map(|src| uart_input(src));
+    let tracer = opts.trace.as_ref().map(|t| tracer(opts, t, &firmware));
+    let guard = opts.stack_guard.map(|_| match opts.stack_limit {
+        Some(limit) => StackGuard::new(limit, firmware.program.len()),
+        None => StackGuard::from_firmware(&firmware),
+    });
+
+    let symbols = firmware.symbols.clone();
+    let lines = firmware.lines.clone();
+    let coverage = opts.coverage.as_ref().map(|_| {
+        if lines.is_empty() {
+            eprintln!("vm: {} has no line table, the coverage will be empty", opts.program);
+        }
+        Coverage::new(&firmware.program)
+    });
+    let mut h = Harness::from_firmware(Arc::new(Board::new()), firmware);
+    h.cpu_mut().mem_mut().uart_mut().set_sink(uart_sink(&opts.uart));
+    // the output only goes to the sink
+    h.cpu_mut().mem_mut().uart_mut().set_capture(0);
+    h.cpu_mut().set_halt_on(halt_on(opts));
+    h.cpu_mut().mem_mut().semihost_mut().set_enabled(opts.semihosting);
+    h.cpu_mut().set_tracer(tracer);
+    h.cpu_mut().set_coverage(coverage);
+    if opts.profile.is_some() || opts.flamegraph.is_some() {
+        h.cpu_mut().set_profiler(Some(Profiler::new(symbols.clone())));
+    }
+    if opts.irq_stats.is_some() {
+        h.cpu_mut().set_interrupt_stats(Some(InterruptStats::new()));
+    }
+    h.cpu_mut().set_stack_guard(guard);
+    h.cpu_mut().set_check_uninit(opts.check_uninit);
+    if opts.backtrace || opts.debug {
+        h.cpu_mut().set_call_stack(Some(CallStack::new()));
+    }
+    if let Some(ref file) = opts.load_snapshot {
+        Snapshot::load(file).and_then(|snapshot| h.cpu_mut().restore(&snapshot))
+            .unwrap_or_else(|e| fail(&format!("{}: {}", file, e)));
+    }
+
+    let interactive = opts.gdb.is_some() || opts.debug;
+    if interactive && (opts.board.is_some() || opts.uart_in.is_some() || opts.trace.is_some() || opts.vcd.is_some()
+                       || opts.save_snapshot.is_some() || max_cycles.is_some() || opts.profile.is_some()
+                       || opts.flamegraph.is_some() || opts.coverage.is_some() || opts.irq_stats.is_some()) {
+        fail("--board, --uart-in, --trace, --vcd, --save-snapshot, --max-cycles, --profile, --flamegraph, \
+              --coverage and --irq-stats can't be used with --gdb or --debug");
+    }
+    if opts.gdb.is_some() && opts.debug {
+        fail("--gdb and --debug can't be used together");
+    }
+    h.cpu_mut().set_fault_policies(fault_policies(opts, interactive));
+    if interactive {
+        h.cpu_mut().set_history(Some(History::new(HISTORY_INTERVAL, HISTORY_DEPTH)));
+    }
+    if opts.debug {
+        let stdin = stdin();
+        Debugger::new(symbols).run(h.cpu_mut(), stdin.lock(), &mut stdout())
+            .unwrap_or_else(|e| fail(&format!("debugger: {}", e)));
+        h.cpu_mut().mem_mut().uart_mut().flush();
+        return h.cpu().halt_reason().map_or(EXIT_OK, exit_code);
+    }
+    if let Some(ref port) = opts.gdb {
+        let addr = if port.contains(':') { port.clone() } else { format!("localhost:{}", port) };
+        let status = gdb::serve(h.cpu_mut(), &addr).unwrap_or_else(|e| fail(&format!("gdb: {}", e)));
+        h.cpu_mut().mem_mut().uart_mut().flush();
+        return status.map_or(EXIT_OK, firmware_exit_code);
+    }
+
+    let mut vcd = opts.vcd.as_ref().map(|file| vcd(opts, file, h.board().io()));
+    let mut stimuli = stimuli.iter().peekable();
+    let mut uninit_reads = HashSet::new();
+    let code = loop {
+        while let Some(&&(cycle, ref stimulus)) = stimuli.peek() {
+            if cycle > h.cycles() {
+                break;
+            }
+            stimulus.apply(&mut h);
+            stimuli.next();
+        }
+        if let Some(ref rx) = uart_in {
+            while let Ok(bytes) = rx.try_recv() {
+                h.send_uart(&bytes);
+            }
+        }
+        if max_cycles.is_some_and(|max| h.cycles() >= max) {
+            break EXIT_MAX_CYCLES;
+        }
+        let result = h.cpu_mut().step();
+        if let Some(ref mut vcd) = vcd {
+            vcd.step(h.cpu()).unwrap_or_else(|e| fail(&format!("couldn't write the vcd: {}", e)));
+        }
+        match h.cpu().fault() {
+            Some(fault) if h.cpu().fault_policies().get(fault.kind) == FaultPolicy::Warn =>
+                eprintln!("vm: {} at {} in cycle {}", fault.describe(), location(&symbols, fault.pc), h.cycles()),
+            _ => {}
+        }
+        if let Some(read) = h.cpu().uninit_read().filter(|&read| uninit_reads.insert(read)) {
+            eprintln!("vm: read of uninitialized {} at {} in cycle {}", uninit_location(&symbols, &read),
+                      location(&symbols, read.pc), h.cycles());
+        }
+        if let Some(error) = h.cpu().stack_error() {
+            eprintln!("vm: {} at {} in cycle {}", error.describe(), location(&symbols, error.pc), h.cycles());
+            if opts.stack_guard == Some(true) {
+                break EXIT_STACK;
+            }
+        }
+        if let StepResult::Halted(reason) = result {
+            break exit_code(reason);
+        }
+    };
+    // before the report, exit doesn't run the destructors
+    h.cpu_mut().mem_mut().uart_mut().flush();
+    match code {
+        EXIT_STACK => eprintln!("vm: halted by the stack guard after {} cycles", h.cycles()),
+        _ => report(h.cpu(), opts.f_cpu),
+    }
+    if let Some(call_stack) = h.cpu().call_stack() {
+        let pc = match (code, h.cpu().halt_reason()) {
+            (EXIT_STACK, _) => h.cpu().stack_error().map(|e| e.pc),
+            (_, Some(HaltReason::Fault(_))) => h.cpu().fault().map(|f| f.pc),
+            (_, Some(HaltReason::Break)) | (_, Some(HaltReason::Addr(_))) => Some(h.cpu().ip() << 1),
+            _ => None,
+        };
+        if let Some(pc) = pc {
+            print_backtrace(&symbols, call_stack, pc);
+        }
+    }
+    if let Some(guard) = h.cpu().stack_guard() {
+        match (guard.lowest_sp(), guard.max_depth()) {
+            (Some(sp), Some(depth)) => eprintln!("vm: stack used up to {} bytes, lowest sp {:#06x}, limit {:#06x}",
+                                                 depth, sp, guard.limit()),
+            _ => eprintln!("vm: the stack pointer was never set"),
+        }
+    }
+    if let Some(profiler) = h.cpu().profiler() {
+        if let Some(ref dest) = opts.profile {
+            write_to(dest, |out| {
+                profiler.write_flat(out)?;
+                writeln!(out)?;
+                profiler.write_graph(out)
+            });
+        }
+        if let Some(ref dest) = opts.flamegraph {
+            write_to(dest, |out| profiler.write_collapsed(out));
+        }
+    }
+    if let (Some(coverage), Some(dest)) = (h.cpu().coverage(), opts.coverage.as_ref()) {
+        write_to(dest, |out| coverage.write_lcov(&lines, &symbols, out));
+        let summary = coverage.summary(&lines, &symbols);
+        eprintln!("vm: coverage: {} of {} lines, {} of {} branches", summary.lines_hit, summary.lines,
+                  summary.branches_hit, summary.branches);
+    }
+    if let (Some(stats), Some(dest)) = (h.cpu().interrupt_stats(), opts.irq_stats.as_ref()) {
+        write_to(dest, |out| stats.write_report(&symbols, out));
+    }
+    if let Some(ref file) = opts.save_snapshot {
+        h.cpu().snapshot().save(file).unwrap_or_else(|e| fail(&format!("{}: {}", file, e)));
+    }
+    // flushes the trace and the vcd, exit doesn't run the destructors
+    h.cpu_mut().set_tracer(None);
+    if let Some(ref mut vcd) = vcd {
+        vcd.flush().unwrap_or_else(|e| fail(&format!("couldn't write the vcd: {}", e)));
+    }
+    code
+}
+
+fn vcd(opts: &Options, file: &str, io: &IO) -> Vcd {
+    let out = File::create(file).unwrap_or_else(|e| fail(&format!("{}: {}", file, e)));
+    let mut vcd = Vcd::new(Box::new(BufWriter::new(out)), opts.f_cpu);
+    vcd.add_pins(io);
+    for reg in &opts.vcd_regs {
+        if !vcd.add_register(reg) {
+            fail(&format!("unknown io register: {}", reg));
+        }
+    }
+    vcd
+}
+
+// writes a report to the file or stdout for -
+fn write_to<F: FnOnce(&mut dyn Write) -> io::Result<()>>(dest: &str, write: F) {
+    let result = if dest == "-" {
+        write(&mut stdout().lock())
+    } else {
+        File::create(dest).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            write(&mut out)?;
+            out.flush()
+        })
+    };
+    result.unwrap_or_else(|e| fail(&format!("{}: {}", dest, e)));
+}
+
+fn tracer(opts: &Options, dest: &str, firmware: &Firmware) -> Tracer {
+    let out: Box<dyn Write + Send> = if dest == "-" {
+        Box::new(BufWriter::new(stdout()))
+    } else {
+        Box::new(BufWriter::new(File::create(dest).unwrap_or_else(|e| fail(&format!("{}: {}", dest, e)))))
+    };
+    let mut tracer = Tracer::new(firmware, out, opts.trace_format);
+    tracer.set_memory(opts.trace_mem);
+    for &(start, end) in &opts.trace_ranges {
+        tracer.add_range(start, end);
+    }
+    for func in &opts.trace_funcs {
+        if !tracer.add_function(func) {
+            fail(&format!("unknown function: {}", func));
+        }
+    }
+    tracer
+}
+
+#[cfg(feature = "gui")]
+fn run_gui(opts: &Options, firmware: Firmware) -> i32 {
+    use std::time::Instant;
+    use avr_vm::{gui, io};
+    use avr_vm::widgets::SPiCboard;
+
+    if opts.board.is_some() || opts.uart_in.is_some() || opts.trace.is_some() || opts.vcd.is_some()
+        || opts.load_snapshot.is_some() || opts.save_snapshot.is_some() || opts.stack_guard.is_some()
+        || opts.check_uninit || opts.profile.is_some() || opts.flamegraph.is_some() || opts.coverage.is_some()
+        || opts.irq_stats.is_some() || opts.backtrace || opts.gdb.is_some() || opts.debug {
+        fail("--board, --uart-in, --trace, --vcd, snapshots, --stack-guard, --check-uninit, --profile, --flamegraph, \
+              --coverage, --irq-stats, --backtrace, --gdb and --debug are only supported headless");
+    }
+    let max_cycles = max_cycles(opts);
+
+    let io = Arc::new(IO::new());
+    let mut gui = gui::init();
+    let mut board = SPiCboard::new(&mut gui, &io);
+    io.nreset.set(io::LOW);
+    io.gnd.set(io::LOW);
+    io.vcc.set(io::HIGH);
+
+    let mut mem = Memory::from_firmware(&firmware, Some(io.clone()));
+    mem.uart_mut().set_sink(uart_sink(&opts.uart));
+    mem.uart_mut().set_capture(0);
+    mem.semihost_mut().set_enabled(opts.semihosting);
+    let mut cpu = Cpu::new(mem, false);
+    // the program keeps running in the gui, if no halt condition is given
+    cpu.set_halt_on(opts.halt_on.unwrap_or_default());
+    cpu.set_fault_policies(fault_policies(opts, false));
+
+    // the program runs in real time, but at most 1/50s is executed
+    // between two updates, so the gui stays responsive on a slow machine
+    let start = Instant::now();
+    let mut running = true;
+    let mut code = EXIT_OK;
+    while gui.step() {
+        let elapsed = start.elapsed();
+        let due = elapsed.as_secs() * opts.f_cpu + elapsed.subsec_nanos() as u64 * opts.f_cpu / 1_000_000_000;
+        let end = due.min(cpu.cycles() + opts.f_cpu / 50);
+        while running && cpu.cycles() < end {
+            if max_cycles.is_some_and(|max| cpu.cycles() >= max) {
+                report(&cpu, opts.f_cpu);
+                running = false;
+            } else if let StepResult::Halted(reason) = cpu.step() {
+                report(&cpu, opts.f_cpu);
+                code = exit_code(reason);
+                running = false;
+            }
+        }
+        // the output is shown once per update
+        cpu.mem_mut().uart_mut().flush();
+        board.step();
+    }
+    code
+}
+
+#[cfg(not(feature = "gui"))]
+fn run_gui(_: &Options, _: Firmware) -> i32 {
+    fail("the vm was built without gui support");
+}
+
+fn run_batch(manifest: &str, junit: Option<&String>) -> bool {
+    let specs = batch::read_manifest(Path::new(manifest)).unwrap_or_else(|e| {
+        fail(&format!("couldn't read {}: {}", manifest, e));
+    });
+    let results = batch::run_all(specs, batch::default_threads());
+
+    batch::write_summary(&results, &mut stdout()).expect("Couldn't write the summary");
+    if let Some(junit) = junit {
+        let mut file = File::create(junit).unwrap_or_else(|e| {
+            fail(&format!("couldn't create {}: {}", junit, e));
+        });
+        batch::write_junit(&results, &mut file).expect("Couldn't write the junit report");
+    }
+    results.iter().all(|r| r.status == batch::Status::Passed)
+}
+
+// runs the program until the first nop or for n cycles with the interpreter and
+// with the jit and compares the speed
+fn run_bench(args: &[String]) {
+    let (max_cycles, program) = match args {
+        [program] => (None, program),
+        [flag, n, program] if flag == "-c" || flag == "--max-cycles" => {
+            (Some(parse_num(n).unwrap_or_else(|| fail(&format!("invalid number of cycles: {}", n)))), program)
+        }
+        _ => fail(USAGE),
+    };
+    let firmware = Firmware::load(OsString::from(program))
+        .unwrap_or_else(|e| fail(&format!("couldn't load {}: {}", program, e)));
+
+    let (cycles, interpreted) = bench(&firmware, false, max_cycles).unwrap();
+    println!("interpreter: {} cycles in {:.3}s, {:.1} MHz", cycles, interpreted.as_secs_f64(),
+             cycles as f64 / interpreted.as_secs_f64() / 1e6);
+    match bench(&firmware, true, max_cycles) {
+        Some((cycles, compiled)) => {
+            println!("jit:         {} cycles in {:.3}s, {:.1} MHz, {:.1}x faster", cycles, compiled.as_secs_f64(),
+                     cycles as f64 / compiled.as_secs_f64() / 1e6,
+                     interpreted.as_secs_f64() / compiled.as_secs_f64());
+        }
+        None => println!("jit:         not available, build with --features jit on x86-64 or aarch64"),
+    }
+}
+
+// the cycles and the time of a run, None if the jit isn't available
+fn bench(firmware: &Firmware, jit: bool, max_cycles: Option<u64>) -> Option<(u64, Duration)> {
+    let mut cpu = Cpu::new(Memory::from_firmware(firmware, None), true);
+    if !set_jit(&mut cpu, jit) {
+        return None;
+    }
+    let start = Instant::now();
+    loop {
+        if max_cycles.is_some_and(|max| cpu.cycles() >= max) {
+            break;
+        }
+        if let StepResult::Halted(_) = cpu.step() {
+            break;
+        }
+    }
+    Some((cpu.cycles(), start.elapsed()))
+}
+
+// false if the jit was requested, but isn't available
+#[cfg(feature = "jit")]
+fn set_jit(cpu: &mut Cpu, jit: bool) -> bool {
+    cpu.set_jit(jit);
+    cpu.jit() == jit
+}
+
+#[cfg(not(feature = "jit"))]
+fn set_jit(_: &mut Cpu, jit: bool) -> bool {
+    !jit
+}
+
+// the output matches avr-objdump -d, unless aliases like lsl and clr are enabled
+fn run_disasm(args: &[String]) {
+    let (aliases, program) = match args {
+        [program] => (false, program),
+        [flag, program] if flag == "-a" || flag == "--aliases" => (true, program),
+        _ => fail(USAGE),
+    };
+    let firmware = Firmware::load(OsString::from(program))
+        .unwrap_or_else(|e| fail(&format!("couldn't load {}: {}", program, e)));
+    let stdout = stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    Disassembler::new(&firmware, aliases).write(&firmware, program, &mut out)
+        .and_then(|_| out.flush())
+        .unwrap_or_else(|e| fail(&format!("couldn't write the disassembly: {}", e)));
+}
+
+fn main() {
+    let args: Vec<String> = args().skip(1).collect();
+    if args.first().map(|a| a.as_str()) == Some("batch") {
+        if args.len() != 2 && args.len() != 3 {
+            fail(USAGE);
+        }
+        exit(if run_batch(&args[1], args.get(2)) { EXIT_OK } else { EXIT_BATCH_FAILED });
+    }
+
+    if args.first().map(|a| a.as_str()) == Some("disasm") {
+        run_disasm(&args[1..]);
+        exit(EXIT_OK);
+    }
+
+    if args.first().map(|a| a.as_str()) == Some("bench") {
+        run_bench(&args[1..]);
+        exit(EXIT_OK);
+    }
+
+    let opts = parse_args(args);
+    let firmware = Firmware::load_as(OsString::from(&opts.program), opts.format)
+        .unwrap_or_else(|e| fail(&format!("couldn't load {}: {}", opts.program, e)));
+    exit(if opts.gui { run_gui(&opts, firmware) } else { run_headless(&opts, firmware) });
 }
@@ -1,15 +1,25 @@
 use data::Instruction;
 use data::Instruction::NOP;
 use decoder::decode;
-use std::fs::File;
+use fault::{Fault, FaultKind};
 use std::ffi::OsString;
-use std::io::prelude::*;
-use std::char;
+use std::io;
+#[cfg(feature = "jit")]
+use std::mem;
+use std::sync::Arc;
+use firmware::Firmware;
+use interrupts::{TimerInterrupts, TCCR1B};
 use io::IO;
 use ports::{Port, adc_write};
+use semihost::Semihost;
+use snapshot::{hash, Snapshot};
+use trace::Access;
+use uart::Uart;
+use uninit::{Shadow, UninitRead};
+use watch::{Watchpoint, WatchHit};
 
-const SRAM_SIZE: usize = 2144;
-const PROGRAM_SIZE: usize = 32 * 1 << 10;
+pub const SRAM_SIZE: usize = 2144;
+pub const PROGRAM_SIZE: usize = 32 * 1 << 10;
 const MAX_INSTRUCTIONS: usize = PROGRAM_SIZE >> 1;
 const REGISTER_OFFSET: u8 = 0;
 const NUM_REGISTER: u8 = 0x20;
@@ -17,22 +27,42 @@ const IO_REGISTER_OFFSET: u8 = NUM_REGISTER;
 const NUM_IO_REGISTER: u8 = 0x40;
 const FLAGS_REG: u8 = 0x3f;
 const SP_REG: u8 = 0x3d;
-const UDR: u16 = 0x2C;
 
-pub struct Memory<'a> {
+pub struct Memory {
     code: [Instruction; MAX_INSTRUCTIONS],
     program: [u8; PROGRAM_SIZE],
     data: [u8; SRAM_SIZE],
-    io: Option<&'a IO>,
-    ports: [Port<'a>; 4],
+    io: Option<Arc<IO>>,
+    ports: [Port; 4],
+    uart: Uart,
+    semihost: Semihost,
+    // of the flash, snapshots can only be restored with the same program
+    program_hash: u32,
+    // the reads and writes of the cpu, only recorded for the trace
+    accesses: Option<Vec<Access>>,
+    watchpoints: Vec<Watchpoint>,
+    // the first hit since it was taken, the pc is set by the cpu
+    watch_hit: Option<WatchHit>,
+    // the first invalid access since it was taken, the pc is set by the cpu
+    fault: Option<Fault>,
+    // only with set_check_uninit
+    shadow: Option<Shadow>,
+    // the first read of an undefined byte since it was taken, the pc is set by the cpu
+    uninit_read: Option<UninitRead>,
+    // a write to the io registers other than the sp or to the semihosting since it was
+    // taken, the jit doesn't chain its blocks after it
+    #[cfg(feature = "jit")]
+    io_written: bool,
 }
 
-impl<'a> Memory<'a> {
-    pub fn new(file: OsString, io: Option<&'a IO>) -> Memory<'a> {
-        let mut output = File::open(file).unwrap();
-        let mut bytes = Vec::new();
-        output.read_to_end(&mut bytes).unwrap();
-        assert!(bytes.len() < PROGRAM_SIZE);
+impl Memory {
+    pub fn new(file: OsString, io: Option<Arc<IO>>) -> Memory {
+        Memory::from_firmware(&Firmware::load(file).unwrap(), io)
+    }
+
+    pub fn from_firmware(firmware: &Firmware, io: Option<Arc<IO>>) -> Memory {
+        let mut bytes = firmware.program.clone();
+        assert!(bytes.len() <= PROGRAM_SIZE, "the program doesn't fit into the flash");
 
         let mut code = decode(bytes.iter().map(|i| *i)).collect::<Vec<Instruction>>();
         let mut program = [0; PROGRAM_SIZE];
@@ -47,16 +77,186 @@ impl<'a> Memory<'a> {
             code: code_array,
             data: [0; SRAM_SIZE],
             program: program,
+            ports: [Port::new(io.clone(), 0), Port::new(io.clone(), 1),
+                    Port::new(io.clone(), 2), Port::new(io.clone(), 3)],
             io: io,
-            ports: [Port::new(io, 0), Port::new(io, 1), Port::new(io, 2), Port::new(io, 3)],
+            uart: Uart::new(),
+            semihost: Semihost::new(),
+            program_hash: hash(&program),
+            accesses: None,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            fault: None,
+            shadow: None,
+            uninit_read: None,
+            #[cfg(feature = "jit")]
+            io_written: false,
+        }
+    }
+
+    pub fn uart(&self) -> &Uart {
+        &self.uart
+    }
+
+    pub fn uart_mut(&mut self) -> &mut Uart {
+        &mut self.uart
+    }
+
+    pub fn semihost(&self) -> &Semihost {
+        &self.semihost
+    }
+
+    pub fn semihost_mut(&mut self) -> &mut Semihost {
+        &mut self.semihost
+    }
+
+    /// records the reads and writes of the cpu until they are taken
+    pub fn set_record_accesses(&mut self, record: bool) {
+        self.accesses = if record { Some(Vec::new()) } else { None };
+    }
+
+    /// the accesses since the last call, empty if they are not recorded
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        self.accesses.as_mut().map(|a| a.split_off(0)).unwrap_or_default()
+    }
+
+    /// stores the data memory, the peripherals and the wires in the snapshot
+    pub fn save(&self, snapshot: &mut Snapshot) {
+        snapshot.program_hash = self.program_hash;
+        snapshot.data = self.data.to_vec();
+        for (i, port) in self.ports.iter().enumerate() {
+            snapshot.ports[i] = port.state();
+        }
+        snapshot.uart_input = self.uart.input();
+        snapshot.wires = self.io.as_ref().map(|io| {
+            [&io.nreset, &io.vcc, &io.gnd].iter().map(|w| w.mv())
+                .chain(io.p.iter().flat_map(|port| port.iter().map(|w| w.mv())))
+                .collect()
+        });
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if snapshot.program_hash != self.program_hash {
+            return Err(invalid("the snapshot was taken with a different program"));
+        }
+        if snapshot.data.len() != SRAM_SIZE {
+            return Err(invalid("the snapshot has a different size of the data memory"));
+        }
+        self.data.copy_from_slice(&snapshot.data);
+        for (port, &(ddr, val)) in self.ports.iter_mut().zip(snapshot.ports.iter()) {
+            port.set_state(ddr, val);
+        }
+        self.uart.set_input(&snapshot.uart_input);
+        if let (Some(io), Some(wires)) = (self.io.as_ref(), snapshot.wires.as_ref()) {
+            let all: Vec<_> = [&io.nreset, &io.vcc, &io.gnd].iter().cloned()
+                .chain(io.p.iter().flat_map(|port| port.iter()))
+                .collect();
+            if all.len() != wires.len() {
+                return Err(invalid("the snapshot has a different number of wires"));
+            }
+            for (wire, mv) in all.iter().zip(wires) {
+                wire.set(*mv);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// returns false, if there is no such watchpoint
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// the first access, which triggered a watchpoint since the last call
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    #[inline(always)]
+    fn check_watchpoints(&mut self, addr: u16, write: bool, old: u8, new: u8) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        if let Some(w) = self.watchpoints.iter().find(|w| w.matches(addr, write, new)) {
+            self.watch_hit = Some(WatchHit { watchpoint: *w, pc: 0, addr: addr, write: write, old: old, new: new });
+        }
+    }
+
+    /// the first read or write outside the data memory since the last call
+    pub fn take_fault(&mut self) -> Option<Fault> {
+        self.fault.take()
+    }
+
+    // the jit stops chaining its blocks after a fault
+    #[cfg(feature = "jit")]
+    pub fn has_fault(&self) -> bool {
+        self.fault.is_some()
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn take_io_written(&mut self) -> bool {
+        mem::replace(&mut self.io_written, false)
+    }
+
+    fn invalid_access(&mut self, addr: u16, write: bool) {
+        self.raise(if write { FaultKind::InvalidWrite } else { FaultKind::InvalidRead }, addr);
+    }
+
+    // the cpu fills in the pc of the instruction
+    fn raise(&mut self, kind: FaultKind, addr: u16) {
+        if self.fault.is_none() {
+            self.fault = Some(Fault { kind: kind, pc: 0, addr: addr as usize });
+        }
+    }
+
+    /// tracks, which bytes were written, to find reads of undefined ones
+    pub fn set_check_uninit(&mut self, check: bool) {
+        self.shadow = if check { Some(Shadow::new()) } else { None };
+    }
+
+    pub fn checks_uninit(&self) -> bool {
+        self.shadow.is_some()
+    }
+
+    /// true for all bytes, if they aren't tracked
+    pub fn is_defined(&self, addr: u16) -> bool {
+        self.shadow.as_ref().is_none_or(|s| s.is_defined(addr))
+    }
+
+    /// records a read of the cpu, if the byte is undefined
+    #[inline(always)]
+    pub fn check_defined(&mut self, addr: u16) {
+        if self.uninit_read.is_none() && !self.is_defined(addr) {
+            self.uninit_read = Some(UninitRead { pc: 0, addr: addr });
         }
     }
 
+    /// the first read of an undefined byte since the last call
+    pub fn take_uninit_read(&mut self) -> Option<UninitRead> {
+        self.uninit_read.take()
+    }
+
     #[inline(always)]
     pub fn get_instruction(&self, ip: usize) -> Instruction {
         self.code[ip]
     }
 
+    /// decodes the instruction at the word address, also if it is
+    /// the second word of another one
+    pub fn decode_at(&self, ip: usize) -> Instruction {
+        decode(self.program[ip << 1..].iter().cloned()).next().unwrap_or(NOP)
+    }
+
     #[inline(always)]
     pub fn reg(&self, index: u8) -> u8 {
         debug_assert!(index < NUM_REGISTER);
@@ -69,6 +269,9 @@ impl<'a> Memory<'a> {
     #[inline(always)]
     pub fn reg_mut(&mut self, index: u8) -> &mut u8 {
         debug_assert!(index < NUM_REGISTER);
+        if let Some(ref mut shadow) = self.shadow {
+            shadow.define((REGISTER_OFFSET + index) as u16);
+        }
         &mut self.data[(REGISTER_OFFSET + index) as usize]
     }
 
@@ -96,6 +299,12 @@ impl<'a> Memory<'a> {
         self.data[(IO_REGISTER_OFFSET + FLAGS_REG) as usize]
     }
 
+    // the registers, the io registers and the sram for the blocks of the jit
+    #[cfg(feature = "jit")]
+    pub fn data_ptr(&mut self) -> *mut u8 {
+        self.data.as_mut_ptr()
+    }
+
     #[inline(always)]
     pub fn set_flags(&mut self, flags: u8) {
         // don't call data because flags is not used
@@ -103,6 +312,7 @@ impl<'a> Memory<'a> {
         self.data[(IO_REGISTER_OFFSET + FLAGS_REG) as usize] = flags;
     }
 
+    // reads the data memory without any side effects
     #[inline(always)]
     pub fn data(&self, index: u16) -> u8 {
         for port in self.ports.iter() {
@@ -110,22 +320,83 @@ impl<'a> Memory<'a> {
                 return ret;
             }
         }
+        if let Some(ret) = self.uart.peek(index) {
+            return ret;
+        }
+        if Semihost::is_reg(index) {
+            return self.semihost.peek(index);
+        }
+
+        // nothing else is mapped above RAMEND
+        self.data.get(index as usize).cloned().unwrap_or(0)
+    }
+
+    // a read by the cpu, which e.g. consumes a received byte from UDR
+    #[inline(always)]
+    pub fn load(&mut self, index: u16) -> u8 {
+        if index as usize >= SRAM_SIZE && !(Semihost::is_reg(index) && self.semihost.enabled()) {
+            self.invalid_access(index, false);
+            return 0;
+        }
+        if self.shadow.is_some() {
+            self.check_defined(index);
+        }
+        let val = match self.uart.read(index) {
+            Some(ret) => ret,
+            None => self.data(index),
+        };
+        if let Some(ref mut accesses) = self.accesses {
+            accesses.push(Access { write: false, addr: index, value: val });
+        }
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(index, false, val, val);
+        }
+        val
+    }
 
-        self.data[index as usize]
+    #[inline(always)]
+    pub fn load_io_reg(&mut self, index: u8) -> u8 {
+        debug_assert!(index < NUM_IO_REGISTER);
+        self.load((IO_REGISTER_OFFSET + index) as u16)
     }
 
     #[inline(always)]
     pub fn set_data(&mut self, index: u16, val: u8) {
+        if index as usize >= SRAM_SIZE && !(Semihost::is_reg(index) && self.semihost.enabled()) {
+            self.invalid_access(index, true);
+            return;
+        }
+        if index == TCCR1B && TimerInterrupts::is_external_clock(val) {
+            self.raise(FaultKind::UnsupportedClock, index);
+        }
+        if let Some(ref mut accesses) = self.accesses {
+            accesses.push(Access { write: true, addr: index, value: val });
+        }
+        #[cfg(feature = "jit")]
+        {
+            let io = index >= IO_REGISTER_OFFSET as u16 && index < (IO_REGISTER_OFFSET + NUM_IO_REGISTER) as u16;
+            let sp = index == (IO_REGISTER_OFFSET + SP_REG) as u16 || index == (IO_REGISTER_OFFSET + SP_REG + 1) as u16;
+            self.io_written |= io && !sp || Semihost::is_reg(index);
+        }
+        if !self.watchpoints.is_empty() {
+            let old = self.data(index);
+            self.check_watchpoints(index, true, old, val);
+        }
+        if Semihost::is_reg(index) {
+            self.semihost.write(index, val, &mut self.data);
+            if let (Some(shadow), Some(range)) = (self.shadow.as_mut(), self.semihost.take_written()) {
+                shadow.define_range(range);
+            }
+            return;
+        }
+        if let Some(ref mut shadow) = self.shadow {
+            shadow.define(index);
+        }
         self.data[index as usize] = val;
 
-        if index == UDR {
-            #[cfg(debug_assertions)]
-            println!("Output: {}", char::from_u32(val as u32).unwrap_or('?'));
-            #[cfg(not(debug_assertions))]
-            print!("{}", char::from_u32(val as u32).unwrap_or('?'));
-        }
+        self.uart.write(index, val);
 
-        if let Some(io) = self.io {
+        if let Some(ref io) = self.io {
             adc_write(io, &mut self.data, index, val);
         }
 
@@ -166,7 +437,7 @@ impl<'a> Memory<'a> {
     pub fn pop(&mut self) -> u8 {
         let sp = self.sp().wrapping_add(1);
 
-        let ret = self.data(sp);
+        let ret = self.load(sp);
         self.set_sp(sp);
         ret
     }
@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use io::{IO, HIGH, LOW};
 use util::{bit, bits};
 
@@ -21,8 +22,8 @@ const PORTA: usize = 0;
 const ADC_PORT: usize = PORTA;
 pub const PIND: usize = 0x30;
 
-pub struct Port<'a> {
-    io: Option<&'a IO>,
+pub struct Port {
+    io: Option<Arc<IO>>,
     ddr: u8,
     port: u8,
     index: u16,
@@ -33,15 +34,26 @@ enum PortReg {
     PIN, PORT, DDR
 }
 
-impl<'a> Port<'a> {
-    pub fn new(io: Option<&'a IO>, index: u16) -> Port<'a> {
+impl Port {
+    pub fn new(io: Option<Arc<IO>>, index: u16) -> Port {
         Port {io: io, index: index, ddr: 0, port: 0}
     }
 
+    /// ddr and port
+    pub fn state(&self) -> (u8, u8) {
+        (self.ddr, self.port)
+    }
+
+    /// only restores the registers, the wires aren't changed
+    pub fn set_state(&mut self, ddr: u8, port: u8) {
+        self.ddr = ddr;
+        self.port = port;
+    }
+
     #[inline]
     pub fn read(&self, index: u16) -> Option<u8> {
         let typ = try_opt!(self.typ(index));
-        let io = try_opt!(self.io);
+        let io = try_opt!(self.io.as_ref());
         match typ {
             PortReg::DDR => Some(self.ddr),
             PortReg::PIN => {
@@ -65,7 +77,7 @@ impl<'a> Port<'a> {
     #[inline]
     pub fn write(&mut self, index: u16, val: u8) {
         let typ = try_opt_void!(self.typ(index));
-        let io = try_opt_void!(self.io);
+        let io = try_opt_void!(self.io.as_ref());
         if let PortReg::PIN = typ {
             return;
         }
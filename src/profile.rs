@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::prelude::*;
+use data::Instruction;
+use data::Instruction::*;
+use firmware::{SymbolKind, Symbols};
+
+/// a function on the call stack of the profiler
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+struct Frame {
+    // the address of the function symbol or the target of the call, if there is none
+    func: u32,
+    // entered by an interrupt
+    isr: bool,
+}
+
+/// the cycles of a function, total includes the functions called by it
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct FunctionStats {
+    pub name: String,
+    pub self_cycles: u64,
+    pub total_cycles: u64,
+    pub calls: u64,
+}
+
+/// attributes the cycles to the functions of the firmware and their callers
+///
+/// the call stack is tracked with call, rcall, icall and the interrupts, which push
+/// a frame, and ret and reti, which pop it. a jump into another function like
+/// a tail call replaces the function of the frame.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    symbols: Symbols,
+    // start and end of the function symbols, sorted by the start
+    funcs: Vec<(u32, u32)>,
+    stack: Vec<Frame>,
+    // the cycles of every call stack, which were spent in its top function
+    stacks: HashMap<Vec<Frame>, u64>,
+    // how often a function was entered by a call, a jump or an interrupt
+    calls: HashMap<u32, u64>,
+    // the calls from the caller to the callee
+    edges: HashMap<(u32, u32), u64>,
+}
+
+impl Profiler {
+    pub fn new(symbols: Symbols) -> Profiler {
+        let funcs = symbols.iter()
+            .filter(|s| s.kind == SymbolKind::Func)
+            .map(|s| (s.value, s.value + s.size.max(1)))
+            .collect();
+        // the reset enters the function at 0 outside of any call
+        let mut profiler = Profiler { symbols: symbols, funcs: funcs, stack: vec![Frame { func: 0, isr: false }],
+                                      stacks: HashMap::new(), calls: HashMap::new(), edges: HashMap::new() };
+        profiler.count_call();
+        profiler
+    }
+
+    /// records a step of the cpu, which executed instr at the byte address pc and
+    /// continues at the word address ip, interrupted is set, if an interrupt
+    /// jumped to pc before
+    pub fn record(&mut self, pc: usize, instr: Option<Instruction>, interrupted: bool, ip: usize, cycles: u64) {
+        let pc = pc as u32;
+        if interrupted {
+            self.push(pc, true);
+        }
+        if let Some(func) = self.function_at(pc) {
+            if self.stack.last().unwrap().func != func {
+                self.stack.last_mut().unwrap().func = func;
+                self.count_call();
+            }
+        }
+        match self.stacks.get_mut(&self.stack[..]) {
+            Some(count) => *count += cycles,
+            None => {
+                self.stacks.insert(self.stack.clone(), cycles);
+            }
+        }
+        match instr {
+            // avr-gcc reserves space on the stack with rcall .+0
+            Some(RCALL(0)) => {}
+            Some(CALL(_)) | Some(RCALL(_)) | Some(ICALL) => self.push((ip << 1) as u32, false),
+            Some(RET) | Some(RETI) if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+
+    fn push(&mut self, addr: u32, isr: bool) {
+        let func = self.function_at(addr).unwrap_or(addr);
+        self.stack.push(Frame { func: func, isr: isr });
+        self.count_call();
+    }
+
+    // the top frame was entered
+    fn count_call(&mut self) {
+        let callee = self.stack[self.stack.len() - 1].func;
+        *self.calls.entry(callee).or_insert(0) += 1;
+        if self.stack.len() > 1 {
+            let caller = self.stack[self.stack.len() - 2].func;
+            *self.edges.entry((caller, callee)).or_insert(0) += 1;
+        }
+    }
+
+    fn function_at(&self, addr: u32) -> Option<u32> {
+        let i = self.funcs.partition_point(|&(start, _)| start <= addr);
+        match self.funcs[..i].last() {
+            Some(&(start, end)) if addr < end => Some(start),
+            _ => None,
+        }
+    }
+
+    // the name of the symbol or the address
+    fn name(&self, func: u32) -> String {
+        self.symbols.iter()
+            .find(|s| s.kind == SymbolKind::Func && s.value == func)
+            .map(|s| s.name.clone())
+            .unwrap_or_else(|| format!("{:#06x}", func))
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// the cycles spent in interrupt handlers and the functions called by them
+    pub fn isr_cycles(&self) -> u64 {
+        self.stacks.iter().filter(|&(stack, _)| stack.iter().any(|f| f.isr)).map(|(_, &c)| c).sum()
+    }
+
+    // the self and total cycles of every function, recursive calls are only counted once
+    fn totals(&self) -> HashMap<u32, (u64, u64)> {
+        let mut totals = HashMap::new();
+        for (stack, &cycles) in &self.stacks {
+            let funcs: HashSet<u32> = stack.iter().map(|f| f.func).collect();
+            for func in funcs {
+                totals.entry(func).or_insert((0, 0)).1 += cycles;
+            }
+            totals.get_mut(&stack.last().unwrap().func).unwrap().0 += cycles;
+        }
+        totals
+    }
+
+    /// all functions, which were executed, sorted by their self cycles
+    pub fn functions(&self) -> Vec<FunctionStats> {
+        let mut stats: Vec<_> = self.totals().into_iter().map(|(func, (self_cycles, total))| FunctionStats {
+            name: self.name(func), self_cycles: self_cycles, total_cycles: total,
+            calls: self.calls.get(&func).cloned().unwrap_or(0),
+        }).collect();
+        stats.sort_by(|a, b| b.self_cycles.cmp(&a.self_cycles).then(a.name.cmp(&b.name)));
+        stats
+    }
+
+    /// the time in the main loop and the interrupts and the cycles of every function
+    pub fn write_flat<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        let cycles = self.cycles();
+        let percent = |c: u64| if cycles == 0 { 0.0 } else { c as f64 * 100.0 / cycles as f64 };
+        let isr = self.isr_cycles();
+        writeln!(out, "{} cycles, main loop {} ({:.2}%), interrupts {} ({:.2}%)",
+                 cycles, cycles - isr, percent(cycles - isr), isr, percent(isr))?;
+        let mut handlers: HashMap<u32, u64> = HashMap::new();
+        for (stack, &c) in &self.stacks {
+            if let Some(frame) = stack.iter().find(|f| f.isr) {
+                *handlers.entry(frame.func).or_insert(0) += c;
+            }
+        }
+        let mut handlers: Vec<_> = handlers.into_iter().map(|(func, c)| (self.name(func), c)).collect();
+        handlers.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (name, c) in handlers {
+            writeln!(out, "  interrupt {}: {} ({:.2}%)", name, c, percent(c))?;
+        }
+        writeln!(out)?;
+        writeln!(out, "{:>12} {:>7} {:>12} {:>7} {:>8}  function", "self", "%", "total", "%", "calls")?;
+        for f in self.functions() {
+            writeln!(out, "{:>12} {:>6.2}% {:>12} {:>6.2}% {:>8}  {}", f.self_cycles, percent(f.self_cycles),
+                     f.total_cycles, percent(f.total_cycles), f.calls, f.name)?;
+        }
+        Ok(())
+    }
+
+    /// every function with its callers and the functions it calls, with the
+    /// cycles spent in them
+    pub fn write_graph<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        // (caller, callee) -> cycles spent in the callee and below
+        let mut below: HashMap<(u32, u32), u64> = HashMap::new();
+        for (stack, &cycles) in &self.stacks {
+            let pairs: HashSet<(u32, u32)> = stack.windows(2).map(|w| (w[0].func, w[1].func)).collect();
+            for pair in pairs {
+                *below.entry(pair).or_insert(0) += cycles;
+            }
+        }
+        let totals = self.totals();
+        let mut funcs: Vec<_> = totals.iter().collect();
+        funcs.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then(a.0.cmp(b.0)));
+        for (&func, &(self_cycles, total)) in funcs {
+            writeln!(out, "{}: {} cycles, {} self, {} calls", self.name(func), total, self_cycles,
+                     self.calls.get(&func).cloned().unwrap_or(0))?;
+            let mut edges: Vec<_> = below.iter().filter(|&(&(caller, _), _)| caller == func).collect();
+            edges.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (&(_, callee), &cycles) in edges {
+                writeln!(out, "    -> {}: {} cycles, {} calls", self.name(callee), cycles,
+                         self.edges.get(&(func, callee)).cloned().unwrap_or(0))?;
+            }
+            let mut callers: Vec<_> = self.edges.iter().filter(|&(&(_, callee), _)| callee == func).collect();
+            callers.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (&(caller, _), &calls) in callers {
+                writeln!(out, "    <- {}: {} calls", self.name(caller), calls)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// a line with the functions of every call stack and its cycles, the
+    /// format of stackcollapse for flamegraph.pl and inferno
+    pub fn write_collapsed<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        let mut lines: Vec<_> = self.stacks.iter().map(|(stack, cycles)| {
+            let names: Vec<_> = stack.iter().map(|f| self.name(f.func)).collect();
+            (names.join(";"), cycles)
+        }).collect();
+        lines.sort();
+        for (stack, cycles) in lines {
+            writeln!(out, "{} {}", stack, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::Cpu;
+    use data::Instruction::*;
+    use firmware::{Firmware, Symbol, SymbolKind, Symbols};
+    use memory::Memory;
+    use super::{FunctionStats, Profiler};
+
+    fn symbols(funcs: &[(&str, u32, u32)]) -> Symbols {
+        Symbols::new(funcs.iter().map(|&(name, value, size)| Symbol {
+            name: name.to_string(), value: value, size: size, kind: SymbolKind::Func, section: Some(".text".to_string()),
+        }).collect())
+    }
+
+    fn stats(name: &str, self_cycles: u64, total_cycles: u64, calls: u64) -> FunctionStats {
+        FunctionStats { name: name.to_string(), self_cycles: self_cycles, total_cycles: total_cycles, calls: calls }
+    }
+
+    #[test]
+    fn calls() {
+        // main: rcall f; rjmp main; f: ldi r16, 1; ret
+        let firmware = Firmware::from_bytes(vec![0x01, 0xd0, 0xfe, 0xcf, 0x01, 0xe0, 0x08, 0x95]).unwrap();
+        let symbols = symbols(&[("main", 0, 4), ("f", 4, 4)]);
+        let mut cpu = Cpu::new(Memory::from_firmware(&firmware, None), false);
+        cpu.mem_mut().set_sp(0x85f);
+        cpu.set_profiler(Some(Profiler::new(symbols)));
+        for _ in 0..8 {
+            cpu.step();
+        }
+        let profiler = cpu.profiler().unwrap();
+        assert_eq!(profiler.functions(), vec![stats("f", 4, 4, 2), stats("main", 4, 8, 1)]);
+        let mut out = Vec::new();
+        profiler.write_collapsed(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "main 4\nmain;f 4\n");
+        let mut out = Vec::new();
+        profiler.write_graph(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "main: 8 cycles, 4 self, 1 calls
+    -> f: 4 cycles, 2 calls
+f: 4 cycles, 4 self, 2 calls
+    <- main: 2 calls
+");
+    }
+
+    #[test]
+    fn interrupts() {
+        let mut profiler = Profiler::new(symbols(&[("main", 0x60, 4), ("__vector_1", 0x80, 4)]));
+        profiler.record(0x60, Some(RJMP(-1)), false, 0x30, 1);
+        // the vector jumps to the handler
+        profiler.record(0x4, Some(JMP(0x40)), true, 0x40, 3);
+        profiler.record(0x80, Some(NOP), false, 0x41, 1);
+        profiler.record(0x82, Some(RETI), false, 0x30, 4);
+        profiler.record(0x60, Some(RJMP(-1)), false, 0x30, 1);
+        assert_eq!((profiler.cycles(), profiler.isr_cycles()), (10, 8));
+        let mut out = Vec::new();
+        profiler.write_collapsed(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "main 2\nmain;0x0004 3\nmain;__vector_1 5\n");
+        let mut out = Vec::new();
+        profiler.write_flat(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("10 cycles, main loop 2 (20.00%), interrupts 8 (80.00%)
+  interrupt __vector_1: 5 (50.00%)
+  interrupt 0x0004: 3 (30.00%)
+"), "{}", out);
+        assert!(out.contains("           5  50.00%            5  50.00%        1  __vector_1\n"), "{}", out);
+    }
+}
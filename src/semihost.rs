@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{stderr, stdin, stdout, Read, Write};
+use std::ops::Range;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Host calls of the firmware over registers, which are placed at
+/// the end of the data address space, where the atmega32 has no memory:
+///
+/// - 0xfff0 - 0xfff5: three 16 bit arguments, little endian
+/// - 0xfff6: writing the command executes the call
+/// - 0xfff7 - 0xfff8: the 16 bit result, 0xffff on errors
+///
+/// The calls are only executed, if semihosting is enabled.
+/// Otherwise the cpu faults on the registers like on other unmapped memory.
+pub const BASE: u16 = 0xfff0;
+const CMD: u16 = BASE + 6;
+const RET: u16 = BASE + 7;
+const END: u16 = BASE + 9;
+
+/// exit(status): stops the cpu with the exit status
+pub const SYS_EXIT: u8 = 1;
+/// write(fd, ptr, len): fd 1 is stdout and 2 stderr of the host
+pub const SYS_WRITE: u8 = 2;
+/// read(fd, ptr, len): fd 0 is stdin of the host
+pub const SYS_READ: u8 = 3;
+/// open(path, mode): path is null terminated, mode 0 reads,
+/// 1 writes and truncates and 2 appends, returns the fd
+pub const SYS_OPEN: u8 = 4;
+/// close(fd)
+pub const SYS_CLOSE: u8 = 5;
+/// time(ptr): stores the unix time of the host as 32 bit value
+pub const SYS_TIME: u8 = 6;
+/// clock(ptr): stores the milliseconds since the start of the vm as 32 bit value
+pub const SYS_CLOCK: u8 = 7;
+
+const ERROR: u16 = 0xffff;
+// the first fd returned by open, 0 - 2 are the standard streams
+const FIRST_FILE: usize = 3;
+
+/// the result of a host call, the history journals them, so that the
+/// calls aren't executed again, when the cpu replays them
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostCall {
+    ret: u16,
+    exit: Option<u8>,
+    // the address and the bytes, which the call wrote into the memory
+    written: Option<(usize, Vec<u8>)>,
+}
+
+pub struct Semihost {
+    enabled: bool,
+    args: [u8; 6],
+    ret: u16,
+    exit: Option<u8>,
+    files: Vec<Option<File>>,
+    start: Instant,
+    // the memory, which the last call wrote
+    written: Option<Range<usize>>,
+    // the last call since it was taken
+    last_call: Option<HostCall>,
+    // the results of the calls, which are replayed instead of calling the host
+    replay: Option<VecDeque<HostCall>>,
+}
+
+impl Default for Semihost {
+    fn default() -> Semihost {
+        Semihost::new()
+    }
+}
+
+impl Semihost {
+    pub fn new() -> Semihost {
+        Semihost {
+            enabled: false, args: [0; 6], ret: 0, exit: None, files: Vec::new(), start: Instant::now(), written: None,
+            last_call: None, replay: None,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// the status, if the firmware called exit
+    pub fn exit_status(&self) -> Option<u8> {
+        self.exit
+    }
+
+    pub fn set_exit_status(&mut self, exit: Option<u8>) {
+        self.exit = exit;
+    }
+
+    #[inline]
+    pub fn is_reg(index: u16) -> bool {
+        (BASE..END).contains(&index)
+    }
+
+    pub fn peek(&self, index: u16) -> u8 {
+        match index {
+            _ if !self.enabled => 0,
+            RET => self.ret as u8,
+            _ if index == RET + 1 => (self.ret >> 8) as u8,
+            CMD => 0,
+            _ => self.args[(index - BASE) as usize],
+        }
+    }
+
+    /// a write by the cpu, data is the memory, on which pointers point
+    pub fn write(&mut self, index: u16, val: u8, data: &mut [u8]) {
+        if !self.enabled {
+            return;
+        }
+        match index {
+            CMD if self.replay.is_some() => {
+                let call = self.replay.as_mut().and_then(|r| r.pop_front());
+                self.replay_call(call, data);
+            }
+            CMD => {
+                self.written = None;
+                self.ret = self.call(val, data).unwrap_or(ERROR);
+                let written = self.written.clone().map(|range| (range.start, data[range].to_vec()));
+                self.last_call = Some(HostCall { ret: self.ret, exit: self.exit, written: written });
+            }
+            _ if index < CMD => self.args[(index - BASE) as usize] = val,
+            _ => {}
+        }
+    }
+
+    /// the range of the data memory, which the last call wrote
+    pub fn take_written(&mut self) -> Option<Range<usize>> {
+        self.written.take()
+    }
+
+    /// the result of the last call, for the history
+    pub fn take_call(&mut self) -> Option<HostCall> {
+        self.last_call.take()
+    }
+
+    /// the calls return these results in order instead of calling the host,
+    /// until it is set to None
+    pub fn set_replay(&mut self, calls: Option<VecDeque<HostCall>>) {
+        self.replay = calls;
+    }
+
+    fn replay_call(&mut self, call: Option<HostCall>, data: &mut [u8]) {
+        let call = match call {
+            Some(call) => call,
+            // the journal doesn't contain the call
+            None => {
+                self.ret = ERROR;
+                return;
+            }
+        };
+        self.ret = call.ret;
+        self.exit = call.exit;
+        if let Some((start, bytes)) = call.written {
+            data[start..start + bytes.len()].copy_from_slice(&bytes);
+            self.written = Some(start..start + bytes.len());
+        }
+    }
+
+    fn arg(&self, i: usize) -> u16 {
+        self.args[i * 2] as u16 | (self.args[i * 2 + 1] as u16) << 8
+    }
+
+    fn call(&mut self, cmd: u8, data: &mut [u8]) -> Option<u16> {
+        let (a0, a1, a2) = (self.arg(0), self.arg(1), self.arg(2));
+        match cmd {
+            SYS_EXIT => {
+                self.exit = Some(a0 as u8);
+                Some(0)
+            }
+            SYS_WRITE => {
+                let buf = data.get(a1 as usize..a1 as usize + a2 as usize)?;
+                match a0 {
+                    1 => stdout().write_all(buf).and_then(|_| stdout().flush()).ok()?,
+                    2 => stderr().write_all(buf).ok()?,
+                    fd => self.file(fd)?.write_all(buf).ok()?,
+                }
+                Some(a2)
+            }
+            SYS_READ => {
+                let buf = data.get_mut(a1 as usize..a1 as usize + a2 as usize)?;
+                let n = match a0 {
+                    0 => stdin().read(buf).ok()?,
+                    fd => self.file(fd)?.read(buf).ok()?,
+                };
+                self.written = Some(a1 as usize..a1 as usize + n);
+                Some(n as u16)
+            }
+            SYS_OPEN => {
+                let path = data.get(a0 as usize..)?;
+                let path = String::from_utf8_lossy(&path[..path.iter().position(|b| *b == 0)?]).into_owned();
+                let file = match a1 {
+                    0 => File::open(path),
+                    1 => File::create(path),
+                    2 => OpenOptions::new().append(true).create(true).open(path),
+                    _ => return None,
+                }.ok()?;
+                let fd = match self.files.iter().position(|f| f.is_none()) {
+                    Some(i) => i,
+                    None => {
+                        self.files.push(None);
+                        self.files.len() - 1
+                    }
+                };
+                self.files[fd] = Some(file);
+                Some((fd + FIRST_FILE) as u16)
+            }
+            SYS_CLOSE => {
+                self.file(a0)?;
+                self.files[a0 as usize - FIRST_FILE] = None;
+                Some(0)
+            }
+            SYS_TIME => {
+                let secs = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as u32;
+                self.written = Some(a0 as usize..a0 as usize + 4);
+                store32(data, a0, secs)
+            }
+            SYS_CLOCK => {
+                let elapsed = self.start.elapsed();
+                self.written = Some(a0 as usize..a0 as usize + 4);
+                store32(data, a0, (elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64) as u32)
+            }
+            _ => None,
+        }
+    }
+
+    fn file(&mut self, fd: u16) -> Option<&mut File> {
+        (fd as usize).checked_sub(FIRST_FILE)
+            .and_then(move |i| self.files.get_mut(i))
+            .and_then(|f| f.as_mut())
+    }
+}
+
+fn store32(data: &mut [u8], ptr: u16, val: u32) -> Option<u16> {
+    let buf = data.get_mut(ptr as usize..ptr as usize + 4)?;
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (val >> (i * 8)) as u8;
+    }
+    Some(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::File;
+    use std::io::Read;
+    use std::thread;
+    use std::time::Duration;
+    use super::{Semihost, BASE, SYS_CLOCK, SYS_CLOSE, SYS_EXIT, SYS_OPEN, SYS_READ, SYS_WRITE};
+
+    fn call(sh: &mut Semihost, data: &mut [u8], cmd: u8, args: [u16; 3]) -> u16 {
+        for (i, arg) in args.iter().enumerate() {
+            sh.write(BASE + i as u16 * 2, *arg as u8, data);
+            sh.write(BASE + i as u16 * 2 + 1, (*arg >> 8) as u8, data);
+        }
+        sh.write(BASE + 6, cmd, data);
+        sh.peek(BASE + 7) as u16 | (sh.peek(BASE + 8) as u16) << 8
+    }
+
+    #[test]
+    fn disabled() {
+        let mut sh = Semihost::new();
+        let mut data = [0; 16];
+        assert_eq!(call(&mut sh, &mut data, SYS_EXIT, [1, 0, 0]), 0);
+        assert_eq!(sh.exit_status(), None);
+    }
+
+    #[test]
+    fn files() {
+        let mut sh = Semihost::new();
+        sh.set_enabled(true);
+        let path = temp_dir().join("vm-semihost.txt");
+        let mut data = [0; 64];
+        data[..path.to_str().unwrap().len()].copy_from_slice(path.to_str().unwrap().as_bytes());
+        data[48..53].copy_from_slice(b"hello");
+
+        let fd = call(&mut sh, &mut data, SYS_OPEN, [0, 1, 0]);
+        assert_eq!(fd, 3);
+        assert_eq!(call(&mut sh, &mut data, SYS_WRITE, [fd, 48, 5]), 5);
+        assert_eq!(call(&mut sh, &mut data, SYS_CLOSE, [fd, 0, 0]), 0);
+        assert_eq!(call(&mut sh, &mut data, SYS_CLOSE, [fd, 0, 0]), 0xffff);
+        let mut content = String::new();
+        File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello");
+
+        let fd = call(&mut sh, &mut data, SYS_OPEN, [0, 0, 0]);
+        assert_eq!(call(&mut sh, &mut data, SYS_READ, [fd, 56, 8]), 5);
+        assert_eq!(&data[56..61], b"hello");
+        // pointers outside of the memory
+        assert_eq!(call(&mut sh, &mut data, SYS_READ, [fd, 60, 8]), 0xffff);
+
+        assert_eq!(call(&mut sh, &mut data, SYS_EXIT, [3, 0, 0]), 0);
+        assert_eq!(sh.exit_status(), Some(3));
+    }
+
+    #[test]
+    fn replay() {
+        let mut sh = Semihost::new();
+        sh.set_enabled(true);
+        let mut data = [0; 16];
+        assert_eq!(call(&mut sh, &mut data, SYS_CLOCK, [8, 0, 0]), 0);
+        let recorded = sh.take_call().unwrap();
+        assert!(sh.take_call().is_none());
+
+        // the clock isn't read again and nothing is written to stdout
+        let clock = data[8..12].to_vec();
+        data = [0; 16];
+        sh.set_replay(Some(vec![recorded].into_iter().collect()));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(call(&mut sh, &mut data, SYS_CLOCK, [8, 0, 0]), 0);
+        assert_eq!(data[8..12].to_vec(), clock);
+        assert_eq!(sh.take_written(), Some(8..12));
+        assert_eq!(call(&mut sh, &mut data, SYS_WRITE, [1, 0, 4]), 0xffff);
+        assert!(sh.take_call().is_none());
+        sh.set_replay(None);
+    }
+}
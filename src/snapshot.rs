@@ -0,0 +1,237 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use cpu::HaltReason;
+use fault::FAULT_KINDS;
+
+/// a snapshot file starts with the magic and the version, followed by the fields
+/// of the snapshot in their order, all numbers are little endian:
+///
+/// - program_hash: u32, ip: u32, cycles: u64, sleeping: u8
+/// - halted: u8 (0 running, 1 nop, 2 break, 3 sleep, 4 addr, 5 exit, 6 fault) and its value: u32,
+///   the index in FAULT_KINDS for faults
+/// - data: u16 length and the bytes
+/// - ports: ddr and port of A - D, port_prev: 2 bytes, timer_steps: u32
+/// - uart_input: u32 length and the bytes
+/// - wires: u8 count and their mV as u16, 0 without wires
+pub const MAGIC: &[u8] = b"AVRSNAP\0";
+pub const VERSION: u8 = 1;
+
+/// the complete state of the machine, which is restored with Cpu::restore,
+/// there is no eeprom in the vm
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Snapshot {
+    // the flash can't change, it must be the same on restore
+    pub program_hash: u32,
+    // word address
+    pub ip: u32,
+    pub cycles: u64,
+    pub sleeping: bool,
+    pub halted: Option<HaltReason>,
+    // registers, io registers and sram
+    pub data: Vec<u8>,
+    // ddr and port of the ports A - D
+    pub ports: [(u8, u8); 4],
+    // the previous pins for the external interrupts
+    pub port_prev: [u8; 2],
+    // the cycles since the last tick of the prescaler of timer 1
+    pub timer_steps: u32,
+    // the bytes, which weren't received yet
+    pub uart_input: Vec<u8>,
+    // nreset, vcc, gnd and PA0 - PD7, if the memory is connected to wires
+    pub wires: Option<Vec<u16>>,
+}
+
+impl Snapshot {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+        Snapshot::read(io::BufReader::new(File::open(path)?))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = io::BufWriter::new(File::create(path)?);
+        self.write(&mut out)?;
+        out.flush()
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        out.write_all(&self.program_hash.to_le_bytes())?;
+        out.write_all(&self.ip.to_le_bytes())?;
+        out.write_all(&self.cycles.to_le_bytes())?;
+        out.write_all(&[self.sleeping as u8])?;
+        let (tag, value) = match self.halted {
+            None => (0, 0),
+            Some(HaltReason::Nop) => (1, 0),
+            Some(HaltReason::Break) => (2, 0),
+            Some(HaltReason::Sleep) => (3, 0),
+            Some(HaltReason::Addr(addr)) => (4, addr as u32),
+            Some(HaltReason::Exit(status)) => (5, status as u32),
+            Some(HaltReason::Fault(kind)) => (6, FAULT_KINDS.iter().position(|&k| k == kind).unwrap() as u32),
+        };
+        out.write_all(&[tag])?;
+        out.write_all(&value.to_le_bytes())?;
+        out.write_all(&(self.data.len() as u16).to_le_bytes())?;
+        out.write_all(&self.data)?;
+        for &(ddr, port) in &self.ports {
+            out.write_all(&[ddr, port])?;
+        }
+        out.write_all(&self.port_prev)?;
+        out.write_all(&self.timer_steps.to_le_bytes())?;
+        out.write_all(&(self.uart_input.len() as u32).to_le_bytes())?;
+        out.write_all(&self.uart_input)?;
+        match self.wires {
+            Some(ref wires) => {
+                out.write_all(&[wires.len() as u8])?;
+                for mv in wires {
+                    out.write_all(&mv.to_le_bytes())?;
+                }
+                Ok(())
+            }
+            None => out.write_all(&[0]),
+        }
+    }
+
+    pub fn read<R: Read>(mut input: R) -> io::Result<Snapshot> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        let version = read_u8(&mut input)?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported snapshot version {}", version)));
+        }
+
+        let program_hash = read_u32(&mut input)?;
+        let ip = read_u32(&mut input)?;
+        let mut cycles = [0; 8];
+        input.read_exact(&mut cycles)?;
+        let sleeping = read_u8(&mut input)? != 0;
+        let tag = read_u8(&mut input)?;
+        let value = read_u32(&mut input)?;
+        let halted = match tag {
+            0 => None,
+            1 => Some(HaltReason::Nop),
+            2 => Some(HaltReason::Break),
+            3 => Some(HaltReason::Sleep),
+            4 => Some(HaltReason::Addr(value as usize)),
+            5 => Some(HaltReason::Exit(value as u8)),
+            6 => match FAULT_KINDS.get(value as usize) {
+                Some(&kind) => Some(HaltReason::Fault(kind)),
+                None => return Err(invalid("invalid fault")),
+            },
+            _ => return Err(invalid("invalid halt reason")),
+        };
+        let len = read_u16(&mut input)? as usize;
+        let data = read_bytes(&mut input, len)?;
+        let mut ports = [(0, 0); 4];
+        for port in ports.iter_mut() {
+            *port = (read_u8(&mut input)?, read_u8(&mut input)?);
+        }
+        let port_prev = [read_u8(&mut input)?, read_u8(&mut input)?];
+        let timer_steps = read_u32(&mut input)?;
+        let len = read_u32(&mut input)? as usize;
+        let uart_input = read_bytes(&mut input, len)?;
+        let wires = match read_u8(&mut input)? {
+            0 => None,
+            n => Some((0..n).map(|_| read_u16(&mut input)).collect::<io::Result<Vec<u16>>>()?),
+        };
+
+        Ok(Snapshot {
+            program_hash: program_hash, ip: ip, cycles: u64::from_le_bytes(cycles), sleeping: sleeping,
+            halted: halted, data: data, ports: ports, port_prev: port_prev, timer_steps: timer_steps,
+            uart_input: uart_input, wires: wires,
+        })
+    }
+}
+
+/// FNV-1a of the flash
+pub fn hash(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, b| (hash ^ *b as u32).wrapping_mul(0x0100_0193))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    input.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16<R: Read>(input: &mut R) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    input.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_bytes<R: Read>(input: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use cpu::HaltReason;
+    use firmware::Firmware;
+    use harness::{Board, Harness};
+    use super::Snapshot;
+
+    // ldi r16, 0xff; out DDRB, r16; out PORTB, r16; ldi r16, 0; out PORTB, r16; rjmp .-2
+    const BLINK: [u8; 12] = [0x0f, 0xef, 0x07, 0xbb, 0x08, 0xbb, 0x00, 0xe0, 0x08, 0xbb, 0xff, 0xcf];
+
+    fn harness(program: &[u8]) -> Harness {
+        Harness::from_firmware(Arc::new(Board::new()), Firmware::from_bytes(program.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn restore() {
+        let mut h = harness(&BLINK);
+        h.run(3);
+        h.cpu_mut().mem_mut().uart_mut().send(b"hi");
+        let snapshot = h.cpu().snapshot();
+        assert_eq!(snapshot.ports[1], (0xff, 0xff));
+        assert_eq!(snapshot.uart_input, b"hi");
+        h.run(3);
+        let later = h.cpu().snapshot();
+        assert_ne!(later, snapshot);
+        assert_eq!(h.board().io().p[1][0].as_bin(), 0);
+
+        h.cpu_mut().restore(&snapshot).unwrap();
+        assert_eq!(h.cycles(), 3);
+        assert_eq!(h.board().io().p[1][0].as_bin(), 1);
+        assert_eq!(h.cpu().snapshot(), snapshot);
+        h.run(3);
+        assert_eq!(h.cpu().snapshot(), later);
+
+        let mut other = harness(&BLINK[..10]);
+        assert!(other.cpu_mut().restore(&snapshot).is_err());
+    }
+
+    #[test]
+    fn file() {
+        let mut h = harness(&BLINK);
+        h.run(5);
+        let mut snapshot = h.cpu().snapshot();
+        snapshot.halted = Some(HaltReason::Addr(0x1a4));
+        let mut bytes = Vec::new();
+        snapshot.write(&mut bytes).unwrap();
+        assert!(bytes.starts_with(b"AVRSNAP\0\x01"));
+        assert_eq!(Snapshot::read(&bytes[..]).unwrap(), snapshot);
+
+        assert!(Snapshot::read(&bytes[..bytes.len() - 1]).is_err());
+        bytes[8] = 2;
+        assert!(Snapshot::read(&bytes[..]).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use firmware::Firmware;
+use memory::SRAM_SIZE;
+
+// the first byte of the sram after the io registers
+pub const SRAM_START: u16 = 0x60;
+pub const RAMEND: u16 = SRAM_SIZE as u16 - 1;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum StackErrorKind {
+    // the stack grew below the limit into .data or .bss
+    Overflow,
+    // the stack grew into the io registers or the registers
+    IoRegion,
+    // a pop above RAMEND
+    Underflow,
+    // a ret or reti jumped to the byte address outside the program
+    BadReturn(usize),
+    // a ret or reti didn't return behind its call, see CallStack
+    UnexpectedReturn { expected: usize, actual: usize },
+}
+
+/// an error of the stack found by the StackGuard
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct StackError {
+    pub kind: StackErrorKind,
+    // byte address of the instruction
+    pub pc: usize,
+    // after the instruction
+    pub sp: u16,
+}
+
+impl StackError {
+    pub fn describe(&self) -> String {
+        match self.kind {
+            StackErrorKind::Overflow => format!("stack overflow into .data/.bss, sp {:#06x}", self.sp),
+            StackErrorKind::IoRegion => format!("stack overflow into the io registers, sp {:#06x}", self.sp),
+            StackErrorKind::Underflow => format!("stack underflow above RAMEND, sp {:#06x}", self.sp),
+            StackErrorKind::BadReturn(addr) => format!("return to {:#06x} outside the program", addr),
+            StackErrorKind::UnexpectedReturn { expected, actual } =>
+                format!("return to {:#06x} instead of {:#06x}, the stack was overwritten", actual, expected),
+        }
+    }
+}
+
+/// checks the stack pointer after every instruction, which changed it,
+/// and the targets of returns
+///
+/// the sp is only checked, when it changes, so the reset value 0 isn't reported
+/// before the startup code initialized it
+#[derive(Debug, Clone)]
+pub struct StackGuard {
+    // the lowest address, the stack may use, it occupies sp + 1 to RAMEND
+    limit: u16,
+    // byte size of the program, returns must jump below it
+    program_len: usize,
+    sp: u16,
+    // the lowest sp in the sram since the guard was set
+    lowest: Option<u16>,
+}
+
+impl StackGuard {
+    pub fn new(limit: u16, program_len: usize) -> StackGuard {
+        StackGuard { limit: limit.max(SRAM_START), program_len: program_len, sp: 0, lowest: None }
+    }
+
+    /// the stack may grow down to __heap_start, the end of .bss, or only
+    /// to the start of the sram, if the firmware has no such symbol
+    pub fn from_firmware(firmware: &Firmware) -> StackGuard {
+        let limit = ["__heap_start", "__bss_end"].iter()
+            .filter_map(|name| firmware.symbols.get(name).and_then(|s| s.data_addr()))
+            .next()
+            .unwrap_or(SRAM_START);
+        StackGuard::new(limit, firmware.program.len())
+    }
+
+    pub fn limit(&self) -> u16 {
+        self.limit
+    }
+
+    /// the high-water mark of the stack, the lowest sp in the sram
+    pub fn lowest_sp(&self) -> Option<u16> {
+        self.lowest
+    }
+
+    /// the maximum number of bytes on the stack, if it starts at RAMEND
+    pub fn max_depth(&self) -> Option<u16> {
+        self.lowest.map(|sp| RAMEND.saturating_sub(sp))
+    }
+
+    /// checks the state after an instruction at pc, ret is set for ret and reti
+    pub fn check(&mut self, pc: usize, sp: u16, ip: usize, ret: bool) -> Option<StackError> {
+        let error = |kind| Some(StackError { kind: kind, pc: pc, sp: sp });
+        if ret && (ip << 1) >= self.program_len {
+            self.sp = sp;
+            return error(StackErrorKind::BadReturn(ip << 1));
+        }
+        if sp == self.sp {
+            return None;
+        }
+        // a push at 0 wraps around
+        let pushed = self.sp.wrapping_sub(sp) < 0x8000;
+        self.sp = sp;
+        if sp > RAMEND && !pushed {
+            error(StackErrorKind::Underflow)
+        } else if sp > RAMEND || sp + 1 < SRAM_START {
+            error(StackErrorKind::IoRegion)
+        } else {
+            self.lowest = Some(self.lowest.map_or(sp, |lowest| lowest.min(sp)));
+            if sp + 1 < self.limit { error(StackErrorKind::Overflow) } else { None }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::Cpu;
+    use fault::{FaultKind, FaultPolicies, FaultPolicy};
+    use firmware::Firmware;
+    use memory::Memory;
+    use super::{StackError, StackErrorKind, StackGuard};
+
+    fn cpu(program: &[u8], sp: u16, limit: u16) -> Cpu {
+        let mem = Memory::from_firmware(&Firmware::from_bytes(program.to_vec()).unwrap(), None);
+        let mut cpu = Cpu::new(mem, false);
+        cpu.mem_mut().set_sp(sp);
+        cpu.set_stack_guard(Some(StackGuard::new(limit, program.len())));
+        cpu
+    }
+
+    #[test]
+    fn overflow() {
+        // push r0; rjmp .-4
+        let mut cpu = cpu(&[0x0f, 0x92, 0xfe, 0xcf], 0x65, 0x64);
+        let mut errors = Vec::new();
+        for _ in 0..14 {
+            cpu.step();
+            errors.extend(cpu.stack_error().map(|e| (e.kind, e.sp)));
+        }
+        // the stack starts at sp + 1
+        assert_eq!(errors, vec![(StackErrorKind::Overflow, 0x62), (StackErrorKind::Overflow, 0x61),
+                                (StackErrorKind::Overflow, 0x60), (StackErrorKind::Overflow, 0x5f),
+                                (StackErrorKind::IoRegion, 0x5e)]);
+        let guard = cpu.stack_guard().unwrap();
+        assert_eq!((guard.lowest_sp(), guard.max_depth()), (Some(0x5f), Some(0x800)));
+
+        cpu.mem_mut().set_sp(0);
+        cpu.step();
+        assert_eq!(cpu.stack_error().map(|e| (e.kind, e.sp)), Some((StackErrorKind::IoRegion, 0xffff)));
+    }
+
+    #[test]
+    fn returns() {
+        // ret
+        let mut cpu = cpu(&[0x08, 0x95], 0x85d, 0x60);
+        cpu.mem_mut().set_data(0x85e, 0x40);
+        cpu.step();
+        assert_eq!(cpu.stack_error(), Some(StackError { kind: StackErrorKind::BadReturn(0x8000), pc: 0, sp: 0x85f }));
+
+        let mut cpu = self::cpu(&[0x08, 0x95], 0x85f, 0x60);
+        let mut policies = FaultPolicies::default();
+        policies.set(FaultKind::InvalidRead, FaultPolicy::Warn);
+        cpu.set_fault_policies(policies);
+        cpu.step();
+        assert_eq!(cpu.stack_error().map(|e| (e.kind, e.sp)), Some((StackErrorKind::Underflow, 0x861)));
+        assert_eq!(cpu.fault().map(|f| (f.kind, f.addr)), Some((FaultKind::InvalidRead, 0x860)));
+        assert_eq!(cpu.ip(), 0);
+        cpu.step();
+        assert_eq!(cpu.stack_error().map(|e| (e.kind, e.sp)), Some((StackErrorKind::Underflow, 0x863)));
+    }
+}
@@ -0,0 +1,328 @@
+use std::io;
+use std::io::prelude::*;
+use cpu::Cpu;
+use debugger::IO_NAMES;
+use disasm::Disassembler;
+use firmware::{Firmware, SymbolKind, Symbols};
+
+/// the binary trace starts with this header, followed by one record per instruction:
+///
+/// - cycles: u64, pc: u16 (byte address)
+/// - number of changes: u8, each with the register and its new value as u8,
+///   r0 - r31 are 0 - 31, then follow SREG, SPL and SPH
+/// - number of accesses: u8, each with the kind (0 read, 1 write): u8,
+///   the address in the data memory: u16 and the value: u8
+///
+/// all numbers are little endian
+pub const MAGIC: &[u8] = b"AVRTRACE";
+pub const VERSION: u8 = 1;
+pub const REG_SREG: u8 = 32;
+pub const REG_SPL: u8 = 33;
+pub const REG_SPH: u8 = 34;
+
+// bit 7 to 0 of SREG
+const SREG_FLAGS: &[u8; 8] = b"ITHSVNZC";
+const IO_OFFSET: u16 = 0x20;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TraceFormat {
+    Text, Binary
+}
+
+/// a read or write of the cpu in the data memory
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Access {
+    pub write: bool,
+    pub addr: u16,
+    pub value: u8,
+}
+
+/// an executed instruction
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Record {
+    pub cycles: u64,
+    // byte address
+    pub pc: u16,
+    // the registers, which changed, with their new value
+    pub changes: Vec<(u8, u8)>,
+    pub accesses: Vec<Access>,
+}
+
+/// the registers before an instruction
+#[derive(Copy, Clone)]
+pub struct State {
+    cycles: u64,
+    pc: u16,
+    regs: [u8; 35],
+}
+
+impl State {
+    pub fn capture(cpu: &Cpu) -> State {
+        let mut regs = [0; 35];
+        for (r, reg) in regs.iter_mut().take(32).enumerate() {
+            *reg = cpu.reg(r as u8);
+        }
+        regs[REG_SREG as usize] = cpu.flags();
+        regs[REG_SPL as usize] = cpu.mem().sp() as u8;
+        regs[REG_SPH as usize] = (cpu.mem().sp() >> 8) as u8;
+        State { cycles: cpu.cycles(), pc: (cpu.ip() << 1) as u16, regs: regs }
+    }
+}
+
+/// writes a record for every executed instruction, which is in one of the ranges
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    // the memory accesses are part of the trace
+    memory: bool,
+    // byte addresses, everything is traced if it is empty
+    ranges: Vec<(u32, u32)>,
+    symbols: Symbols,
+    disasm: Disassembler,
+    header: bool,
+}
+
+impl Tracer {
+    pub fn new(firmware: &Firmware, out: Box<dyn Write + Send>, format: TraceFormat) -> Tracer {
+        Tracer {
+            out: out, format: format, memory: false, ranges: Vec::new(),
+            symbols: firmware.symbols.clone(), disasm: Disassembler::new(firmware, false), header: false,
+        }
+    }
+
+    pub fn set_memory(&mut self, memory: bool) {
+        self.memory = memory;
+    }
+
+    pub fn memory(&self) -> bool {
+        self.memory
+    }
+
+    /// traces the instructions from start to end (exclusive), byte addresses
+    pub fn add_range(&mut self, start: u32, end: u32) {
+        self.ranges.push((start, end));
+    }
+
+    /// traces the instructions of the function, returns false, if it is unknown
+    pub fn add_function(&mut self, name: &str) -> bool {
+        let range = match self.symbols.get(name) {
+            Some(sym) if sym.kind == SymbolKind::Func => (sym.value, sym.value + sym.size.max(2)),
+            _ => return false,
+        };
+        self.ranges.push(range);
+        true
+    }
+
+    pub fn traces(&self, pc: usize) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|&(start, end)| start <= pc as u32 && (pc as u32) < end)
+    }
+
+    /// writes the instruction, which started in the state before
+    pub fn record(&mut self, cpu: &Cpu, before: State, accesses: Vec<Access>) {
+        let after = State::capture(cpu);
+        let changes = (0..35).filter(|&r| before.regs[r] != after.regs[r])
+            .map(|r| (r as u8, after.regs[r]))
+            .collect();
+        let record = Record {
+            cycles: before.cycles, pc: before.pc, changes: changes,
+            accesses: if self.memory { accesses } else { Vec::new() },
+        };
+        match self.format {
+            TraceFormat::Text => self.write_text(cpu, &record, &before),
+            TraceFormat::Binary => self.write_binary(&record),
+        }.expect("Couldn't write the trace");
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn write_text(&mut self, cpu: &Cpu, record: &Record, before: &State) -> io::Result<()> {
+        let instr = cpu.mem().get_instruction((record.pc >> 1) as usize);
+        let text = self.disasm.instruction(instr, record.pc as u32).replace("\t; ", "  ; ").replace('\t', " ");
+        let mut line = format!("{:>10} {:<24} {:<40}", record.cycles, self.disasm.location(record.pc as u32), text);
+        let mut sp = false;
+        for &(r, val) in &record.changes {
+            match r {
+                REG_SREG => for (i, flag) in SREG_FLAGS.iter().enumerate() {
+                    let bit = 7 - i;
+                    if (before.regs[r as usize] ^ val) >> bit & 1 == 1 {
+                        line.push_str(&format!(" {}={}", *flag as char, val >> bit & 1));
+                    }
+                },
+                REG_SPL | REG_SPH => sp = true,
+                _ => line.push_str(&format!(" r{}={:02x}", r, val)),
+            }
+        }
+        if sp {
+            line.push_str(&format!(" SP={:04x}", cpu.mem().sp()));
+        }
+        for access in &record.accesses {
+            line.push_str(&format!(" {}[{}]={:02x}", if access.write { "W" } else { "R" },
+                                   self.data_name(access.addr), access.value));
+        }
+        writeln!(self.out, "{}", line.trim_end())
+    }
+
+    fn write_binary(&mut self, record: &Record) -> io::Result<()> {
+        if !self.header {
+            self.out.write_all(MAGIC)?;
+            self.out.write_all(&[VERSION])?;
+            self.header = true;
+        }
+        let mut buf = Vec::with_capacity(16);
+        for i in 0..8 {
+            buf.push((record.cycles >> (i * 8)) as u8);
+        }
+        buf.extend_from_slice(&[record.pc as u8, (record.pc >> 8) as u8, record.changes.len() as u8]);
+        for &(r, val) in &record.changes {
+            buf.extend_from_slice(&[r, val]);
+        }
+        assert!(record.accesses.len() < 256, "too many memory accesses for one instruction");
+        buf.push(record.accesses.len() as u8);
+        for access in &record.accesses {
+            buf.extend_from_slice(&[access.write as u8, access.addr as u8, (access.addr >> 8) as u8, access.value]);
+        }
+        self.out.write_all(&buf)
+    }
+
+    // io registers and variables by name
+    fn data_name(&self, addr: u16) -> String {
+        if addr >= IO_OFFSET && addr < IO_OFFSET + IO_NAMES.len() as u16 {
+            return IO_NAMES[(addr - IO_OFFSET) as usize].to_string();
+        }
+        match self.symbols.object_at(addr) {
+            Some(sym) if sym.data_addr() == Some(addr) => sym.name.clone(),
+            Some(sym) => format!("{}+{}", sym.name, addr - sym.data_addr().unwrap()),
+            None => format!("{:#06x}", addr),
+        }
+    }
+}
+
+/// reads a binary trace
+pub fn read_binary<R: Read>(mut input: R) -> io::Result<Vec<Record>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    if bytes.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !bytes.starts_with(MAGIC) || bytes.get(MAGIC.len()) != Some(&VERSION) {
+        return Err(invalid("not a binary trace of this version"));
+    }
+
+    let mut records = Vec::new();
+    let mut pos = MAGIC.len() + 1;
+    let mut next = |n: usize| -> io::Result<&[u8]> {
+        let slice = bytes.get(pos..pos + n).ok_or_else(|| invalid("unexpected end of the trace"))?;
+        pos += n;
+        Ok(slice)
+    };
+    while let Ok(head) = next(11).map(|head| head.to_vec()) {
+        let cycles = head[..8].iter().rev().fold(0, |acc, b| acc << 8 | *b as u64);
+        let pc = head[8] as u16 | (head[9] as u16) << 8;
+        let changes = next(head[10] as usize * 2)?.chunks(2).map(|c| (c[0], c[1])).collect();
+        let n = next(1)?[0] as usize;
+        let accesses = next(n * 4)?.chunks(4)
+            .map(|a| Access { write: a[0] == 1, addr: a[1] as u16 | (a[2] as u16) << 8, value: a[3] })
+            .collect();
+        records.push(Record { cycles: cycles, pc: pc, changes: changes, accesses: accesses });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+    use std::sync::{Arc, Mutex};
+    use cpu::Cpu;
+    use firmware::{Firmware, Section, Symbol, SymbolKind, Symbols};
+    use memory::Memory;
+    use super::{read_binary, Access, TraceFormat, Tracer};
+
+    // ldi r16, 'A'; sts 0x60, r16; subi r16, -1; rjmp .-8
+    const COUNTER: [u8; 10] = [0x01, 0xe4, 0x00, 0x93, 0x60, 0x00, 0x0f, 0x5f, 0xfc, 0xcf];
+
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // runs the counter for the number of steps and returns the trace
+    fn trace(format: TraceFormat, steps: usize, setup: fn(&mut Tracer)) -> Vec<u8> {
+        let mut firmware = Firmware::from_bytes(COUNTER.to_vec()).unwrap();
+        firmware.symbols = Symbols::new(vec![
+            Symbol { name: "main".to_string(), value: 0, size: 6, kind: SymbolKind::Func, section: Some(".text".to_string()) },
+            Symbol { name: "loop".to_string(), value: 6, size: 4, kind: SymbolKind::Func, section: Some(".text".to_string()) },
+            Symbol { name: "counter".to_string(), value: 0x800060, size: 1, kind: SymbolKind::Object, section: Some(".data".to_string()) },
+        ]);
+        firmware.code = vec![Section { name: ".text".to_string(), addr: 0, size: 10 }];
+        let out = Shared(Arc::new(Mutex::new(Vec::new())));
+        let mut tracer = Tracer::new(&firmware, Box::new(out.clone()), format);
+        setup(&mut tracer);
+        let mut cpu = Cpu::new(Memory::from_firmware(&firmware, None), false);
+        cpu.set_tracer(Some(tracer));
+        for _ in 0..steps {
+            cpu.step();
+        }
+        cpu.set_tracer(None);
+        let bytes = out.0.lock().unwrap().clone();
+        bytes
+    }
+
+    #[test]
+    fn text() {
+        let out = String::from_utf8(trace(TraceFormat::Text, 5, |_| {})).unwrap();
+        assert_eq!(out, "         1 0x0 <main>               ldi r16, 0x41  ; 65                      r16=41
+         2 0x2 <main+0x2>           sts 0x0060, r16  ; 0x800060 <counter>
+         3 0x6 <loop>               subi r16, 0xFF  ; 255                    r16=42 H=1 C=1
+         4 0x8 <loop+0x2>           rjmp .-8        ; 0x2 <main+0x2>
+         5 0x2 <main+0x2>           sts 0x0060, r16  ; 0x800060 <counter>
+");
+    }
+
+    #[test]
+    fn memory() {
+        let out = String::from_utf8(trace(TraceFormat::Text, 2, |t| t.set_memory(true))).unwrap();
+        assert_eq!(out.lines().nth(1).unwrap(),
+                   "         2 0x2 <main+0x2>           sts 0x0060, r16  ; 0x800060 <counter>    W[counter]=41");
+    }
+
+    #[test]
+    fn filter() {
+        let out = String::from_utf8(trace(TraceFormat::Text, 8, |t| assert!(t.add_function("loop")))).unwrap();
+        let pcs: Vec<_> = out.lines().map(|l| l.split_whitespace().nth(1).unwrap()).collect();
+        assert_eq!(pcs, vec!["0x6", "0x8", "0x6", "0x8"]);
+
+        let out = String::from_utf8(trace(TraceFormat::Text, 8, |t| {
+            assert!(!t.add_function("counter"));
+            t.add_range(0, 4);
+        })).unwrap();
+        let cycles: Vec<_> = out.lines().map(|l| l.split_whitespace().next().unwrap()).collect();
+        assert_eq!(cycles, vec!["1", "2", "5", "8"]);
+    }
+
+    #[test]
+    fn binary() {
+        let bytes = trace(TraceFormat::Binary, 3, |t| t.set_memory(true));
+        assert!(bytes.starts_with(b"AVRTRACE\x01"));
+        let records = read_binary(&bytes[..]).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!((records[0].cycles, records[0].pc, &records[0].changes[..]), (1, 0, &[(16, 0x41)][..]));
+        assert_eq!(records[1].accesses, vec![Access { write: true, addr: 0x60, value: 0x41 }]);
+        // r16 and SREG
+        assert_eq!((records[2].pc, &records[2].changes[..]), (6, &[(16, 0x42), (32, 0x21)][..]));
+
+        assert!(read_binary(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_binary(&b"AVRTRACE\x02"[..]).is_err());
+    }
+}
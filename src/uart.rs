@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::io::{stdout, Write};
+
+pub const UDR: u16 = 0x2C;
+const UCSRA: u16 = 0x2B;
+const RXC: usize = 7;
+const TXC: usize = 6;
+const UDRE: usize = 5;
+
+/// the usart of the atmega32
+/// Transmitting and receiving is done instantly,
+/// baud rate and frame format are ignored.
+pub struct Uart {
+    output: Vec<u8>,
+    // the number of transmitted bytes, which are kept in output
+    capture: usize,
+    input: VecDeque<u8>,
+    // the transmitted characters are also written to it, stdout by default
+    sink: Option<Box<dyn Write + Send>>,
+}
+
+impl Default for Uart {
+    fn default() -> Uart {
+        Uart::new()
+    }
+}
+
+impl Uart {
+    pub fn new() -> Uart {
+        Uart { output: Vec::new(), capture: usize::MAX, input: VecDeque::new(), sink: Some(Box::new(stdout())) }
+    }
+
+    /// enables or disables printing the transmitted characters to stdout
+    pub fn set_echo(&mut self, echo: bool) {
+        self.sink = if echo { Some(Box::new(stdout())) } else { None };
+    }
+
+    /// writes the transmitted characters to the sink instead of stdout
+    pub fn set_sink(&mut self, sink: Option<Box<dyn Write + Send>>) {
+        self.sink = sink;
+    }
+
+    pub fn take_sink(&mut self) -> Option<Box<dyn Write + Send>> {
+        self.sink.take()
+    }
+
+    /// the sink may buffer the transmitted characters until it is flushed,
+    /// e.g. when the program halts
+    pub fn flush(&mut self) {
+        if let Some(ref mut sink) = self.sink {
+            let _ = sink.flush();
+        }
+    }
+
+    /// keeps only the first limit transmitted bytes in output, 0 keeps none.
+    /// all bytes are kept by default
+    pub fn set_capture(&mut self, limit: usize) {
+        self.capture = limit;
+        self.output.truncate(limit);
+    }
+
+    /// the bytes, which were transmitted by the microcontroller, up to the capture limit
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// forgets the output after the first len bytes
+    pub fn truncate_output(&mut self, len: usize) {
+        self.output.truncate(len);
+    }
+
+    /// queues bytes, which the microcontroller can receive
+    pub fn send(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    /// the queued bytes, which weren't received yet
+    pub fn input(&self) -> Vec<u8> {
+        self.input.iter().cloned().collect()
+    }
+
+    pub fn set_input(&mut self, bytes: &[u8]) {
+        self.input = bytes.iter().cloned().collect();
+    }
+
+    #[inline]
+    pub fn read(&mut self, index: u16) -> Option<u8> {
+        match index {
+            UDR => Some(self.input.pop_front().unwrap_or(0)),
+            UCSRA => Some((!self.input.is_empty() as u8) << RXC | 1 << TXC | 1 << UDRE),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn peek(&self, index: u16) -> Option<u8> {
+        match index {
+            UDR => Some(self.input.front().cloned().unwrap_or(0)),
+            UCSRA => Some((!self.input.is_empty() as u8) << RXC | 1 << TXC | 1 << UDRE),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn write(&mut self, index: u16, val: u8) {
+        if index != UDR {
+            return;
+        }
+
+        if self.output.len() < self.capture {
+            self.output.push(val);
+        }
+        if let Some(ref mut sink) = self.sink {
+            // the output is only for the user, so errors are ignored
+            let _ = sink.write_all(&[val]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Uart, UDR};
+
+    #[test]
+    fn capture() {
+        let mut uart = Uart::new();
+        uart.set_sink(None);
+        uart.set_capture(2);
+        for &b in b"abc" {
+            uart.write(UDR, b);
+        }
+        assert_eq!(uart.output(), b"ab");
+        uart.set_capture(0);
+        assert_eq!(uart.output(), b"");
+    }
+}
@@ -0,0 +1,145 @@
+use std::ops::Range;
+use data::{Instruction, LDMode, LDType, LPMType, Register};
+use data::Instruction::*;
+use memory::SRAM_SIZE;
+
+const IO_START: usize = 0x20;
+const IO_END: usize = 0x60;
+
+/// a read of the cpu from a byte, which was never written
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+pub struct UninitRead {
+    // byte address of the instruction
+    pub pc: usize,
+    // in the data memory, the registers are at 0 - 31
+    pub addr: u16,
+}
+
+impl UninitRead {
+    /// the register, if it was no byte of the sram
+    pub fn register(&self) -> Option<u8> {
+        if self.addr < 32 { Some(self.addr as u8) } else { None }
+    }
+}
+
+/// a defined bit for every byte of the data memory like memcheck of valgrind,
+/// the io registers are defined after the reset, the registers and the sram
+/// only after they were written, e.g. by the c runtime for .data and .bss
+///
+/// copies with mov, movw, push, st and sts aren't reported and their
+/// destination is defined afterwards
+#[derive(Debug, Clone)]
+pub struct Shadow {
+    defined: Vec<bool>,
+}
+
+impl Default for Shadow {
+    fn default() -> Shadow {
+        Shadow::new()
+    }
+}
+
+impl Shadow {
+    pub fn new() -> Shadow {
+        let mut defined = vec![false; SRAM_SIZE];
+        for d in &mut defined[IO_START..IO_END] {
+            *d = true;
+        }
+        Shadow { defined: defined }
+    }
+
+    pub fn is_defined(&self, addr: u16) -> bool {
+        self.defined.get(addr as usize).cloned().unwrap_or(true)
+    }
+
+    #[inline(always)]
+    pub fn define(&mut self, addr: u16) {
+        if let Some(d) = self.defined.get_mut(addr as usize) {
+            *d = true;
+        }
+    }
+
+    pub fn define_range(&mut self, range: Range<usize>) {
+        for d in self.defined.iter_mut().take(range.end).skip(range.start) {
+            *d = true;
+        }
+    }
+}
+
+/// the registers, whose values the instruction uses, without the copies
+/// and the idioms like eor r1, r1, whose result doesn't depend on the value
+pub fn used_registers(instr: Instruction) -> Vec<Register> {
+    match instr {
+        EOR(rd, rr) | SUB(rd, rr) | SBC(rd, rr) | CP(rd, rr) | CPC(rd, rr) if rd == rr => vec![],
+        ADC(rd, rr) | ADD(rd, rr) | AND(rd, rr) | CP(rd, rr) | CPC(rd, rr) | CPSE(rd, rr) | EOR(rd, rr)
+            | MUL(rd, rr) | OR(rd, rr) | SBC(rd, rr) | SUB(rd, rr) => vec![rd, rr],
+        ADIW(reg, _) | SBIW(reg, _) => vec![reg, reg + 1],
+        ANDI(reg, _) | ASR(reg) | BLD_ST(_, reg, _) | COM(reg) | CPI(reg, _) | DEC(reg) | INC(reg)
+            | LSR(reg) | NEG(reg) | ORI(reg, _) | OUT(reg, _) | ROR(reg) | SBCI(reg, _) | SBR(_, reg, _)
+            | SUBI(reg, _) | SWAP(reg) => vec![reg],
+        ICALL | LPM(..) => vec![30, 31],
+        LD_ST(_, _, addrreg, _) => vec![addrreg, addrreg + 1],
+        _ => vec![],
+    }
+}
+
+/// the registers, which the instruction writes, the jit writes them
+/// without Memory::reg_mut
+pub fn written_registers(instr: Instruction) -> Vec<Register> {
+    match instr {
+        ADIW(reg, _) | SBIW(reg, _) | MOVW(reg, _) => vec![reg, reg + 1],
+        MUL(..) => vec![0, 1],
+        ADC(rd, _) | ADD(rd, _) | AND(rd, _) | EOR(rd, _) | MOV(rd, _) | OR(rd, _) | SBC(rd, _)
+            | SUB(rd, _) => vec![rd],
+        ANDI(reg, _) | ASR(reg) | BLD_ST(LDType::LD, reg, _) | COM(reg) | DEC(reg) | IN(reg, _)
+            | INC(reg) | LD_STS(LDType::LD, reg, _) | LDI(reg, _) | LPM(reg, LPMType::Z) | LSR(reg)
+            | NEG(reg) | ORI(reg, _) | POP(reg) | ROR(reg) | SBCI(reg, _) | SUBI(reg, _) | SWAP(reg) => vec![reg],
+        LPM(reg, LPMType::ZPostIncrement) => vec![reg, 30, 31],
+        LD_ST(LDType::LD, reg, _, LDMode::Displacement(_)) => vec![reg],
+        LD_ST(LDType::LD, reg, addrreg, _) => vec![reg, addrreg, addrreg + 1],
+        LD_ST(LDType::ST, _, addrreg, LDMode::PostIncrement)
+            | LD_ST(LDType::ST, _, addrreg, LDMode::PreDecrement) => vec![addrreg, addrreg + 1],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::Cpu;
+    use firmware::Firmware;
+    use memory::Memory;
+    use super::UninitRead;
+
+    fn cpu(program: &[u8]) -> Cpu {
+        let mem = Memory::from_firmware(&Firmware::from_bytes(program.to_vec()).unwrap(), None);
+        let mut cpu = Cpu::new(mem, false);
+        cpu.set_check_uninit(true);
+        cpu
+    }
+
+    fn reads(cpu: &mut Cpu, steps: usize) -> Vec<Option<UninitRead>> {
+        (0..steps).map(|_| { cpu.step(); cpu.uninit_read() }).collect()
+    }
+
+    #[test]
+    fn memory() {
+        // lds r17, 0x100; ldi r16, 0x42; sts 0x100, r16; lds r17, 0x100
+        let mut cpu = cpu(&[0x10, 0x91, 0x00, 0x01, 0x02, 0xe4, 0x00, 0x93, 0x00, 0x01, 0x10, 0x91, 0x00, 0x01]);
+        assert!(!cpu.mem().is_defined(0x100));
+        assert_eq!(reads(&mut cpu, 4), vec![Some(UninitRead { pc: 0, addr: 0x100 }), None, None, None]);
+        assert!(cpu.mem().is_defined(0x100));
+    }
+
+    #[test]
+    fn registers() {
+        // eor r1, r1; mov r20, r21; dec r20; add r18, r19
+        let program = [0x11, 0x24, 0x45, 0x2f, 0x4a, 0x95, 0x23, 0x0f];
+        let mut cpu = cpu(&program);
+        assert_eq!(reads(&mut cpu, 4), vec![None, None, None, Some(UninitRead { pc: 6, addr: 18 })]);
+        assert_eq!(cpu.uninit_read().and_then(|r| r.register()), Some(18));
+
+        let mut cpu = self::cpu(&program);
+        cpu.set_check_uninit(false);
+        assert_eq!(reads(&mut cpu, 4), vec![None; 4]);
+    }
+}
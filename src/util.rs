@@ -21,13 +21,23 @@ macro_rules! try_opt_void(
     ($e:expr) => (match $e { Some(e) => e, None => return })
 );
 
+/// parses a decimal or hex number with 0x prefix, underscores are ignored
+pub fn parse_num(s: &str) -> Option<u64> {
+    let s = s.replace('_', "");
+    if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
 #[inline(always)]
 pub fn bits(b: u16, start: u8, len: u8) -> u8 {
     bits16(b, start, len) as u8
 }
 #[inline(always)]
 pub fn bits16(b: u16, start: u8, len: u8) -> u16 {
-    (b >> start & ((1 << len) - 1))
+    b >> start & ((1 << len) - 1)
 }
 
 #[inline(always)]
@@ -0,0 +1,253 @@
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use cpu::Cpu;
+use debugger::IO_NAMES;
+use io::{IO, Wire};
+
+const IO_OFFSET: u16 = 0x20;
+const PS_PER_SECOND: u64 = 1_000_000_000_000;
+
+enum Source {
+    // the wire is recorded as 0 or 1, or in mV with analog
+    Wire(Arc<Wire>, bool),
+    // the address of the low byte in the data memory and the width in bits
+    Register(u16, usize),
+}
+
+struct Var {
+    name: String,
+    scope: &'static str,
+    source: Source,
+    last: Option<u16>,
+}
+
+/// records the changes of wires and io registers in a value change dump,
+/// which can be viewed e.g. with GTKWave
+///
+/// the wires are recorded, when they are set, and the registers are compared after
+/// each step, the changes get the cycle of the step as timestamp
+pub struct Vcd {
+    out: Box<dyn Write + Send>,
+    vars: Vec<Var>,
+    // indices of the wires, which were set since the last step
+    changed: Arc<Mutex<Vec<usize>>>,
+    // length of a cycle
+    period_ps: u64,
+    started: bool,
+    time: Option<u64>,
+}
+
+impl Vcd {
+    pub fn new(out: Box<dyn Write + Send>, f_cpu: u64) -> Vcd {
+        Vcd {
+            out: out, vars: Vec::new(), changed: Arc::new(Mutex::new(Vec::new())),
+            period_ps: PS_PER_SECOND / f_cpu, started: false, time: None,
+        }
+    }
+
+    /// records the wire, as 0 and 1 or its voltage in mV with analog
+    pub fn add_wire(&mut self, scope: &'static str, name: &str, wire: &Arc<Wire>, analog: bool) {
+        assert!(!self.started, "wires must be added before the first step");
+        let index = self.vars.len();
+        let changed = self.changed.clone();
+        wire.add_listener(move || changed.lock().unwrap().push(index));
+        self.vars.push(Var {
+            name: name.to_string(), scope: scope, source: Source::Wire(wire.clone(), analog), last: None,
+        });
+    }
+
+    /// records all pins PA0 - PD7
+    pub fn add_pins(&mut self, io: &IO) {
+        for (port, wires) in io.p.iter().enumerate() {
+            let letter = (b'A' + port as u8) as char;
+            for (pin, wire) in wires.iter().enumerate() {
+                self.add_wire("pins", &format!("P{}{}", letter, pin), wire, false);
+            }
+        }
+    }
+
+    /// records the io register, e.g. TIFR, pairs like TCNT1 are recorded with 16 bits,
+    /// returns false, if the register is unknown
+    pub fn add_register(&mut self, name: &str) -> bool {
+        assert!(!self.started, "registers must be added before the first step");
+        let find = |name: &str| IO_NAMES.iter().position(|n| *n == name).map(|i| i as u16 + IO_OFFSET);
+        let source = match (find(name), find(&format!("{}L", name)), find(&format!("{}H", name))) {
+            (Some(addr), _, _) => Source::Register(addr, 8),
+            (None, Some(low), Some(high)) if high == low + 1 => Source::Register(low, 16),
+            _ => return false,
+        };
+        self.vars.push(Var { name: name.to_string(), scope: "io", source: source, last: None });
+        true
+    }
+
+    /// writes the changes since the last step with the cycles of the cpu
+    pub fn step(&mut self, cpu: &Cpu) -> io::Result<()> {
+        if !self.started {
+            self.write_header()?;
+            self.started = true;
+        }
+        let changed: Vec<usize> = self.changed.lock().unwrap().drain(..).collect();
+        let mut lines = String::new();
+        for (i, var) in self.vars.iter_mut().enumerate() {
+            let value = match var.source {
+                Source::Wire(ref wire, analog) if var.last.is_none() || changed.contains(&i) =>
+                    if analog { wire.mv() } else { wire.as_bin() as u16 },
+                Source::Register(addr, width) => {
+                    let low = cpu.mem().data(addr) as u16;
+                    if width == 16 { low | (cpu.mem().data(addr + 1) as u16) << 8 } else { low }
+                },
+                _ => continue,
+            };
+            if var.last != Some(value) {
+                var.last = Some(value);
+                lines.push_str(&format_value(var, value, &id(i)));
+                lines.push('\n');
+            }
+        }
+
+        if self.time.is_none() {
+            write!(self.out, "#{}\n$dumpvars\n{}$end\n", cpu.cycles() * self.period_ps, lines)?;
+            self.time = Some(cpu.cycles());
+        } else if !lines.is_empty() {
+            if self.time != Some(cpu.cycles()) {
+                writeln!(self.out, "#{}", cpu.cycles() * self.period_ps)?;
+                self.time = Some(cpu.cycles());
+            }
+            self.out.write_all(lines.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        write!(self.out, "$version avr-vm {} $end\n$timescale 1 ps $end\n$scope module avr $end\n",
+               env!("CARGO_PKG_VERSION"))?;
+        let mut scope = None;
+        for (i, var) in self.vars.iter().enumerate() {
+            if scope != Some(var.scope) {
+                if scope.is_some() {
+                    writeln!(self.out, "$upscope $end")?;
+                }
+                writeln!(self.out, "$scope module {} $end", var.scope)?;
+                scope = Some(var.scope);
+            }
+            let (kind, width) = match var.source {
+                Source::Wire(_, true) => ("real", 64),
+                Source::Wire(_, false) => ("wire", 1),
+                Source::Register(_, width) => ("reg", width),
+            };
+            writeln!(self.out, "$var {} {} {} {} $end", kind, width, id(i), var.name)?;
+        }
+        if scope.is_some() {
+            writeln!(self.out, "$upscope $end")?;
+        }
+        writeln!(self.out, "$upscope $end\n$enddefinitions $end")
+    }
+}
+
+fn format_value(var: &Var, value: u16, id: &str) -> String {
+    match var.source {
+        Source::Wire(_, true) => format!("r{} {}", value, id),
+        Source::Wire(_, false) => format!("{}{}", value, id),
+        Source::Register(_, width) => format!("b{:0width$b} {}", value, id, width = width),
+    }
+}
+
+// the identifiers of the variables use the printable characters ! to ~
+fn id(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+    use std::sync::{Arc, Mutex};
+    use cpu::Cpu;
+    use firmware::Firmware;
+    use io::IO;
+    use memory::Memory;
+    use super::{id, Vcd};
+
+    // ldi r16, 0xff; out DDRB, r16; out PORTB, r16; ldi r16, 0; out PORTB, r16; rjmp .-2
+    const BLINK: [u8; 12] = [0x0f, 0xef, 0x07, 0xbb, 0x08, 0xbb, 0x00, 0xe0, 0x08, 0xbb, 0xff, 0xcf];
+
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dump() {
+        let io = Arc::new(IO::new());
+        let mem = Memory::from_firmware(&Firmware::from_bytes(BLINK.to_vec()).unwrap(), Some(io.clone()));
+        let mut cpu = Cpu::new(mem, false);
+        cpu.set_single_step(true);
+        let out = Shared(Arc::new(Mutex::new(Vec::new())));
+        let mut vcd = Vcd::new(Box::new(out.clone()), 8_000_000);
+        vcd.add_wire("pins", "PB0", &io.p[1][0], false);
+        vcd.add_wire("analog", "PA7", &io.p[0][7], true);
+        assert!(vcd.add_register("PORTB"));
+        assert!(vcd.add_register("TCNT1"));
+        assert!(!vcd.add_register("FOO"));
+
+        vcd.step(&cpu).unwrap();
+        for _ in 0..6 {
+            cpu.step();
+            vcd.step(&cpu).unwrap();
+        }
+        io.p[0][7].set(2500);
+        vcd.step(&cpu).unwrap();
+        let out = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let body = &out[out.find("$enddefinitions $end\n").unwrap()..];
+        assert!(out.contains("$scope module pins $end\n$var wire 1 ! PB0 $end\n$upscope $end\n"));
+        assert!(out.contains("$var real 64 \" PA7 $end\n"));
+        assert!(out.contains("$var reg 8 # PORTB $end\n$var reg 16 $ TCNT1 $end\n"));
+        // 125000 ps per cycle, PORTB is set in cycle 3 and cleared in 5
+        assert_eq!(body, "$enddefinitions $end
+#0
+$dumpvars
+0!
+r0 \"
+b00000000 #
+b0000000000000000 $
+$end
+#375000
+1!
+b11111111 #
+#625000
+0!
+b00000000 #
+#750000
+r2500 \"
+");
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!(id(0), "!");
+        assert_eq!(id(93), "~");
+        assert_eq!(id(94), "!!");
+        assert_eq!(id(95), "\"!");
+    }
+}
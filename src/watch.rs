@@ -0,0 +1,66 @@
+/// the accesses, which trigger a watchpoint
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum WatchKind {
+    Read, Write,
+    // reads and writes
+    Access,
+}
+
+/// a range in the data memory, the cpu stops after an instruction accessed it
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Watchpoint {
+    pub addr: u16,
+    pub len: u16,
+    pub kind: WatchKind,
+    // only accesses with this value trigger it
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    pub fn new(addr: u16, len: u16, kind: WatchKind) -> Watchpoint {
+        Watchpoint { addr: addr, len: len, kind: kind, value: None }
+    }
+
+    /// only triggers, if the value is read or written
+    pub fn with_value(self, value: u8) -> Watchpoint {
+        Watchpoint { value: Some(value), ..self }
+    }
+
+    pub fn matches(&self, addr: u16, write: bool, value: u8) -> bool {
+        let kind = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        };
+        kind && addr >= self.addr && (addr - self.addr) < self.len && self.value.is_none_or(|v| v == value)
+    }
+}
+
+/// the first access of an instruction, which triggered a watchpoint
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    // byte address of the instruction
+    pub pc: usize,
+    pub addr: u16,
+    pub write: bool,
+    // the value before and after the access, both are the read value for reads
+    pub old: u8,
+    pub new: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Watchpoint, WatchKind};
+
+    #[test]
+    fn matches() {
+        let w = Watchpoint::new(0x60, 2, WatchKind::Write);
+        assert!(w.matches(0x60, true, 1) && w.matches(0x61, true, 1));
+        assert!(!w.matches(0x5f, true, 1) && !w.matches(0x62, true, 1) && !w.matches(0x60, false, 1));
+        let r = Watchpoint::new(0x60, 1, WatchKind::Read).with_value(0x42);
+        assert!(r.matches(0x60, false, 0x42));
+        assert!(!r.matches(0x60, false, 0x41) && !r.matches(0x60, true, 0x42));
+        assert!(Watchpoint::new(0x60, 1, WatchKind::Access).matches(0x60, false, 0));
+    }
+}
@@ -2,12 +2,12 @@ use gtk;
 use gtk::prelude::*;
 use gui::Gui;
 use io::{Wire, HIGH, LOW};
-use std::rc::Rc;
+use std::sync::Arc;
 
 pub struct Button { }
 
 impl Button {
-    pub fn new(gui: &mut Gui, name: &str, wire: Rc<Wire>) -> Button {
+    pub fn new(gui: &mut Gui, name: &str, wire: Arc<Wire>) -> Button {
         let button = gtk::Button::new();
         button.show();
         gui.add(name, &button);
@@ -3,21 +3,19 @@ use gtk::prelude::*;
 use gdk_pixbuf::{Colorspace, Pixbuf, PixbufExt};
 use gui::Gui;
 use io::Wire;
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::Arc;
 
-struct LedState {
+pub struct Led {
     image: gtk::Image,
     icon: [Pixbuf; 2],
+    on: bool,
 
-    wire_a: Rc<Wire>,
-    wire_b: Rc<Wire>,
+    wire_a: Arc<Wire>,
+    wire_b: Arc<Wire>,
 }
 
-pub struct Led { }
-
 impl Led {
-    pub fn new(gui: &mut Gui, name: &str, r: u8, g: u8, b: u8, porta: Rc<Wire>, portb: Rc<Wire>) -> Led {
+    pub fn new(gui: &mut Gui, name: &str, r: u8, g: u8, b: u8, porta: Arc<Wire>, portb: Arc<Wire>) -> Led {
         assert!((r as u32) + (g as u32) + (b as u32) >= 0xff);
 
         let mut icon = [
@@ -33,26 +31,23 @@ impl Led {
 
         gui.add(name, &image);
 
-        let state = Rc::new(RefCell::new(LedState {
+        Led {
             image: image,
             icon: icon,
-            wire_a: porta.clone(),
-            wire_b: portb.clone(),
-        }));
-
-        state.borrow_mut().update();
-        let statea = state.clone();
-        porta.add_listener(move || {statea.borrow_mut().update();});
-        let stateb = state.clone();
-        portb.add_listener(move || {stateb.borrow_mut().update();});
-
-        Led { }
+            on: true,
+            wire_a: porta,
+            wire_b: portb,
+        }
     }
-}
 
-impl LedState {
-    fn update(&mut self) {
-        self.image.set_from_pixbuf(Some(&self.icon[if self.wire_b.mv() < self.wire_a.mv() { 1 } else { 0 }]));
+    // the wires are polled, because the gtk widgets
+    // can't be accessed from the listeners of the wires
+    pub fn step(&mut self) {
+        let on = self.wire_b.mv() < self.wire_a.mv();
+        if on != self.on {
+            self.on = on;
+            self.image.set_from_pixbuf(Some(&self.icon[on as usize]));
+        }
     }
 }
 
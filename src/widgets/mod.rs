@@ -2,8 +2,10 @@ mod button;
 mod led;
 mod poti;
 mod seg7;
+mod spicboard;
 
 pub use self::button::Button;
 pub use self::led::Led;
 pub use self::poti::Poti;
 pub use self::seg7::Seg7;
+pub use self::spicboard::SPiCboard;
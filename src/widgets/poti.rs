@@ -1,41 +1,46 @@
 use gtk;
 use gtk::prelude::*;
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
 use io::Wire;
 use gui::Gui;
 
 struct PotiState {
-    adjustment: gtk::Adjustment,
+    // the value of the adjustment, it is copied,
+    // because the listeners of the wires can't access gtk widgets
+    value: f64,
 
-    wire_left: Rc<Wire>,
-    wire_middle: Rc<Wire>,
-    wire_right: Rc<Wire>,
+    wire_left: Arc<Wire>,
+    wire_middle: Arc<Wire>,
+    wire_right: Arc<Wire>,
 }
 
 pub struct Poti { }
 
 impl Poti {
-    pub fn new(gui: &mut Gui, name: &str, wire_left: Rc<Wire>, wire_middle: Rc<Wire>, wire_right: Rc<Wire>) -> Poti {
+    pub fn new(gui: &mut Gui, name: &str, wire_left: Arc<Wire>, wire_middle: Arc<Wire>, wire_right: Arc<Wire>) -> Poti {
         let adjustment = gtk::Adjustment::new(0.0, 0.0, 110.0, 5.0, 10.0, 10.0);
         let scale = gtk::Scale::new(gtk::Orientation::Horizontal, Some(&adjustment));
         scale.show();
         gui.add(name, &scale);
 
-        let state = Rc::new(RefCell::new(PotiState {
-            adjustment: adjustment,
-            wire_left: wire_left,
+        let state = Arc::new(Mutex::new(PotiState {
+            value: adjustment.get_value(),
+            wire_left: wire_left.clone(),
             wire_middle: wire_middle,
-            wire_right: wire_right,
+            wire_right: wire_right.clone(),
         }));
-        state.borrow_mut().update();
+        state.lock().unwrap().update();
 
         let state_adj = state.clone();
-        state.borrow().adjustment.connect_value_changed(move |_| { state_adj.borrow_mut().update(); });
+        adjustment.connect_value_changed(move |adj| {
+            let mut state = state_adj.lock().unwrap();
+            state.value = adj.get_value();
+            state.update();
+        });
         let state_left = state.clone();
-        state.borrow().wire_left.add_listener(move || { state_left.borrow_mut().update(); });
+        wire_left.add_listener(move || { state_left.lock().unwrap().update(); });
         let state_right = state.clone();
-        state.borrow().wire_right.add_listener(move || { state_right.borrow_mut().update(); });
+        wire_right.add_listener(move || { state_right.lock().unwrap().update(); });
 
         Poti { }
     }
@@ -45,6 +50,6 @@ impl PotiState {
     fn update(&mut self) {
         self.wire_middle.set((self.wire_left.mv() as f64
                               + (self.wire_right.mv() as f64 - self.wire_left.mv() as f64)
-                              * self.adjustment.get_value() / 100.0) as u16);
+                              * self.value / 100.0) as u16);
     }
 }
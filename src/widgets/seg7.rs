@@ -5,6 +5,7 @@ use gdk::WindowExt;
 use cairo::Context;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::Arc;
 use io::Wire;
 use gui::Gui;
 
@@ -17,12 +18,12 @@ const LSPACE: f64 = 16.0;
 struct Seg7Data {
     drawing_area: gtk::DrawingArea,
 
-    wires: [Rc<Wire>; 8],
+    wires: [Arc<Wire>; 8],
     sum_seg: [u32; 8],
     state: [bool; 8],
 
-    wire_anode0: Rc<Wire>,
-    _wire_anode1: Rc<Wire>,
+    wire_anode0: Arc<Wire>,
+    _wire_anode1: Arc<Wire>,
 }
 
 pub struct Seg7 {
@@ -84,9 +85,9 @@ fn draw_dot(cr: &Context, state: bool, x: f64, y: f64) {
 
 impl Seg7 {
     pub fn new(gui: &mut Gui, name: &str,
-               wire_e: Rc<Wire>, wire_d: Rc<Wire>, wire_anode0: Rc<Wire>, wire_c: Rc<Wire>,
-               wire_dp: Rc<Wire>, wire_b: Rc<Wire>, wire_a: Rc<Wire>, wire_anode1: Rc<Wire>,
-               wire_f: Rc<Wire>, wire_g: Rc<Wire>) -> Seg7 {
+               wire_e: Arc<Wire>, wire_d: Arc<Wire>, wire_anode0: Arc<Wire>, wire_c: Arc<Wire>,
+               wire_dp: Arc<Wire>, wire_b: Arc<Wire>, wire_a: Arc<Wire>, wire_anode1: Arc<Wire>,
+               wire_f: Arc<Wire>, wire_g: Arc<Wire>) -> Seg7 {
         let area = gtk::DrawingArea::new();
         area.set_size_request((OFFX + LX + OFFX) as i32, (OFFY + 2. * LY + OFFY) as i32);
         area.show();
@@ -0,0 +1,46 @@
+use std::sync::Arc;
+use gui::Gui;
+use io::IO;
+use super::{Button, Led, Poti, Seg7};
+
+/// the widgets of the SPiCboard connected to the pins of the microcontroller
+pub struct SPiCboard {
+    leds: Vec<Led>,
+    dis1: Seg7,
+    dis2: Seg7,
+}
+
+impl SPiCboard {
+    pub fn new(gui: &mut Gui, io: &Arc<IO>) -> SPiCboard {
+        let mut leds = vec![];
+        leds.push(Led::new(gui, "red0", 0xff, 0x00, 0x00, io.vcc.clone(), io.p[3][7].clone()));
+        leds.push(Led::new(gui, "green0", 0x00, 0xff, 0x00, io.vcc.clone(), io.p[2][0].clone()));
+        leds.push(Led::new(gui, "yellow0", 0xff, 0xff, 0x00, io.vcc.clone(), io.p[2][1].clone()));
+        leds.push(Led::new(gui, "blue0", 0x00, 0x00, 0xff, io.vcc.clone(), io.p[2][6].clone()));
+        leds.push(Led::new(gui, "red1", 0xff, 0x00, 0x00, io.vcc.clone(), io.p[2][7].clone()));
+        leds.push(Led::new(gui, "green1", 0x00, 0xff, 0x00, io.vcc.clone(), io.p[0][7].clone()));
+        leds.push(Led::new(gui, "yellow1", 0xff, 0xff, 0x00, io.vcc.clone(), io.p[0][6].clone()));
+        leds.push(Led::new(gui, "blue1", 0x00, 0x00, 0xff, io.vcc.clone(), io.p[0][5].clone()));
+        let _ = Button::new(gui, "button0", io.p[3][3].clone());
+        let _ = Button::new(gui, "button1", io.p[3][2].clone());
+        let _ = Poti::new(gui, "potentiometer", io.gnd.clone(), io.p[0][1].clone(), io.vcc.clone());
+        let _ = Poti::new(gui, "light sensor", io.gnd.clone(), io.p[0][0].clone(), io.vcc.clone());
+        let dis2 = Seg7::new(gui, "dis2", io.p[1][1].clone(), io.p[1][0].clone(), io.p[3][1].clone(),
+                             io.p[1][6].clone(), io.vcc.clone(), io.p[1][5].clone(), io.p[1][4].clone(),
+                             io.p[3][1].clone(), io.p[1][3].clone(), io.p[1][2].clone());
+        let dis1 = Seg7::new(gui, "dis1", io.p[1][1].clone(), io.p[1][0].clone(), io.p[3][0].clone(),
+                             io.p[1][6].clone(), io.vcc.clone(), io.p[1][5].clone(), io.p[1][4].clone(),
+                             io.p[3][0].clone(), io.p[1][3].clone(), io.p[1][2].clone());
+
+        SPiCboard { leds: leds, dis1: dis1, dis2: dis2 }
+    }
+
+    /// updates the widgets, which show the state of the wires
+    pub fn step(&mut self) {
+        for led in self.leds.iter_mut() {
+            led.step();
+        }
+        self.dis1.step();
+        self.dis2.step();
+    }
+}
@@ -0,0 +1,14 @@
+#include "semihost.h"
+
+void
+main(void)
+{
+	sh_puts(1, "Hallo Host!\n");
+	if (sh_time() == 0) {
+		sh_exit(1);
+	}
+	if (sh_close(42) != SH_ERROR) {
+		sh_exit(2);
+	}
+	sh_exit(3);
+}
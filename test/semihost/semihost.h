@@ -0,0 +1,90 @@
+#ifndef SEMIHOST_H
+#define SEMIHOST_H
+
+#include <inttypes.h>
+#include <string.h>
+
+/* host calls of the vm, they need to be enabled with --semihosting */
+
+#define SH_ARG(i) (*(volatile uint16_t *) (0xfff0 + 2 * (i)))
+#define SH_CMD (*(volatile uint8_t *) 0xfff6)
+#define SH_RET (*(volatile uint16_t *) 0xfff7)
+
+#define SH_EXIT  1
+#define SH_WRITE 2
+#define SH_READ  3
+#define SH_OPEN  4
+#define SH_CLOSE 5
+#define SH_TIME  6
+#define SH_CLOCK 7
+
+#define SH_ERROR 0xffff
+
+static inline uint16_t
+sh_call(uint8_t cmd, uint16_t a0, uint16_t a1, uint16_t a2)
+{
+	SH_ARG(0) = a0;
+	SH_ARG(1) = a1;
+	SH_ARG(2) = a2;
+	SH_CMD = cmd;
+	return SH_RET;
+}
+
+static inline void
+sh_exit(uint8_t status)
+{
+	sh_call(SH_EXIT, status, 0, 0);
+	for (;;) {
+	}
+}
+
+static inline uint16_t
+sh_write(uint16_t fd, const void *buf, uint16_t len)
+{
+	return sh_call(SH_WRITE, fd, (uint16_t) buf, len);
+}
+
+static inline uint16_t
+sh_puts(uint16_t fd, const char *str)
+{
+	return sh_write(fd, str, strlen(str));
+}
+
+static inline uint16_t
+sh_read(uint16_t fd, void *buf, uint16_t len)
+{
+	return sh_call(SH_READ, fd, (uint16_t) buf, len);
+}
+
+/* mode 0 reads, 1 writes and truncates, 2 appends */
+static inline uint16_t
+sh_open(const char *path, uint16_t mode)
+{
+	return sh_call(SH_OPEN, (uint16_t) path, mode, 0);
+}
+
+static inline uint16_t
+sh_close(uint16_t fd)
+{
+	return sh_call(SH_CLOSE, fd, 0, 0);
+}
+
+/* unix time of the host in seconds */
+static inline uint32_t
+sh_time(void)
+{
+	uint32_t t;
+	sh_call(SH_TIME, (uint16_t) &t, 0, 0);
+	return t;
+}
+
+/* milliseconds since the start of the vm */
+static inline uint32_t
+sh_clock(void)
+{
+	uint32_t t;
+	sh_call(SH_CLOCK, (uint16_t) &t, 0, 0);
+	return t;
+}
+
+#endif
@@ -0,0 +1,136 @@
+//! golden tests, which run the programs in ./test on the harness
+
+extern crate avr_vm;
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::prelude::*;
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use avr_vm::harness::{AdcDevice, Board, Button, Harness};
+
+const TIMEOUT: u64 = 50_000_000;
+
+// builds the elf file of a test program with its makefile
+fn firmware(dir: &str, name: &str) -> OsString {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/test/").to_string() + dir;
+    let elf = name.to_string() + ".elf";
+    let o = Command::new("make").arg("-C").arg(&dir).arg(&elf).output().expect("make failed");
+    assert!(o.status.success(), "make: {}", String::from_utf8_lossy(&o.stderr));
+    OsString::from(dir + "/" + &elf)
+}
+
+// the expected uart output of a test program
+fn golden(dir: &str, name: &str) -> String {
+    let mut expected = String::new();
+    File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/test/").to_string() + dir + "/" + name + ".expected")
+        .and_then(|mut f| f.read_to_string(&mut expected))
+        .expect("golden file missing");
+    expected
+}
+
+#[test]
+fn simple() {
+    let mut h = Harness::new(firmware("simple", "simple")).unwrap();
+    assert!(h.run_until_uart(TIMEOUT, "Hallo VM!\nHallo VM!\n"));
+    h.assert_uart("Hallo VM!\nHallo VM!\n");
+}
+
+#[test]
+fn calc() {
+    let mut h = Harness::new(firmware("calc", "calc")).unwrap();
+    let expected = golden("calc", "calc");
+    assert!(h.run_until(TIMEOUT, |h| h.uart_output().len() >= expected.len()));
+    h.run(100_000);
+    h.assert_uart(&expected);
+}
+
+#[test]
+fn jump() {
+    let mut h = Harness::new(firmware("jump", "jump")).unwrap();
+    let expected = golden("jump", "jump");
+    assert!(h.run_until(10 * TIMEOUT, |h| h.uart_output().len() >= expected.len()));
+    h.run(100_000);
+    h.assert_uart(&expected);
+}
+
+#[test]
+fn led() {
+    let mut h = Harness::new(firmware("led", "led")).unwrap();
+    for i in 0..8 {
+        assert!(h.run_until(TIMEOUT, |h| h.board().leds() == 1 << i), "led {} not on", i);
+        assert!(h.board().led(i));
+    }
+    assert!(h.run_until(TIMEOUT, |h| h.board().leds() == 0xff));
+}
+
+#[test]
+fn seg7() {
+    let mut h = Harness::new(firmware("seg7", "seg7")).unwrap();
+    h.run(100_000);
+    assert_eq!(h.board().seg7_text(), "00");
+    assert!(h.run_until(TIMEOUT, |h| h.board().seg7_text() == "01"));
+    assert!(h.run_until(TIMEOUT, |h| h.board().seg7_text() == "02"));
+}
+
+#[test]
+fn button() {
+    let mut h = Harness::new(firmware("button", "button")).unwrap();
+    h.run(1_000_000);
+    assert_eq!(h.board().leds(), 0x00);
+    h.board().press(Button::Button0);
+    assert!(h.run_until(TIMEOUT, |h| h.board().leds() == 0xf0));
+    h.board().release(Button::Button0);
+    h.board().press(Button::Button1);
+    assert!(h.run_until(TIMEOUT, |h| h.board().leds() == 0xff));
+}
+
+#[test]
+fn adc() {
+    let board = Arc::new(Board::new());
+    board.set_adc(AdcDevice::Poti, 5000);
+    board.set_adc(AdcDevice::Photo, 0);
+    let mut h = Harness::with_board(board.clone(), firmware("adc", "adc")).unwrap();
+    assert!(h.run_until(TIMEOUT, |_| board.leds() == (1 << 3) | (1 << 4)));
+    board.set_adc(AdcDevice::Poti, 0);
+    assert!(h.run_until(TIMEOUT, |_| board.leds() == (1 << 0) | (1 << 4)));
+}
+
+#[test]
+fn boardtest() {
+    let mut h = Harness::new(firmware("boardtest", "boardtest")).unwrap();
+    h.run(1_000_000);
+    h.assert_symbol("mode", 1);
+    assert!(h.board().seg7_text() != "  ");
+
+    h.board().press(Button::Button0);
+    assert!(h.run_until(TIMEOUT, |h| h.read_symbol_uint("mode") == 0));
+    h.board().release(Button::Button0);
+    h.run(1_000_000);
+    h.board().press(Button::Button1);
+    assert!(h.run_until(TIMEOUT, |h| h.read_symbol_uint("mode") == 3));
+}
+
+#[test]
+fn semihost() {
+    let mut h = Harness::new(firmware("semihost", "semihost")).unwrap();
+    h.set_semihosting(true);
+    assert!(!h.run(TIMEOUT));
+    assert_eq!(h.exit_status(), Some(3));
+}
+
+#[test]
+fn parallel() {
+    let threads: Vec<_> = (0..4).map(|_| {
+        let mut h = Harness::new(firmware("calc", "calc")).unwrap();
+        thread::spawn(move || {
+            h.run_until(TIMEOUT, |h| h.uart_output().contains("fac(9)"));
+            h.uart_output()
+        })
+    }).collect();
+
+    for t in threads {
+        assert!(t.join().unwrap().starts_with(&golden("calc", "calc")[..100]));
+    }
+}